@@ -35,6 +35,7 @@ impl Lang {
 /// here.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum ActionKind {
+  CompileSourceToBc,
   AddDeviceLibs,
   LinkBcToBc,
   LinkRelocToExe,
@@ -46,6 +47,7 @@ pub enum ActionKind {
 impl ActionKind {
   pub(crate) fn to_sys(&self) -> sys::amd_comgr_action_kind_s {
     match self {
+      CompileSourceToBc => sys::AMD_COMGR_ACTION_COMPILE_SOURCE_TO_BC,
       AddDeviceLibs => sys::AMD_COMGR_ACTION_ADD_DEVICE_LIBRARIES,
       LinkBcToBc => sys::AMD_COMGR_ACTION_LINK_BC_TO_BC,
       LinkRelocToExe => sys::AMD_COMGR_ACTION_LINK_RELOCATABLE_TO_EXECUTABLE,
@@ -0,0 +1,311 @@
+//! Disassembly of executable code objects, through comgr's LLVM MC disassembler.
+
+use std::ffi::{CStr, CString, };
+use std::fmt::Write;
+use std::num::NonZeroU64;
+use std::os::raw::{c_char, c_void, };
+use std::ptr;
+
+use sys;
+
+use crate::data::{Data, ExecutableData, };
+use crate::error::Error;
+use crate::set::DataSet;
+use crate::symbol::{Symbol, SymbolKind, };
+
+const PT_LOAD: u32 = 1;
+
+/// A loadable segment of an ELF file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Segment {
+  vaddr: u64,
+  offset: u64,
+  filesz: u64,
+}
+/// The `PT_LOAD` segments of a little endian ELF64 image, which is all comgr produces.
+fn load_segments(elf: &[u8]) -> Vec<Segment> {
+  fn read<T: Copy + Default>(elf: &[u8], at: u64, f: fn(&[u8]) -> T, len: usize) -> T {
+    elf.get(at as usize..(at as usize).saturating_add(len))
+      .map(f)
+      .unwrap_or_default()
+  }
+  fn u16_at(elf: &[u8], at: u64) -> u16 {
+    read(elf, at, |b| u16::from_le_bytes([b[0], b[1]]), 2)
+  }
+  fn u32_at(elf: &[u8], at: u64) -> u32 {
+    read(elf, at, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]), 4)
+  }
+  fn u64_at(elf: &[u8], at: u64) -> u64 {
+    read(elf, at, |b| {
+      let mut v = [0u8; 8];
+      v.copy_from_slice(b);
+      u64::from_le_bytes(v)
+    }, 8)
+  }
+
+  // magic, ELFCLASS64, ELFDATA2LSB
+  if !elf.starts_with(b"\x7fELF\x02\x01") {
+    return vec![];
+  }
+
+  let phoff = u64_at(elf, 0x20);
+  let phentsize = u16_at(elf, 0x36) as u64;
+  let phnum = u16_at(elf, 0x38) as u64;
+  (0..phnum)
+    .map(|i| phoff.saturating_add(i * phentsize) )
+    .filter(|&ph| u32_at(elf, ph) == PT_LOAD )
+    .map(|ph| {
+      Segment {
+        offset: u64_at(elf, ph.saturating_add(0x08)),
+        vaddr: u64_at(elf, ph.saturating_add(0x10)),
+        filesz: u64_at(elf, ph.saturating_add(0x20)),
+      }
+    })
+    .collect()
+}
+
+struct State<'a> {
+  elf: &'a [u8],
+  segments: Vec<Segment>,
+  /// The text of the current instruction.
+  text: String,
+  /// Addresses referenced by the current instruction, eg branch targets.
+  targets: Vec<u64>,
+}
+impl<'a> State<'a> {
+  /// The file contents at `vaddr`, up to `len` bytes, which may be fewer if the segment
+  /// ends first.
+  fn read(&self, vaddr: u64, len: u64) -> &'a [u8] {
+    let elf = self.elf;
+    self.segments.iter()
+      .find(|s| s.vaddr <= vaddr && vaddr - s.vaddr < s.filesz )
+      .and_then(|s| {
+        let start = s.offset + (vaddr - s.vaddr);
+        let end = start + len.min(s.filesz - (vaddr - s.vaddr));
+        elf.get(start as usize..end as usize)
+      })
+      .unwrap_or(&[])
+  }
+}
+
+unsafe extern "C" fn read_memory(from: u64, to: *mut c_char, size: u64,
+                                 user_data: *mut c_void)
+  -> u64
+{
+  let state = &*(user_data as *const State);
+  let src = state.read(from, size);
+  ptr::copy_nonoverlapping(src.as_ptr(), to as *mut u8, src.len());
+  src.len() as u64
+}
+unsafe extern "C" fn print_instruction(instruction: *const c_char, user_data: *mut c_void) {
+  let state = &mut *(user_data as *mut State);
+  state.text.push_str(&CStr::from_ptr(instruction).to_string_lossy());
+}
+unsafe extern "C" fn print_address_annotation(address: u64, user_data: *mut c_void) {
+  let state = &mut *(user_data as *mut State);
+  state.targets.push(address);
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct DisassemblyInfo(NonZeroU64);
+impl !Sync for DisassemblyInfo { }
+impl !Send for DisassemblyInfo { }
+impl DisassemblyInfo {
+  pub fn new(isa_name: &str) -> Result<Self, Error> {
+    let isa_name = CString::new(isa_name)
+      .map_err(|_| Error::InvalidArgument )?;
+
+    let mut out = sys::amd_comgr_disassembly_info_s {
+      handle: 0,
+    };
+    let s = unsafe {
+      sys::amd_comgr_create_disassembly_info(isa_name.as_ptr(),
+                                             Some(read_memory),
+                                             Some(print_instruction),
+                                             Some(print_address_annotation),
+                                             &mut out as *mut _)
+    };
+    Error::check(s)?;
+
+    debug_assert_ne!(out.handle, 0);
+
+    unsafe {
+      Ok(DisassemblyInfo(NonZeroU64::new_unchecked(out.handle)))
+    }
+  }
+
+  fn handle(&self) -> sys::amd_comgr_disassembly_info_s {
+    sys::amd_comgr_disassembly_info_s {
+      handle: self.0.get(),
+    }
+  }
+
+  /// Disassemble `symbol` in the executable image `elf`. Each instruction is on its own line,
+  /// with its address and encoding. Addresses the instruction refers to are annotated with
+  /// the symbol they fall in, from `symbols`.
+  pub fn disassemble(&self, elf: &[u8], symbol: &Symbol, symbols: &[Symbol])
+    -> Result<String, Error>
+  {
+    let mut state = State {
+      elf,
+      segments: load_segments(elf),
+      text: String::new(),
+      targets: vec![],
+    };
+
+    let mut out = String::new();
+    writeln!(out, "{}:", symbol.name).unwrap();
+
+    let end = symbol.value + symbol.size;
+    let mut addr = symbol.value;
+    while addr < end {
+      state.text.clear();
+      state.targets.clear();
+
+      let mut size = 0u64;
+      let s = unsafe {
+        sys::amd_comgr_disassemble_instruction(self.handle(), addr,
+                                               &mut state as *mut State as *mut _,
+                                               &mut size)
+      };
+      let bytes = if Error::check(s).is_ok() && size != 0 {
+        state.read(addr, size)
+      } else {
+        // not a valid instruction; show a word of data instead.
+        let bytes = state.read(addr, 4.min(end - addr));
+        if bytes.is_empty() {
+          // outside of the image.
+          return Err(Error::InvalidArgument);
+        }
+        state.text.clear();
+        state.text.push_str(".long ");
+        for b in bytes.iter().rev() {
+          write!(state.text, "{:02x}", b).unwrap();
+        }
+        bytes
+      };
+
+      write!(out, "  {:016x}: {:<56} //", addr, state.text.trim()).unwrap();
+      for word in bytes.chunks(4) {
+        out.push(' ');
+        for b in word.iter().rev() {
+          write!(out, "{:02X}", b).unwrap();
+        }
+      }
+      for &target in state.targets.iter() {
+        write!(out, " -> {}", describe_address(target, symbols)).unwrap();
+      }
+      out.push('\n');
+
+      addr += bytes.len() as u64;
+    }
+
+    Ok(out)
+  }
+}
+impl Drop for DisassemblyInfo {
+  fn drop(&mut self) {
+    // XXX return status unchecked
+    unsafe {
+      sys::amd_comgr_destroy_disassembly_info(self.handle())
+    };
+  }
+}
+
+/// `symbol+0xoffset`, or just the address if it isn't in a symbol.
+fn describe_address(addr: u64, symbols: &[Symbol]) -> String {
+  let symbol = symbols.iter()
+    .filter(|s| !s.undefined && s.kind != SymbolKind::Section )
+    .find(|s| s.value <= addr && addr < s.value + s.size.max(1) );
+  match symbol {
+    Some(s) if s.value == addr => s.name.clone(),
+    Some(s) => format!("{}+0x{:x}", s.name, addr - s.value),
+    None => format!("0x{:x}", addr),
+  }
+}
+
+impl ExecutableData {
+  /// Disassemble the function `name`; see `DisassemblyInfo::disassemble`. Kernels are
+  /// named without the `.kd` suffix of their descriptor. Returns `None` if there is no such
+  /// symbol.
+  pub fn disassemble(&self, name: &str) -> Result<Option<String>, Error> {
+    let symbols = self.symbols()?;
+    let symbol = match symbols.iter().find(|s| s.name == name && !s.undefined ) {
+      Some(symbol) => symbol,
+      None => { return Ok(None); },
+    };
+
+    let isa_name = self.isa_name()
+      .map_err(|_| Error::InvalidArgument )?;
+    let info = DisassemblyInfo::new(&isa_name)?;
+    let elf = self.data()?;
+    info.disassemble(&elf, symbol, &symbols).map(Some)
+  }
+}
+impl DataSet {
+  /// Disassemble the function `name` from the first executable which defines it.
+  pub fn disassemble(&self, name: &str) -> Result<Option<String>, Error> {
+    for exe in self.executable_iter()? {
+      if let Some(text) = exe?.disassemble(name)? {
+        return Ok(Some(text));
+      }
+    }
+    Ok(None)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn load_segments_and_read() {
+    let mut elf = vec![0u8; 0x40 + 2 * 0x38 + 0x10];
+    elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+    elf[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+    elf[0x38..0x3a].copy_from_slice(&2u16.to_le_bytes());
+    // a PT_NOTE, then a PT_LOAD with its code at the end of the file.
+    elf[0x40..0x44].copy_from_slice(&4u32.to_le_bytes());
+    let ph = 0x40 + 0x38;
+    let code = (elf.len() - 0x10) as u64;
+    elf[ph..ph + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+    elf[ph + 0x08..ph + 0x10].copy_from_slice(&code.to_le_bytes());
+    elf[ph + 0x10..ph + 0x18].copy_from_slice(&0x1000u64.to_le_bytes());
+    elf[ph + 0x20..ph + 0x28].copy_from_slice(&0x10u64.to_le_bytes());
+    for (i, b) in elf[code as usize..].iter_mut().enumerate() {
+      *b = i as u8;
+    }
+
+    let segments = load_segments(&elf);
+    assert_eq!(segments, vec![Segment { vaddr: 0x1000, offset: code, filesz: 0x10, }]);
+    assert!(load_segments(&elf[..0x20]).is_empty());
+
+    let state = State {
+      elf: &elf,
+      segments,
+      text: String::new(),
+      targets: vec![],
+    };
+    assert_eq!(state.read(0x1004, 4), &[4, 5, 6, 7]);
+    assert_eq!(state.read(0x100e, 8), &[14, 15]);
+    assert!(state.read(0x1010, 4).is_empty());
+    assert!(state.read(0xfff, 4).is_empty());
+  }
+
+  #[test]
+  fn describe() {
+    let symbol = |name: &str, value, size| Symbol {
+      name: name.into(),
+      kind: SymbolKind::Func,
+      size,
+      value,
+      undefined: false,
+    };
+    let symbols = [symbol("a", 0x1000, 0x100), symbol("b", 0x1100, 0)];
+    assert_eq!(describe_address(0x1000, &symbols), "a");
+    assert_eq!(describe_address(0x1010, &symbols), "a+0x10");
+    assert_eq!(describe_address(0x1100, &symbols), "b");
+    assert_eq!(describe_address(0x2000, &symbols), "0x2000");
+  }
+}
@@ -5,6 +5,8 @@ extern crate amd_comgr_sys as sys;
 
 pub mod action;
 pub mod data;
+pub mod disassembly;
 pub mod error;
 pub mod isa;
 pub mod set;
+pub mod symbol;
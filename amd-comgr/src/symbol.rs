@@ -0,0 +1,145 @@
+use std::os::raw::c_void;
+
+use sys;
+
+use crate::data::{Data, RelocatableData, ExecutableData, };
+use crate::error::Error;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SymbolKind {
+  Unknown,
+  NoType,
+  Object,
+  Func,
+  Section,
+  File,
+  Common,
+  /// Only found in code object v2 executables. Later versions use a `Func` for the kernel's
+  /// code and an `Object` with the `.kd` suffix for its descriptor.
+  AmdGpuHsaKernel,
+}
+impl SymbolKind {
+  fn from_sys(v: sys::amd_comgr_symbol_type_t) -> Self {
+    match v {
+      sys::AMD_COMGR_SYMBOL_TYPE_NOTYPE => SymbolKind::NoType,
+      sys::AMD_COMGR_SYMBOL_TYPE_OBJECT => SymbolKind::Object,
+      sys::AMD_COMGR_SYMBOL_TYPE_FUNC => SymbolKind::Func,
+      sys::AMD_COMGR_SYMBOL_TYPE_SECTION => SymbolKind::Section,
+      sys::AMD_COMGR_SYMBOL_TYPE_FILE => SymbolKind::File,
+      sys::AMD_COMGR_SYMBOL_TYPE_COMMON => SymbolKind::Common,
+      sys::AMD_COMGR_SYMBOL_TYPE_AMDGPU_HSA_KERNEL => SymbolKind::AmdGpuHsaKernel,
+      _ => SymbolKind::Unknown,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Symbol {
+  pub name: String,
+  pub kind: SymbolKind,
+  /// In bytes.
+  pub size: u64,
+  /// For executables, this is the symbol's virtual address.
+  pub value: u64,
+  pub undefined: bool,
+}
+impl Symbol {
+  unsafe fn get_info<T>(symbol: sys::amd_comgr_symbol_t,
+                        info: sys::amd_comgr_symbol_info_t,
+                        out: &mut T)
+    -> Result<(), Error>
+  {
+    let s = sys::amd_comgr_symbol_get_info(symbol, info,
+                                           out as *mut T as *mut _);
+    Error::check(s)
+  }
+  unsafe fn from_sys(symbol: sys::amd_comgr_symbol_t) -> Result<Self, Error> {
+    let mut len = 0usize;
+    Self::get_info(symbol, sys::AMD_COMGR_SYMBOL_INFO_NAME_LENGTH, &mut len)?;
+
+    // plus the null terminator:
+    let mut name = vec![0u8; len + 1];
+    let s = sys::amd_comgr_symbol_get_info(symbol, sys::AMD_COMGR_SYMBOL_INFO_NAME,
+                                           name.as_mut_ptr() as *mut _);
+    Error::check(s)?;
+    name.truncate(len);
+
+    let mut kind = sys::AMD_COMGR_SYMBOL_TYPE_UNKNOWN;
+    Self::get_info(symbol, sys::AMD_COMGR_SYMBOL_INFO_TYPE, &mut kind)?;
+    let mut size = 0u64;
+    Self::get_info(symbol, sys::AMD_COMGR_SYMBOL_INFO_SIZE, &mut size)?;
+    let mut undefined = false;
+    Self::get_info(symbol, sys::AMD_COMGR_SYMBOL_INFO_IS_UNDEFINED, &mut undefined)?;
+    let mut value = 0u64;
+    Self::get_info(symbol, sys::AMD_COMGR_SYMBOL_INFO_VALUE, &mut value)?;
+
+    Ok(Symbol {
+      name: String::from_utf8_lossy(&name).into_owned(),
+      kind: SymbolKind::from_sys(kind),
+      size,
+      value,
+      undefined,
+    })
+  }
+}
+
+unsafe extern "C" fn push_symbol(symbol: sys::amd_comgr_symbol_t, user_data: *mut c_void)
+  -> sys::amd_comgr_status_t
+{
+  let out = &mut *(user_data as *mut Result<Vec<Symbol>, Error>);
+  match (out, Symbol::from_sys(symbol)) {
+    (&mut Ok(ref mut out), Ok(symbol)) => {
+      out.push(symbol);
+      sys::AMD_COMGR_STATUS_SUCCESS
+    },
+    (out, Err(err)) => {
+      *out = Err(err);
+      sys::AMD_COMGR_STATUS_ERROR
+    },
+    (&mut Err(_), Ok(_)) => sys::AMD_COMGR_STATUS_ERROR,
+  }
+}
+
+macro_rules! symbols_fn {
+  ($ty:ty) => (
+
+impl $ty {
+  /// Every symbol in this object, in the order they appear in the symbol table.
+  pub fn symbols(&self) -> Result<Vec<Symbol>, Error> {
+    let h = self.handle().handle();
+    let mut out: Result<Vec<Symbol>, Error> = Ok(Vec::new());
+    let s = unsafe {
+      sys::amd_comgr_iterate_symbols(h, Some(push_symbol),
+                                     &mut out as *mut _ as *mut _)
+    };
+    let out = out?;
+    Error::check(s)?;
+    Ok(out)
+  }
+  pub fn symbol(&self, name: &str) -> Result<Option<Symbol>, Error> {
+    let h = self.handle().handle();
+    let mut name = name.to_string();
+    name.push('\0');
+
+    let mut symbol = sys::amd_comgr_symbol_s {
+      handle: 0,
+    };
+    let s = unsafe {
+      sys::amd_comgr_symbol_lookup(h, name.as_ptr() as *const _,
+                                   &mut symbol as *mut _)
+    };
+    match Error::check(s) {
+      Ok(()) => { },
+      // comgr doesn't have a not found status:
+      Err(Error::InvalidArgument) => { return Ok(None); },
+      Err(err) => { return Err(err); },
+    }
+
+    unsafe { Symbol::from_sys(symbol).map(Some) }
+  }
+}
+
+  );
+}
+symbols_fn!(RelocatableData);
+symbols_fn!(ExecutableData);
@@ -0,0 +1,111 @@
+//! The `NT_AMDGPU_METADATA` note LLVM attaches to AMDGPU code objects. It describes each
+//! kernel's arguments and resource usage.
+//!
+//! See https://llvm.org/docs/AMDGPUUsage.html#code-object-v3-metadata-mattr-code-object-v3
+
+use std::error::Error as StdError;
+use std::fmt;
+
+use goblin::elf::Elf;
+
+use rmp_serde::decode::{from_slice as rmps_from_slice, };
+use serde::{Serialize, Deserialize, };
+
+pub const NT_AMDGPU_METADATA: u32 = 32;
+
+#[derive(Debug)]
+pub enum Error {
+  Elf(goblin::error::Error),
+  MessagePack(rmp_serde::decode::Error),
+  /// The code object doesn't have a `NT_AMDGPU_METADATA` note.
+  MissingNote,
+}
+impl StdError for Error {
+  fn source(&self) -> Option<&(dyn StdError + 'static)> {
+    match self {
+      Error::Elf(inner) => Some(inner),
+      Error::MessagePack(inner) => Some(inner),
+      Error::MissingNote => None,
+    }
+  }
+}
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Elf(inner) => write!(f, "failed to read the code object: {}", inner),
+      Error::MessagePack(inner) => write!(f, "failed to decode the metadata: {}", inner),
+      Error::MissingNote => f.pad("the code object has no NT_AMDGPU_METADATA note"),
+    }
+  }
+}
+impl From<goblin::error::Error> for Error {
+  fn from(v: goblin::error::Error) -> Self {
+    Error::Elf(v)
+  }
+}
+impl From<rmp_serde::decode::Error> for Error {
+  fn from(v: rmp_serde::decode::Error) -> Self {
+    Error::MessagePack(v)
+  }
+}
+
+/// The MessagePack encoded contents of the metadata note of `elf`, whose image is `data`.
+pub fn metadata_note<'a>(elf: &Elf<'a>, data: &'a [u8]) -> Result<Option<&'a [u8]>, Error> {
+  if let Some(notes) = elf.iter_note_sections(data, None) {
+    for note in notes {
+      let note = note?;
+      if note.n_type == NT_AMDGPU_METADATA {
+        return Ok(Some(note.desc));
+      }
+    }
+  }
+
+  Ok(None)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HsaMetadataMap<'a> {
+  #[serde(rename = "amdhsa.version")]
+  pub version: (u32, u32),
+  // skip "amdhsa.printf"
+  #[serde(borrow, rename = "amdhsa.kernels")]
+  pub kernels: Vec<HsaKernelMetadataMap<'a>>,
+}
+impl<'a> HsaMetadataMap<'a> {
+  /// Parse the metadata note of `elf`, whose image is `data`.
+  pub fn parse(elf: &Elf<'a>, data: &'a [u8]) -> Result<Self, Error> {
+    let note = metadata_note(elf, data)?
+      .ok_or(Error::MissingNote)?;
+    Ok(rmps_from_slice(note)?)
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HsaKernelMetadataMap<'a> {
+  #[serde(borrow, rename = ".name")]
+  pub name: &'a str,
+  #[serde(borrow, rename = ".symbol")]
+  pub kernel_desc_symbol: &'a str,
+  #[serde(rename = ".kernarg_segment_size")]
+  pub kernarg_segment_size: u32,
+  #[serde(rename = ".group_segment_fixed_size")]
+  pub group_segment_size: u32,
+  #[serde(rename = ".private_segment_fixed_size")]
+  pub private_segment_size: u32,
+  #[serde(rename = ".kernarg_segment_align")]
+  pub kernarg_segment_align: u32,
+  #[serde(rename = ".wavefront_size")]
+  pub wavefront_size: u32,
+  #[serde(rename = ".sgpr_count")]
+  pub sgpr_count: u32,
+  #[serde(rename = ".vgpr_count")]
+  pub vgpr_count: u32,
+  #[serde(rename = ".max_flat_workgroup_size")]
+  pub max_flat_workgroup_size: u32,
+  /// Optional in the metadata.
+  #[serde(default, rename = ".sgpr_spill_count")]
+  pub sgpr_spill_count: Option<u32>,
+  /// Optional in the metadata.
+  #[serde(default, rename = ".vgpr_spill_count")]
+  pub vgpr_spill_count: Option<u32>,
+}
@@ -5,21 +5,17 @@ use std::geobacter::platform::platform;
 use std::geobacter::spirv::{*, workitem::*};
 use std::iter;
 use std::num::NonZeroU32;
-use std::sync::Arc;
 
 use grt_vk::VkAccel;
+use grt_vk::compute::Bindings;
 use grt_core::context::Context;
 
 use vk::buffer::BufferUsage;
 use vk::buffer::cpu_access::{CpuAccessibleBuffer, };
-use vk::command_buffer::{CommandBuffer, AutoCommandBufferBuilder, };
-use vk::descriptor::descriptor_set::{PersistentDescriptorSet, UnsafeDescriptorSetLayout};
 use vk::device::{Device, Features, DeviceExtensions};
 use vk::instance::{layers_list, Instance, InstanceExtensions, PhysicalDevice,
                    debug::DebugCallback, debug::MessageSeverity,
                    debug::MessageType};
-use vk::pipeline::ComputePipeline;
-use vk::sync::GpuFuture;
 
 
 const ELEMENTS: usize = 4096;
@@ -123,7 +119,8 @@ pub fn main() {
 
     let wg_size = (NonZeroU32::new(256).unwrap(), NonZeroU32::new(1).unwrap(),
                    NonZeroU32::new(1).unwrap());
-    let compiled = dev.compile_compute_testing(kernel, Some(wg_size))
+    let module = dev.compute_module(kernel, wg_size);
+    module.pipeline()
       .expect("failed to compile kernel");
 
     // finished core initialization
@@ -154,51 +151,14 @@ pub fn main() {
     };
     println!("finished initializing data buffer");
 
-    println!("sets = {:#?}", compiled.pipeline_layout());
-    let set_0 = compiled.pipeline_layout()
-      .set_iter()
-      .nth(0)
-      .unwrap();
+    println!("sets = {:#?}", module.compile().unwrap().pipeline_layout());
+    let bindings = Bindings::new()
+      .buffer(0, 0, data_buffer.clone());
 
-    let layout = UnsafeDescriptorSetLayout::new(device.clone(), set_0)
-      .expect("UnsafeDescriptorSetLayout::new");
-    let layout = Arc::new(layout);
-
-    let desc_set_builder = PersistentDescriptorSet::start(layout);
-
-    let desc_set_builder = desc_set_builder
-      .add_buffer(data_buffer.clone())
-      .expect("set/binding mismatch?");
-
-    let desc_set = desc_set_builder.build()
-      .expect("failed to build desc set");
-    let desc_sets = vec![desc_set];
-
-    let compute_mod = compiled.compute_entry_ref().unwrap();
-
-    let pipeline = ComputePipeline::new(device.clone(),
-                                        &compute_mod,
-                                        &())
-      .expect("create compute pipeline");
-    let pipeline = Arc::new(pipeline);
-
-    let mut cmd_buf = AutoCommandBufferBuilder::new(device.clone(), queue.family())
-      .expect("failed to create auto cmd buf");
-
-    cmd_buf
-      .dispatch([(ELEMENTS / 256) as _, 1, 1], pipeline.clone(),
-                desc_sets, ())
-      .expect("failed to build cmd buf dispatch");
-
-    let cmd_buf = cmd_buf.build()
-      .expect("build cmd buf");
-
-    cmd_buf.execute(queue.clone())
-      .expect("start compute")
-      .then_signal_fence_and_flush()
-      .expect("then_signal_fence_and_flush")
+    module.dispatch(&queue, [ELEMENTS as _, 1, 1], &bindings)
+      .expect("failed to dispatch")
       .wait(None)
-      .expect("GpuFuture::wait");
+      .expect("ComputeCompletion::wait");
 
     println!("Kernel finished; checking results");
 
@@ -4,7 +4,8 @@ use std::os::raw::{c_void};
 use std::ops::Range;
 use std::result::Result;
 use std::str::from_utf8;
-use std::mem::transmute;
+use std::mem::{size_of, transmute, };
+use std::slice;
 
 #[cfg(feature = "serde")]
 use serde::{Serialize, Deserialize, };
@@ -150,13 +151,25 @@ impl Cache {
   pub fn name(&self) -> Result<String, Error> {
     let len = cache_info!(self, ffi::hsa_cache_info_t_HSA_CACHE_INFO_NAME_LENGTH,
                           [0u32; 1])?[0] as usize;
-    let str = vec![0; len + 1];
-    let mut str = cache_info!(self, ffi::hsa_cache_info_t_HSA_CACHE_INFO_NAME, str)?;
-    while let Some(&0) = str.last() {
-      str.pop();
-    }
-    let str = String::from_utf8(str)?;
-    Ok(str)
+    // The spec says this is a `char[64]`, but ROCr instead writes a pointer to its
+    // own copy of the name. Either way, this must be large enough for it to write
+    // into, else it scribbles over our stack.
+    let bytes = cache_info!(self, ffi::hsa_cache_info_t_HSA_CACHE_INFO_NAME,
+                            [0u8; 64])?;
+    let inline = len < bytes.len() && bytes[len] == 0 &&
+      !bytes[..len].contains(&0);
+    let name = if inline {
+      &bytes[..len]
+    } else {
+      let mut ptr = [0u8; size_of::<*const u8>()];
+      ptr.copy_from_slice(&bytes[..size_of::<*const u8>()]);
+      let ptr = usize::from_ne_bytes(ptr) as *const u8;
+      if ptr.is_null() {
+        return Err(Error::General);
+      }
+      unsafe { slice::from_raw_parts(ptr, len) }
+    };
+    Ok(from_utf8(name)?.into())
   }
   pub fn level(&self) -> Result<u8, Error> {
     Ok(cache_info!(self, ffi::hsa_cache_info_t_HSA_CACHE_INFO_LEVEL,
@@ -370,7 +383,7 @@ impl Agent {
     Ok(from_utf8(str)?.into())
   }
   pub fn vendor_name(&self) -> Result<String, Error> {
-    let bytes = agent_info!(self, ffi::hsa_agent_info_t_HSA_AGENT_INFO_VENDOR_NAME, [0u8; 54])?;
+    let bytes = agent_info!(self, ffi::hsa_agent_info_t_HSA_AGENT_INFO_VENDOR_NAME, [0u8; 64])?;
     let mut str = &bytes[..];
     while let Some(&0u8) = str.last() {
       let l = str.len();
@@ -403,6 +416,13 @@ impl Agent {
       _ => Err(Error::General),
     }
   }
+  /// AMD extension.
+  pub fn compute_unit_count(&self) -> Result<u32, Error> {
+    let count = agent_info!(self,
+                            ffi::hsa_amd_agent_info_s_HSA_AMD_AGENT_INFO_COMPUTE_UNIT_COUNT as _,
+                            [0u32; 1])?;
+    Ok(count[0])
+  }
   pub fn extensions(&self) -> Result<[u8; 128], Error> {
     Ok(agent_info!(self, ffi::hsa_agent_info_t_HSA_AGENT_INFO_EXTENSIONS, [0u8; 128])?)
   }
@@ -545,4 +565,17 @@ impl ApiContext {
       .collect();
     Ok(out)
   }
+
+  /// The current system timestamp. Profiling timestamps are in this domain.
+  pub fn timestamp(&self) -> Result<u64, Error> {
+    let mut out = 0u64;
+    check_err!(ffi::hsa_system_get_info(ffi::hsa_system_info_t_HSA_SYSTEM_INFO_TIMESTAMP,
+                                        &mut out as *mut u64 as *mut _) => out)
+  }
+  /// Ticks per second of `ApiContext::timestamp`.
+  pub fn timestamp_frequency(&self) -> Result<u64, Error> {
+    let mut out = 0u64;
+    check_err!(ffi::hsa_system_get_info(ffi::hsa_system_info_t_HSA_SYSTEM_INFO_TIMESTAMP_FREQUENCY,
+                                        &mut out as *mut u64 as *mut _) => out)
+  }
 }
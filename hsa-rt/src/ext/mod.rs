@@ -1,5 +1,6 @@
 
 pub mod amd;
 pub mod image;
+pub mod profiling;
 pub mod queue;
 pub mod signal;
@@ -0,0 +1,65 @@
+//! AMD profiling extensions. Unless noted otherwise, timestamps are in the system
+//! domain, ie the same domain as `ApiContext::timestamp`.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize, };
+
+use crate::agent::Agent;
+use crate::error::Error;
+use crate::ffi;
+use crate::queue::{KernelQueue, QueueKind, };
+use crate::signal::SignalRef;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ProfilingTime {
+  pub start: u64,
+  pub end: u64,
+}
+
+impl<T> KernelQueue<T>
+  where T: QueueKind,
+{
+  /// Record start/end timestamps for every dispatch packet processed by this queue.
+  /// Only packets enqueued after this is called will have timestamps.
+  pub fn set_profiling(&self, enable: bool) -> Result<(), Error> {
+    check_err!(ffi::hsa_amd_profiling_set_profiler_enabled(self.sys.0, enable as _))
+  }
+}
+
+impl Agent {
+  /// Get the timestamps of the completed dispatch which used `signal` as its completion
+  /// signal. The dispatch must have been processed by a queue with profiling enabled.
+  pub fn dispatch_time(&self, signal: SignalRef) -> Result<ProfilingTime, Error> {
+    let mut out = ffi::hsa_amd_profiling_dispatch_time_t::default();
+    check_err!(ffi::hsa_amd_profiling_get_dispatch_time(self.handle(), signal.0,
+                                                        &mut out as *mut _))?;
+    Ok(ProfilingTime {
+      start: out.start,
+      end: out.end,
+    })
+  }
+  /// Convert a timestamp in this agent's domain (ie one read on the device) into the
+  /// system domain.
+  pub fn convert_tick_to_system_domain(&self, tick: u64) -> Result<u64, Error> {
+    let mut out = 0u64;
+    check_err!(ffi::hsa_amd_profiling_convert_tick_to_system_domain(self.handle(), tick,
+                                                                    &mut out as *mut _) => out)
+  }
+}
+
+/// Enable or disable timestamps for async copies. This is process wide; only copies
+/// started while this is enabled will have timestamps.
+pub fn set_async_copy_profiling(enable: bool) -> Result<(), Error> {
+  check_err!(ffi::hsa_amd_profiling_async_copy_enable(enable))
+}
+/// Get the timestamps of the completed async copy which used `signal` as its completion
+/// signal.
+pub fn async_copy_time(signal: SignalRef) -> Result<ProfilingTime, Error> {
+  let mut out = ffi::hsa_amd_profiling_async_copy_time_t::default();
+  check_err!(ffi::hsa_amd_profiling_get_async_copy_time(signal.0, &mut out as *mut _))?;
+  Ok(ProfilingTime {
+    start: out.start,
+    end: out.end,
+  })
+}
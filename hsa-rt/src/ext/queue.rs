@@ -1,6 +1,10 @@
 
 use std::sync::atomic::{AtomicU64, AtomicU32};
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize, };
+
+use crate::error::Error;
 use crate::ffi;
 use queue::{KernelQueue, QueueKind};
 
@@ -40,3 +44,37 @@ impl<T> AsAmdQueue for KernelQueue<T>
     &*(self.sys.0 as *const _ as *const AmdQueue)
   }
 }
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum QueuePriority {
+  Low,
+  Normal,
+  High,
+}
+impl Default for QueuePriority {
+  fn default() -> Self { QueuePriority::Normal }
+}
+impl Into<ffi::hsa_amd_queue_priority_t> for QueuePriority {
+  fn into(self) -> ffi::hsa_amd_queue_priority_t {
+    match self {
+      QueuePriority::Low => ffi::hsa_amd_queue_priority_s_HSA_AMD_QUEUE_PRIORITY_LOW,
+      QueuePriority::Normal => ffi::hsa_amd_queue_priority_s_HSA_AMD_QUEUE_PRIORITY_NORMAL,
+      QueuePriority::High => ffi::hsa_amd_queue_priority_s_HSA_AMD_QUEUE_PRIORITY_HIGH,
+    }
+  }
+}
+
+impl<T> KernelQueue<T>
+  where T: QueueKind,
+{
+  /// Restrict dispatches from this queue to the compute units set in `mask`. CU `i`
+  /// is bit `i % 32` of `mask[i / 32]`. Set every bit to undo.
+  pub fn set_cu_mask(&self, mask: &[u32]) -> Result<(), Error> {
+    let bits = (mask.len() * 32) as u32;
+    check_err!(ffi::hsa_amd_queue_cu_set_mask(self.sys.0, bits, mask.as_ptr()))
+  }
+  pub fn set_priority(&self, priority: QueuePriority) -> Result<(), Error> {
+    check_err!(ffi::hsa_amd_queue_set_priority(self.sys.0, priority.into()))
+  }
+}
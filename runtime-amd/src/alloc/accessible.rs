@@ -1,76 +1,137 @@
 use super::*;
 
-use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::RwLock;
 
 use indexvec::Idx;
 
-/// As of time of writ, the largest count of GPUs per server I could find for any price was 32.
-/// A more common max was 20 GPUs per server.
+const WORD_BITS: usize = 64;
+
+/// The set of accelerators an allocation has been mapped into, indexed by
+/// `AcceleratorId::index()`. This grows as needed, so there's no limit on the number of
+/// accelerators in a context; one word covers the common case of a few GPUs per server.
 pub struct Accessible {
-  bits: AtomicU64,
+  words: RwLock<SmallVec<[u64; 1]>>,
+  /// Held while changing the HSA access grants, so that concurrent grants don't trample each
+  /// other. `words` is only locked briefly, so lookups don't wait on HSA.
   pub(crate) lock: Mutex<()>,
 }
 
+#[inline(always)]
+fn word_bit(id: AcceleratorId) -> (usize, u64) {
+  let idx = id.index();
+  (idx / WORD_BITS, 1u64 << (idx % WORD_BITS))
+}
+
 impl Accessible {
   #[inline(always)]
-  pub(crate) fn get(&self, id: AcceleratorId) -> bool {
-    let bitvec = self.bits.load(Ordering::Acquire);
-    bitvec & (1u64 << id.index()) == 0
+  pub(crate) fn contains(&self, id: AcceleratorId) -> bool {
+    let (word, bit) = word_bit(id);
+    self.words.read()
+      .get(word)
+      .map(|&w| w & bit != 0 )
+      .unwrap_or(false)
   }
   #[inline(always)]
-  pub(crate) fn get_mut(&mut self, id: AcceleratorId) -> bool {
-    (*self.bits.get_mut()) & (1u64 << id.index()) == 0
+  pub(crate) fn contains_mut(&mut self, id: AcceleratorId) -> bool {
+    let (word, bit) = word_bit(id);
+    self.words.get_mut()
+      .get(word)
+      .map(|&w| w & bit != 0 )
+      .unwrap_or(false)
   }
+  /// Returns true if `id` wasn't already present.
   #[inline(always)]
-  pub(crate) fn set(&self, order: Ordering, id: AcceleratorId) -> bool {
-    let bit = 1u64 << id.index();
-    let prev = self.bits.fetch_or(bit, order);
-    (prev & bit) == 0
+  pub(crate) fn set(&self, id: AcceleratorId) -> bool {
+    Self::set_in(&mut *self.words.write(), id)
   }
+  /// Returns true if `id` wasn't already present.
   #[inline(always)]
   pub(crate) fn set_mut(&mut self, id: AcceleratorId) -> bool {
-    let bit = 1u64 << id.index();
-    let bitvec = self.bits.get_mut();
-    let b = (*bitvec & bit) == 0;
-    *bitvec |= bit;
+    Self::set_in(self.words.get_mut(), id)
+  }
+  fn set_in(words: &mut SmallVec<[u64; 1]>, id: AcceleratorId) -> bool {
+    let (word, bit) = word_bit(id);
+    if words.len() <= word {
+      words.resize(word + 1, 0);
+    }
+    let b = (words[word] & bit) == 0;
+    words[word] |= bit;
     b
   }
   #[inline(always)]
   pub(crate) fn unset(&self, id: AcceleratorId) {
-    let bit = 1u64 << id.index();
-    self.bits.fetch_and(!bit, Ordering::AcqRel);
+    Self::unset_in(&mut *self.words.write(), id)
+  }
+  #[inline(always)]
+  pub(crate) fn unset_mut(&mut self, id: AcceleratorId) {
+    Self::unset_in(self.words.get_mut(), id)
+  }
+  fn unset_in(words: &mut SmallVec<[u64; 1]>, id: AcceleratorId) {
+    let (word, bit) = word_bit(id);
+    if let Some(w) = words.get_mut(word) {
+      *w &= !bit;
+    }
   }
 
-  /// You *must* hold the lock! Or otherwise promise that concurrent access isn't possible.
-  pub(crate) unsafe fn agents(&self, order: Ordering, out: &mut SmallVec<[Agent; 16]>) {
+  /// Writes the ids present, in order, to `out`.
+  pub(crate) fn ids(&self, out: &mut SmallVec<[AcceleratorId; 16]>) {
     out.clear();
 
-    let bitvec = self.bits.load(order);
+    let words = self.words.read();
+    for (wi, &w) in words.iter().enumerate() {
+      let mut w = w;
+      while w != 0 {
+        let b = w.trailing_zeros() as usize;
+        w &= w - 1;
+        out.push(AcceleratorId::new(wi * WORD_BITS + b));
+      }
+    }
+  }
+
+  /// You *must* hold the lock! Or otherwise promise that concurrent access isn't possible.
+  pub(crate) unsafe fn agents(&self, out: &mut SmallVec<[Agent; 16]>) {
+    out.clear();
 
-    if bitvec == 0 { return; }
+    let mut ids = SmallVec::new();
+    self.ids(&mut ids);
+    if ids.is_empty() { return; }
 
-    let ctx = Context::global().expect("no Geobacter context?");
-    for i in 0..64 {
-      if bitvec & (1 << i) != 0 {
-        let id = AcceleratorId::new(i as _);
-        if let Some(dev) = ctx.get_dev_ref::<HsaAmdGpuAccel>(id) {
-          out.push(dev.agent().clone());
-        }
+    // Allocations can be shared between contexts, so look up each accelerator's own context.
+    let mut ctx = Context::global();
+    for id in ids {
+      let in_ctx = ctx.as_ref()
+        .map(|ctx| ctx.get_accel_ref(id).is_some() )
+        .unwrap_or(false);
+      if !in_ctx {
+        ctx = Context::for_accel(id);
+      }
+      let dev = ctx.as_ref()
+        .and_then(|ctx| ctx.get_dev_ref::<HsaAmdGpuAccel>(id) );
+      if let Some(dev) = dev {
+        out.push(dev.agent().clone());
       }
     }
   }
 
   #[inline]
   pub fn len(&self) -> usize {
-    let bits = self.bits.load(Ordering::Acquire);
-    bits.count_ones() as _
+    self.words.read()
+      .iter()
+      .map(|w| w.count_ones() as usize )
+      .sum()
+  }
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.words.read()
+      .iter()
+      .all(|&w| w == 0 )
   }
 }
 impl Default for Accessible {
   #[inline(always)]
   fn default() -> Self {
     Accessible {
-      bits: AtomicU64::new(0),
+      words: RwLock::new(SmallVec::new()),
       lock: Mutex::new(()),
     }
   }
@@ -79,14 +140,46 @@ impl Clone for Accessible {
   #[inline(always)]
   fn clone(&self) -> Self {
     Accessible {
-      bits: AtomicU64::new(self.bits.load(Ordering::Acquire)),
+      words: RwLock::new(self.words.read().clone()),
       lock: Mutex::new(()),
     }
   }
 }
 impl fmt::Debug for Accessible {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    let bits = self.bits.load(Ordering::Acquire);
-    write!(f, "Accessible({:#b})", bits)
+    let mut ids = SmallVec::new();
+    self.ids(&mut ids);
+    f.debug_tuple("Accessible")
+      .field(&ids)
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn more_than_64_accelerators() {
+    let mut a = Accessible::default();
+    let ids: Vec<_> = [0usize, 3, 63, 64, 200].iter()
+      .map(|&i| AcceleratorId::new(i) )
+      .collect();
+    for &id in ids.iter() {
+      assert!(a.set(id));
+      assert!(!a.set_mut(id));
+    }
+    assert_eq!(a.len(), ids.len());
+    assert!(a.contains(ids[4]) && !a.contains(AcceleratorId::new(199)));
+    assert!(!a.contains(AcceleratorId::new(1000)));
+
+    a.unset(ids[3]);
+    a.unset_mut(AcceleratorId::new(1000));
+    assert!(!a.contains_mut(ids[3]));
+
+    let mut out = SmallVec::new();
+    a.ids(&mut out);
+    assert_eq!(&out[..], &[ids[0], ids[1], ids[2], ids[4]]);
+    assert!(!a.is_empty());
   }
 }
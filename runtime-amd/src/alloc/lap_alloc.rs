@@ -1,11 +1,11 @@
 
 use super::*;
 
-use std::sync::atomic::Ordering;
-
 #[derive(Clone, Debug)]
 pub struct LapAlloc {
   pub(crate) pool: MemoryPool,
+  /// The CPU agent of the NUMA node `pool` is in.
+  pub(crate) owner: Agent,
   pub(crate) accessible: Accessible,
 }
 impl LapAlloc {
@@ -27,14 +27,14 @@ impl LapAlloc {
 
   #[inline]
   pub fn is_accessible_to(&self, dev: &HsaAmdGpuAccel) -> bool {
-    !self.accessible.get(dev.id())
+    self.accessible.contains(dev.id())
   }
 
   /// Update access grants to a new allocation
   unsafe fn update_access_grants(&self, ptr: NonNull<u8>,
                                  _layout: Layout) -> Result<(), HsaError> {
     let mut agents = SmallVec::new();
-    self.accessible.agents(Ordering::Acquire, &mut agents);
+    self.accessible.agents(&mut agents);
     if agents.len() == 0 { return Ok(()); }
 
     // Handle cases where `LapAllocAccess::add_access` is called before allocation.
@@ -48,6 +48,45 @@ impl LapAlloc {
 
     Ok(())
   }
+
+  /// Unmap `pool_ptr` from `dev`. The allocation remains mapped into every other
+  /// accelerator it was shared with. `pool_ptr` is `None` if nothing has been allocated yet.
+  pub(crate) fn revoke_access(&self, dev: &HsaAmdGpuAccel,
+                              pool_ptr: Option<MemoryPoolPtr<[u8]>>)
+    -> Result<(), Error>
+  {
+    let _lock = self.accessible.lock.lock();
+    if !self.accessible.contains(dev.id()) { return Ok(()); }
+
+    if self.pool.agent_access(dev.agent())?.default_allowed() {
+      // The whole pool is mapped into this device; we can't unmap just this allocation.
+      return Err(Error::LapAccessDefaultAllowed);
+    }
+
+    self.accessible.unset(dev.id());
+    let pool_ptr = match pool_ptr {
+      Some(ptr) => ptr,
+      None => { return Ok(()); },
+    };
+
+    // Granting overwrites the set of agents this region is mapped into.
+    let mut agents = SmallVec::new();
+    unsafe {
+      self.accessible.agents(&mut agents);
+    }
+    if agents.is_empty() {
+      // HSA rejects an empty agent list, so map it back into just the pool's owner.
+      agents.push(self.owner.clone());
+    }
+    match pool_ptr.grant_agents_access(&agents[..]) {
+      Ok(()) => Ok(()),
+      Err(err) => {
+        self.accessible.set(dev.id());
+        warn!("failed to revoke access to allocation in pool({:?}): {:?}", self.pool, err);
+        Err(err.into())
+      },
+    }
+  }
 }
 impl<'a> From<&'a Arc<HsaAmdGpuAccel>> for LapAlloc {
   fn from(accel: &'a Arc<HsaAmdGpuAccel>) -> Self {
@@ -133,7 +172,7 @@ impl<'a> AllocMaybeSyncAccessGrant for &'a mut LapAlloc {
   fn grant_access(self, dev: &HsaAmdGpuAccel,
                   pool_ptr: Option<MemoryPoolPtr<[u8]>>) -> Result<(), HsaError>
   {
-    if !self.accessible.get_mut(dev.id()) {
+    if self.accessible.contains_mut(dev.id()) {
       // nothing to do.
       return Ok(());
     }
@@ -156,7 +195,7 @@ impl<'a> AllocMaybeSyncAccessGrant for &'a mut LapAlloc {
       // no need to worry about races here; we have a mut reference.
       let mut agents: SmallVec<[Agent; 16]> = SmallVec::default();
       unsafe {
-        self.accessible.agents(Ordering::Relaxed, &mut agents);
+        self.accessible.agents(&mut agents);
       }
       agents.push(dev.agent().clone());
       match pool_ptr.grant_agents_access(&agents) {
@@ -169,6 +208,7 @@ impl<'a> AllocMaybeSyncAccessGrant for &'a mut LapAlloc {
       self.accessible.set_mut(dev.id());
     } else if aa.default_allowed() {
       // nothing to do
+      self.accessible.set_mut(dev.id());
     } else {
       unreachable!("{:?}", aa);
     }
@@ -180,7 +220,7 @@ impl<'a> AllocMaybeSyncAccessGrant for &'a LapAlloc {
   fn grant_access(self, dev: &HsaAmdGpuAccel,
                   pool_ptr: Option<MemoryPoolPtr<[u8]>>) -> Result<(), HsaError>
   {
-    if !self.accessible.get(dev.id()) { return Ok(()); }
+    if self.accessible.contains(dev.id()) { return Ok(()); }
 
     let aa = match self.pool.agent_access(dev.agent()) {
       Ok(v) => v,
@@ -201,12 +241,12 @@ impl<'a> AllocMaybeSyncAccessGrant for &'a LapAlloc {
       agents.reserve(self.accessible.len());
 
       let _lock = self.accessible.lock.lock(); // Ensure we don't trample other threads.
-      if !self.accessible.set(Ordering::AcqRel, dev.id()) {
+      if !self.accessible.set(dev.id()) {
         // another thread beat us
         return Ok(());
       }
       unsafe {
-        self.accessible.agents(Ordering::Acquire, &mut agents);
+        self.accessible.agents(&mut agents);
       }
       match pool_ptr.grant_agents_access(&agents[..]) {
         Ok(()) => {},
@@ -222,7 +262,7 @@ impl<'a> AllocMaybeSyncAccessGrant for &'a LapAlloc {
 
     } else if aa.default_allowed() {
       // nothing to do
-      self.accessible.set(Ordering::AcqRel, dev.id());
+      self.accessible.set(dev.id());
     } else {
       unreachable!("{:?}", aa);
     }
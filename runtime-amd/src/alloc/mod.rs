@@ -16,12 +16,21 @@ pub trait LapAccessible {
   fn is_accessible_to(&self, dev: &HsaAmdGpuAccel) -> bool;
 }
 
-/// No device removal.
+/// See `LapRevokeAccess` for device removal.
 pub trait LapGrantAccess {
   /// TODO: it would be nice to allow granting new devices access (which is expensive)
   /// concurrently. This is safe because only removal is concurrent-unsafe.
   fn add_access(self, device: &HsaAmdGpuAccel) -> Result<(), HsaError>;
 }
+/// Unmap an allocation from a device it was granted access to.
+///
+/// This is implemented for exclusive references only: kernel arguments, transfers, etc which
+/// borrow the allocation hold it as a dep until their completion is dropped, and dropping the
+/// completion waits for the device to finish with it. So once we have a `&mut`, nothing
+/// outstanding can reference the allocation. `LapArc`s must also be unique.
+pub trait LapRevokeAccess {
+  fn remove_access(self, device: &HsaAmdGpuAccel) -> Result<(), Error>;
+}
 
 trait AllocMaybeSyncAccessGrant {
   fn grant_access(self, dev: &HsaAmdGpuAccel, pool_ptr: Option<MemoryPoolPtr<[u8]>>) -> Result<(), HsaError>;
@@ -69,6 +78,17 @@ impl<'a, T> LapGrantAccess for &'a mut LapVec<T> {
   }
 }
 
+impl<'a, T> LapRevokeAccess for &'a mut LapVec<T> {
+  fn remove_access(self, device: &HsaAmdGpuAccel) -> Result<(), Error> {
+    if std::mem::size_of::<T>() == 0 {
+      return Ok(());
+    }
+
+    let ptr = unsafe { self.pool_ptr() };
+    self.alloc_ref().revoke_access(device, ptr)
+  }
+}
+
 impl<T> LapAccessible for LapBox<T>
   where T: ?Sized,
 {
@@ -96,6 +116,18 @@ impl<'a, T> LapGrantAccess for &'a mut LapBox<T>
     self.alloc_ref_mut().grant_access(device, ptr)
   }
 }
+impl<'a, T> LapRevokeAccess for &'a mut LapBox<T>
+  where T: ?Sized,
+{
+  fn remove_access(self, device: &HsaAmdGpuAccel) -> Result<(), Error> {
+    if std::mem::size_of_val(&**self) == 0 {
+      return Ok(());
+    }
+
+    let ptr = unsafe { self.pool_ptr() };
+    self.alloc_ref().revoke_access(device, ptr)
+  }
+}
 impl<T> LapAccessible for LapArc<T>
   where T: ?Sized,
 {
@@ -113,6 +145,19 @@ impl<'a, T> LapGrantAccess for &'a LapArc<T>
     LapArc::alloc_ref(self).grant_access(device, ptr)
   }
 }
+impl<'a, T> LapRevokeAccess for &'a mut LapArc<T>
+  where T: ?Sized,
+{
+  fn remove_access(self, device: &HsaAmdGpuAccel) -> Result<(), Error> {
+    // Other strong or weak references could be in use by a device.
+    if LapArc::get_mut(self).is_none() {
+      return Err(Error::LapAccessShared);
+    }
+
+    let ptr = unsafe { self.pool_ptr() };
+    LapArc::alloc_ref(self).revoke_access(device, ptr)
+  }
+}
 impl<T> LapAccessible for LapWeak<T>
   where T: ?Sized,
 {
@@ -189,4 +234,52 @@ mod test {
     let m2 = m.clone();
     assert!(m2.is_accessible_to(&dev));
   }
+  #[test]
+  fn lap_vec_remove_access() {
+    let dev = device();
+
+    let mut m = LapVec::new_in(dev.fine_lap_node_alloc(0));
+    m.resize(16, 0u32);
+    m.add_access(&dev).unwrap();
+    assert!(m.is_accessible_to(&dev));
+
+    m.remove_access(&dev).unwrap();
+    assert!(!m.is_accessible_to(&dev));
+    assert_eq!(m.alloc_ref().accessible().len(), 0);
+
+    // and access can be granted again:
+    m.add_access(&dev).unwrap();
+    assert!(m.is_accessible_to(&dev));
+  }
+  #[test]
+  fn lap_vec_remove_only_access() {
+    let dev = device();
+
+    let mut m = LapVec::new_in(dev.fine_lap_node_alloc(0));
+    m.resize(16, 0u32);
+    m.add_access(&dev).unwrap();
+    assert_eq!(m.alloc_ref().accessible().len(), 1);
+
+    // revoking the only device leaves the allocation mapped into just the host:
+    m.remove_access(&dev).unwrap();
+    assert!(m.alloc_ref().accessible().is_empty());
+    m[15] = 1;
+    assert_eq!(m.iter().sum::<u32>(), 1);
+  }
+  #[test]
+  fn lap_arc_remove_access_shared() {
+    let dev = device();
+
+    let mut m = LapArc::new_in(0u32, dev.fine_lap_node_alloc(0));
+    m.add_access(&dev).unwrap();
+
+    let m2 = m.clone();
+    match m.remove_access(&dev) {
+      Err(Error::LapAccessShared) => { },
+      r => panic!("unexpected result: {:?}", r),
+    }
+    drop(m2);
+    m.remove_access(&dev).unwrap();
+    assert!(!m.is_accessible_to(&dev));
+  }
 }
@@ -1,3 +1,15 @@
+//! Conditions for `#[geobacter_attr(..)]`:
+//!
+//! * `platform = "amdgpu"`
+//! * `gfx = "gfx90a"`: the exact processor.
+//! * `feature = "dpp"`: an LLVM target feature the device was compiled with, eg
+//!   `"16-bit-insts"`.
+//! * `wavefront_size = 64`
+//! * `min_lds_size = 65536`: the device has at least this many bytes of LDS.
+//!
+//! ```ignore
+//! #[geobacter_attr(all(platform = "amdgpu", feature = "dpp"), inline(always))]
+//! ```
 
 use grt_core::codegen::attrs::*;
 
@@ -7,20 +19,123 @@ use rustc_span::symbol::Symbol;
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub enum Condition {
-  /// XXX make more specific
   Platform,
+  Gfx(String),
+  /// Without the leading `+`.
+  Feature(String),
+  WavefrontSize(u32),
+  /// As a root condition, the device's LDS size, in bytes.
+  LdsSize(u32),
+  /// Only used in `geobacter_attr`s.
+  MinLdsSize(u32),
+  /// A condition for another platform. Never satisfied.
+  Foreign,
+}
+impl Condition {
+  /// The root conditions for a device.
+  pub fn for_target(gpu: &str, features: &str, wavefront_sizes: &[u32],
+                    lds_size: Option<u32>)
+    -> Vec<Self>
+  {
+    let mut out = vec![
+      Condition::Platform,
+      Condition::Gfx(gpu.into()),
+    ];
+    out.extend({
+      features.split(',')
+        .filter_map(|f| f.strip_prefix('+') )
+        .map(|f| Condition::Feature(f.into()) )
+    });
+    out.extend(wavefront_sizes.iter().map(|&size| Condition::WavefrontSize(size) ));
+    out.extend(lds_size.map(Condition::LdsSize));
+    out
+  }
 }
 impl ConditionItem for Condition {
   fn parse_name_value(tcx: TyCtxt, item: &MetaItem) -> Option<Self> {
-    if item.has_name(Symbol::intern("platform")) &&
-      item.value_str().unwrap().as_str() == "amdgpu" {
-      return Some(Condition::Platform);
+    if item.has_name(Symbol::intern("platform")) {
+      let v = value_str(tcx, item)?;
+      return Some(if v.as_str() == "amdgpu" {
+        Condition::Platform
+      } else {
+        Condition::Foreign
+      });
+    }
+    if item.has_name(Symbol::intern("gfx")) {
+      return Some(Condition::Gfx(value_str(tcx, item)?.to_string()));
+    }
+    if item.has_name(Symbol::intern("feature")) {
+      let v = value_str(tcx, item)?;
+      let v = v.as_str();
+      let v = v.strip_prefix('+').unwrap_or(&v);
+      return Some(Condition::Feature(v.into()));
+    }
+    if item.has_name(Symbol::intern("wavefront_size")) {
+      return Some(Condition::WavefrontSize(value_u32(tcx, item)?));
     }
-    let msg = format!("unknown attr key `{}`; (no keys currently)",
+    if item.has_name(Symbol::intern("min_lds_size")) {
+      return Some(Condition::MinLdsSize(value_u32(tcx, item)?));
+    }
+    if is_known_condition_key(item) {
+      return Some(Condition::Foreign);
+    }
+
+    let msg = format!("unknown attr key `{}`; expected one of `platform`, `gfx`, \
+                       `feature`, `wavefront_size`, or `min_lds_size`",
                       item.name_or_empty());
     tcx.sess.span_err(item.span, &msg);
 
-
     None
   }
+
+  fn satisfied_by(&self, root_conditions: &[Self]) -> bool {
+    match self {
+      &Condition::MinLdsSize(min) => {
+        root_conditions.iter()
+          .any(|root_cond| match root_cond {
+            &Condition::LdsSize(size) => size >= min,
+            _ => false,
+          })
+      },
+      &Condition::Foreign => false,
+      _ => root_conditions.iter().any(|root_cond| root_cond == self ),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn target_conditions() {
+    let roots = Condition::for_target("gfx90a", "+dpp,+s-memrealtime,+16-bit-insts",
+                                      &[64], Some(65536));
+    let sat = |c: Condition| c.satisfied_by(&roots);
+
+    assert!(sat(Condition::Platform));
+    assert!(sat(Condition::Gfx("gfx90a".into())));
+    assert!(!sat(Condition::Gfx("gfx900".into())));
+    assert!(sat(Condition::Feature("dpp".into())));
+    assert!(sat(Condition::Feature("16-bit-insts".into())));
+    assert!(!sat(Condition::Feature("wavefrontsize32".into())));
+    assert!(sat(Condition::WavefrontSize(64)));
+    assert!(!sat(Condition::WavefrontSize(32)));
+    assert!(sat(Condition::MinLdsSize(32 * 1024)));
+    assert!(sat(Condition::MinLdsSize(64 * 1024)));
+    assert!(!sat(Condition::MinLdsSize(64 * 1024 + 1)));
+    assert!(!sat(Condition::Foreign));
+
+    let expr = ConditionalExpr::All(vec![
+      ConditionalExpr::Item(Condition::Platform),
+      ConditionalExpr::Not(Box::new(ConditionalExpr::Item(Condition::WavefrontSize(32)))),
+    ]);
+    assert!(expr.eval(&|c: &Condition| c.satisfied_by(&roots) ));
+  }
+
+  #[test]
+  fn no_lds_size() {
+    let roots = Condition::for_target("gfx900", "", &[64], None);
+    assert!(!Condition::MinLdsSize(1).satisfied_by(&roots));
+  }
 }
@@ -1,18 +1,20 @@
 
 use std::collections::HashMap;
 use std::env::var_os;
-use std::fs::{File, };
+use std::fs::{self, File, };
 use std::geobacter::platform::{*, hsa::AmdGcn, };
+use std::fmt::Write as FmtWrite;
 use std::io::{Write, Read, stderr, };
 use std::path::Path;
 use std::process::Command;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Weak, };
 
-use crate::log::{info, debug};
+use crate::log::{info, debug, warn, };
 
 use rustc_hir::def_id::DefId;
 use rustc_data_structures::sync::Lrc;
+use rustc_geobacter::TyCtxtKernelInstance;
 use rustc_geobacter::intrinsics::IntrinsicName;
 use rustc_geobacter::intrinsics::platform::PlatformIntrinsic;
 use rustc_geobacter::intrinsics::arch::amdgpu::AmdGpuSuicide;
@@ -20,21 +22,27 @@ use rustc_middle::middle::codegen_fn_attrs::CodegenFnAttrs;
 use rustc_middle::mir::CustomIntrinsicMirGen;
 use rustc_middle::ty::{TyCtxt, Instance, };
 
-use amd_comgr::{set::DataSet, data::RelocatableData,
+use amdgpu_metadata::HsaMetadataMap;
+
+use amd_comgr::{set::DataSet, data::BitcodeData, data::RelocatableData,
                 data::Data, action::*, };
 
-use crate::grt_core::{AcceleratorTargetDesc, };
+use crate::grt_core::{AcceleratorTargetDesc, CrateNameHash, };
 use crate::grt_core::codegen as core_codegen;
 use crate::grt_core::codegen::*;
 use crate::grt_core::codegen::help::LlvmBuildRoot;
+use crate::grt_core::codegen::stubbing;
 use crate::grt_core::codegen::products::*;
 
 use crate::serde::{Serialize, Deserialize, };
-use crate::rmps::decode::{from_slice as rmps_from_slice, };
 
 use crate::{HsaAmdGpuAccel, HsaAmdTargetDescHelper, Error};
+use crate::math::MathOptions;
+
+use self::sources::{DeviceSource, DeviceSources, };
 
 pub mod attrs;
+pub mod sources;
 
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Codegenner;
@@ -53,6 +61,16 @@ impl PlatformCodegen for Codegenner {
     opts.cg.no_vectorize_slp = true;
   }
 
+  fn modify_stubber(&self, _target_desc: &Arc<AcceleratorTargetDesc>,
+                    stubber: &mut stubbing::Stubber)
+  {
+    // Kernels which don't have a heap attached will still abort on alloc, just
+    // with a different message.
+    crate::heap::stubs::insert_stubs(stubber);
+    // These call into the device libraries only if they're linked.
+    crate::math::stubs::insert_stubs(stubber);
+  }
+
   fn insert_intrinsics<F>(&self,
                           target_desc: &Arc<AcceleratorTargetDesc>,
                           into: &mut F)
@@ -70,13 +88,20 @@ impl PlatformCodegen for Codegenner {
 
   fn root<'tcx>(&self, desc: PKernelDesc<Self>,
                 instance: Instance<'tcx>,
-                _tcx: TyCtxt<'tcx>,
+                tcx: TyCtxt<'tcx>,
                 _dd: &DriverData<'tcx, Self>)
     -> Result<PCodegenDesc<'tcx, Self>, Error>
   {
     // most of these fields are filled-in post-codegen
     let mut cg_desc = CodegenDesc::default();
     cg_desc.max_vgpr_count = desc.platform_desc.max_vgpr_count;
+    cg_desc.math = desc.platform_desc.math;
+    let krate = CrateNameHash::of(tcx, instance.def_id().krate);
+    cg_desc.sources = sources::crate_device_sources(&krate, |f| {
+      let f = tcx.convert_kernel_instance(f)?;
+      Some(CrateNameHash::of(tcx, f.def_id().krate))
+    });
+    cg_desc.sources.extend(desc.platform_desc.sources.iter().cloned());
 
     Ok(core_codegen::CodegenDesc {
       instance,
@@ -89,10 +114,26 @@ impl PlatformCodegen for Codegenner {
   fn root_conditions<'tcx>(&self,
                            _root: &PCodegenDesc<'tcx, Self>,
                            _tcx: TyCtxt<'tcx>,
-                           _dd: &DriverData<'tcx, Self>)
+                           dd: &DriverData<'tcx, Self>)
     -> Result<Vec<Self::Condition>, Error>
   {
-    Ok(vec![attrs::Condition::Platform])
+    let target = &dd.target_desc.target;
+    let wavefront_sizes = dd.target_desc.isa_info()
+      .wavefronts
+      .iter()
+      .map(|wf| wf.size )
+      .collect::<Vec<_>>();
+    // All accels sharing a target desc are the same processor, so any of them will do.
+    let lds_size = dd.accels.iter()
+      .filter_map(Weak::upgrade)
+      .next()
+      .map(|accel| accel.group_segment_size() )
+      .transpose()?
+      .map(|size| size as u32 );
+    Ok(attrs::Condition::for_target(&target.options.cpu,
+                                    &target.options.features,
+                                    &wavefront_sizes,
+                                    lds_size))
   }
 
   fn pre_codegen<'tcx>(&self,
@@ -116,7 +157,17 @@ impl PlatformCodegen for Codegenner {
       entry.symbol.push_str(".kd");
     }
 
-    let obj_data = if let Some(obj) = codegen.take_object() {
+    let math = codegen.root().platform.math;
+    let sources = codegen.root().platform.sources.clone();
+
+    let obj_data = if math.device_libs || !sources.is_empty() {
+      // rustc's object has unresolved calls into the device libraries or sources,
+      // so start again from the bitcode:
+      codegen.take_object();
+      let bc = codegen.take_bitcode()
+        .expect("no bitcode output");
+      link_bitcode(target_desc, tdir, &math, &sources, &bc)?
+    } else if let Some(obj) = codegen.take_object() {
       obj
     } else {
       // fallback to invoking llc manually:
@@ -165,14 +216,6 @@ impl PlatformCodegen for Codegenner {
 
       info!("finished running llc");
 
-      // run llc again, but write asm this time
-      let mut llc = llc_cmd();
-
-      llc.arg("-filetype=asm")
-        .arg("-o").arg(tdir.join("codegen.s"));
-
-      run_cmd(llc)?;
-
       let mut b = Vec::new();
       File::open(&obj)?.read_to_end(&mut b)?;
       b
@@ -182,39 +225,23 @@ impl PlatformCodegen for Codegenner {
     let mut data = RelocatableData::new()?;
     data.set_data(&obj_data)?;
     data.set_name("obj-for-linking.o".into())?;
+
+    // The final link produces a shared object, which would happily leave these
+    // unresolved until the kernel is loaded.
+    let undefined = data.symbols()?
+      .into_iter()
+      .filter(|symbol| symbol.undefined )
+      .map(|symbol| symbol.name )
+      .collect::<Vec<_>>();
+    if !undefined.is_empty() {
+      return Err(Error::UndefinedDeviceSymbols(undefined));
+    }
+
     let mut set = DataSet::new()?;
     set.add_data(&data)?;
 
-    let mut action = Action {
-      kind: ActionKind::LinkRelocToExe,
-      info: ActionInfo::new()?,
-    };
-    action.set_working_path(Some(tdir.into()))?;
-    action.set_logging(true)?;
-    // amd-comgr requires an isa, even for linking:
-    action.set_isa_name(Some(target_desc.isa_name()))?;
-    let mut out_set = DataSet::new()?;
-    match set.perform_into(&action, &mut out_set) {
-      Ok(_) => { },
-      Err(err) => {
-        if out_set.logs_len()? > 0 {
-          let stderr = stderr();
-          let mut stderr = stderr.lock();
-          writeln!(stderr, "Link error; logs follow:").expect("stderr write");
-          // dump the logs:
-          for log in out_set.log_iter()? {
-            let log = log.expect("unwrap set log data");
-            let name = log.name().expect("non-utf8 log name");
-            let data = log.data_str().expect("non-utf8 log data");
-            writeln!(stderr, "log entry `{}`:", name).expect("stderr write");
-            stderr.write_all(data.as_ref()).expect("stderr write");
-            writeln!(stderr).expect("stderr write");
-          }
-        }
-
-        return Err(err.into());
-      },
-    }
+    let action = comgr_action(target_desc, tdir, ActionKind::LinkRelocToExe)?;
+    let out_set = perform_action(&set, &action)?;
 
     assert_eq!(out_set.executables_len()?, 1);
     let exe = out_set.get_executable(0)?;
@@ -230,22 +257,8 @@ impl PlatformCodegen for Codegenner {
         _ => unreachable!("can only load from elf files"),
       };
 
-      let mut metadata: Option<HsaMetadataMap> = None;
-      if let Some(notes) = object.iter_note_sections(&exe, None) {
-        for note in notes {
-          let note = note?;
-          if note.n_type != NT_AMDGPU_METADATA { continue; }
-
-          let desc = note.desc;
-          let md = rmps_from_slice(desc)?;
-          info!("found NT_AMDGPU_METADATA note: {:#?}", md);
-          metadata = Some(md);
-          break;
-        }
-      }
-
-      let metadata = metadata
-        .ok_or(Error::MissingKernelMetadataNote)?;
+      let metadata = HsaMetadataMap::parse(&object, &exe)?;
+      info!("found NT_AMDGPU_METADATA note: {:#?}", metadata);
 
       let name_to_idx: HashMap<_, _> = metadata
         .kernels
@@ -279,6 +292,10 @@ impl PlatformCodegen for Codegenner {
       }
     }
 
+    if let Some(dir) = var_os("GEOBACTER_KERNEL_DUMP_DIR") {
+      dump_kernel(Path::new(&dir), &codegen.root().symbol, &exe);
+    }
+
     codegen.put_exe(exe);
 
     Ok(())
@@ -324,6 +341,7 @@ impl PlatformCodegen for Codegenner {
         let rt_item = Symbol::intern("runtime_item");
         let amdgpu = Symbol::intern("amdgpu");
         let lds_array = Symbol::intern("lds_array");
+        let lds_dyn = Symbol::intern("lds_dyn");
         geobacter_attrs(tcx, adt_did.did, |meta| {
           if attrs.addr_space.is_some() { return; }
 
@@ -344,7 +362,7 @@ impl PlatformCodegen for Codegenner {
             }
 
             if let Some(rt_item) = item.value_str() {
-              if rt_item == lds_array {
+              if rt_item == lds_array || rt_item == lds_dyn {
                 attrs.addr_space = Some(AddrSpaceIdx(3));
                 break;
               }
@@ -357,17 +375,20 @@ impl PlatformCodegen for Codegenner {
   }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[derive(Hash)]
 pub struct KernelDesc {
   pub max_vgpr_count: Option<usize>,
+  pub math: MathOptions,
+  /// Linked into the kernel, in order.
+  pub sources: DeviceSources,
 }
 impl KernelDesc { }
 impl PlatformKernelDesc for KernelDesc { }
 
 /// Most fields are filled in during `post_codegen`, after the worker
 /// asks us to create this info.
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[derive(Default, Hash)]
 pub struct CodegenDesc {
   pub group_segment_size: u32,
@@ -383,43 +404,118 @@ pub struct CodegenDesc {
   pub private_segment_p2align: u8,
 
   pub max_vgpr_count: Option<usize>,
+  pub math: MathOptions,
+  pub sources: DeviceSources,
 }
 
 impl PlatformCodegenDesc for CodegenDesc { }
 
-// See https://llvm.org/docs/AMDGPUUsage.html#code-object-v3-metadata-mattr-code-object-v3
-const NT_AMDGPU_METADATA: u32 = 32;
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-struct HsaMetadataMap<'a> {
-  #[serde(rename = "amdhsa.version")]
-  version: (u32, u32),
-  // skip "amdhsa.printf"
-  #[serde(borrow, rename = "amdhsa.kernels")]
-  kernels: Vec<HsaKernelMetadataMap<'a>>,
+fn comgr_action(target_desc: &AcceleratorTargetDesc, tdir: &Path, kind: ActionKind)
+  -> Result<Action, Error>
+{
+  let mut action = Action {
+    kind,
+    info: ActionInfo::new()?,
+  };
+  action.set_working_path(Some(tdir.into()))?;
+  action.set_logging(true)?;
+  // amd-comgr requires an isa, even for linking:
+  action.set_isa_name(Some(target_desc.isa_name()))?;
+  Ok(action)
 }
+/// Run `action`, dumping amd-comgr's logs to stderr if it fails.
+fn perform_action(set: &DataSet, action: &Action) -> Result<DataSet, Error> {
+  perform_action_with(set, action, |err, log| {
+    if !log.is_empty() {
+      let stderr = stderr();
+      let mut stderr = stderr.lock();
+      writeln!(stderr, "{:?} error; logs follow:", action.kind)
+        .expect("stderr write");
+      stderr.write_all(log.as_ref()).expect("stderr write");
+    }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-struct HsaKernelMetadataMap<'a> {
-  #[serde(borrow, rename = ".name")]
-  name: &'a str,
-  #[serde(borrow, rename = ".symbol")]
-  kernel_desc_symbol: &'a str,
-  #[serde(rename = ".kernarg_segment_size")]
-  kernarg_segment_size: u32,
-  #[serde(rename = ".group_segment_fixed_size")]
-  group_segment_size: u32,
-  #[serde(rename = ".private_segment_fixed_size")]
-  private_segment_size: u32,
-  #[serde(rename = ".kernarg_segment_align")]
-  kernarg_segment_align: u32,
-  #[serde(rename = ".wavefront_size")]
-  wavefront_size: u32,
-  #[serde(rename = ".sgpr_count")]
-  sgpr_count: u32,
-  #[serde(rename = ".vgpr_count")]
-  vgpr_count: u32,
-  #[serde(rename = ".max_flat_workgroup_size")]
-  max_flat_workgroup_size: u32,
+    err.into()
+  })
+}
+/// Run `action`. If it fails, `on_err` gets amd-comgr's logs to build the error.
+fn perform_action_with<F>(set: &DataSet, action: &Action, on_err: F)
+  -> Result<DataSet, Error>
+  where F: FnOnce(amd_comgr::error::Error, String) -> Error,
+{
+  let mut out_set = DataSet::new()?;
+  match set.perform_into(action, &mut out_set) {
+    Ok(_) => Ok(out_set),
+    Err(err) => {
+      let mut logs = String::new();
+      for log in out_set.log_iter()? {
+        let log = log.expect("unwrap set log data");
+        let name = log.name().expect("non-utf8 log name");
+        let data = log.data_str().expect("non-utf8 log data");
+        writeln!(logs, "log entry `{}`:", name).unwrap();
+        logs.push_str(&data);
+        logs.push('\n');
+      }
+
+      Err(on_err(err, logs))
+    },
+  }
+}
+
+/// Link the kernel's bitcode with its device sources and ROCm's device libraries
+/// (ocml, ockl, and the `oclc_*` control libraries selected by `math`), and codegen
+/// the result into a relocatable object.
+fn link_bitcode(target_desc: &AcceleratorTargetDesc, tdir: &Path,
+                math: &MathOptions, sources: &[DeviceSource], bc: &[u8])
+  -> Result<Vec<u8>, Error>
+{
+  let mut data = BitcodeData::new()?;
+  data.set_data(bc)?;
+  data.set_name("codegen.bc".into())?;
+  let mut set = DataSet::new()?;
+  set.add_data(&data)?;
+
+  for source in sources.iter() {
+    source.add_to(target_desc, tdir, &mut set)?;
+  }
+
+  // OpenCL sources need the device libraries for their builtins.
+  if math.device_libs || sources.iter().any(DeviceSource::is_opencl) {
+    let mut add_libs = comgr_action(target_desc, tdir, ActionKind::AddDeviceLibs)?;
+    // amd-comgr only knows which libraries to add for OpenCL; ocml and ockl are
+    // language independent.
+    add_libs.set_lang(Some(Lang::OpenCl(2)))?;
+    let options = math.device_lib_options();
+    if !options.is_empty() {
+      add_libs.set_options(Some(&options))?;
+    }
+    set = perform_action(&set, &add_libs)?;
+  }
+
+  let link = comgr_action(target_desc, tdir, ActionKind::LinkBcToBc)?;
+  let set = perform_action_with(&set, &link, |_, log| {
+    Error::DeviceSourceLink(log)
+  })?;
+
+  let mut codegen = comgr_action(target_desc, tdir, ActionKind::CodegenBcToReloc)?;
+  codegen.set_options(Some("-O3"))?;
+  let set = perform_action(&set, &codegen)?;
+
+  assert_eq!(set.relocatables_len()?, 1);
+  let obj = set.get_relocatable(0)?;
+  Ok(obj.data()?)
+}
+
+/// Write the code object of the kernel whose descriptor is `symbol` to `dir`, for
+/// `geobacter-inspect`. Failures are only logged.
+fn dump_kernel(dir: &Path, symbol: &str, exe: &[u8]) {
+  let name = symbol.strip_suffix(".kd").unwrap_or(symbol);
+  let path = dir.join(format!("{}.co", name));
+  let r = fs::create_dir_all(dir)
+    .and_then(|()| fs::write(&path, exe) );
+  match r {
+    Ok(()) => info!("wrote code object to {}", path.display()),
+    Err(err) => warn!("failed to write code object to {}: {}", path.display(), err),
+  }
 }
 
 pub fn run_cmd(mut cmd: Command) -> Result<(), Error> {
@@ -0,0 +1,365 @@
+//! Device code written outside of Rust, eg existing HIP or OpenCL functions, linked into
+//! Rust kernels.
+//!
+//! Declare the functions in an `extern "C"` block in the kernel's crate, and attach
+//! their definitions either to the `FuncModule` with `FuncModule::add_device_source`, or
+//! to the crate with `add_crate_device_source`, which links them into every kernel defined
+//! in that crate. A crate wrapping such code can bundle it with
+//! `include_bytes!`/`include_str!` and hand out the `DeviceSource`s for its users to attach.
+//!
+//! Sources are linked with the kernel's bitcode before codegen, so they're optimized
+//! together. Calls to functions no source defines are reported as
+//! `Error::UndefinedDeviceSymbols`.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::geobacter::kernel::{KernelInstanceRef, OptionalKernelFn, };
+use std::hash::{Hash, Hasher, };
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use amd_comgr::{set::DataSet, data::BitcodeData, data::SourceData, data::Data,
+                action::*, };
+
+use crate::grt_core::{AcceleratorTargetDesc, CrateNameHash, };
+use crate::log::warn;
+use crate::serde::{Serialize, Deserialize, };
+
+use crate::Error;
+
+use super::{comgr_action, perform_action_with, };
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum DeviceSource {
+  /// LLVM bitcode targeting `amdgcn-amd-amdhsa`, eg from
+  /// `hipcc --cuda-device-only -emit-llvm -c`.
+  Bitcode {
+    name: String,
+    data: Arc<[u8]>,
+  },
+  /// OpenCL C 2.0 source. OpenCL builtins are provided by the device libraries, which
+  /// are always linked for kernels with OpenCL sources.
+  OpenCl {
+    name: String,
+    source: Arc<str>,
+  },
+}
+impl DeviceSource {
+  pub fn bitcode(name: impl Into<String>, data: impl Into<Arc<[u8]>>) -> Self {
+    DeviceSource::Bitcode {
+      name: name.into(),
+      data: data.into(),
+    }
+  }
+  pub fn opencl(name: impl Into<String>, source: impl Into<Arc<str>>) -> Self {
+    DeviceSource::OpenCl {
+      name: name.into(),
+      source: source.into(),
+    }
+  }
+  /// Read a bitcode file. The source is named after the file.
+  pub fn bitcode_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+    let path = path.as_ref();
+    Ok(Self::bitcode(file_name(path), fs::read(path)?))
+  }
+  /// Read an OpenCL C file. The source is named after the file.
+  pub fn opencl_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+    let path = path.as_ref();
+    Ok(Self::opencl(file_name(path), fs::read_to_string(path)?))
+  }
+
+  pub fn name(&self) -> &str {
+    match self {
+      DeviceSource::Bitcode { name, .. } |
+      DeviceSource::OpenCl { name, .. } => name,
+    }
+  }
+  pub fn is_opencl(&self) -> bool {
+    match self {
+      DeviceSource::OpenCl { .. } => true,
+      _ => false,
+    }
+  }
+
+  /// Add this source's bitcode to `set`, compiling it first if needed.
+  pub(crate) fn add_to(&self, target_desc: &AcceleratorTargetDesc, tdir: &Path,
+                       set: &mut DataSet)
+    -> Result<(), Error>
+  {
+    match self {
+      DeviceSource::Bitcode { name, data: bc, } => {
+        let mut data = BitcodeData::new()?;
+        data.set_data(bc)?;
+        data.set_name(bc_name(name))?;
+        set.add_data(&data)?;
+      },
+      DeviceSource::OpenCl { name, source, } => {
+        let mut data = SourceData::new()?;
+        data.set_data(source.as_bytes())?;
+        // amd-comgr writes this out to compile it, so it needs the right extension:
+        if name.ends_with(".cl") {
+          data.set_name(name.clone())?;
+        } else {
+          data.set_name(format!("{}.cl", name))?;
+        }
+        let mut src_set = DataSet::new()?;
+        src_set.add_data(&data)?;
+
+        let mut compile = comgr_action(target_desc, tdir, ActionKind::CompileSourceToBc)?;
+        compile.set_lang(Some(Lang::OpenCl(2)))?;
+        compile.set_options(Some("-O3"))?;
+        let out = perform_action_with(&src_set, &compile, |_, log| {
+          Error::DeviceSourceCompile {
+            name: name.clone(),
+            log,
+          }
+        })?;
+        for bc in out.bitcode_iter()? {
+          set.add_data(&bc?)?;
+        }
+      },
+    }
+
+    Ok(())
+  }
+}
+
+/// `DeviceSource`s linked into a kernel, in order. These are compared and hashed by a
+/// hash of their contents, updated as sources are added, so the codegen cache doesn't
+/// hash or compare whole sources on every lookup.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeviceSources {
+  hash: u64,
+  sources: Vec<DeviceSource>,
+}
+impl DeviceSources {
+  pub fn push(&mut self, source: DeviceSource) {
+    let mut hasher = DefaultHasher::new();
+    self.hash.hash(&mut hasher);
+    source.hash(&mut hasher);
+    self.hash = hasher.finish();
+    self.sources.push(source);
+  }
+  pub fn clear(&mut self) {
+    self.hash = 0;
+    self.sources.clear();
+  }
+  pub fn content_hash(&self) -> u64 { self.hash }
+}
+impl Deref for DeviceSources {
+  type Target = [DeviceSource];
+  fn deref(&self) -> &[DeviceSource] { &self.sources }
+}
+impl Extend<DeviceSource> for DeviceSources {
+  fn extend<I>(&mut self, iter: I)
+    where I: IntoIterator<Item = DeviceSource>,
+  {
+    for source in iter {
+      self.push(source);
+    }
+  }
+}
+impl Eq for DeviceSources { }
+impl PartialEq for DeviceSources {
+  fn eq(&self, rhs: &Self) -> bool {
+    self.hash == rhs.hash && self.sources.len() == rhs.sources.len()
+  }
+}
+impl Hash for DeviceSources {
+  fn hash<H>(&self, hasher: &mut H)
+    where H: Hasher,
+  {
+    self.hash.hash(hasher);
+  }
+}
+
+/// Sources attached to crates. Crates are named by one of their functions when sources
+/// are attached, and we can only find out which crate that is during codegen, so
+/// attachments are queued in `pending` until the next kernel is compiled.
+struct CrateSources {
+  /// `None` clears the crate's sources.
+  pending: Vec<(KernelInstanceRef<'static>, Option<DeviceSource>)>,
+  by_crate: Vec<(CrateNameHash, DeviceSources)>,
+}
+impl CrateSources {
+  fn resolve<F>(&mut self, mut crate_of: F)
+    where F: FnMut(KernelInstanceRef<'static>) -> Option<CrateNameHash>,
+  {
+    for (f, source) in self.pending.drain(..) {
+      let krate = match crate_of(f) {
+        Some(krate) => krate,
+        None => {
+          warn!("dropping device source attached with {:?}: its crate isn't loaded", f);
+          continue;
+        },
+      };
+      let idx = self.by_crate.iter()
+        .position(|(name, _)| name == &krate );
+      match (idx, source) {
+        (Some(idx), Some(source)) => self.by_crate[idx].1.push(source),
+        (None, Some(source)) => {
+          let mut sources = DeviceSources::default();
+          sources.push(source);
+          self.by_crate.push((krate, sources));
+        },
+        (Some(idx), None) => { self.by_crate.remove(idx); },
+        (None, None) => { },
+      }
+    }
+  }
+  fn get(&self, krate: &CrateNameHash) -> DeviceSources {
+    self.by_crate.iter()
+      .find(|(name, _)| name == krate )
+      .map(|(_, sources)| sources.clone() )
+      .unwrap_or_default()
+  }
+}
+
+static CRATE_SOURCES: RwLock<CrateSources> = parking_lot::const_rwlock(CrateSources {
+  pending: Vec::new(),
+  by_crate: Vec::new(),
+});
+
+/// Link `source` into every kernel defined in the crate which defines `f`, after the
+/// crate's previously attached sources and before the kernel's `FuncModule` sources.
+/// Only the crate of `f` matters, so a crate can attach its own sources with any of its
+/// functions, eg `add_crate_device_source(attach_sources, source)` in
+/// `fn attach_sources()`. Crates are told apart by their hash too, so two versions of
+/// a crate have separate sources.
+///
+/// Kernels which were already compiled won't be compiled again, so attach sources before
+/// the first kernel is compiled.
+pub fn add_crate_device_source<F, R>(f: F, source: DeviceSource)
+  where F: Fn() -> R,
+{
+  CRATE_SOURCES.write()
+    .pending
+    .push((f.kernel_instance(), Some(source)));
+}
+/// Remove every source attached to the crate which defines `f`. Kernels which were
+/// already compiled will keep them.
+pub fn clear_crate_device_sources<F, R>(f: F)
+  where F: Fn() -> R,
+{
+  CRATE_SOURCES.write()
+    .pending
+    .push((f.kernel_instance(), None));
+}
+/// The sources attached to `krate`. `crate_of` finds the crate of the functions sources
+/// were attached with since the last call.
+pub(crate) fn crate_device_sources<F>(krate: &CrateNameHash, crate_of: F)
+  -> DeviceSources
+  where F: FnMut(KernelInstanceRef<'static>) -> Option<CrateNameHash>,
+{
+  {
+    let sources = CRATE_SOURCES.read();
+    if sources.pending.is_empty() {
+      return sources.get(krate);
+    }
+  }
+
+  let mut sources = CRATE_SOURCES.write();
+  sources.resolve(crate_of);
+  sources.get(krate)
+}
+
+fn file_name(path: &Path) -> String {
+  path.file_name()
+    .unwrap_or(path.as_os_str())
+    .to_string_lossy()
+    .into_owned()
+}
+/// amd-comgr identifies inputs by name, so make sure we never collide with the kernel's
+/// own bitcode.
+fn bc_name(name: &str) -> String {
+  let stem = name.strip_suffix(".bc").unwrap_or(name);
+  format!("device-source-{}.bc", stem)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use rustc_span::symbol::Symbol;
+
+  #[test]
+  fn names() {
+    assert_eq!(file_name(Path::new("/a/b/math.cl")), "math.cl");
+    assert_eq!(bc_name("codegen.bc"), "device-source-codegen.bc");
+    assert_eq!(bc_name("hip"), "device-source-hip.bc");
+
+    let src = DeviceSource::opencl("a.cl", "void f() { }");
+    assert_eq!(src.name(), "a.cl");
+    assert!(src.is_opencl());
+    assert!(!DeviceSource::bitcode("b.bc", Vec::<u8>::new()).is_opencl());
+  }
+
+  #[test]
+  fn device_sources() {
+    let a = DeviceSource::opencl("a.cl", "void f() { }");
+    let b = DeviceSource::bitcode("b.bc", Vec::<u8>::new());
+    let mut ab = DeviceSources::default();
+    ab.extend(vec![a.clone(), b.clone()]);
+    let mut ab2 = DeviceSources::default();
+    ab2.push(a.clone());
+    ab2.push(b.clone());
+    let mut ba = DeviceSources::default();
+    ba.extend(vec![b.clone(), a.clone()]);
+    assert_eq!(ab, ab2);
+    assert_eq!(ab.content_hash(), ab2.content_hash());
+    assert_ne!(ab, ba);
+    assert_eq!(&*ab, [a, b]);
+
+    ab.clear();
+    assert_eq!(ab, DeviceSources::default());
+  }
+
+  #[test]
+  fn crate_sources() {
+    fn in_v1() { }
+    fn in_v1_too() { }
+    fn in_v2() { }
+
+    rustc_span::with_default_session_globals(|| {
+      // two versions of the same crate:
+      let v1 = CrateNameHash {
+        name: Symbol::intern("grt_sources_test"),
+        hash: 1,
+      };
+      let v2 = CrateNameHash {
+        hash: 2,
+        .. v1.clone()
+      };
+      let crate_of = |f: KernelInstanceRef<'static>| {
+        if f == in_v1.kernel_instance() || f == in_v1_too.kernel_instance() {
+          Some(v1.clone())
+        } else if f == in_v2.kernel_instance() {
+          Some(v2.clone())
+        } else {
+          None
+        }
+      };
+
+      let a = DeviceSource::opencl("a.cl", "void f() { }");
+      let b = DeviceSource::bitcode("b.bc", Vec::<u8>::new());
+      let mut sources = CrateSources {
+        pending: vec![],
+        by_crate: vec![],
+      };
+      sources.pending.push((in_v1.kernel_instance(), Some(a.clone())));
+      sources.pending.push((in_v2.kernel_instance(), Some(b.clone())));
+      sources.pending.push((in_v1_too.kernel_instance(), Some(b.clone())));
+      sources.resolve(&crate_of);
+      assert!(sources.pending.is_empty());
+      assert_eq!(&*sources.get(&v1), [a, b.clone()]);
+      assert_eq!(&*sources.get(&v2), [b.clone()]);
+
+      sources.pending.push((in_v1_too.kernel_instance(), None));
+      sources.resolve(&crate_of);
+      assert!(sources.get(&v1).is_empty());
+      assert_eq!(&*sources.get(&v2), [b]);
+    });
+  }
+}
@@ -0,0 +1,518 @@
+//! Workgroup and wavefront wide collective operations: reductions, scans, broadcasts and
+//! ballots, generic over the workgroup's dimensionality.
+//!
+//! Like a barrier, every function here must be called by all workitems of the workgroup (or
+//! of the wavefront, for the `wave_*` functions), from uniform control flow. Within a
+//! wavefront, values are moved between lanes with DPP, `ds_swizzle` and `ds_bpermute`. The
+//! per-wavefront results are then exchanged through a small LDS scratch buffer.
+//!
+//! Wavefronts are assumed to be 64 lanes wide, and to be formed from consecutive workitem linear
+//! ids, with only the last wavefront of a workgroup possibly partially filled.
+//!
+//! ```ignore
+//! fn kernel(&self, vp: KVectorParams<Self>) {
+//!   let v = self.src[vp.gl_id() as usize];
+//!   let sum = collective::reduce(&vp, v, 0u32, |l, r| l + r );
+//!   let offset = collective::exclusive_scan(&vp, v, 0u32, |l, r| l + r );
+//! }
+//! ```
+//!
+//! See `reference` for host implementations.
+
+use std::cell::UnsafeCell;
+use std::geobacter::amdgpu::sync::workgroup_barrier;
+use std::geobacter::platform::platform;
+use std::mem::MaybeUninit;
+use std::ops::Range;
+
+use crate::module::{GridDims, VectorParams, WorkgroupDims, };
+
+pub mod reference;
+
+/// Lanes per wavefront.
+pub const WAVEFRONT_SIZE: u32 = 64;
+/// The number of wavefronts in the largest (1024 workitem) workgroup.
+pub const MAX_WAVEFRONTS: usize = 1024 / WAVEFRONT_SIZE as usize;
+
+// DPP controls:
+const QUAD_PERM_XOR1: u32 = 0xb1; // [1, 0, 3, 2]
+const QUAD_PERM_XOR2: u32 = 0x4e; // [2, 3, 0, 1]
+const ROW_SHR1: u32 = 0x111;
+const ROW_SHR2: u32 = 0x112;
+const ROW_SHR4: u32 = 0x114;
+const ROW_SHR8: u32 = 0x118;
+// `ds_swizzle` bit mode patterns: `and_mask = 0x1f`, `or_mask = 0`, `xor_mask` in bits 10..15.
+const SWIZZLE_XOR4: u32 = 0x101f;
+const SWIZZLE_XOR8: u32 = 0x201f;
+const SWIZZLE_XOR16: u32 = 0x401f;
+
+const ROW_SIZE: u32 = 16;
+
+/// Values which can be moved between lanes. The hardware moves 32 bit words, so larger values
+/// are moved a word at a time. Values must fit in 8 bytes.
+pub unsafe trait CrossLane: Copy {
+  #[doc(hidden)]
+  fn zip_words<F>(self, other: Self, f: F) -> Self
+    where F: FnMut(u32, u32) -> u32;
+  #[doc(hidden)] #[inline(always)]
+  fn map_words<F>(self, mut f: F) -> Self
+    where F: FnMut(u32) -> u32,
+  {
+    self.zip_words(self, move |w, _| f(w) )
+  }
+}
+macro_rules! cross_lane_word {
+  ($($ty:ty,)*) => {$(
+    unsafe impl CrossLane for $ty {
+      #[inline(always)]
+      fn zip_words<F>(self, other: Self, mut f: F) -> Self
+        where F: FnMut(u32, u32) -> u32,
+      {
+        f(self as u32, other as u32) as $ty
+      }
+    }
+  )*};
+}
+cross_lane_word! {
+  u8, i8, u16, i16, u32, i32,
+}
+macro_rules! cross_lane_dword {
+  ($($ty:ty,)*) => {$(
+    unsafe impl CrossLane for $ty {
+      #[inline(always)]
+      fn zip_words<F>(self, other: Self, mut f: F) -> Self
+        where F: FnMut(u32, u32) -> u32,
+      {
+        let (l, r) = (self as u64, other as u64);
+        let lo = f(l as u32, r as u32) as u64;
+        let hi = f((l >> 32) as u32, (r >> 32) as u32) as u64;
+        (lo | (hi << 32)) as $ty
+      }
+    }
+  )*};
+}
+cross_lane_dword! {
+  u64, i64, usize, isize,
+}
+unsafe impl CrossLane for f32 {
+  #[inline(always)]
+  fn zip_words<F>(self, other: Self, mut f: F) -> Self
+    where F: FnMut(u32, u32) -> u32,
+  {
+    f32::from_bits(f(self.to_bits(), other.to_bits()))
+  }
+}
+unsafe impl CrossLane for f64 {
+  #[inline(always)]
+  fn zip_words<F>(self, other: Self, f: F) -> Self
+    where F: FnMut(u32, u32) -> u32,
+  {
+    f64::from_bits(self.to_bits().zip_words(other.to_bits(), f))
+  }
+}
+unsafe impl CrossLane for bool {
+  #[inline(always)]
+  fn zip_words<F>(self, other: Self, mut f: F) -> Self
+    where F: FnMut(u32, u32) -> u32,
+  {
+    f(self as u32, other as u32) != 0
+  }
+}
+
+// The DPP control and swizzle pattern operands must be immediates, hence the macros.
+macro_rules! dpp {
+  ($old:expr, $src:expr, $ctrl:expr) => {{
+    extern "C" {
+      #[link_name = "llvm.amdgcn.update.dpp.i32"]
+      fn update_dpp(old: u32, src: u32, ctrl: u32, row_mask: u32, bank_mask: u32,
+                    bound_ctrl: bool) -> u32;
+    }
+    // lanes with an invalid source lane get `old`.
+    $src.zip_words($old, |src, old| unsafe { update_dpp(old, src, $ctrl, 0xf, 0xf, false) })
+  }};
+}
+macro_rules! swizzle {
+  ($src:expr, $pattern:expr) => {{
+    extern "C" {
+      #[link_name = "llvm.amdgcn.ds.swizzle"]
+      fn ds_swizzle(src: u32, pattern: u32) -> u32;
+    }
+    $src.map_words(|w| unsafe { ds_swizzle(w, $pattern) })
+  }};
+}
+
+#[inline(always)]
+fn readlane<T>(v: T, lane: u32) -> T
+  where T: CrossLane,
+{
+  extern "C" {
+    #[link_name = "llvm.amdgcn.readlane"]
+    fn readlane(v: u32, lane: u32) -> u32;
+  }
+  v.map_words(|w| unsafe { readlane(w, lane) })
+}
+#[inline(always)]
+fn bpermute<T>(v: T, src_lane: u32) -> T
+  where T: CrossLane,
+{
+  extern "C" {
+    #[link_name = "llvm.amdgcn.ds.bpermute"]
+    fn ds_bpermute(addr: u32, v: u32) -> u32;
+  }
+  v.map_words(|w| unsafe { ds_bpermute(src_lane * 4, w) })
+}
+
+fn enforce_amdgpu() {
+  if !platform().is_amdgcn() {
+    panic!("Use collective operations only on AMDGPU");
+  }
+}
+
+/// Where a workitem is within its wavefront, and its wavefront within the workgroup.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct WaveInfo {
+  pub lane: u32,
+  pub wave: u32,
+  /// The number of lanes in this wavefront which have a workitem.
+  pub active: u32,
+  /// The number of wavefronts in the workgroup.
+  pub waves: u32,
+}
+impl WaveInfo {
+  pub fn new(linear_wi: usize, wg_len: usize) -> Self {
+    let (linear_wi, wg_len) = (linear_wi as u32, wg_len as u32);
+    let wave = linear_wi / WAVEFRONT_SIZE;
+    WaveInfo {
+      lane: linear_wi % WAVEFRONT_SIZE,
+      wave,
+      active: (wg_len - wave * WAVEFRONT_SIZE).min(WAVEFRONT_SIZE),
+      waves: (wg_len + WAVEFRONT_SIZE - 1) / WAVEFRONT_SIZE,
+    }
+  }
+  #[inline(always)]
+  pub fn from_vp<G>(vp: &VectorParams<G>) -> Self
+    where G: GridDims,
+          <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+  {
+    Self::new(vp.linear_wi().into(), vp.wg_len())
+  }
+}
+
+/// Reduce `v` over the wavefront. `op` must be associative and commutative, and `identity`
+/// must be its identity. Every lane gets the result.
+pub fn wave_reduce<T, F>(w: &WaveInfo, v: T, identity: T, op: F) -> T
+  where T: CrossLane,
+        F: Fn(T, T) -> T,
+{
+  enforce_amdgpu();
+  if w.active == WAVEFRONT_SIZE {
+    // xor butterfly:
+    let mut x = v;
+    x = op(x, dpp!(x, x, QUAD_PERM_XOR1));
+    x = op(x, dpp!(x, x, QUAD_PERM_XOR2));
+    x = op(x, swizzle!(x, SWIZZLE_XOR4));
+    x = op(x, swizzle!(x, SWIZZLE_XOR8));
+    x = op(x, swizzle!(x, SWIZZLE_XOR16));
+    op(x, bpermute(x, w.lane ^ 32))
+  } else {
+    // The butterfly would read from the inactive lanes. The scan only reads from lower lanes.
+    let x = wave_inclusive_scan(w, v, identity, op);
+    readlane(x, w.active - 1)
+  }
+}
+/// `op` must be associative, and `identity` must be its identity.
+pub fn wave_inclusive_scan<T, F>(w: &WaveInfo, v: T, identity: T, op: F) -> T
+  where T: CrossLane,
+        F: Fn(T, T) -> T,
+{
+  enforce_amdgpu();
+
+  // First within each row of 16 lanes:
+  let mut x = v;
+  x = op(dpp!(identity, x, ROW_SHR1), x);
+  x = op(dpp!(identity, x, ROW_SHR2), x);
+  x = op(dpp!(identity, x, ROW_SHR4), x);
+  x = op(dpp!(identity, x, ROW_SHR8), x);
+
+  // then add the totals of the preceding rows, which are always full.
+  let row = w.lane / ROW_SIZE;
+  let mut prefix = identity;
+  for r in 0..(WAVEFRONT_SIZE / ROW_SIZE - 1) {
+    let total = readlane(x, r * ROW_SIZE + ROW_SIZE - 1);
+    if r < row {
+      prefix = op(prefix, total);
+    }
+  }
+  op(prefix, x)
+}
+/// `op` must be associative, and `identity` must be its identity. The first lane gets
+/// `identity`.
+pub fn wave_exclusive_scan<T, F>(w: &WaveInfo, v: T, identity: T, op: F) -> T
+  where T: CrossLane,
+        F: Fn(T, T) -> T,
+{
+  let x = wave_inclusive_scan(w, v, identity, op);
+  shift_up(w, x, identity)
+}
+#[inline(always)]
+fn shift_up<T>(w: &WaveInfo, x: T, identity: T) -> T
+  where T: CrossLane,
+{
+  let prev = bpermute(x, w.lane.wrapping_sub(1) % WAVEFRONT_SIZE);
+  if w.lane == 0 { identity } else { prev }
+}
+/// `lane` must be the same in every lane.
+#[inline(always)]
+pub fn wave_broadcast<T>(v: T, lane: u32) -> T
+  where T: CrossLane,
+{
+  enforce_amdgpu();
+  readlane(v, lane)
+}
+/// Returns a mask with a bit set for every lane whose `pred` is true.
+#[inline(always)]
+pub fn wave_ballot(pred: bool) -> u64 {
+  extern "C" {
+    #[link_name = "llvm.amdgcn.icmp.i64.i32"]
+    fn icmp(l: u32, r: u32, cond: u32) -> u64;
+  }
+  const ICMP_NE: u32 = 33;
+
+  enforce_amdgpu();
+  unsafe { icmp(pred as u32, 0, ICMP_NE) }
+}
+
+/// Wavefront results are exchanged through here. Every value is at most 8 bytes.
+#[allow(unused_attributes)] // TODO
+#[geobacter(amdgpu(runtime_item = "lds_array"))]
+struct Scratch(UnsafeCell<MaybeUninit<[u64; MAX_WAVEFRONTS]>>);
+unsafe impl Sync for Scratch { }
+static SCRATCH: Scratch = Scratch(UnsafeCell::new(MaybeUninit::uninit()));
+
+#[inline(always)]
+fn scratch<T>(idx: u32) -> *mut T
+  where T: CrossLane,
+{
+  unsafe { (SCRATCH.0.get() as *mut u64).add(idx as usize) as *mut T }
+}
+/// Write `v` from the first lane of every wavefront into the scratch buffer.
+#[inline(always)]
+fn publish<T>(w: &WaveInfo, v: T)
+  where T: CrossLane,
+{
+  // Wait for readers from any previous collective operation.
+  workgroup_barrier();
+  if w.lane == 0 {
+    unsafe { scratch::<T>(w.wave).write(v) };
+  }
+  workgroup_barrier();
+}
+#[inline(always)]
+fn fold_published<T, F>(waves: Range<u32>, identity: T, op: F) -> T
+  where T: CrossLane,
+        F: Fn(T, T) -> T,
+{
+  waves.fold(identity, |acc, wave| {
+    op(acc, unsafe { scratch::<T>(wave).read() })
+  })
+}
+
+/// Reduce `v` over the workgroup. `op` must be associative and commutative, and `identity`
+/// must be its identity. Every workitem gets the result.
+pub fn reduce<G, T, F>(vp: &VectorParams<G>, v: T, identity: T, op: F) -> T
+  where G: GridDims,
+        <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+        T: CrossLane,
+        F: Fn(T, T) -> T,
+{
+  let w = WaveInfo::from_vp(vp);
+  let x = wave_reduce(&w, v, identity, &op);
+  if w.waves == 1 { return x; }
+
+  publish(&w, x);
+  fold_published(0..w.waves, identity, &op)
+}
+/// Scan `v` over the workgroup, in workitem linear id order. `op` must be associative, and
+/// `identity` must be its identity.
+pub fn inclusive_scan<G, T, F>(vp: &VectorParams<G>, v: T, identity: T, op: F) -> T
+  where G: GridDims,
+        <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+        T: CrossLane,
+        F: Fn(T, T) -> T,
+{
+  let w = WaveInfo::from_vp(vp);
+  let x = wave_inclusive_scan(&w, v, identity, &op);
+  if w.waves == 1 { return x; }
+
+  publish(&w, readlane(x, w.active - 1));
+  op(fold_published(0..w.wave, identity, &op), x)
+}
+/// Like `inclusive_scan`, but the first workitem gets `identity` and every other workitem gets
+/// the scan of the workitems before it.
+pub fn exclusive_scan<G, T, F>(vp: &VectorParams<G>, v: T, identity: T, op: F) -> T
+  where G: GridDims,
+        <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+        T: CrossLane,
+        F: Fn(T, T) -> T,
+{
+  let w = WaveInfo::from_vp(vp);
+  let x = wave_inclusive_scan(&w, v, identity, &op);
+  let prev = shift_up(&w, x, identity);
+  if w.waves == 1 { return prev; }
+
+  publish(&w, readlane(x, w.active - 1));
+  op(fold_published(0..w.wave, identity, &op), prev)
+}
+/// Every workitem gets the `v` of the workitem with linear id `from`.
+pub fn broadcast<G, T>(vp: &VectorParams<G>, v: T, from: usize) -> T
+  where G: GridDims,
+        <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+        T: CrossLane,
+{
+  enforce_amdgpu();
+  let linear_wi: usize = vp.linear_wi().into();
+
+  workgroup_barrier();
+  if linear_wi == from {
+    unsafe { scratch::<T>(0).write(v) };
+  }
+  workgroup_barrier();
+  unsafe { scratch::<T>(0).read() }
+}
+pub fn ballot<G>(vp: &VectorParams<G>, pred: bool) -> Ballot
+  where G: GridDims,
+        <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+{
+  let w = WaveInfo::from_vp(vp);
+  let mut out = Ballot {
+    masks: [0; MAX_WAVEFRONTS],
+    len: vp.wg_len() as _,
+  };
+  let mask = wave_ballot(pred);
+  if w.waves == 1 {
+    out.masks[0] = mask;
+    return out;
+  }
+
+  publish(&w, mask);
+  for wave in 0..w.waves {
+    out.masks[wave as usize] = unsafe { scratch::<u64>(wave).read() };
+  }
+  out
+}
+/// Is `pred` true in any workitem?
+#[inline(always)]
+pub fn any<G>(vp: &VectorParams<G>, pred: bool) -> bool
+  where G: GridDims,
+        <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+{
+  ballot(vp, pred).any()
+}
+/// Is `pred` true in every workitem?
+#[inline(always)]
+pub fn all<G>(vp: &VectorParams<G>, pred: bool) -> bool
+  where G: GridDims,
+        <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+{
+  ballot(vp, pred).all()
+}
+
+/// A workgroup wide ballot: a bit for every workitem, indexed by workitem linear id.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct Ballot {
+  masks: [u64; MAX_WAVEFRONTS],
+  len: u32,
+}
+impl Ballot {
+  /// The workgroup size.
+  pub fn len(&self) -> usize { self.len as _ }
+  pub fn is_empty(&self) -> bool { self.len == 0 }
+  pub fn get(&self, linear_wi: usize) -> bool {
+    let (wave, lane) = (linear_wi / WAVEFRONT_SIZE as usize,
+                        linear_wi % WAVEFRONT_SIZE as usize);
+    linear_wi < self.len() && self.masks[wave] & (1 << lane) != 0
+  }
+  pub fn wave_mask(&self, wave: usize) -> u64 { self.masks[wave] }
+  pub fn count(&self) -> u32 {
+    self.masks.iter().map(|m| m.count_ones() ).sum()
+  }
+  pub fn any(&self) -> bool { self.count() != 0 }
+  pub fn all(&self) -> bool { self.count() == self.len }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::utils::test::*;
+
+  #[test]
+  fn partial_wavefronts() {
+    #[derive(GeobacterDeps)]
+    struct CollectiveKernel {
+      dst: *mut [(u32, u32, u32, bool, bool)],
+      completion: GlobalSignal,
+    }
+    unsafe impl Send for CollectiveKernel { }
+    unsafe impl Sync for CollectiveKernel { }
+    impl Completion for CollectiveKernel {
+      type CompletionSignal = GlobalSignal;
+      fn completion(&self) -> &GlobalSignal { &self.completion }
+    }
+    impl Kernel for CollectiveKernel {
+      type Grid = Dim2D<RangeTo<u32>>;
+      // 100 workitems; the second wavefront is partially filled.
+      const WORKGROUP: Dim2D<RangeTo<u16>> = Dim2D {
+        x: ..10,
+        y: ..10,
+      };
+
+      type Queue = DeviceMultiQueue;
+      fn queue(&self) -> &DeviceMultiQueue { queue() }
+
+      fn kernel(&self, vp: KVectorParams<Self>) {
+        let wi = vp.linear_wi() as u32;
+        let sum = reduce(&vp, wi, 0, |l, r| l + r );
+        let scan = exclusive_scan(&vp, wi, 0, |l, r| l + r );
+        let from_last = broadcast(&vp, wi, 99);
+        let every = all(&vp, wi < 100);
+        // false on only some lanes of the second wavefront:
+        let not_every = all(&vp, wi < 70);
+        unsafe {
+          (&mut *self.dst)[vp.gl_id() as usize] =
+            (sum, scan, from_last, every, not_every);
+        }
+      }
+    }
+
+    let dev = device();
+
+    let grid = Dim2D {
+      x: ..10,
+      y: ..10,
+    };
+
+    let mut m = LapVec::new_in(dev.fine_lap_node_alloc(0));
+    m.add_access(&dev).unwrap();
+    m.resize(grid.linear_len().unwrap() as _, Default::default());
+
+    let module = CollectiveKernel::module(&dev);
+    let mut invoc = module.into_invoc(args_pool());
+    unsafe {
+      let args = CollectiveKernel {
+        dst: m.as_mut_slice(),
+        completion: GlobalSignal::new(1).unwrap(),
+      };
+      invoc.unchecked_call_async(&grid, args)
+        .unwrap()
+        .wait_for_zero(true)
+        .unwrap();
+    }
+
+    let wis: Vec<u32> = (0..100).collect();
+    let sum = reference::reduce(&wis, 0, |l, r| l + r );
+    let scan = reference::exclusive_scan(&wis, 0, |l, r| l + r );
+    for (i, &v) in m.iter().enumerate() {
+      assert_eq!(v, (sum, scan[i], 99, true, false), "i = {}", i);
+    }
+  }
+}
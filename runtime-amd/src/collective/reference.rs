@@ -0,0 +1,271 @@
+//! Host implementations of the collective operations, for checking kernels which use them
+//! without a GPU. These take every workitem's value, indexed by workitem linear id, and
+//! return every workitem's result.
+//!
+//! The `model_*` functions instead run the device algorithms lane by lane, including the DPP
+//! and swizzle lane patterns, and panic if a result depends on an inactive lane.
+
+use super::*;
+
+pub fn reduce<T, F>(values: &[T], identity: T, op: F) -> T
+  where T: Clone,
+        F: Fn(T, T) -> T,
+{
+  values.iter().cloned().fold(identity, op)
+}
+pub fn inclusive_scan<T, F>(values: &[T], identity: T, op: F) -> Vec<T>
+  where T: Clone,
+        F: Fn(T, T) -> T,
+{
+  let mut acc = identity;
+  values.iter()
+    .map(|v| {
+      acc = op(acc.clone(), v.clone());
+      acc.clone()
+    })
+    .collect()
+}
+pub fn exclusive_scan<T, F>(values: &[T], identity: T, op: F) -> Vec<T>
+  where T: Clone,
+        F: Fn(T, T) -> T,
+{
+  let mut acc = identity;
+  values.iter()
+    .map(|v| {
+      let prev = acc.clone();
+      acc = op(acc.clone(), v.clone());
+      prev
+    })
+    .collect()
+}
+pub fn broadcast<T>(values: &[T], from: usize) -> Vec<T>
+  where T: Clone,
+{
+  vec![values[from].clone(); values.len()]
+}
+pub fn ballot(preds: &[bool]) -> Ballot {
+  assert!(preds.len() <= MAX_WAVEFRONTS * WAVEFRONT_SIZE as usize);
+
+  let mut out = Ballot {
+    masks: [0; MAX_WAVEFRONTS],
+    len: preds.len() as _,
+  };
+  for (i, _) in preds.iter().enumerate().filter(|&(_, &p)| p ) {
+    out.masks[i / WAVEFRONT_SIZE as usize] |= 1 << (i % WAVEFRONT_SIZE as usize);
+  }
+  out
+}
+
+/// The lane `lane` reads from, or `None` if the source is out of bounds and `old` is used.
+/// Only the DPP controls the device code uses are modeled.
+fn dpp_src(lane: u32, ctrl: u32) -> Option<u32> {
+  match ctrl {
+    QUAD_PERM_XOR1 | QUAD_PERM_XOR2 => {
+      Some((lane & !3) | ((ctrl >> ((lane & 3) * 2)) & 3))
+    },
+    ROW_SHR1 | ROW_SHR2 | ROW_SHR4 | ROW_SHR8 => {
+      let shift = ctrl & 0xf;
+      if lane % ROW_SIZE >= shift { Some(lane - shift) } else { None }
+    },
+    _ => {
+      debug_assert!(false, "unmodeled DPP control {:#x}", ctrl);
+      None
+    },
+  }
+}
+fn swizzle_src(lane: u32, pattern: u32) -> u32 {
+  assert_eq!(pattern & 0x8000, 0, "only bit mode swizzles are modeled");
+  let and_mask = pattern & 0x1f;
+  let or_mask = (pattern >> 5) & 0x1f;
+  let xor_mask = (pattern >> 10) & 0x1f;
+  (lane & !0x1f) | (((lane & and_mask) | or_mask) ^ xor_mask)
+}
+/// `lanes` only has the active lanes.
+fn read<T>(lanes: &[T], lane: u32) -> T
+  where T: Clone,
+{
+  lanes.get(lane as usize)
+    .cloned()
+    .unwrap_or_else(|| panic!("read from inactive lane {}", lane) )
+}
+
+fn model_wave_inclusive_scan<T, F>(lanes: &[T], identity: &T, op: &F) -> Vec<T>
+  where T: Clone,
+        F: Fn(T, T) -> T,
+{
+  let mut x = lanes.to_vec();
+  for &ctrl in [ROW_SHR1, ROW_SHR2, ROW_SHR4, ROW_SHR8].iter() {
+    let prev = x.clone();
+    for (lane, x) in x.iter_mut().enumerate() {
+      let src = dpp_src(lane as u32, ctrl)
+        .map(|src| read(&prev, src) )
+        .unwrap_or_else(|| identity.clone() );
+      *x = op(src, x.clone());
+    }
+  }
+
+  (0..x.len() as u32)
+    .map(|lane| {
+      let row = lane / ROW_SIZE;
+      let prefix = (0..row)
+        .map(|r| read(&x, r * ROW_SIZE + ROW_SIZE - 1) )
+        .fold(identity.clone(), op);
+      op(prefix, x[lane as usize].clone())
+    })
+    .collect()
+}
+fn model_wave_reduce<T, F>(lanes: &[T], identity: &T, op: &F) -> Vec<T>
+  where T: Clone,
+        F: Fn(T, T) -> T,
+{
+  if lanes.len() != WAVEFRONT_SIZE as usize {
+    let x = model_wave_inclusive_scan(lanes, identity, op);
+    return vec![read(&x, lanes.len() as u32 - 1); lanes.len()];
+  }
+
+  let steps: [&dyn Fn(u32) -> u32; 6] = [
+    &|lane| dpp_src(lane, QUAD_PERM_XOR1).unwrap(),
+    &|lane| dpp_src(lane, QUAD_PERM_XOR2).unwrap(),
+    &|lane| swizzle_src(lane, SWIZZLE_XOR4),
+    &|lane| swizzle_src(lane, SWIZZLE_XOR8),
+    &|lane| swizzle_src(lane, SWIZZLE_XOR16),
+    &|lane| lane ^ 32,
+  ];
+  let mut x = lanes.to_vec();
+  for src in steps.iter() {
+    let prev = x.clone();
+    for (lane, x) in x.iter_mut().enumerate() {
+      *x = op(x.clone(), read(&prev, src(lane as u32)));
+    }
+  }
+  x
+}
+/// Run `wave` on every wavefront, then `combine` each wavefront's results, given its index and
+/// every wavefront's total.
+fn model_workgroup<T, W, C>(values: &[T], wave: W, mut combine: C) -> Vec<T>
+  where T: Clone,
+        W: Fn(&[T]) -> (Vec<T>, T),
+        C: FnMut(usize, Vec<T>, &[T]) -> Vec<T>,
+{
+  let (results, totals): (Vec<_>, Vec<_>) = values
+    .chunks(WAVEFRONT_SIZE as usize)
+    .map(wave)
+    .unzip();
+  results.into_iter()
+    .enumerate()
+    .flat_map(|(wave, results)| combine(wave, results, &totals) )
+    .collect()
+}
+
+pub fn model_reduce<T, F>(values: &[T], identity: T, op: F) -> Vec<T>
+  where T: Clone,
+        F: Fn(T, T) -> T,
+{
+  model_workgroup(values, |lanes| {
+    let x = model_wave_reduce(lanes, &identity, &op);
+    let total = x[0].clone();
+    (x, total)
+  }, |_, x, totals| {
+    let total = totals.iter().cloned().fold(identity.clone(), &op);
+    vec![total; x.len()]
+  })
+}
+pub fn model_inclusive_scan<T, F>(values: &[T], identity: T, op: F) -> Vec<T>
+  where T: Clone,
+        F: Fn(T, T) -> T,
+{
+  model_workgroup(values, |lanes| {
+    let x = model_wave_inclusive_scan(lanes, &identity, &op);
+    let total = read(&x, lanes.len() as u32 - 1);
+    (x, total)
+  }, |wave, x, totals| {
+    let prefix = totals[..wave].iter().cloned().fold(identity.clone(), &op);
+    x.into_iter().map(|x| op(prefix.clone(), x) ).collect()
+  })
+}
+pub fn model_exclusive_scan<T, F>(values: &[T], identity: T, op: F) -> Vec<T>
+  where T: Clone,
+        F: Fn(T, T) -> T,
+{
+  model_workgroup(values, |lanes| {
+    let x = model_wave_inclusive_scan(lanes, &identity, &op);
+    let total = read(&x, lanes.len() as u32 - 1);
+    let prev = (0..lanes.len() as u32)
+      .map(|lane| {
+        if lane == 0 { identity.clone() } else { read(&x, lane - 1) }
+      })
+      .collect();
+    (prev, total)
+  }, |wave, prev, totals| {
+    let prefix = totals[..wave].iter().cloned().fold(identity.clone(), &op);
+    prev.into_iter().map(|x| op(prefix.clone(), x) ).collect()
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const LENS: &[usize] = &[1, 2, 15, 16, 17, 33, 63, 64, 65, 100, 128, 129, 1000, 1024];
+
+  #[test]
+  fn lane_patterns() {
+    for lane in 0..WAVEFRONT_SIZE {
+      assert_eq!(dpp_src(lane, QUAD_PERM_XOR1), Some(lane ^ 1));
+      assert_eq!(dpp_src(lane, QUAD_PERM_XOR2), Some(lane ^ 2));
+      assert_eq!(swizzle_src(lane, SWIZZLE_XOR4), lane ^ 4);
+      assert_eq!(swizzle_src(lane, SWIZZLE_XOR8), lane ^ 8);
+      assert_eq!(swizzle_src(lane, SWIZZLE_XOR16), lane ^ 16);
+      assert_eq!(dpp_src(lane, ROW_SHR4), (lane % 16).checked_sub(4).map(|_| lane - 4 ));
+    }
+  }
+  #[test]
+  fn wave_info() {
+    assert_eq!(WaveInfo::new(70, 100), WaveInfo {
+      lane: 6,
+      wave: 1,
+      active: 36,
+      waves: 2,
+    });
+    assert_eq!(WaveInfo::new(63, 128).active, 64);
+  }
+  #[test]
+  fn model_sum() {
+    let add = |l: u32, r: u32| l.wrapping_add(r);
+    for &len in LENS {
+      let values: Vec<u32> = (0..len as u32).map(|v| v * 7 + 3 ).collect();
+      let sum = reduce(&values, 0, add);
+      assert_eq!(model_reduce(&values, 0, add), vec![sum; len], "len = {}", len);
+      assert_eq!(model_inclusive_scan(&values, 0, add), inclusive_scan(&values, 0, add),
+                 "len = {}", len);
+      assert_eq!(model_exclusive_scan(&values, 0, add), exclusive_scan(&values, 0, add),
+                 "len = {}", len);
+    }
+  }
+  /// Scans only require associativity.
+  #[test]
+  fn model_scan_order() {
+    let concat = |mut l: Vec<usize>, r: Vec<usize>| {
+      l.extend(r);
+      l
+    };
+    for &len in LENS {
+      let values: Vec<_> = (0..len).map(|v| vec![v] ).collect();
+      assert_eq!(model_inclusive_scan(&values, vec![], concat),
+                 inclusive_scan(&values, vec![], concat), "len = {}", len);
+      assert_eq!(model_exclusive_scan(&values, vec![], concat),
+                 exclusive_scan(&values, vec![], concat), "len = {}", len);
+    }
+  }
+  #[test]
+  fn ballot_bits() {
+    let preds: Vec<_> = (0..100).map(|i| i % 3 == 0 ).collect();
+    let b = ballot(&preds);
+    assert_eq!(b.len(), 100);
+    assert_eq!(b.count(), 34);
+    assert!(b.get(99) && !b.get(98) && !b.get(100));
+    assert!(b.any() && !b.all());
+    assert!(ballot(&[true; 65]).all());
+    assert!(!ballot(&[false; 65]).any());
+  }
+}
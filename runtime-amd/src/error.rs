@@ -9,6 +9,7 @@ use alloc_wg::alloc::Layout;
 use hsa_rt::queue::QueueError;
 
 use crate::HsaError;
+use crate::heap::HeapStats;
 
 #[derive(Debug)]
 #[non_exhaustive]
@@ -51,6 +52,45 @@ pub enum Error {
   KernelWorkgroupLenTooLargeForDevice,
   LaunchGridDimTooLargeForDevice,
   LaunchGridLenTooLargeForDevice,
+  DeviceHeapTooSmall,
+  /// One or more device allocations failed. The kernel will have aborted.
+  DeviceHeapExhausted(HeapStats),
+  /// The `DeviceHeap` was created for a different device.
+  DeviceHeapWrongDevice,
+  /// A queue's compute unit mask must have at least one CU set.
+  EmptyCuMask,
+  /// A queue's compute unit mask sets a CU the device doesn't have. `cu_count` is
+  /// the device's.
+  CuMaskOutOfRange {
+    cu_count: u32,
+  },
+  /// Eg `QueuePoolConfig::max_queues` is zero.
+  InvalidQueuePoolConfig(&'static str),
+  /// The element type of an `LdsDyn` is aligned more strictly than the start of the dynamic
+  /// group segment.
+  LdsDynAlign(usize),
+  /// Access can't be revoked from an allocation shared through a `LapArc`/`LapWeak` which
+  /// isn't unique.
+  LapAccessShared,
+  /// Access can't be revoked from an allocation in a pool every device can access by default.
+  LapAccessDefaultAllowed,
+  /// An OpenCL device source failed to compile. Includes amd-comgr's log.
+  DeviceSourceCompile {
+    name: String,
+    log: String,
+  },
+  /// Linking the kernel with its device sources failed, eg because two define the same
+  /// function. Includes amd-comgr's log.
+  DeviceSourceLink(String),
+  /// The kernel calls these functions, but nothing linked into it defines them.
+  UndefinedDeviceSymbols(Vec<String>),
+  /// A `Primitives` buffer has the wrong length. For `select_if`'s `dst`, `expected` is
+  /// the minimum.
+  PrimitiveBufferLength {
+    buffer: &'static str,
+    expected: usize,
+    actual: usize,
+  },
 }
 impl StdError for Error {
   fn source(&self) -> Option<&(dyn StdError + 'static)> {
@@ -101,6 +141,16 @@ impl From<rmps::decode::Error> for Error {
     Error::KernelInfoMessagePack(v)
   }
 }
+impl From<amdgpu_metadata::Error> for Error {
+  #[inline(always)]
+  fn from(v: amdgpu_metadata::Error) -> Error {
+    match v {
+      amdgpu_metadata::Error::Elf(inner) => Error::KernelInfoElf(inner),
+      amdgpu_metadata::Error::MessagePack(inner) => Error::KernelInfoMessagePack(inner),
+      amdgpu_metadata::Error::MissingNote => Error::MissingKernelMetadataNote,
+    }
+  }
+}
 impl From<QueueError> for Error {
   #[inline(always)]
   fn from(v: QueueError) -> Error {
@@ -0,0 +1,107 @@
+//! A device side heap, so `Box`, `Vec`, etc can be used inside kernels.
+//!
+//! Normally `__rust_alloc` and friends are stubbed to abort on the device. When a
+//! `DeviceHeap` is attached to a `FuncModule` (see `FuncModule::set_device_heap`),
+//! the kernel is specialized to the heap's address and those functions instead
+//! allocate from the heap's pre-reserved region. Allocations are not freed when
+//! the dispatch completes; the heap lives until it is dropped or `reset`.
+//!
+//! Exhaustion on the device results in the usual `handle_alloc_error` abort. The
+//! heap records the failure, which can be checked afterwards on the host with
+//! `DeviceHeap::check_exhaustion`.
+
+use std::sync::Arc;
+
+use crate::{HsaAmdGpuAccel, Error, };
+use crate::boxed::RawPoolBox;
+
+pub use self::slab::HeapStats;
+use self::slab::SlabHeap;
+
+pub mod slab;
+pub(crate) mod stubs;
+
+/// Hardware wavefront size. Each wavefront gets its own cache slot.
+const WAVEFRONT_SIZE: usize = 64;
+const DEFAULT_CACHE_SLOTS: usize = slab::MAX_CACHE_SLOTS;
+
+pub struct DeviceHeap {
+  device: Arc<HsaAmdGpuAccel>,
+  storage: RawPoolBox<[u8]>,
+}
+impl DeviceHeap {
+  /// Reserve `bytes` bytes for a new heap. A small part of this is used for the
+  /// allocator's bookkeeping.
+  pub fn new(accel: &Arc<HsaAmdGpuAccel>, bytes: usize) -> Result<Arc<Self>, Error> {
+    Self::with_cache_slots(accel, bytes, DEFAULT_CACHE_SLOTS)
+  }
+  /// `cache_slots` is clamped to `[1, slab::MAX_CACHE_SLOTS]`. Fewer slots means
+  /// more contention between wavefronts, but less memory lost to blocks sitting in
+  /// other wavefront's caches.
+  pub fn with_cache_slots(accel: &Arc<HsaAmdGpuAccel>, bytes: usize,
+                          cache_slots: usize)
+    -> Result<Arc<Self>, Error>
+  {
+    if bytes < SlabHeap::min_region_size() {
+      return Err(Error::DeviceHeapTooSmall);
+    }
+
+    // The header is read and written by both the host and the device, so this
+    // must be fine grained memory. Prefer device local memory if we have it.
+    let storage = if let Some(&pool) = accel.device_pool_fine() {
+      unsafe { RawPoolBox::<[u8]>::new_uninit_slice(pool, bytes)? }
+    } else {
+      let storage = unsafe {
+        RawPoolBox::<[u8]>::new_uninit_slice(*accel.host_pool(), bytes)?
+      };
+      storage.as_pool_ptr()
+        .grant_agent_access(accel.agent())?;
+      storage
+    };
+
+    unsafe {
+      SlabHeap::init(storage.as_ptr() as *mut u8, storage.len(), cache_slots)
+        .ok_or(Error::DeviceHeapTooSmall)?;
+    }
+
+    Ok(Arc::new(DeviceHeap {
+      device: accel.clone(),
+      storage,
+    }))
+  }
+
+  pub fn device(&self) -> &Arc<HsaAmdGpuAccel> { &self.device }
+
+  fn heap(&self) -> &SlabHeap {
+    unsafe { SlabHeap::from_base(self.storage.as_ptr() as *const u8) }
+  }
+  /// The address which is given to kernels.
+  pub(crate) fn base_addr(&self) -> usize { self.storage.as_ptr() as *const u8 as usize }
+
+  /// Bytes available for allocations.
+  pub fn capacity(&self) -> usize { self.heap().capacity() }
+
+  /// Note: this is racy if a kernel using this heap is running.
+  pub fn stats(&self) -> HeapStats { self.heap().stats() }
+
+  /// Returns an error if any allocation has failed since this heap was created or
+  /// last `reset`. Call after waiting on the dispatch completion signal, as the
+  /// kernel will have aborted.
+  pub fn check_exhaustion(&self) -> Result<(), Error> {
+    let stats = self.stats();
+    if stats.exhausted() {
+      Err(Error::DeviceHeapExhausted(stats))
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Forget every allocation and reset the stats.
+  ///
+  /// # Safety
+  ///
+  /// No kernel may be using this heap, and no device allocations may be used afterwards.
+  pub unsafe fn reset(&self) {
+    self.heap().reset()
+  }
+}
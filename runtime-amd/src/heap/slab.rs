@@ -0,0 +1,544 @@
+//! The allocator algorithm behind `DeviceHeap`. This knows nothing about HSA or
+//! the GPU: it operates on a single pre-reserved region of bytes, which it
+//! carves into power of two sized blocks. Free blocks are kept in lock-free
+//! stacks, one per size class per cache slot. On the GPU, the cache slot is
+//! derived from the wavefront id, so concurrently running wavefronts mostly
+//! don't contend with each other. When a slot's stack is empty, we first try
+//! to steal from the other slots before bumping the high water mark.
+//!
+//! Blocks are never returned to the bump region, thus the memory backing a
+//! popped block is always valid to read from, which is what makes the stacks
+//! safe without hazard pointers. ABA is prevented by a tag in the upper bits
+//! of each stack head.
+
+use std::alloc::Layout;
+use std::cmp::{max, min, };
+use std::mem::size_of;
+use std::ptr::{self, null_mut, };
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering, };
+
+use serde::{Deserialize, Serialize, };
+
+pub const MIN_BLOCK_SHIFT: u32 = 4;
+/// The smallest block we'll hand out. Every block must be able to store the
+/// free list link.
+pub const MIN_BLOCK_SIZE: usize = 1 << MIN_BLOCK_SHIFT;
+pub const SIZE_CLASSES: usize = 24;
+/// 128Mb.
+pub const MAX_BLOCK_SIZE: usize = MIN_BLOCK_SIZE << (SIZE_CLASSES - 1);
+pub const MAX_CACHE_SLOTS: usize = 64;
+/// Blocks larger than this are only aligned to this when bumped.
+const BUMP_ALIGN_LIMIT: usize = 4096;
+
+const OFFSET_BITS: u32 = 40;
+const OFFSET_MASK: u64 = (1 << OFFSET_BITS) - 1;
+const TAG_MASK: u64 = (1 << (64 - OFFSET_BITS)) - 1;
+
+/// A snapshot of the heap's counters.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+#[derive(Serialize, Deserialize)]
+pub struct HeapStats {
+  /// Bytes usable for allocations.
+  pub capacity: usize,
+  /// The bump high water mark, ie bytes which have been carved into blocks.
+  pub reserved: usize,
+  /// Bytes currently allocated, rounded up to the block size.
+  pub live: usize,
+  /// The largest `live` has ever been.
+  pub peak: usize,
+  /// How many allocations have failed.
+  pub failed_allocs: u32,
+  /// The size of the largest failed allocation request.
+  pub largest_failed: usize,
+}
+impl HeapStats {
+  pub fn exhausted(&self) -> bool { self.failed_allocs != 0 }
+}
+
+/// The header, which is placed at the start of the region. Everything here is
+/// accessed through atomics, so it can be shared between any number of host
+/// threads or device wavefronts.
+#[repr(C)]
+pub struct SlabHeap {
+  /// Offset from `self` to the first byte of the arena.
+  arena_offset: usize,
+  capacity: usize,
+  cache_slots: usize,
+
+  bump: AtomicUsize,
+  live: AtomicUsize,
+  peak: AtomicUsize,
+  failed_allocs: AtomicU32,
+  largest_failed: AtomicUsize,
+
+  /// Approximate count of blocks in every slot's stack for each class. Used to
+  /// avoid scanning every slot when there's nothing to steal. Incremented before
+  /// a block is pushed and decremented after it's popped, so it's never less than
+  /// the real count.
+  free_blocks: [AtomicUsize; SIZE_CLASSES],
+  free: [[AtomicU64; SIZE_CLASSES]; MAX_CACHE_SLOTS],
+}
+
+#[inline(always)]
+fn align_up(v: usize, align: usize) -> usize {
+  (v + align - 1) & !(align - 1)
+}
+
+/// Subtract `v` from `count`, stopping at zero. Only a double free, or freeing a block
+/// this heap didn't allocate, can take a counter below zero.
+#[inline(always)]
+fn checked_dec(count: &AtomicUsize, v: usize) {
+  let r = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| c.checked_sub(v) );
+  debug_assert!(r.is_ok(), "heap counter underflow; double free?");
+}
+
+#[inline(always)]
+pub fn size_class(layout: Layout) -> Option<usize> {
+  let size = max(max(layout.size(), layout.align()), MIN_BLOCK_SIZE)
+    .checked_next_power_of_two()?;
+  let class = (size.trailing_zeros() - MIN_BLOCK_SHIFT) as usize;
+  if class < SIZE_CLASSES {
+    Some(class)
+  } else {
+    None
+  }
+}
+#[inline(always)]
+pub fn block_size(class: usize) -> usize {
+  MIN_BLOCK_SIZE << class
+}
+
+impl SlabHeap {
+  /// The smallest region `init` will accept.
+  pub const fn min_region_size() -> usize {
+    size_of::<Self>() + BUMP_ALIGN_LIMIT + MIN_BLOCK_SIZE
+  }
+
+  /// Write a fresh header to `base`. Returns `None` if `len` bytes isn't enough
+  /// for the header and at least one block.
+  ///
+  /// # Safety
+  ///
+  /// `base` must be aligned for `Self`, valid for `len` bytes, and outlive every use
+  /// of the returned reference.
+  pub unsafe fn init<'a>(base: *mut u8, len: usize, cache_slots: usize)
+    -> Option<&'a SlabHeap>
+  {
+    debug_assert_eq!(base as usize % std::mem::align_of::<Self>(), 0);
+
+    let start = base as usize;
+    let arena_start = align_up(start + size_of::<Self>(), BUMP_ALIGN_LIMIT);
+    let end = start.checked_add(len)?;
+    if arena_start + MIN_BLOCK_SIZE > end { return None; }
+    // don't let block offsets overflow the stack head encoding:
+    let capacity = min(end - arena_start,
+                       (OFFSET_MASK as usize - 1) << MIN_BLOCK_SHIFT);
+
+    let this = base as *mut Self;
+    // All zeros is a valid state for every atomic.
+    ptr::write_bytes(this, 0, 1);
+    (*this).arena_offset = arena_start - start;
+    (*this).capacity = capacity;
+    (*this).cache_slots = min(max(cache_slots, 1), MAX_CACHE_SLOTS);
+
+    Some(&*this)
+  }
+  /// # Safety
+  ///
+  /// `base` must have been previously passed to `init`.
+  #[inline(always)]
+  pub unsafe fn from_base<'a>(base: *const u8) -> &'a SlabHeap {
+    &*(base as *const Self)
+  }
+
+  #[inline(always)]
+  fn arena(&self) -> usize {
+    self as *const Self as usize + self.arena_offset
+  }
+  pub fn capacity(&self) -> usize { self.capacity }
+  pub fn cache_slots(&self) -> usize { self.cache_slots }
+
+  pub fn stats(&self) -> HeapStats {
+    HeapStats {
+      capacity: self.capacity,
+      reserved: self.bump.load(Ordering::Relaxed),
+      live: self.live.load(Ordering::Relaxed),
+      peak: self.peak.load(Ordering::Relaxed),
+      failed_allocs: self.failed_allocs.load(Ordering::Relaxed),
+      largest_failed: self.largest_failed.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Forget every allocation.
+  ///
+  /// # Safety
+  ///
+  /// There must not be any live allocations or concurrent users.
+  pub unsafe fn reset(&self) {
+    self.bump.store(0, Ordering::Relaxed);
+    self.live.store(0, Ordering::Relaxed);
+    self.peak.store(0, Ordering::Relaxed);
+    self.failed_allocs.store(0, Ordering::Relaxed);
+    self.largest_failed.store(0, Ordering::Relaxed);
+    for count in self.free_blocks.iter() {
+      count.store(0, Ordering::Relaxed);
+    }
+    for slot in self.free.iter() {
+      for head in slot.iter() {
+        head.store(0, Ordering::Relaxed);
+      }
+    }
+    std::sync::atomic::fence(Ordering::Release);
+  }
+
+  #[inline(always)]
+  fn link(&self, offset: usize) -> &AtomicU64 {
+    unsafe { &*((self.arena() + offset) as *const AtomicU64) }
+  }
+
+  fn pop(&self, head: &AtomicU64) -> Option<usize> {
+    let mut current = head.load(Ordering::Acquire);
+    loop {
+      let idx = current & OFFSET_MASK;
+      if idx == 0 { return None; }
+      let offset = ((idx - 1) as usize) << MIN_BLOCK_SHIFT;
+      let next = self.link(offset).load(Ordering::Relaxed);
+      let tag = ((current >> OFFSET_BITS) + 1) & TAG_MASK;
+      let new = (tag << OFFSET_BITS) | next;
+      match head.compare_exchange_weak(current, new, Ordering::Acquire,
+                                       Ordering::Acquire) {
+        Ok(_) => { return Some(offset); },
+        Err(actual) => { current = actual; },
+      }
+    }
+  }
+  fn push(&self, head: &AtomicU64, offset: usize) {
+    let idx = ((offset >> MIN_BLOCK_SHIFT) + 1) as u64;
+    let mut current = head.load(Ordering::Relaxed);
+    loop {
+      self.link(offset).store(current & OFFSET_MASK, Ordering::Relaxed);
+      let tag = ((current >> OFFSET_BITS) + 1) & TAG_MASK;
+      let new = (tag << OFFSET_BITS) | idx;
+      match head.compare_exchange_weak(current, new, Ordering::Release,
+                                       Ordering::Relaxed) {
+        Ok(_) => { return; },
+        Err(actual) => { current = actual; },
+      }
+    }
+  }
+
+  fn pop_any(&self, slot: usize, class: usize) -> Option<usize> {
+    if let Some(offset) = self.pop(&self.free[slot][class]) {
+      checked_dec(&self.free_blocks[class], 1);
+      return Some(offset);
+    }
+    if self.free_blocks[class].load(Ordering::Relaxed) == 0 {
+      return None;
+    }
+
+    // steal from our neighbours:
+    for i in 1..self.cache_slots {
+      let victim = (slot + i) % self.cache_slots;
+      if let Some(offset) = self.pop(&self.free[victim][class]) {
+        checked_dec(&self.free_blocks[class], 1);
+        return Some(offset);
+      }
+    }
+
+    None
+  }
+  fn bump(&self, size: usize, align: usize) -> Option<usize> {
+    // `align` can be larger than the arena's alignment, so align the address and
+    // not just the offset.
+    let arena = self.arena();
+    let mut current = self.bump.load(Ordering::Relaxed);
+    loop {
+      let start = align_up(arena + current, align) - arena;
+      let end = start.checked_add(size)?;
+      if end > self.capacity { return None; }
+      match self.bump.compare_exchange_weak(current, end, Ordering::Relaxed,
+                                            Ordering::Relaxed) {
+        Ok(_) => { return Some(start); },
+        Err(actual) => { current = actual; },
+      }
+    }
+  }
+
+  fn record_failure(&self, size: usize) {
+    self.failed_allocs.fetch_add(1, Ordering::Relaxed);
+    self.largest_failed.fetch_max(size, Ordering::Relaxed);
+  }
+
+  /// Returns null if the heap is exhausted. The failure is recorded in the stats.
+  pub fn alloc(&self, layout: Layout, slot: usize) -> *mut u8 {
+    let class = match size_class(layout) {
+      Some(class) => class,
+      None => {
+        self.record_failure(layout.size());
+        return null_mut();
+      },
+    };
+    let size = block_size(class);
+    let slot = slot % self.cache_slots;
+
+    // Blocks are always bumped to at least `min(size, BUMP_ALIGN_LIMIT)`, so any
+    // block in the free lists is suitably aligned unless the requested alignment
+    // is over that limit.
+    let offset = if layout.align() <= BUMP_ALIGN_LIMIT {
+      self.pop_any(slot, class)
+    } else {
+      None
+    };
+    let offset = offset.or_else(|| {
+      let align = max(min(size, BUMP_ALIGN_LIMIT), layout.align());
+      self.bump(size, align)
+    });
+    let offset = match offset {
+      Some(offset) => offset,
+      None => {
+        self.record_failure(layout.size());
+        return null_mut();
+      },
+    };
+
+    let live = self.live.fetch_add(size, Ordering::Relaxed) + size;
+    self.peak.fetch_max(live, Ordering::Relaxed);
+
+    (self.arena() + offset) as *mut u8
+  }
+  pub fn alloc_zeroed(&self, layout: Layout, slot: usize) -> *mut u8 {
+    let ptr = self.alloc(layout, slot);
+    if !ptr.is_null() {
+      unsafe { ptr::write_bytes(ptr, 0, layout.size()); }
+    }
+    ptr
+  }
+
+  /// # Safety
+  ///
+  /// `ptr` must have been allocated by this heap with `layout`.
+  pub unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout, slot: usize) {
+    let class = size_class(layout)
+      .expect("dealloc of a layout this heap could never allocate");
+    let offset = ptr as usize - self.arena();
+    debug_assert!(offset < self.capacity);
+
+    checked_dec(&self.live, block_size(class));
+    self.free_blocks[class].fetch_add(1, Ordering::Relaxed);
+    self.push(&self.free[slot % self.cache_slots][class], offset);
+  }
+
+  /// # Safety
+  ///
+  /// `ptr` must have been allocated by this heap with `layout`.
+  pub unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize,
+                        slot: usize)
+    -> *mut u8
+  {
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    if size_class(layout) == size_class(new_layout) {
+      // still fits in the same block.
+      return ptr;
+    }
+
+    let new = self.alloc(new_layout, slot);
+    if !new.is_null() {
+      ptr::copy_nonoverlapping(ptr, new, min(layout.size(), new_size));
+      self.dealloc(ptr, layout, slot);
+    }
+    new
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashSet;
+  use std::sync::Arc;
+  use std::thread;
+
+  use super::*;
+
+  struct Region(Vec<u64>);
+  impl Region {
+    fn new(bytes: usize) -> Self {
+      Region(vec![0u64; bytes / size_of::<u64>()])
+    }
+    fn heap(&mut self, slots: usize) -> &'static SlabHeap {
+      let len = self.0.len() * size_of::<u64>();
+      unsafe {
+        let heap = SlabHeap::init(self.0.as_mut_ptr() as *mut u8, len, slots)
+          .unwrap();
+        &*(heap as *const SlabHeap)
+      }
+    }
+  }
+
+  fn layout(size: usize, align: usize) -> Layout {
+    Layout::from_size_align(size, align).unwrap()
+  }
+
+  #[test]
+  fn size_classes() {
+    assert_eq!(size_class(layout(0, 1)), Some(0));
+    assert_eq!(size_class(layout(16, 1)), Some(0));
+    assert_eq!(size_class(layout(17, 1)), Some(1));
+    assert_eq!(size_class(layout(4, 64)), Some(2));
+    assert_eq!(size_class(layout(MAX_BLOCK_SIZE, 1)), Some(SIZE_CLASSES - 1));
+    assert_eq!(size_class(layout(MAX_BLOCK_SIZE + 1, 1)), None);
+  }
+
+  #[test]
+  #[cfg_attr(debug_assertions, should_panic(expected = "double free"))]
+  fn counter_underflow() {
+    let count = AtomicUsize::new(1);
+    checked_dec(&count, 1);
+    checked_dec(&count, 1);
+    assert_eq!(count.load(Ordering::Relaxed), 0);
+  }
+
+  #[test]
+  fn too_small() {
+    let mut r = Region::new(size_of::<SlabHeap>());
+    let len = r.0.len() * size_of::<u64>();
+    assert!(unsafe { SlabHeap::init(r.0.as_mut_ptr() as *mut u8, len, 1) }.is_none());
+  }
+
+  #[test]
+  fn reuse() {
+    let mut r = Region::new(1 << 20);
+    let heap = r.heap(4);
+
+    let l = layout(24, 8);
+    let a = heap.alloc(l, 0);
+    assert!(!a.is_null());
+    assert_eq!(heap.stats().live, 32);
+    unsafe { heap.dealloc(a, l, 0); }
+    assert_eq!(heap.stats().live, 0);
+
+    // same slot gets the same block back:
+    let b = heap.alloc(l, 0);
+    assert_eq!(a, b);
+    unsafe { heap.dealloc(b, l, 0); }
+
+    // other slots steal it:
+    let c = heap.alloc(l, 3);
+    assert_eq!(a, c);
+    assert_eq!(heap.stats().peak, 32);
+  }
+
+  #[test]
+  fn alignment() {
+    let mut r = Region::new(1 << 20);
+    let heap = r.heap(1);
+
+    for &align in [1usize, 8, 64, 256, 4096, 8192].iter() {
+      let l = layout(3, align);
+      let p = heap.alloc(l, 0);
+      assert!(!p.is_null());
+      assert_eq!(p as usize % align, 0, "align {}", align);
+      unsafe { heap.dealloc(p, l, 0); }
+      let p = heap.alloc(l, 0);
+      assert_eq!(p as usize % align, 0, "align {} (reused)", align);
+    }
+  }
+
+  #[test]
+  fn exhaustion() {
+    let mut r = Region::new(SlabHeap::min_region_size() + 1024);
+    let heap = r.heap(1);
+    let cap = heap.capacity();
+
+    let l = layout(cap + 1, 1);
+    assert!(heap.alloc(l, 0).is_null());
+    assert!(heap.alloc(layout(MAX_BLOCK_SIZE * 2, 1), 0).is_null());
+
+    let stats = heap.stats();
+    assert!(stats.exhausted());
+    assert_eq!(stats.failed_allocs, 2);
+    assert_eq!(stats.largest_failed, MAX_BLOCK_SIZE * 2);
+
+    unsafe { heap.reset(); }
+    assert!(!heap.stats().exhausted());
+    assert!(!heap.alloc(layout(16, 1), 0).is_null());
+  }
+
+  #[test]
+  fn realloc_preserves_contents() {
+    let mut r = Region::new(1 << 20);
+    let heap = r.heap(2);
+
+    unsafe {
+      let l = layout(10, 1);
+      let p = heap.alloc(l, 0);
+      for i in 0..10 { *p.add(i) = i as u8; }
+      // same class:
+      assert_eq!(heap.realloc(p, l, 16, 0), p);
+
+      let p2 = heap.realloc(p, l, 1000, 1);
+      assert_ne!(p2, p);
+      for i in 0..10 { assert_eq!(*p2.add(i), i as u8); }
+      assert_eq!(heap.stats().live, 1024);
+    }
+  }
+
+  #[test]
+  fn stress() {
+    const THREADS: usize = 8;
+    const ITERS: usize = 20_000;
+
+    let mut r = Region::new(64 << 20);
+    let heap = r.heap(THREADS / 2);
+    let r = Arc::new(r);
+
+    let threads: Vec<_> = (0..THREADS)
+      .map(|t| {
+        let _r = r.clone();
+        thread::spawn(move || {
+          let mut live: Vec<(*mut u8, Layout, u8)> = Vec::new();
+          let mut seed = 0x9e37_79b9u32.wrapping_mul(t as u32 + 1);
+          for i in 0..ITERS {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+
+            if seed % 2 == 0 || live.is_empty() {
+              let size = 1 + (seed as usize % 2000);
+              let l = layout(size, 1 << (seed % 7));
+              let p = heap.alloc(l, t);
+              assert!(!p.is_null());
+              let fill = (i % 251) as u8;
+              unsafe { ptr::write_bytes(p, fill, size); }
+              live.push((p, l, fill));
+            } else {
+              let idx = seed as usize % live.len();
+              let (p, l, fill) = live.swap_remove(idx);
+              unsafe {
+                for b in 0..l.size() {
+                  assert_eq!(*p.add(b), fill, "heap corruption");
+                }
+                heap.dealloc(p, l, t);
+              }
+            }
+          }
+          live.into_iter()
+            .map(|(p, l, _)| (p as usize, l) )
+            .collect::<Vec<_>>()
+        })
+      })
+      .collect();
+
+    let mut all = HashSet::new();
+    let mut live_bytes = 0;
+    for t in threads {
+      for (p, l) in t.join().unwrap() {
+        assert!(all.insert(p), "block handed out twice");
+        live_bytes += block_size(size_class(l).unwrap());
+      }
+    }
+
+    let stats = heap.stats();
+    assert_eq!(stats.live, live_bytes);
+    assert!(!stats.exhausted());
+    assert!(stats.reserved <= stats.capacity);
+  }
+}
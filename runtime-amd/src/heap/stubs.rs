@@ -0,0 +1,65 @@
+//! The device side functions which replace `__rust_alloc` etc. These are only
+//! ever called on the device.
+
+use std::alloc::Layout;
+use std::geobacter::kernel::OptionalKernelFn;
+use std::geobacter::spec_param as param;
+
+use crate::grt_core::codegen::stubbing::Stubber;
+
+use super::{slab::SlabHeap, WAVEFRONT_SIZE, };
+
+/// The address of the heap, or zero if no heap was attached to the `FuncModule`.
+pub(crate) fn device_heap_param() -> usize {
+  param::get(&device_heap_param)
+    .copied()
+    .unwrap_or(0)
+}
+
+#[inline(always)]
+fn heap() -> &'static SlabHeap {
+  let base = device_heap_param();
+  if base == 0 {
+    unsafe {
+      std::geobacter::intrinsics::geobacter_suicide("no device heap attached");
+    }
+  }
+  unsafe { SlabHeap::from_base(base as *const u8) }
+}
+#[inline(always)]
+fn slot() -> usize {
+  std::geobacter::amdgpu::dispatch_packet().global_linear_id() / WAVEFRONT_SIZE
+}
+
+// These must not be named `__rust_*`, else the stubber will think we're the
+// builtin alloc functions.
+
+fn device_alloc(size: usize, align: usize) -> *mut u8 {
+  let layout = unsafe { Layout::from_size_align_unchecked(size, align) };
+  heap().alloc(layout, slot())
+}
+fn device_dealloc(ptr: *mut u8, size: usize, align: usize) {
+  unsafe {
+    let layout = Layout::from_size_align_unchecked(size, align);
+    heap().dealloc(ptr, layout, slot())
+  }
+}
+fn device_realloc(ptr: *mut u8, old_size: usize, align: usize,
+                  new_size: usize) -> *mut u8 {
+  unsafe {
+    let layout = Layout::from_size_align_unchecked(old_size, align);
+    heap().realloc(ptr, layout, new_size, slot())
+  }
+}
+fn device_alloc_zeroed(size: usize, align: usize) -> *mut u8 {
+  let layout = unsafe { Layout::from_size_align_unchecked(size, align) };
+  heap().alloc_zeroed(layout, slot())
+}
+
+pub(crate) fn insert_stubs(stubber: &mut Stubber) {
+  stubber.add_stub("alloc::alloc::__rust_alloc", device_alloc.kernel_instance());
+  stubber.add_stub("alloc::alloc::__rust_dealloc", device_dealloc.kernel_instance());
+  stubber.add_stub("alloc::alloc::__rust_realloc", device_realloc.kernel_instance());
+  stubber.add_stub("alloc::alloc::__rust_alloc_zeroed",
+                   device_alloc_zeroed.kernel_instance());
+}
@@ -273,6 +273,7 @@
 //!
 
 use std::cell::{Cell, UnsafeCell, };
+use std::iter::StepBy;
 use std::marker::*;
 use std::mem::{MaybeUninit, };
 use std::ops::*;
@@ -651,22 +652,24 @@ impl<'a, A1, A2, E, G> CoerceUnsized<Slice<'a, A2, E, G>> for Slice<'a, A1, E, G
 /// Drops the workitem's LDS slot when this goes out of scope.
 // Not safe to construct.
 pub struct Mut<'a, A>(&'a mut A)
-  where A: 'a;
+  where A: ?Sized + 'a;
 
 impl<'a, A> Deref for Mut<'a, A>
-  where A: 'a,
+  where A: ?Sized + 'a,
 {
   type Target = A;
   #[inline(always)]
   fn deref(&self) -> &A { self.0 }
 }
 impl<'a, A> DerefMut for Mut<'a, A>
-  where A: 'a,
+  where A: ?Sized + 'a,
 {
   #[inline(always)]
   fn deref_mut(&mut self) -> &mut A { self.0 }
 }
-unsafe impl<'a, #[may_dangle] A> Drop for Mut<'a, A> {
+unsafe impl<'a, #[may_dangle] A> Drop for Mut<'a, A>
+  where A: ?Sized,
+{
   fn drop(&mut self) {
     unsafe { drop_in_place(self.0) }
     // Note: this doesn't need a post-barrier:
@@ -677,16 +680,18 @@ unsafe impl<'a, #[may_dangle] A> Drop for Mut<'a, A> {
 /// wide.
 // Not safe to construct.
 pub struct Ref<'a, A>(NonNull<A>, PhantomData<&'a A>, bool)
-  where A: 'a;
+  where A: ?Sized + 'a;
 
 impl<'a, A> Deref for Ref<'a, A>
-  where A: 'a,
+  where A: ?Sized + 'a,
 {
   type Target = A;
   #[inline(always)]
   fn deref(&self) -> &A { unsafe { self.0.as_ref() } }
 }
-unsafe impl<'a, #[may_dangle] A> Drop for Ref<'a, A> {
+unsafe impl<'a, #[may_dangle] A> Drop for Ref<'a, A>
+  where A: ?Sized,
+{
   fn drop(&mut self) {
     // This barrier is required regardless of `impl Drop for E`; we must be sure all other
     // workitems have finished possibly using our LDS slot. Either we run the barrier now, or we
@@ -748,6 +753,344 @@ impl<E, const X: usize, const Y: usize, const Z: usize> LdsArray<E> for [[[E; X]
   }
 }
 
+/// The start of the dynamic group segment. LLVM places zero sized, externally visible LDS globals
+/// directly after the kernel's static LDS, aligned to the global's alignment.
+#[doc(hidden)]
+#[allow(unused_attributes)] // TODO
+#[geobacter(amdgpu(runtime_item = "lds_dyn"))]
+#[repr(C, align(16))]
+pub struct LdsDynBase(UnsafeCell<MaybeUninit<[u8; 0]>>);
+unsafe impl Sync for LdsDynBase { }
+
+#[doc(hidden)]
+#[no_mangle]
+pub static GEOBACTER_DYNAMIC_LDS: LdsDynBase =
+  LdsDynBase(UnsafeCell::new(MaybeUninit::uninit()));
+
+/// The alignment of the start of the dynamic group segment. `LdsDyn` element types can't be
+/// aligned more strictly than this.
+pub const LDS_DYN_ALIGN: u32 = 16;
+
+/// An array of `T` in the dynamic group segment, whose length is chosen at launch time instead of
+/// at compile time. Allocate one with `FuncModule::alloc_lds_dyn` and pass it to the kernel in
+/// its arguments; each workgroup gets its own array. Inside the kernel, it's used like an `lds!`
+/// borrow:
+/// ```ignore
+/// fn kernel(&self, vp: KVectorParams<Self>) {
+///   let mut tile = unsafe { self.tile.borrow() };
+///   tile.with_shared(|mut tile| {
+///     // each workitem initializes every `wg_len`th element:
+///     let tile = tile.init_with(&vp, |idx| idx as f32 );
+///     // after which the whole array can be read:
+///     let sum: f32 = tile.iter().sum();
+///   });
+/// }
+///
+/// let mut module = YourGpuKernel::module(&dev);
+/// let tile = module.alloc_lds_dyn::<f32>(tile_len)?;
+/// ```
+pub struct LdsDyn<T> {
+  /// In bytes, from the start of the dynamic group segment.
+  offset: u32,
+  len: u32,
+  _t: PhantomData<fn() -> T>,
+}
+
+impl<T> LdsDyn<T> {
+  pub(crate) fn new(offset: u32, len: u32) -> Self {
+    LdsDyn {
+      offset,
+      len,
+      _t: PhantomData,
+    }
+  }
+
+  #[inline(always)]
+  pub fn len(&self) -> usize { self.len as _ }
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool { self.len == 0 }
+  /// In bytes, from the start of the dynamic group segment.
+  #[inline(always)]
+  pub fn offset(&self) -> u32 { self.offset }
+
+  /// `LdsDyn` is `Copy`, so nothing prevents two borrows of the same array from existing at the
+  /// same time. Like two `lds!` borrows of the same static (see the module docs), initializing
+  /// both at the same time is UB. Don't.
+  #[inline(always)]
+  pub unsafe fn borrow(&self) -> LdsDynBorrow<T> {
+    let base = &GEOBACTER_DYNAMIC_LDS as *const LdsDynBase as *mut u8;
+    let ptr = base.wrapping_add(self.offset as usize) as *mut T;
+    LdsDynBorrow(NonNull::new_unchecked(ptr), self.len as usize, false)
+  }
+}
+impl<T> Clone for LdsDyn<T> {
+  #[inline(always)]
+  fn clone(&self) -> Self { *self }
+}
+impl<T> Copy for LdsDyn<T> { }
+impl<T> fmt::Debug for LdsDyn<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("LdsDyn")
+      .field("offset", &self.offset)
+      .field("len", &self.len)
+      .finish()
+  }
+}
+
+/// The dynamic LDS version of `LdsBorrow`.
+// No Copy or Clone on this structure.
+pub struct LdsDynBorrow<T>(NonNull<T>, usize, bool);
+
+impl<T> LdsDynBorrow<T> {
+  #[inline(always)]
+  pub fn len(&self) -> usize { self.1 }
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool { self.1 == 0 }
+
+  #[inline(always)]
+  fn mark_used(&mut self) {
+    if self.2 {
+      std::geobacter::amdgpu::sync::workgroup_barrier();
+    }
+    self.2 = true;
+  }
+  #[inline(always)]
+  fn slice(&self) -> NonNull<[T]> {
+    unsafe {
+      NonNull::new_unchecked(slice_from_raw_parts_mut(self.0.as_ptr(), self.1))
+    }
+  }
+  #[inline(always)]
+  pub fn with_singleton<'a, F, R>(&'a mut self, f: F) -> R
+    where F: FnOnce(DynSingleton<'a, T>) -> R,
+  {
+    self.mark_used();
+    f(DynSingleton(self))
+  }
+  #[inline(always)]
+  pub fn with_shared<'a, F, R>(&'a mut self, f: F) -> R
+    where F: FnOnce(DynShared<'a, T>) -> R,
+  {
+    self.mark_used();
+    f(DynShared(self))
+  }
+  #[inline(always)]
+  pub fn with_mut<'a, F, R>(&'a mut self, f: F) -> R
+    where F: FnOnce(DynUnique<'a, T>) -> R,
+  {
+    self.mark_used();
+    f(DynUnique(self))
+  }
+}
+
+macro_rules! lds_dyn_usage_type {
+  ($($ty:ident, )*) => {$(
+    #[repr(transparent)]
+    pub struct $ty<'a, T>(&'a mut LdsDynBorrow<T>);
+  )*}
+}
+lds_dyn_usage_type! {
+  DynSingleton, DynShared, DynUnique,
+}
+
+impl<'a, T> DynSingleton<'a, T> {
+  pub fn init<G>(&mut self, vp: &VectorParams<G>, v: T) -> Ref<[T]>
+    where T: Clone + Sync,
+          G: GridDims,
+          <G::Workgroup as WorkgroupDims>::Idx: num_traits::Zero,
+  {
+    self.init_with(vp, move |_| v.clone() )
+  }
+  /// Only the first workitem calls `f`, once for every index. The whole array is visible to
+  /// the workgroup on return.
+  pub fn init_with<F, G>(&mut self, vp: &VectorParams<G>, mut f: F) -> Ref<[T]>
+    where T: Sync,
+          G: GridDims,
+          <G::Workgroup as WorkgroupDims>::Idx: num_traits::Zero,
+          F: FnMut(usize) -> T,
+  {
+    enforce_amdgpu();
+
+    // Ensure all workitems have dropped any previous LDS reference:
+    std::geobacter::amdgpu::sync::workgroup_barrier();
+
+    let wi0 = vp.is_wi0();
+    let s = self.0.slice();
+    if wi0 {
+      let base = (self.0).0.as_ptr();
+      for idx in 0..self.0.len() {
+        // Safety: this section is only run on the first workitem.
+        unsafe { write(base.add(idx), f(idx)); }
+      }
+    }
+
+    std::geobacter::amdgpu::sync::workgroup_barrier();
+
+    Ref(s, PhantomData, wi0)
+  }
+}
+
+impl<'a, T> DynUnique<'a, T> {
+  #[inline(always)]
+  pub fn init<'b, G>(&'b mut self, vp: &VectorParams<G>, v: T) -> Mut<'b, [T]>
+    where T: Clone + 'b,
+          G: GridDims,
+          <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+  {
+    self.init_with(vp, move |_| v.clone() )
+  }
+  /// The array is split into one contiguous `len / wg_len` element chunk per workitem, in
+  /// workitem linear id order; any remaining elements at the end are left unused. `f` is called
+  /// with the array index of each element in this workitem's chunk.
+  pub fn init_with<'b, F, G>(&'b mut self, vp: &VectorParams<G>, mut f: F)
+    -> Mut<'b, [T]>
+    where T: 'b,
+          F: FnMut(usize) -> T,
+          G: GridDims,
+          <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+  {
+    enforce_amdgpu();
+
+    let chunk = self.0.len() / vp.wg_len();
+    let start: usize = vp.linear_wi().into();
+    let start = start * chunk;
+    Mut(unsafe {
+      // safety: chunks don't overlap, and the runtime places the array in LDS, so it is
+      // unique per workgroup.
+      let base = (self.0).0.as_ptr().add(start);
+      for i in 0..chunk {
+        write(base.add(i), f(start + i));
+      }
+
+      &mut *slice_from_raw_parts_mut(base, chunk)
+    })
+  }
+}
+
+impl<'a, T> DynShared<'a, T> {
+  #[inline(always)]
+  pub fn init<'b, G>(&'b mut self, vp: &VectorParams<G>, v: T) -> DynSlice<'b, T>
+    where T: Clone + Sync + 'b,
+          G: GridDims,
+          <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+  {
+    self.init_with(vp, move |_| v.clone() )
+  }
+  #[inline(always)]
+  pub fn init_default<'b, G>(&'b mut self, vp: &VectorParams<G>) -> DynSlice<'b, T>
+    where T: Default + Sync + 'b,
+          G: GridDims,
+          <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+  {
+    self.init_with(vp, |_| T::default() )
+  }
+  /// Each workitem initializes, and later drops, every `wg_len`th element starting at its
+  /// workitem linear id. `f` is called with the index of the element.
+  pub fn init_with<'b, F, G>(&'b mut self, vp: &VectorParams<G>, mut f: F)
+    -> DynSlice<'b, T>
+    where T: Sync + 'b,
+          F: FnMut(usize) -> T,
+          G: GridDims,
+          <G::Workgroup as WorkgroupDims>::Elem: Into<usize>,
+  {
+    enforce_amdgpu();
+    // No barrier needed before initializing, for the same reasons as in `Shared::init_with`.
+    let stride = vp.wg_len();
+    let first: usize = vp.linear_wi().into();
+    let base = (self.0).0.as_ptr();
+    let mut idx = first;
+    while idx < self.0.len() {
+      unsafe { write(base.add(idx), f(idx)); }
+      idx += stride;
+    }
+
+    DynSlice {
+      s: self.0.slice(),
+      first,
+      stride,
+      initial_barrier: Cell::new(false),
+      _mb: PhantomData,
+    }
+  }
+}
+
+/// The dynamic LDS version of `Slice`.
+pub struct DynSlice<'a, T>
+  where T: Sync + 'a,
+{
+  /// The entire workgroup array.
+  s: NonNull<[T]>,
+  /// The first element we're responsible for dropping.
+  first: usize,
+  stride: usize,
+  initial_barrier: Cell<bool>,
+  _mb: PhantomData<&'a mut [T]>,
+}
+
+impl<'a, T> DynSlice<'a, T>
+  where T: Sync + 'a,
+{
+  /// Disable the initial barrier. This is unsafe.
+  pub unsafe fn force_no_barrier(&self) {
+    self.initial_barrier.set(true);
+  }
+  pub fn enforce_initial_barrier(&self) {
+    if !self.initial_barrier.get() {
+      self.initial_barrier.set(true);
+      std::geobacter::amdgpu::sync::workgroup_barrier();
+    }
+  }
+  /// The indices this workitem initialized.
+  pub fn owned_indices(&self) -> StepBy<Range<usize>> {
+    let len = unsafe { self.s.as_ref() }.len();
+    (self.first..len).step_by(self.stride)
+  }
+  /// Gets the LDS data without ensuring the barrier was executed.
+  pub unsafe fn unchecked_ref(&self) -> &[T] {
+    self.s.as_ref()
+  }
+
+  /// Does not execute a barrier before dropping, nor afterwards
+  pub unsafe fn unsynced_drop(self) {
+    self.drop_owned();
+    std::mem::forget(self);
+  }
+  unsafe fn drop_owned(&self) {
+    if !std::mem::needs_drop::<T>() { return; }
+
+    let base = self.s.as_ptr() as *mut T;
+    for idx in self.owned_indices() {
+      drop_in_place(base.add(idx));
+    }
+  }
+}
+impl<'a, T> Deref for DynSlice<'a, T>
+  where T: Sync + 'a,
+{
+  type Target = [T];
+  fn deref(&self) -> &[T] {
+    self.enforce_initial_barrier();
+    unsafe { self.s.as_ref() }
+  }
+}
+impl<'a, T> fmt::Debug for DynSlice<'a, T>
+  where T: fmt::Debug + Sync + 'a,
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    fmt::Debug::fmt(&**self, f)
+  }
+}
+unsafe impl<'a, #[may_dangle] T> Drop for DynSlice<'a, T>
+  where T: Sync + 'a,
+{
+  fn drop(&mut self) {
+    // See `Slice`'s drop.
+    std::geobacter::amdgpu::sync::workgroup_barrier();
+
+    unsafe { self.drop_owned() }
+  }
+}
+
 #[cfg(test)]
 mod test {
   use super::*;
@@ -1201,4 +1544,92 @@ mod test {
     static T: Lds<[u8; 1]> = unsafe { Lds::new() };
     unsafe { T.borrow() };
   }
+
+  #[test]
+  fn dyn_shared() {
+    #[derive(GeobacterDeps)]
+    struct LDSKernel {
+      dst: *mut [u32],
+      lds: LdsDyn<u32>,
+      completion: GlobalSignal,
+    }
+    unsafe impl Send for LDSKernel { }
+    unsafe impl Sync for LDSKernel { }
+    impl Completion for LDSKernel {
+      type CompletionSignal = GlobalSignal;
+      fn completion(&self) -> &GlobalSignal { &self.completion }
+    }
+    impl Kernel for LDSKernel {
+      type Grid = Dim1D<RangeTo<u32>>;
+      const WORKGROUP: Dim1D<RangeTo<u16>> = Dim1D {
+        x: ..16,
+      };
+
+      type Queue = DeviceMultiQueue;
+      fn queue(&self) -> &DeviceMultiQueue { queue() }
+
+      fn kernel(&self, vp: KVectorParams<Self>) {
+        let mut lds = unsafe { self.lds.borrow() };
+        lds.with_shared(|mut lds| {
+          let lds = lds.init_with(&vp, |idx| idx as u32 );
+          // read elements initialized by other workitems:
+          let sum: u32 = lds.iter().sum();
+          unsafe {
+            (&mut *self.dst)[vp.gl_id() as usize] = sum;
+          }
+        });
+      }
+    }
+
+    const LEN: u32 = 100;
+
+    let dev = device();
+
+    let grid = Dim1D {
+      x: ..32,
+    };
+
+    let mut m = LapVec::new_in(dev.fine_lap_node_alloc(0));
+    m.add_access(&dev).unwrap();
+
+    m.resize(grid.linear_len().unwrap() as _, u32::max_value());
+
+    let mut module = LDSKernel::module(&dev);
+    // misalign the second allocation:
+    let _ = module.alloc_lds_dyn::<u8>(3).unwrap();
+    let lds = module.alloc_lds_dyn::<u32>(LEN).unwrap();
+    assert_eq!(lds.offset(), 4);
+    assert_eq!(module.dynamic_group_size, 4 + 4 * LEN);
+
+    let mut invoc = module.into_invoc(args_pool());
+    unsafe {
+      let args = LDSKernel {
+        dst: m.as_mut_slice(),
+        lds,
+        completion: GlobalSignal::new(1).unwrap(),
+      };
+      invoc.unchecked_call_async(&grid, args)
+        .unwrap()
+        .wait_for_zero(true)
+        .unwrap();
+    }
+
+    let expected: u32 = (0..LEN).sum();
+    for &v in m.iter() {
+      assert_eq!(v, expected);
+    }
+  }
+  #[test] #[should_panic]
+  fn lds_dyn_shared_host_panic() {
+    let lds: LdsDyn<u8> = LdsDyn::new(0, 1);
+    let mut t = unsafe { lds.borrow() };
+
+    let vp = MaybeUninit::uninit();
+    let vp: VectorParams<Dim1D<Range<u32>>> =
+      unsafe { vp.assume_init() }; // actually unused
+
+    t.with_shared(|mut t| {
+      t.init(&vp, 0);
+    });
+  }
 }
@@ -74,6 +74,7 @@ use std::geobacter::platform::hsa::AmdGcn;
 use std::ptr::{NonNull, };
 use std::str::FromStr;
 use std::sync::{Arc, };
+use std::sync::atomic::{AtomicBool, Ordering, };
 
 use log::{info, warn, error, };
 
@@ -89,9 +90,10 @@ pub use hsa_rt::error::Error as HsaError;
 use hsa_rt::executable::{Executable, CommonExecutable};
 use hsa_rt::ext::amd::{MemoryPool, MemoryPoolPtr, async_copy, unlock_memory,
                        MemoryPoolAlloc, GlobalFlags, };
-use hsa_rt::mem::region::{RegionAlloc, };
-use hsa_rt::queue::{KernelSingleQueue, KernelMultiQueue};
-use hsa_rt::signal::{Signal, SignalBinops};
+use hsa_rt::ext::profiling::{async_copy_time, set_async_copy_profiling, };
+use hsa_rt::mem::region::{RegionAlloc, Segment, };
+use hsa_rt::queue::{KernelQueue, KernelSingleQueue, KernelMultiQueue, QueueKind, };
+use hsa_rt::signal::{Signal, SignalBinops, SignalRef, };
 
 use grt_core::{Accelerator, AcceleratorTargetDesc,
                PlatformTargetDesc, Device, };
@@ -109,15 +111,25 @@ use crate::alloc::*;
 use crate::boxed::{RawPoolBox, };
 use crate::mem::*;
 use crate::module::{HsaModuleData, Deps};
-use crate::signal::{HostSignal, DeviceSignal, SignalHandle};
+use crate::profiling::{ClockConverter, DispatchTiming, };
+use crate::queue_pool::{QueuePool, QueuePoolConfig, };
+use crate::signal::{HostSignal, DeviceSignal, GlobalSignal, SignalHandle};
+use crate::signal::pool::{SignalPool, PooledSignal, };
 
 pub mod alloc;
 pub mod boxed;
 pub mod codegen;
+pub mod collective;
 pub mod error;
+pub mod heap;
 pub mod lds;
+pub mod math;
 pub mod mem;
 pub mod module;
+pub mod primitives;
+pub mod profiling;
+pub mod queue_pool;
+pub mod select;
 pub mod signal;
 pub mod texture;
 
@@ -132,15 +144,24 @@ pub mod prelude {
   pub use crate::{lds, HsaAmdGpuAccel, };
   pub use crate::alloc::*;
   pub use crate::error::Error;
+  pub use crate::codegen::sources::DeviceSource;
+  pub use crate::heap::DeviceHeap;
+  pub use crate::math::MathOptions;
   pub use crate::mem::*;
   pub use crate::module::*;
+  pub use crate::queue_pool::{QueuePool, QueuePoolConfig, CuMask, };
   pub use crate::signal::{*, completion::Completion, };
+  pub use crate::signal::pool::{PooledSignal, PooledGlobalSignal, PooledDeviceSignal, };
   pub use crate::texture::*;
   pub use crate::lds::{
     Lds,
     Shared as LdsShared,
     Unique as LdsUnique,
     Singleton as LdsSingleton,
+    LdsDyn,
+    DynShared as LdsDynShared,
+    DynUnique as LdsDynUnique,
+    DynSingleton as LdsDynSingleton,
   };
 }
 
@@ -172,6 +193,12 @@ pub struct HsaAmdGpuAccel {
   device: HsaAmdNode,
   kernarg_region: RegionAlloc,
 
+  /// Enable profiling on queues we create.
+  profiling: AtomicBool,
+  clock: ClockConverter,
+
+  signal_pool: SignalPool,
+
   // TODO need to create a `geobacter_runtime_host` crate
   //host_codegen: CodegenUnsafeSyncComms<Self>,
   self_codegen: Option<Arc<CodegenDriver<Codegenner>>>,
@@ -266,6 +293,8 @@ impl HsaAmdGpuAccel {
       isa,
     };
 
+    let clock = ClockConverter::calibrate(&ApiContext::try_upref()?)?;
+
     let mut out = HsaAmdGpuAccel {
       id: ctx.take_accel_id(),
 
@@ -285,6 +314,11 @@ impl HsaAmdGpuAccel {
       },
       kernarg_region,
 
+      profiling: AtomicBool::new(false),
+      clock,
+
+      signal_pool: SignalPool::new(),
+
       self_codegen: None,
     };
     out.init_target_desc()?;
@@ -297,6 +331,7 @@ impl HsaAmdGpuAccel {
     let mut out = Arc::new(out);
 
     ctx.initialize_accel(&mut out)?;
+    select::HsaDeviceEnumerator::register_once();
 
     Ok(out)
   }
@@ -351,6 +386,15 @@ impl HsaAmdGpuAccel {
   pub fn isa_info(&self) -> &IsaInfo { self.target_desc.isa_info() }
   pub fn agent(&self) -> &Agent { &self.device.agent }
   pub fn kernargs_region(&self) -> &RegionAlloc { &self.kernarg_region }
+  /// The size of the device's group segment (LDS), in bytes.
+  pub fn group_segment_size(&self) -> Result<usize, HsaError> {
+    for pool in self.agent().amd_memory_pools()? {
+      if pool.segment()? == Segment::Group {
+        return pool.total_size();
+      }
+    }
+    Ok(0)
+  }
 
   pub fn numa_node_len(&self) -> u32 { self.host_nodes().len() as _ }
 
@@ -358,8 +402,10 @@ impl HsaAmdGpuAccel {
   /// This allocator will allocate coarse memory regions. GPU writes to this
   /// region *are not cache-coherent* with the CPU, thus this is unsafe.
   pub unsafe fn coarse_lap_node_alloc(&self, node: u32) -> alloc::LapAlloc {
+    let node = &self.host_nodes()[node as usize];
     alloc::LapAlloc {
-      pool: self.host_nodes()[node as usize].coarse.pool(),
+      pool: node.coarse.pool(),
+      owner: node.agent.clone(),
       accessible: Default::default(),
     }
   }
@@ -367,12 +413,13 @@ impl HsaAmdGpuAccel {
   /// This allocator will allocate fine memory regions. GPU writes to this
   /// region are cache-coherent with the CPU.
   pub fn fine_lap_node_alloc(&self, node: u32) -> alloc::LapAlloc {
+    let node = &self.host_nodes[node as usize];
     alloc::LapAlloc {
-      pool: self.host_nodes[node as usize]
-        .fine
+      pool: node.fine
         .as_ref()
         .unwrap()
         .pool(),
+      owner: node.agent.clone(),
       accessible: Default::default(),
     }
   }
@@ -557,6 +604,22 @@ impl HsaAmdGpuAccel {
       .map(HostSignal)
   }
 
+  pub fn signal_pool(&self) -> &SignalPool { &self.signal_pool }
+  /// Like `new_device_signal`, but the signal is taken from (and returned to) this
+  /// device's signal pool.
+  pub fn pooled_device_signal(self: &Arc<Self>, initial: signal::Value)
+    -> Result<PooledSignal<DeviceSignal>, HsaError>
+  {
+    PooledSignal::new(self, initial)
+  }
+  /// Like `GlobalSignal::new`, but the signal is taken from (and returned to) this
+  /// device's signal pool.
+  pub fn pooled_global_signal(self: &Arc<Self>, initial: signal::Value)
+    -> Result<PooledSignal<GlobalSignal>, HsaError>
+  {
+    PooledSignal::new(self, initial)
+  }
+
   /// Allocate some device local memory. This memory may not be visible from the host,
   /// and may not be cache-coherent with host CPUs!
   ///
@@ -605,6 +668,61 @@ impl HsaAmdGpuAccel {
     unlock_memory(ptr, count)
   }
 
+  fn setup_queue<T>(&self, q: KernelQueue<T>) -> Result<KernelQueue<T>, HsaError>
+    where T: QueueKind,
+  {
+    if self.profiling_enabled() {
+      q.set_profiling(true)?;
+    }
+    Ok(q)
+  }
+
+  /// Have dispatches and async copies record timestamps, so that
+  /// `InvocCompletion::timing` etc will work. Queues created before this is called
+  /// won't have profiling enabled.
+  pub fn enable_profiling(&self) -> Result<(), HsaError> {
+    set_async_copy_profiling(true)?;
+    self.profiling.store(true, Ordering::Release);
+    Ok(())
+  }
+  /// Note: this doesn't disable async copy profiling, as it's process wide.
+  pub fn disable_profiling(&self) {
+    self.profiling.store(false, Ordering::Release);
+  }
+  pub fn profiling_enabled(&self) -> bool {
+    self.profiling.load(Ordering::Acquire)
+  }
+  /// Maps HSA system timestamps to host time.
+  pub fn clock(&self) -> &ClockConverter { &self.clock }
+
+  /// Get the timing of the completed dispatch which used `completion` as its
+  /// completion signal.
+  pub fn dispatch_timing(&self, completion: SignalRef) -> Result<DispatchTiming, HsaError> {
+    let time = self.agent().dispatch_time(completion)?;
+    Ok(self.clock.convert(time))
+  }
+  /// Get the timing of the completed async copy which used `completion` as its
+  /// completion signal.
+  pub fn async_copy_timing(&self, completion: SignalRef) -> Result<DispatchTiming, HsaError> {
+    let time = async_copy_time(completion)?;
+    Ok(self.clock.convert(time))
+  }
+  /// The track name used for this device's spans in the global trace.
+  pub fn trace_track(&self) -> String {
+    format!("{:?} {}", self.id, self.target_desc.target.options.cpu)
+  }
+
+  /// AMD extension.
+  pub fn compute_unit_count(&self) -> Result<u32, HsaError> {
+    self.agent().compute_unit_count()
+  }
+  /// Create a pool of queues which share `config`'s priority and CU mask.
+  pub fn new_queue_pool(self: &Arc<Self>, config: QueuePoolConfig)
+    -> Result<QueuePool, Error>
+  {
+    QueuePool::new(self, config)
+  }
+
   pub fn create_single_queue(&self, min: Option<u32>)
     -> Result<KernelSingleQueue, HsaError>
   {
@@ -617,7 +735,7 @@ impl HsaAmdGpuAccel {
     let q = self.agent()
       .new_kernel_queue(queue_size, None,
                         None)?;
-    Ok(q)
+    self.setup_queue(q)
   }
   pub fn create_single_queue2(&self, min: Option<u32>,
                               private: u32,
@@ -633,7 +751,7 @@ impl HsaAmdGpuAccel {
     let q = self.agent()
       .new_kernel_queue(queue_size, Some(private),
                         Some(group))?;
-    Ok(q)
+    self.setup_queue(q)
   }
   pub fn create_multi_queue(&self, min: Option<u32>)
     -> Result<KernelMultiQueue, HsaError>
@@ -647,7 +765,7 @@ impl HsaAmdGpuAccel {
     let q = self.agent()
       .new_kernel_multi_queue(queue_size, None,
                               None)?;
-    Ok(q)
+    self.setup_queue(q)
   }
   pub fn create_multi_queue2(&self, min: Option<u32>,
                              private: u32,
@@ -663,7 +781,7 @@ impl HsaAmdGpuAccel {
     let q = self.agent()
       .new_kernel_multi_queue(queue_size, Some(private),
                               Some(group))?;
-    Ok(q)
+    self.setup_queue(q)
   }
 }
 impl Accelerator for HsaAmdGpuAccel {
@@ -775,6 +893,8 @@ impl Device for HsaAmdGpuAccel {
       exe,
       kernel_object: main_object,
       desc: root.platform.clone(),
+      code_object: codegen.exe_ref().unwrap().into(),
+      symbol: root.symbol.clone(),
     }))
   }
 }
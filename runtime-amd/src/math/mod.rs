@@ -0,0 +1,83 @@
+//! Float math in kernels, backed by ROCm's device libraries.
+//!
+//! LLVM can lower only a few float operations (`sqrt`, and low precision `sin`/`cos`,
+//! `exp2`, `log2`) directly on AMDGPU; the rest end up as calls to libm symbols which
+//! don't exist on the device. When `MathOptions::device_libs` is set on a `FuncModule`,
+//! `f32::sin`, `f64::powf`, `libm::expf`, etc are instead mapped onto the matching
+//! `__ocml_*` functions, and the ocml/ockl bitcode is linked into the kernel by
+//! amd-comgr before the final link.
+//!
+//! Calls made directly to `core::intrinsics::sinf32` and friends can't be mapped, and
+//! keep LLVM's lowering.
+
+use crate::serde::{Serialize, Deserialize, };
+
+pub(crate) mod stubs;
+
+/// How a kernel's float math is compiled. Changing these recompiles the kernel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Hash)]
+pub struct MathOptions {
+  /// Link ROCm's device libraries, and call into them for math functions LLVM can't
+  /// lower itself. Without this, the options below do nothing.
+  pub device_libs: bool,
+  /// Assume no NaNs or infinities, and allow less precise approximations.
+  pub fast_math: bool,
+  /// Use the variants of the library functions which flush f32 denormals to zero.
+  pub flush_denormals: bool,
+  /// Use a correctly rounded f32 `sqrt`. Division in Rust is always correctly rounded.
+  pub correctly_rounded_sqrt: bool,
+}
+impl MathOptions {
+  /// Link the device libraries, with the defaults otherwise.
+  pub fn device_libs() -> Self {
+    MathOptions {
+      device_libs: true,
+      ..Default::default()
+    }
+  }
+
+  /// The options string given to amd-comgr's `AddDeviceLibs` action; these pick which
+  /// `oclc_*` control libraries are linked.
+  pub(crate) fn device_lib_options(&self) -> String {
+    let mut options = Vec::new();
+    if self.fast_math {
+      options.push("finite_only");
+      options.push("unsafe_math");
+    }
+    if self.flush_denormals {
+      options.push("daz_opt");
+    }
+    if self.correctly_rounded_sqrt {
+      options.push("correctly_rounded_sqrt");
+    }
+    options.join(" ")
+  }
+}
+impl Default for MathOptions {
+  fn default() -> Self {
+    MathOptions {
+      device_libs: false,
+      fast_math: false,
+      flush_denormals: false,
+      // Match the host.
+      correctly_rounded_sqrt: true,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn device_lib_options() {
+    let mut math = MathOptions::device_libs();
+    assert_eq!(math.device_lib_options(), "correctly_rounded_sqrt");
+
+    math.fast_math = true;
+    math.flush_denormals = true;
+    math.correctly_rounded_sqrt = false;
+    assert_eq!(math.device_lib_options(), "finite_only unsafe_math daz_opt");
+  }
+}
@@ -0,0 +1,119 @@
+//! The device side replacements for the std and libm float functions. These are only
+//! ever called on the device.
+//!
+//! The stubs are `#[inline(always)]` so the host never emits them, and thus never
+//! references the `__ocml_*` symbols, which only exist in the device libraries.
+
+use std::geobacter::kernel::OptionalKernelFn;
+use std::geobacter::spec_param as param;
+use std::intrinsics;
+
+use crate::grt_core::codegen::stubbing::Stubber;
+
+/// True if the device libraries are linked into this kernel. See
+/// `FuncModule::set_math_options`.
+pub(crate) fn device_libs_param() -> bool {
+  param::get(&device_libs_param)
+    .copied()
+    .unwrap_or(false)
+}
+
+macro_rules! math_stubs {
+  (@fallback -, $method:ident, ($($arg:ident),+)) => {{
+    $(let _ = $arg;)+
+    unsafe {
+      std::geobacter::intrinsics::geobacter_suicide(concat!("`", stringify!($method),
+                                                            "` requires the device \
+                                                             libraries"));
+    }
+  }};
+  (@fallback $intrinsic:ident, $method:ident, ($($arg:ident),+)) => {
+    unsafe { intrinsics::$intrinsic($($arg),+) }
+  };
+
+  ($($ty:ident in $module:ident {
+    $($method:ident($($arg:ident),+) => $ocml:ident, $libm:literal, $fallback:tt;)*
+  })*) => {
+    $(mod $module {
+      use super::*;
+
+      extern "C" {
+        $(fn $ocml($($arg: $ty),+) -> $ty;)*
+      }
+
+      $(
+        #[inline(always)]
+        pub(super) fn $method($($arg: $ty),+) -> $ty {
+          if device_libs_param() {
+            unsafe { $ocml($($arg),+) }
+          } else {
+            math_stubs!(@fallback $fallback, $method, ($($arg),+))
+          }
+        }
+      )*
+    })*
+
+    pub(crate) fn insert_stubs(stubber: &mut Stubber) {
+      $($(
+        let stub = $module::$method.kernel_instance();
+        stubber.add_stub(concat!("std::", stringify!($ty), "::<impl ", stringify!($ty),
+                                 ">::", stringify!($method)),
+                         stub);
+        // libm re-exports each function from a module of the same name:
+        stubber.add_stub(concat!("libm::", $libm), stub);
+        stubber.add_stub(concat!("libm::math::", $libm, "::", $libm), stub);
+      )*)*
+    }
+  };
+}
+
+// Functions without an intrinsic fallback are implemented by calling into the
+// system's libm, which doesn't exist on the device.
+math_stubs! {
+  f32 in ocml_f32 {
+    sin(x) => __ocml_sin_f32, "sinf", sinf32;
+    cos(x) => __ocml_cos_f32, "cosf", cosf32;
+    tan(x) => __ocml_tan_f32, "tanf", -;
+    asin(x) => __ocml_asin_f32, "asinf", -;
+    acos(x) => __ocml_acos_f32, "acosf", -;
+    atan(x) => __ocml_atan_f32, "atanf", -;
+    atan2(y, x) => __ocml_atan2_f32, "atan2f", -;
+    sinh(x) => __ocml_sinh_f32, "sinhf", -;
+    cosh(x) => __ocml_cosh_f32, "coshf", -;
+    tanh(x) => __ocml_tanh_f32, "tanhf", -;
+    exp(x) => __ocml_exp_f32, "expf", expf32;
+    exp2(x) => __ocml_exp2_f32, "exp2f", exp2f32;
+    exp_m1(x) => __ocml_expm1_f32, "expm1f", -;
+    ln(x) => __ocml_log_f32, "logf", logf32;
+    log2(x) => __ocml_log2_f32, "log2f", log2f32;
+    log10(x) => __ocml_log10_f32, "log10f", log10f32;
+    ln_1p(x) => __ocml_log1p_f32, "log1pf", -;
+    powf(x, y) => __ocml_pow_f32, "powf", powf32;
+    sqrt(x) => __ocml_sqrt_f32, "sqrtf", sqrtf32;
+    cbrt(x) => __ocml_cbrt_f32, "cbrtf", -;
+    hypot(x, y) => __ocml_hypot_f32, "hypotf", -;
+  }
+  f64 in ocml_f64 {
+    sin(x) => __ocml_sin_f64, "sin", sinf64;
+    cos(x) => __ocml_cos_f64, "cos", cosf64;
+    tan(x) => __ocml_tan_f64, "tan", -;
+    asin(x) => __ocml_asin_f64, "asin", -;
+    acos(x) => __ocml_acos_f64, "acos", -;
+    atan(x) => __ocml_atan_f64, "atan", -;
+    atan2(y, x) => __ocml_atan2_f64, "atan2", -;
+    sinh(x) => __ocml_sinh_f64, "sinh", -;
+    cosh(x) => __ocml_cosh_f64, "cosh", -;
+    tanh(x) => __ocml_tanh_f64, "tanh", -;
+    exp(x) => __ocml_exp_f64, "exp", expf64;
+    exp2(x) => __ocml_exp2_f64, "exp2", exp2f64;
+    exp_m1(x) => __ocml_expm1_f64, "expm1", -;
+    ln(x) => __ocml_log_f64, "log", logf64;
+    log2(x) => __ocml_log2_f64, "log2", log2f64;
+    log10(x) => __ocml_log10_f64, "log10", log10f64;
+    ln_1p(x) => __ocml_log1p_f64, "log1p", -;
+    powf(x, y) => __ocml_pow_f64, "pow", powf64;
+    sqrt(x) => __ocml_sqrt_f64, "sqrt", sqrtf64;
+    cbrt(x) => __ocml_cbrt_f64, "cbrt", -;
+    hypot(x, y) => __ocml_hypot_f64, "hypot", -;
+  }
+}
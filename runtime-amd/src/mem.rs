@@ -11,7 +11,9 @@ use crate::{HsaAmdGpuAccel, AcceleratorId, Error, };
 use crate::alloc::*;
 use crate::boxed::RawPoolBox;
 use crate::module::{Deps, CallError, };
+use crate::profiling::DispatchTiming;
 use crate::signal::*;
+use crate::signal::pool::PooledSignal;
 
 pub trait BoxPoolPtr {
   #[doc(hidden)]
@@ -109,6 +111,10 @@ pub type H2DGlobalLapVecMemTransfer<T, R> =
 pub type H2DGlobalRLapVecMemTransfer<'a, T, R> =
   <&'a LapVec<T> as H2DMemcpyGroup<Arc<GlobalSignal>, R>>::Transfer;
 
+/// The signal is returned to the device's signal pool when the transfer is dropped.
+pub type H2DPooledMemTransfer<H, D, R> =
+  H2DMemoryTransfer<Arc<PooledSignal<GlobalSignal>>, H, D, R>;
+
 impl<S, H, D, R> H2DMemoryTransfer<S, H, D, R>
   where S: SignalHandle,
         H: ?Sized,
@@ -116,6 +122,13 @@ impl<S, H, D, R> H2DMemoryTransfer<S, H, D, R>
 {
   pub fn src(&self) -> &H { &self.src }
   pub fn dst(&self) -> &D { &self.dst }
+
+  /// The start and end times of this copy. The copy must be complete, and must have
+  /// been started after `HsaAmdGpuAccel::enable_profiling` was called. `device` should
+  /// be the device which started the copy.
+  pub fn timing(&self, device: &HsaAmdGpuAccel) -> Result<DispatchTiming, Error> {
+    Ok(device.async_copy_timing(self.signal_ref())?)
+  }
 }
 impl<'a, S, H, D, R> H2DMemoryTransfer<&'a S, H, D, R>
   where S: Clone + SignalHandle,
@@ -119,6 +119,16 @@ impl<G> VectorParams<G>
   #[inline(always)]
   pub fn grid(&self) -> &G { &self.grid }
   #[inline(always)]
+  pub fn wg_size(&self) -> &G::Workgroup { &self.wg_size }
+  /// The number of workitems in a workgroup.
+  #[inline(always)]
+  pub fn wg_len(&self) -> usize {
+    // the workgroup size is checked when the kernel is dispatched.
+    let l = self.wg_size.full_launch_grid()
+      .expect("workgroup size overflow");
+    l.x as usize * l.y as usize * l.z as usize
+  }
+  #[inline(always)]
   pub fn grid_size(&self) -> G::Idx {
     self.grid_size
   }
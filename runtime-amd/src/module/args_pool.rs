@@ -5,18 +5,50 @@ use std::slice::from_raw_parts;
 
 use alloc_wg::vec::Vec;
 
+use parking_lot::Mutex;
+
+use serde::{Deserialize, Serialize, };
+
 use super::*;
+use super::args_ring::RingAlloc;
 
 pub type ArgsBox<T> = alloc_wg::boxed::Box<T, hsa_rt::mem::region::RegionAlloc>;
 
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct ArgsPoolStats {
+  /// Number of kernarg region allocations backing the pool.
+  pub chunks: usize,
+  /// Total bytes across every chunk.
+  pub capacity: usize,
+  /// Bytes currently allocated.
+  pub in_use: usize,
+  /// The max `in_use` seen.
+  pub high_water: usize,
+  /// Not tracked by arena pools.
+  pub allocs: u64,
+  /// Not tracked by arena pools.
+  pub frees: u64,
+}
+
 /// Use this to invoc in a loop without allocating every iteration
 /// AND without running amuck of Rust's borrow checker.
 /// XXX Use the AMD vendor extensions to get the cacheline size, instead of
 /// hardcoding to 1 << 7 here.
+///
+/// Arena pools (`new`, `new_arena`) never free individual args; use `wash` once
+/// nothing is in flight. Ring pools (`new_ring`) free each dispatch's args when its
+/// `InvocCompletion` is dropped, and grow as needed.
 pub struct ArgsPool {
   device: Arc<HsaAmdGpuAccel>,
   base: ArgsBox<[u8]>,
   allocated: AtomicUsize,
+  /// `Some` for ring pools. `base` is the ring's first chunk.
+  ring: Option<Mutex<RingArgs>>,
+}
+struct RingArgs {
+  alloc: RingAlloc,
+  /// Chunks added after `base` filled up.
+  extra: std::vec::Vec<ArgsBox<[u8]>>,
 }
 impl ArgsPool {
   /// Create storage for `n` function calls for use on the provided accelerator.
@@ -50,6 +82,7 @@ impl ArgsPool {
       device: accel.clone(),
       allocated: AtomicUsize::new(arena.as_ptr() as usize),
       base: arena.try_into_boxed_slice()?,
+      ring: None,
     })
   }
 
@@ -73,12 +106,61 @@ impl ArgsPool {
       device: accel.clone(),
       allocated: AtomicUsize::new(arena.as_ptr() as usize),
       base: arena.try_into_boxed_slice()?,
+      ring: None,
     })
   }
 
+  /// Create a ring pool, starting with `bytes` of storage.
+  pub fn new_ring(accel: &Arc<HsaAmdGpuAccel>, bytes: usize)
+    -> Result<Self, Error>
+  {
+    let mut pool = Self::new_arena(accel, bytes)?;
+    let mut alloc = RingAlloc::new();
+    alloc.add_chunk(pool.start_byte(), pool.size());
+    pool.ring = Some(Mutex::new(RingArgs {
+      alloc,
+      extra: std::vec::Vec::new(),
+    }));
+    Ok(pool)
+  }
+
+  fn alloc_chunk(&self, bytes: usize) -> Result<ArgsBox<[u8]>, Error> {
+    use std::cmp::max;
+
+    let kernargs_region = self.device.kernargs_region().clone();
+    let bytes = max(kernargs_region.alloc_granule(), bytes);
+    let mut chunk: Vec<u8, _> =
+      Vec::try_with_capacity_in(bytes, kernargs_region)?;
+    unsafe {
+      chunk.set_len(bytes);
+    }
+    Ok(chunk.try_into_boxed_slice()?)
+  }
+
+  pub fn is_ring(&self) -> bool { self.ring.is_some() }
+
+  pub fn stats(&self) -> ArgsPoolStats {
+    match self.ring {
+      Some(ref ring) => ring.lock().alloc.stats(),
+      None => {
+        // the bump pointer never goes backwards, so this is also the high-water
+        let used = self.allocated.load(Ordering::Relaxed) - self.start_byte();
+        ArgsPoolStats {
+          chunks: 1,
+          capacity: self.size(),
+          in_use: used,
+          high_water: used,
+          allocs: 0,
+          frees: 0,
+        }
+      },
+    }
+  }
+
   fn base(&self) -> &ArgsBox<[u8]> {
     &self.base
   }
+  /// The size of the initial storage. For ring pools, see `stats` for the total.
   pub fn size(&self) -> usize { self.base().len() }
 
   fn start_byte(&self) -> usize { self.base().as_ptr() as usize }
@@ -107,6 +189,11 @@ impl ArgsPool {
       // TODO return an error here so users don't think it's OOM.
       .ok()?;
 
+    if let Some(ref ring) = self.ring {
+      return self.ring_alloc(&mut *ring.lock(), layout)
+        .map(|ptr| Unique::new_unchecked(ptr.as_ptr() as *mut A) );
+    }
+
     let mut allocated_start = self.allocated.load(Ordering::Acquire);
 
     loop {
@@ -139,18 +226,61 @@ impl ArgsPool {
     }
   }
 
+  fn ring_alloc(&self, ring: &mut RingArgs, layout: Layout) -> Option<NonNull<u8>> {
+    use std::cmp::max;
+
+    let addr = match ring.alloc.alloc(layout.size(), layout.align()) {
+      Some(addr) => addr,
+      None => {
+        // Grow. Double the capacity so a busy pool settles quickly.
+        let bytes = max(ring.alloc.stats().capacity,
+                        layout.size() + layout.align());
+        let chunk = match self.alloc_chunk(bytes) {
+          Ok(chunk) => chunk,
+          Err(err) => {
+            log::warn!("failed to grow kernel args ring: {:?}", err);
+            return None;
+          },
+        };
+        ring.alloc.add_chunk(chunk.as_ptr() as usize, chunk.len());
+        ring.extra.push(chunk);
+        ring.alloc.alloc(layout.size(), layout.align())?
+      },
+    };
+    NonNull::new(addr as *mut u8)
+  }
+
+  /// Free the args allocation containing `ptr`. Does nothing for arena pools.
+  ///
+  /// # Safety
+  ///
+  /// The device must be done with the allocation.
+  pub unsafe fn dealloc(&self, ptr: NonNull<u8>) {
+    if let Some(ref ring) = self.ring {
+      let freed = ring.lock().alloc.free(ptr.as_ptr() as usize);
+      debug_assert!(freed, "kernel args ptr not from this pool");
+    }
+  }
+
   /// Reset the allocation ptr to the base. The mutable requirement ensures
-  /// no device calls are in flight.
+  /// no device calls are in flight. Ring pools keep any chunks they've grown.
   pub fn wash(&mut self) {
     let base = self.base().as_ptr() as usize;
     *self.allocated.get_mut() = base;
+    if let Some(ref mut ring) = self.ring {
+      ring.get_mut().alloc.reset();
+    }
   }
 }
 impl Clone for ArgsPool {
   fn clone(&self) -> Self {
     let size = self.size();
-    ArgsPool::new_arena(&self.device, size)
-      .expect("failed to clone ArgsPool")
+    let pool = if self.is_ring() {
+      ArgsPool::new_ring(&self.device, size)
+    } else {
+      ArgsPool::new_arena(&self.device, size)
+    };
+    pool.expect("failed to clone ArgsPool")
   }
 }
 
@@ -165,8 +295,9 @@ unsafe impl<P> AllocRef for ArgsPoolAlloc<P>
     Err(AllocError)
   }
   #[inline(always)]
-  unsafe fn dealloc(&self, _ptr: NonNull<u8>, _layout: Layout) {
-    // no-op
+  unsafe fn dealloc(&self, ptr: NonNull<u8>, _layout: Layout) {
+    // no-op for arenas
+    self.0.dealloc(ptr)
   }
 
   #[inline(always)]
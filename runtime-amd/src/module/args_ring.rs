@@ -0,0 +1,254 @@
+//! The allocator behind `ArgsPool::new_ring`. This only deals with addresses, so the
+//! memory itself is owned (and allocated) by the `ArgsPool`.
+//!
+//! Each chunk is a ring buffer: allocations are made at the head, in dispatch order.
+//! Frees can happen in any order; a freed slot is only reclaimed once every slot
+//! allocated before it (or after it, if it's at the head) is also freed. Since
+//! dispatches on a queue mostly complete in order, this is usually immediate.
+
+use std::collections::VecDeque;
+
+use super::args_pool::ArgsPoolStats;
+
+#[inline(always)]
+fn align_up(v: usize, align: usize) -> usize {
+  (v + align - 1) & !(align - 1)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Slot {
+  start: usize,
+  end: usize,
+  live: bool,
+}
+
+#[derive(Debug)]
+struct RingChunk {
+  base: usize,
+  len: usize,
+  /// In allocation order.
+  slots: VecDeque<Slot>,
+}
+impl RingChunk {
+  fn new(base: usize, len: usize) -> Self {
+    RingChunk {
+      base,
+      len,
+      slots: VecDeque::new(),
+    }
+  }
+  fn end(&self) -> usize { self.base + self.len }
+  fn contains(&self, addr: usize) -> bool {
+    self.base <= addr && addr < self.end()
+  }
+
+  fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+    let start = match (self.slots.front(), self.slots.back()) {
+      (Some(front), Some(back)) => {
+        let tail = front.start;
+        let head = align_up(back.end, align);
+        // The ring has wrapped if the newest slot is before the oldest.
+        if back.start < front.start {
+          if head + size > tail { return None; }
+          head
+        } else if head + size <= self.end() {
+          head
+        } else {
+          let start = align_up(self.base, align);
+          if start + size > tail { return None; }
+          start
+        }
+      },
+      _ => {
+        let start = align_up(self.base, align);
+        if start + size > self.end() { return None; }
+        start
+      },
+    };
+
+    self.slots.push_back(Slot {
+      start,
+      end: start + size,
+      live: true,
+    });
+    Some(start)
+  }
+  /// `addr` can point anywhere inside the allocation. Returns the size freed.
+  fn free(&mut self, addr: usize) -> Option<usize> {
+    let slot = self.slots.iter_mut()
+      .find(|s| s.live && s.start <= addr && addr < s.end )?;
+    slot.live = false;
+    let size = slot.end - slot.start;
+
+    while self.slots.front().map(|s| !s.live ).unwrap_or(false) {
+      self.slots.pop_front();
+    }
+    while self.slots.back().map(|s| !s.live ).unwrap_or(false) {
+      self.slots.pop_back();
+    }
+    Some(size)
+  }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RingAlloc {
+  chunks: Vec<RingChunk>,
+  /// The chunk we last allocated from.
+  current: usize,
+  stats: ArgsPoolStats,
+}
+impl RingAlloc {
+  pub(crate) fn new() -> Self { Default::default() }
+
+  pub(crate) fn add_chunk(&mut self, base: usize, len: usize) {
+    self.chunks.push(RingChunk::new(base, len));
+    self.current = self.chunks.len() - 1;
+    self.stats.chunks += 1;
+    self.stats.capacity += len;
+  }
+
+  /// `align` must be a power of two. Returns `None` if no chunk has room; add a
+  /// chunk and try again.
+  pub(crate) fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+    debug_assert!(align.is_power_of_two());
+    let size = size.max(1);
+
+    let count = self.chunks.len();
+    for i in 0..count {
+      let idx = (self.current + i) % count;
+      if let Some(addr) = self.chunks[idx].alloc(size, align) {
+        self.current = idx;
+        self.stats.allocs += 1;
+        self.stats.in_use += size;
+        self.stats.high_water = self.stats.high_water.max(self.stats.in_use);
+        return Some(addr);
+      }
+    }
+    None
+  }
+  /// Returns false if `addr` isn't in a live allocation.
+  pub(crate) fn free(&mut self, addr: usize) -> bool {
+    let size = self.chunks.iter_mut()
+      .find(|c| c.contains(addr) )
+      .and_then(|c| c.free(addr) );
+    match size {
+      Some(size) => {
+        self.stats.frees += 1;
+        self.stats.in_use -= size;
+        true
+      },
+      None => false,
+    }
+  }
+  /// Forget every allocation. Stats other than `in_use` are kept.
+  pub(crate) fn reset(&mut self) {
+    for chunk in self.chunks.iter_mut() {
+      chunk.slots.clear();
+    }
+    self.current = 0;
+    self.stats.in_use = 0;
+  }
+
+  pub(crate) fn stats(&self) -> ArgsPoolStats { self.stats }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const BASE: usize = 0x1000;
+
+  fn ring(len: usize) -> RingAlloc {
+    let mut r = RingAlloc::new();
+    r.add_chunk(BASE, len);
+    r
+  }
+
+  #[test]
+  fn in_order() {
+    let mut r = ring(512);
+    let a = r.alloc(128, 128).unwrap();
+    let b = r.alloc(128, 128).unwrap();
+    let c = r.alloc(200, 128).unwrap();
+    assert_eq!((a, b, c), (BASE, BASE + 128, BASE + 256));
+    assert_eq!(r.alloc(1, 128), None);
+
+    assert!(r.free(a));
+    // wraps around:
+    assert_eq!(r.alloc(128, 128), Some(BASE));
+    assert_eq!(r.alloc(1, 1), None);
+
+    assert!(r.free(b));
+    assert!(r.free(c + 10)); // interior pointers are fine
+    assert_eq!(r.alloc(256, 128), Some(BASE + 128));
+
+    let stats = r.stats();
+    assert_eq!(stats.allocs, 5);
+    assert_eq!(stats.frees, 3);
+    assert_eq!(stats.in_use, 384);
+    assert_eq!(stats.high_water, 456);
+  }
+
+  #[test]
+  fn out_of_order() {
+    let mut r = ring(384);
+    let a = r.alloc(128, 128).unwrap();
+    let b = r.alloc(128, 128).unwrap();
+    let c = r.alloc(128, 128).unwrap();
+
+    // `b` can't be reclaimed until `a` is:
+    assert!(r.free(b));
+    assert_eq!(r.alloc(128, 128), None);
+    assert!(r.free(a));
+    assert_eq!(r.alloc(256, 128), Some(BASE));
+    assert!(r.free(c));
+    assert!(r.free(BASE));
+    assert!(!r.free(BASE));
+    assert_eq!(r.stats().in_use, 0);
+  }
+
+  #[test]
+  fn head_reclaim() {
+    let mut r = ring(384);
+    let _a = r.alloc(128, 128).unwrap();
+    let b = r.alloc(128, 128).unwrap();
+    let c = r.alloc(128, 128).unwrap();
+    // freeing the newest allocations returns them immediately:
+    assert!(r.free(c));
+    assert!(r.free(b));
+    assert_eq!(r.alloc(256, 128), Some(BASE + 128));
+  }
+
+  #[test]
+  fn chunks() {
+    let mut r = ring(256);
+    assert_eq!(r.alloc(256, 128), Some(BASE));
+    assert_eq!(r.alloc(64, 64), None);
+
+    r.add_chunk(0x10_000, 1024);
+    let b = r.alloc(64, 64).unwrap();
+    assert_eq!(b, 0x10_000);
+    assert!(r.free(BASE));
+    // we stick with the newest chunk until it's full:
+    assert_eq!(r.alloc(64, 64), Some(0x10_040));
+    assert_eq!(r.alloc(1024, 64), None);
+
+    let stats = r.stats();
+    assert_eq!(stats.chunks, 2);
+    assert_eq!(stats.capacity, 1280);
+
+    assert!(!r.free(0x50_000));
+    r.reset();
+    assert_eq!(r.stats().in_use, 0);
+    assert_eq!(r.alloc(1024, 64), Some(0x10_000));
+  }
+
+  #[test]
+  fn alignment() {
+    let mut r = RingAlloc::new();
+    r.add_chunk(BASE + 8, 1024);
+    assert_eq!(r.alloc(10, 1), Some(BASE + 8));
+    assert_eq!(r.alloc(10, 128), Some(BASE + 128));
+    assert_eq!(r.alloc(10, 4), Some(BASE + 140));
+  }
+}
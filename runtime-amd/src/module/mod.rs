@@ -1,6 +1,7 @@
+use std::any::type_name;
 use std::geobacter::kernel::{KernelInstanceRef, OptionalKernelFn, };
 use std::marker::{PhantomData, Unsize, };
-use std::mem::{transmute, size_of, };
+use std::mem::{transmute, size_of, align_of, };
 use std::num::NonZeroU64;
 use std::ops::{CoerceUnsized, Deref, };
 use std::pin::Pin;
@@ -12,6 +13,9 @@ use alloc_wg::boxed::Box;
 
 use log::{error, };
 
+use amd_comgr::data::{Data, ExecutableData, };
+use amd_comgr::symbol::Symbol;
+
 use hsa_rt::agent::Agent;
 use hsa_rt::executable::FrozenExecutable;
 use hsa_rt::queue::{DispatchPacket, RingQueue, };
@@ -23,26 +27,32 @@ pub use hsa_rt::queue::{FenceScope, QueueError, };
 pub use hsa_rt::queue::KernelMultiQueue as DeviceMultiQueue;
 pub use hsa_rt::queue::KernelSingleQueue as DeviceSingleQueue;
 
-use crate::grt_core::{Device, AcceleratorId, };
+use crate::grt_core::{Accelerator, Device, AcceleratorId, };
 use crate::grt_core::codegen as core_codegen;
 use crate::grt_core::codegen::PKernelDesc;
 use crate::grt_core::context::{ModuleContextData, PlatformModuleData, ModuleData, };
 
 use crate::{HsaAmdGpuAccel, Error};
 use crate::codegen::{Codegenner, KernelDesc, CodegenDesc};
+use crate::codegen::sources::DeviceSource;
+use crate::heap::{DeviceHeap, stubs::device_heap_param, };
+use crate::lds::{LdsDyn, LDS_DYN_ALIGN, };
+use crate::math::{MathOptions, stubs::device_libs_param, };
+use crate::profiling::{DispatchTiming, TraceRecorder, };
 use crate::signal::{DeviceConsumable, HostConsumable, SignalHandle,
                     SignaledDeref, Value};
 
 use self::args_pool::ArgsPoolAlloc;
 
 pub use self::args::*;
-pub use self::args_pool::ArgsPool;
+pub use self::args_pool::{ArgsPool, ArgsPoolStats, };
 pub use self::grid::*;
 pub use crate::signal::deps;
 pub use crate::signal::deps::Deps;
 
 pub mod args;
 pub mod args_pool;
+mod args_ring;
 pub mod grid;
 
 #[cfg(test)]
@@ -81,6 +91,8 @@ pub struct FuncModule<A>
   instance: KernelInstanceRef<'static>,
   desc: KernelDesc,
   spec_params: core_codegen::SpecParamsDesc,
+  /// Kept alive for as long as we could be dispatched.
+  device_heap: Option<Arc<DeviceHeap>>,
 
   /// To ensure we are only called with this argument type.
   _arg: PhantomData<*const A>,
@@ -104,8 +116,11 @@ impl<A> FuncModule<A>
         .get_cache_data(accel.ctx()),
       desc: KernelDesc {
         max_vgpr_count: A::MAX_VGPR_USAGE,
+        math: Default::default(),
+        sources: Default::default(),
       },
       spec_params: Default::default(),
+      device_heap: None,
 
       _arg: PhantomData,
     }
@@ -120,7 +135,34 @@ impl<A> FuncModule<A>
 
   pub fn group_size(&mut self) -> Result<u32, Error> {
     let module_data = self.compile_internal()?;
-    Ok(module_data.desc.group_segment_size + self.dynamic_group_size)
+    let static_size = module_data.desc.group_segment_size;
+    if self.dynamic_group_size == 0 {
+      return Ok(static_size);
+    }
+    // The dynamic segment starts after the static LDS, aligned to `LDS_DYN_ALIGN`.
+    let static_size = (static_size + LDS_DYN_ALIGN - 1) & !(LDS_DYN_ALIGN - 1);
+    Ok(static_size + self.dynamic_group_size)
+  }
+  /// Reserve `len` `T`s in the dynamic group segment of every workgroup this kernel is
+  /// dispatched with. Pass the returned handle to the kernel in its arguments. The space
+  /// is added to `dynamic_group_size`, so don't reset that afterwards.
+  pub fn alloc_lds_dyn<T>(&mut self, len: u32) -> Result<LdsDyn<T>, Error> {
+    let align = align_of::<T>() as u32;
+    if align > LDS_DYN_ALIGN {
+      return Err(Error::LdsDynAlign(align as _));
+    }
+
+    let offset = self.dynamic_group_size
+      .checked_add(align - 1)
+      .ok_or(Error::Overflow)? & !(align - 1);
+    let end = size_of::<T>()
+      .checked_mul(len as usize)
+      .and_then(|size| size.checked_add(offset as usize) )
+      .filter(|&end| end <= u32::max_value() as usize )
+      .ok_or(Error::Overflow)?;
+
+    self.dynamic_group_size = end as u32;
+    Ok(LdsDyn::new(offset, len))
   }
   pub fn private_size(&mut self) -> Result<u32, Error> {
     let module_data = self.compile_internal()?;
@@ -195,6 +237,10 @@ impl<A> FuncModule<A>
     self.compile_internal()?;
     Ok(())
   }
+  /// Compile if needed, then disassemble the kernel; see `HsaModuleData::disassemble`.
+  pub fn disassemble(&mut self) -> Result<String, Error> {
+    self.compile_internal()?.disassemble()
+  }
   pub fn compile_async(&self) {
     use rustc_data_structures::rayon::*;
 
@@ -216,6 +262,12 @@ impl<A> FuncModule<A>
   pub fn clear_params(&mut self) {
     self.module_data.take();
     self.spec_params.clear();
+    if let Some(heap) = self.device_heap.as_ref() {
+      self.spec_params.define(device_heap_param, &heap.base_addr());
+    }
+    if self.desc.math.device_libs {
+      self.spec_params.define(device_libs_param, &true);
+    }
   }
   /// Undefine a specialization entry. If the key (`f`) has no entry, this does nothing.
   ///
@@ -245,6 +297,61 @@ impl<A> FuncModule<A>
     self.spec_params.define(f, value)
   }
 
+  /// Route `Box`, `Vec`, etc allocations made by this kernel to `heap`. Without a
+  /// heap, allocating on the device aborts the dispatch. Multiple functions may
+  /// share a heap.
+  ///
+  /// If this function was already compiled, it will be compiled again.
+  pub fn set_device_heap(&mut self, heap: &Arc<DeviceHeap>) -> Result<(), Error> {
+    if heap.device().id() != self.device.id() {
+      return Err(Error::DeviceHeapWrongDevice);
+    }
+
+    self.module_data.take();
+    self.spec_params.define(device_heap_param, &heap.base_addr());
+    self.device_heap = Some(heap.clone());
+    Ok(())
+  }
+  /// If this function was already compiled, it will be compiled again.
+  pub fn clear_device_heap(&mut self) {
+    if self.device_heap.take().is_some() {
+      self.undefine_param(device_heap_param);
+    }
+  }
+  pub fn device_heap(&self) -> Option<&Arc<DeviceHeap>> { self.device_heap.as_ref() }
+
+  /// Choose how float math in this kernel is compiled, eg to link ROCm's device
+  /// libraries so `f32::sin` and friends work. See `MathOptions`.
+  ///
+  /// If this function was already compiled, it will be compiled again.
+  pub fn set_math_options(&mut self, math: MathOptions) {
+    self.module_data.take();
+    if math.device_libs {
+      self.spec_params.define(device_libs_param, &true);
+    } else {
+      self.spec_params.undefine(device_libs_param);
+    }
+    self.desc.math = math;
+  }
+  pub fn math_options(&self) -> &MathOptions { &self.desc.math }
+
+  /// Link `source` into this kernel, to define functions the kernel declares in an
+  /// `extern "C"` block. See `crate::codegen::sources`.
+  ///
+  /// If this function was already compiled, it will be compiled again.
+  pub fn add_device_source(&mut self, source: DeviceSource) {
+    self.module_data.take();
+    self.desc.sources.push(source);
+  }
+  /// If this function was already compiled, it will be compiled again.
+  pub fn clear_device_sources(&mut self) {
+    if !self.desc.sources.is_empty() {
+      self.module_data.take();
+      self.desc.sources.clear();
+    }
+  }
+  pub fn device_sources(&self) -> &[DeviceSource] { &self.desc.sources }
+
   /// Attach a kernel args pool, in preparation for dispatching.
   pub fn invoc<P>(&self, pool: P) -> Invoc<A, P, Self>
     where P: Deref<Target = ArgsPool> + Clone,
@@ -291,6 +398,7 @@ impl<A> Clone for FuncModule<A>
       context_data: self.context_data.clone(),
       desc: self.desc.clone(),
       spec_params: self.spec_params.clone(),
+      device_heap: self.device_heap.clone(),
 
       _arg: PhantomData,
     }
@@ -491,6 +599,7 @@ impl<A, P, FM> Invoc<A, P, FM>
           ..
         } = ptr::read(launch_args);
         *args = Some(launch_args);
+        self.pool.dealloc(ptr::NonNull::new_unchecked(kernargs.as_ptr() as *mut u8));
         return Err(err.into());
       }
     }
@@ -499,6 +608,8 @@ impl<A, P, FM> Invoc<A, P, FM>
                                  ArgsPoolAlloc(self.pool.clone()));
 
     Ok(InvocCompletion {
+      device: self.f.fm_mut().device.clone(),
+      name: type_name::<A>(),
       args: Box::into_pin(kargs),
     })
   }
@@ -512,6 +623,9 @@ pub struct InvocCompletion<P, A, S>
         S: SignalHandle + ?Sized,
         A: Completion<CompletionSignal = S> + ?Sized,
 {
+  device: Arc<HsaAmdGpuAccel>,
+  /// Used for tracing.
+  name: &'static str,
   args: Pin<Box<A, args_pool::ArgsPoolAlloc<P>>>,
 }
 pub type LaunchCompletion<P, A, S, G> = InvocCompletion<P, LaunchArgs<A, G>, S>;
@@ -545,6 +659,28 @@ impl<P, A, S> InvocCompletion<P, A, S>
       invoc: self,
     }
   }
+
+  /// The GPU start and end times of this dispatch. The dispatch must be complete, and
+  /// must have been enqueued on a queue created after
+  /// `HsaAmdGpuAccel::enable_profiling` was called.
+  pub fn timing(&self) -> Result<DispatchTiming, Error> {
+    Ok(self.device.dispatch_timing(self.signal_ref())?)
+  }
+  fn record_trace(&self) {
+    if !self.device.profiling_enabled() { return; }
+    let recorder = TraceRecorder::global();
+    if !recorder.is_recording() { return; }
+
+    match self.timing() {
+      Ok(timing) => {
+        recorder.record(timing.to_span(self.name, "dispatch",
+                                       self.device.trace_track()));
+      },
+      Err(err) => {
+        log::warn!("failed to get dispatch timing: {:?}", err);
+      },
+    }
+  }
 }
 unsafe impl<P, A> deps::Deps for InvocCompletion<P, A, dyn DeviceConsumable>
   where P: Deref<Target = ArgsPool> + Clone,
@@ -638,6 +774,7 @@ impl<P, A, S> Drop for InvocCompletion<P, A, S>
     }
 
     if waited {
+      self.record_trace();
       return;
     }
 
@@ -729,6 +866,35 @@ pub struct HsaModuleData {
   pub(crate) exe: FrozenExecutable,
   pub(crate) kernel_object: NonZeroU64,
   pub(crate) desc: CodegenDesc,
+  /// The executable `exe` was loaded from.
+  pub(crate) code_object: Vec<u8>,
+  /// The kernel descriptor's symbol.
+  pub(crate) symbol: String,
+}
+impl HsaModuleData {
+  /// The ELF executable the kernel was loaded from.
+  pub fn code_object(&self) -> &[u8] { &self.code_object }
+  /// The name of the kernel's code in `code_object`. Its descriptor has the `.kd` suffix.
+  pub fn kernel_symbol(&self) -> &str {
+    self.symbol.trim_end_matches(".kd")
+  }
+
+  fn executable(&self) -> Result<ExecutableData, Error> {
+    let mut exe = ExecutableData::new()?;
+    exe.set_data(&self.code_object)?;
+    Ok(exe)
+  }
+  /// Every symbol in `code_object`, including the code of any functions which weren't
+  /// inlined into the kernel.
+  pub fn symbols(&self) -> Result<Vec<Symbol>, Error> {
+    Ok(self.executable()?.symbols()?)
+  }
+  /// The ISA of the kernel, as annotated assembly text.
+  pub fn disassemble(&self) -> Result<String, Error> {
+    self.executable()?
+      .disassemble(self.kernel_symbol())?
+      .ok_or_else(|| Error::MissingKernelSymbol(self.kernel_symbol().into()) )
+  }
 }
 impl PlatformModuleData for HsaModuleData {
   fn eq(&self, rhs: &dyn PlatformModuleData) -> bool {
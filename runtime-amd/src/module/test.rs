@@ -869,3 +869,90 @@ mod three_d {
     }
   }
 }
+
+mod device_sources {
+  use super::*;
+
+  use amd_comgr::data::Data;
+  use amd_comgr::set::DataSet;
+
+  use crate::grt_core::Accelerator;
+
+  extern "C" {
+    fn grt_test_cl_add(a: u32, b: u32) -> u32;
+    fn grt_test_bc_mul(a: u32, b: u32) -> u32;
+    fn grt_test_undefined(a: u32) -> u32;
+  }
+
+  /// Compile `source` to bitcode now, so the kernel links it as bitcode.
+  fn bitcode(dev: &Arc<HsaAmdGpuAccel>, name: &str, source: &str) -> DeviceSource {
+    let mut set = DataSet::new().unwrap();
+    DeviceSource::opencl(name, source)
+      .add_to(dev.accel_target_desc(), &std::env::temp_dir(), &mut set)
+      .unwrap();
+    let bc = set.bitcode_iter().unwrap()
+      .next()
+      .expect("no bitcode")
+      .unwrap();
+    DeviceSource::bitcode(format!("{}.bc", name), bc.data().unwrap())
+  }
+
+  #[test]
+  fn link_opencl_and_bitcode() {
+    let dev = device();
+
+    let mut m = LapVec::new_in(dev.fine_lap_node_alloc(0));
+
+    const GRID: Dim1D<Range<u32>> = Dim1D { x: 0..16, };
+
+    fn f(dst: *mut [u32], vp: VectorParams<Dim1D<Range<u32>>>) {
+      unsafe {
+        let v = grt_test_bc_mul(grt_test_cl_add(vp.gl_id(), 1), 3);
+        (&mut *dst)[vp.gl_id() as usize] = v;
+      }
+    }
+
+    unsafe {
+      let (mut invoc, k) = TestKernel::new_global(&dev, &mut m,
+                                                  &GRID, 0u32, f);
+      invoc.f.add_device_source(DeviceSource::opencl("add.cl", "\
+        uint grt_test_cl_add(uint a, uint b) { return a + b; }"));
+      invoc.f.add_device_source(bitcode(&dev, "mul", "\
+        uint grt_test_bc_mul(uint a, uint b) { return a * b; }"));
+      let _wait = invoc
+        .unchecked_call_async(&GRID, k)
+        .unwrap();
+    }
+
+    for (i, &v) in m.iter().enumerate() {
+      assert_eq!(v as usize, (i + 1) * 3);
+    }
+  }
+
+  #[test]
+  fn undefined_symbols() {
+    let dev = device();
+
+    let mut m = LapVec::new_in(dev.fine_lap_node_alloc(0));
+
+    const GRID: Dim1D<Range<u32>> = Dim1D { x: 0..16, };
+
+    fn f(dst: *mut [u32], vp: VectorParams<Dim1D<Range<u32>>>) {
+      unsafe {
+        (&mut *dst)[vp.gl_id() as usize] = grt_test_undefined(vp.gl_id());
+      }
+    }
+
+    let (mut invoc, _k) = TestKernel::new_global(&dev, &mut m,
+                                                 &GRID, 0u32, f);
+    match invoc.f.compile() {
+      Err(Error::CodegenPostCodegen(err)) => match *err {
+        Error::UndefinedDeviceSymbols(ref symbols) => {
+          assert_eq!(symbols, &["grt_test_undefined"]);
+        },
+        err => panic!("unexpected error: {:?}", err),
+      },
+      r => panic!("expected undefined symbols, got {:?}", r),
+    }
+  }
+}
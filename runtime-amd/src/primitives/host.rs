@@ -0,0 +1,166 @@
+//! Host implementations of the device-wide primitives. These have the same semantics as the
+//! `Primitives` methods of the same name, except that floating point reductions may round
+//! differently, as the device combines elements in a different order.
+
+use super::*;
+
+pub fn reduce<T, O>(src: &[T]) -> T
+  where T: Copy,
+        O: Monoid<T>,
+{
+  src.iter().fold(O::identity(), |acc, &v| O::op(acc, v) )
+}
+pub fn segmented_reduce<T, O>(src: &[T], offsets: &[u32], dst: &mut [T])
+  where T: Copy,
+        O: Monoid<T>,
+{
+  assert_eq!(offsets.len(), dst.len() + 1, "segment offsets length");
+
+  for (dst, w) in dst.iter_mut().zip(offsets.windows(2)) {
+    let (start, end) = (w[0] as usize, w[1] as usize);
+    *dst = if start < end {
+      reduce::<T, O>(&src[start..end])
+    } else {
+      O::identity()
+    };
+  }
+}
+pub fn inclusive_scan<T, O>(src: &[T], dst: &mut [T])
+  where T: Copy,
+        O: Monoid<T>,
+{
+  assert_eq!(src.len(), dst.len(), "scan output length");
+
+  let mut acc = O::identity();
+  for (dst, &v) in dst.iter_mut().zip(src.iter()) {
+    acc = O::op(acc, v);
+    *dst = acc;
+  }
+}
+pub fn exclusive_scan<T, O>(src: &[T], dst: &mut [T])
+  where T: Copy,
+        O: Monoid<T>,
+{
+  assert_eq!(src.len(), dst.len(), "scan output length");
+
+  let mut acc = O::identity();
+  for (dst, &v) in dst.iter_mut().zip(src.iter()) {
+    *dst = acc;
+    acc = O::op(acc, v);
+  }
+}
+pub fn radix_sort_keys<K>(keys: &mut [K])
+  where K: RadixKey,
+{
+  // `sort_by_key` is stable, like a radix sort.
+  keys.sort_by_key(|&k| k.radix_bits() );
+}
+pub fn radix_sort_pairs<K, V>(keys: &mut [K], values: &mut [V])
+  where K: RadixKey,
+        V: Copy,
+{
+  assert_eq!(keys.len(), values.len(), "radix sort values length");
+
+  let mut pairs: Vec<_> = keys.iter().cloned()
+    .zip(values.iter().cloned())
+    .collect();
+  pairs.sort_by_key(|&(k, _)| k.radix_bits() );
+  for ((k, v), (pk, pv)) in keys.iter_mut().zip(values.iter_mut()).zip(pairs) {
+    *k = pk;
+    *v = pv;
+  }
+}
+pub fn histogram_even<T>(samples: &[T], lower: T, upper: T, bins: &mut [u32])
+  where T: AsPrimitive<f64>,
+{
+  for bin in bins.iter_mut() {
+    *bin = 0;
+  }
+  for &s in samples.iter() {
+    if let Some(bin) = even_bin(s, lower, upper, bins.len()) {
+      bins[bin] += 1;
+    }
+  }
+}
+/// Returns the number of selected elements.
+pub fn select_if<T, P>(src: &[T], dst: &mut [T]) -> usize
+  where T: Copy,
+        P: Predicate<T>,
+{
+  assert!(dst.len() >= src.len(), "select output length");
+
+  let mut n = 0;
+  for v in src.iter().filter(|v| P::test(v) ) {
+    dst[n] = *v;
+    n += 1;
+  }
+  n
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn radix_key_order() {
+    let ints = [i32::min_value(), -70000, -1, 0, 1, 255, i32::max_value()];
+    for w in ints.windows(2) {
+      assert!(w[0].radix_bits() < w[1].radix_bits(), "{:?}", w);
+    }
+    let floats = [std::f32::NEG_INFINITY, -1.5e10, -1.0, -0.5, 0.0, 1e-30, 2.0,
+                  std::f32::INFINITY];
+    for w in floats.windows(2) {
+      assert!(w[0].radix_bits() < w[1].radix_bits(), "{:?}", w);
+    }
+    assert!((-0.0f64).radix_bits() < 0.0f64.radix_bits());
+    assert_eq!(u8::max_value().radix_bits(), 255);
+    assert_eq!(<i16 as RadixKey>::BITS, 16);
+  }
+  #[test]
+  fn sort_pairs_stable() {
+    let mut keys = [3i8, -1, 3, 0, -1, -128];
+    let mut values = [0, 1, 2, 3, 4, 5];
+    radix_sort_pairs(&mut keys, &mut values);
+    assert_eq!(keys, [-128, -1, -1, 0, 3, 3]);
+    assert_eq!(values, [5, 1, 4, 3, 0, 2]);
+  }
+  #[test]
+  fn reductions_and_scans() {
+    let src = [5u32, 1, 4, 2, 3];
+    assert_eq!(reduce::<u32, Sum>(&src), 15);
+    assert_eq!(reduce::<u32, Min>(&src), 1);
+    assert_eq!(reduce::<u32, Max>(&[]), 0);
+
+    let mut dst = [0; 5];
+    inclusive_scan::<u32, Sum>(&src, &mut dst);
+    assert_eq!(dst, [5, 6, 10, 12, 15]);
+    exclusive_scan::<u32, Max>(&src, &mut dst);
+    assert_eq!(dst, [0, 5, 5, 5, 5]);
+
+    let mut dst = [1; 4];
+    segmented_reduce::<u32, Sum>(&src, &[0, 2, 2, 5, 5], &mut dst);
+    assert_eq!(dst, [6, 0, 9, 0]);
+  }
+  #[test]
+  fn even_bins() {
+    let mut bins = [9; 4];
+    histogram_even(&[-1.0f32, 0.0, 0.24, 0.25, 0.99, 0.999_999_9, 1.0, std::f32::NAN],
+                   0.0, 1.0, &mut bins);
+    assert_eq!(bins, [2, 1, 0, 2]);
+    assert_eq!(even_bin(5u8, 0, 10, 0), None);
+    assert_eq!(even_bin(9u8, 0, 10, 3), Some(2));
+  }
+  #[test]
+  fn select() {
+    struct Odd;
+    impl Predicate<u32> for Odd {
+      fn test(v: &u32) -> bool { v % 2 == 1 }
+    }
+
+    let src = [1u32, 2, 3, 4, 5];
+    let mut dst = [0; 5];
+    assert_eq!(select_if::<u32, Odd>(&src, &mut dst), 3);
+    assert_eq!(&dst[..3], &[1, 3, 5]);
+    assert_eq!(select_if::<u32, Odd>(&[], &mut []), 0);
+  }
+}
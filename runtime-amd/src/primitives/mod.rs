@@ -0,0 +1,1088 @@
+//! Device-wide parallel primitives: reductions, segmented reductions, prefix scans, radix
+//! sorts, histograms and stream compaction, operating on `RawPoolBox<[T]>` buffers.
+//!
+//! Each primitive is made up of one or more Geobacter kernels, launched through `FuncModule`
+//! on the `Primitives` queue. The kernels are sequenced with barrier packets: every stage
+//! depends on the completion signal of the stage before it, and the first stage depends on
+//! the signal given by the caller, if any. The returned `Pending` owns every stage and any
+//! temporary device buffers, and its `signal()` can be used as a dep by later dispatches.
+//!
+//! ```ignore
+//! let prims = Primitives::new(&dev)?;
+//! let sorted = unsafe { prims.radix_sort_keys(&mut keys, None)? };
+//! let scanned = unsafe {
+//!   prims.exclusive_scan::<u32, Sum>(&src, &mut dst, sorted.signal().cloned())?
+//! };
+//! scanned.wait().unwrap();
+//! ```
+//!
+//! `host` has implementations of every primitive with the same semantics, for testing and for
+//! running without a GPU.
+
+use std::any::Any;
+use std::cmp::max;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::{Add, RangeTo, };
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering, };
+
+use num_traits::{AsPrimitive, Bounded, Zero, };
+
+use crate::{Error, HsaAmdGpuAccel, };
+use crate::boxed::RawPoolBox;
+use crate::collective::{self, CrossLane, };
+use crate::module::{ArgsPool, Completion, Deps, DeviceMultiQueue, Dim1D, Kernel,
+                    KVectorParams, };
+use crate::signal::{DeviceConsumable, GlobalSignal, HostConsumable, Value, };
+
+pub mod host;
+
+/// Workitems per workgroup, and so elements per tile, for every primitive kernel.
+const WG: u16 = 256;
+const WORKGROUP: Dim1D<RangeTo<u16>> = Dim1D {
+  x: ..WG,
+};
+type Grid = Dim1D<RangeTo<u32>>;
+
+/// Radix sort passes sort by this many bits at a time.
+const RADIX_BITS: u32 = 4;
+const DIGITS: usize = 1 << RADIX_BITS;
+
+const ARGS_POOL_SIZE: usize = 1024 * 1024;
+
+/// An associative and commutative operation with an identity, ie something to reduce with.
+pub trait Monoid<T>: Send + Sync + 'static {
+  fn identity() -> T;
+  fn op(l: T, r: T) -> T;
+}
+
+/// Addition. Integer overflow is the same as `+`: it panics in debug builds on the host.
+pub struct Sum;
+impl<T> Monoid<T> for Sum
+  where T: Zero + Add<Output = T>,
+{
+  #[inline(always)]
+  fn identity() -> T { T::zero() }
+  #[inline(always)]
+  fn op(l: T, r: T) -> T { l + r }
+}
+/// The minimum. The identity is `T::max_value()`, not infinity, for floats.
+pub struct Min;
+impl<T> Monoid<T> for Min
+  where T: Bounded + PartialOrd,
+{
+  #[inline(always)]
+  fn identity() -> T { T::max_value() }
+  #[inline(always)]
+  fn op(l: T, r: T) -> T { if r < l { r } else { l } }
+}
+/// The maximum. The identity is `T::min_value()`, not negative infinity, for floats.
+pub struct Max;
+impl<T> Monoid<T> for Max
+  where T: Bounded + PartialOrd,
+{
+  #[inline(always)]
+  fn identity() -> T { T::min_value() }
+  #[inline(always)]
+  fn op(l: T, r: T) -> T { if r > l { r } else { l } }
+}
+
+/// The stream compaction predicate.
+pub trait Predicate<T>: Send + Sync + 'static {
+  fn test(v: &T) -> bool;
+}
+
+/// Keys which can be radix sorted.
+pub trait RadixKey: CrossLane + Send + Sync + 'static {
+  /// The number of significant bits returned by `radix_bits`. Always a multiple of eight.
+  const BITS: u32;
+  /// Map `self` to an unsigned integer which sorts in the same order as `self`.
+  fn radix_bits(self) -> u64;
+}
+macro_rules! unsigned_radix_key {
+  ($($ty:ty,)*) => {$(
+    impl RadixKey for $ty {
+      const BITS: u32 = (size_of::<$ty>() * 8) as u32;
+      #[inline(always)]
+      fn radix_bits(self) -> u64 { self as u64 }
+    }
+  )*};
+}
+unsigned_radix_key!(u8, u16, u32, u64, usize, );
+macro_rules! signed_radix_key {
+  ($($ty:ty => $uty:ty,)*) => {$(
+    impl RadixKey for $ty {
+      const BITS: u32 = (size_of::<$ty>() * 8) as u32;
+      /// Flip the sign bit so negative values sort first.
+      #[inline(always)]
+      fn radix_bits(self) -> u64 {
+        (self as $uty ^ (1 << (Self::BITS - 1))) as u64
+      }
+    }
+  )*};
+}
+signed_radix_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, isize => usize, );
+macro_rules! float_radix_key {
+  ($($ty:ty,)*) => {$(
+    impl RadixKey for $ty {
+      const BITS: u32 = (size_of::<$ty>() * 8) as u32;
+      /// Negative values have all of their bits flipped, so larger magnitudes sort first;
+      /// positive values just have their sign bit set.
+      #[inline(always)]
+      fn radix_bits(self) -> u64 {
+        let bits = self.to_bits();
+        let sign = 1 << (Self::BITS - 1);
+        (if bits & sign != 0 { !bits } else { bits | sign }) as u64
+      }
+    }
+  )*};
+}
+float_radix_key!(f32, f64, );
+
+#[inline(always)]
+fn digit<K>(key: K, shift: u32) -> usize
+  where K: RadixKey,
+{
+  ((key.radix_bits() >> shift) as usize) & (DIGITS - 1)
+}
+
+/// The bin `sample` falls into when `lower..upper` is split into `bins` equal width bins, or
+/// `None` if it is outside that range. Shared by the device and host implementations so they
+/// round identically.
+#[inline(always)]
+pub fn even_bin<T>(sample: T, lower: T, upper: T, bins: usize) -> Option<usize>
+  where T: AsPrimitive<f64>,
+{
+  let (s, l, u) = (sample.as_(), lower.as_(), upper.as_());
+  if bins == 0 || !(s >= l && s < u) { return None; }
+
+  let bin = ((s - l) / (u - l) * bins as f64) as usize;
+  // guard against rounding up to `bins` when `s` is just below `u`.
+  Some(if bin < bins { bin } else { bins - 1 })
+}
+
+/// The number of tiles needed to cover `len` elements. Always at least one, so that empty
+/// inputs still write their (identity) outputs.
+fn tiles(len: usize) -> usize {
+  max(1, (len + WG as usize - 1) / WG as usize)
+}
+
+/// The fields every primitive kernel has in common.
+struct Stage {
+  queue: Arc<DeviceMultiQueue>,
+  /// The previous stage's completion, or the caller's dep.
+  dep: Option<Arc<GlobalSignal>>,
+  completion: Arc<GlobalSignal>,
+}
+
+/// Implemented manually so we don't require `Deps` bounds on the element types.
+macro_rules! stage_args {
+  ($($name:ident<$($p:ident),*>,)*) => {$(
+    impl<$($p),*> Completion for $name<$($p),*> {
+      type CompletionSignal = Arc<GlobalSignal>;
+      #[inline(always)]
+      fn completion(&self) -> &Arc<GlobalSignal> { &self.stage.completion }
+    }
+    unsafe impl<$($p),*> Deps for $name<$($p),*> {
+      fn iter_deps<'a>(&'a self,
+                       f: &mut dyn FnMut(&'a dyn DeviceConsumable) -> Result<(), Error>)
+        -> Result<(), Error>
+      {
+        self.stage.dep.iter_deps(f)?;
+        self.stage.completion.iter_deps(f)
+      }
+    }
+    unsafe impl<$($p),*> Send for $name<$($p),*> { }
+    unsafe impl<$($p),*> Sync for $name<$($p),*> { }
+  )*};
+}
+
+/// Reduce each tile of `src` into an element of `dst`.
+struct TileReduceKernel<T, O> {
+  src: *const [T],
+  dst: *mut [T],
+  stage: Stage,
+  _o: PhantomData<O>,
+}
+impl<T, O> Kernel for TileReduceKernel<T, O>
+  where T: CrossLane,
+        O: Monoid<T>,
+{
+  type Grid = Grid;
+  const WORKGROUP: Dim1D<RangeTo<u16>> = WORKGROUP;
+
+  type Queue = DeviceMultiQueue;
+  fn queue(&self) -> &DeviceMultiQueue { &self.stage.queue }
+
+  fn kernel(&self, vp: KVectorParams<Self>) {
+    let (src, dst) = unsafe { (&*self.src, &mut *self.dst) };
+    let i = vp.gl_id() as usize;
+    let v = if i < src.len() { src[i] } else { O::identity() };
+    let total = collective::reduce(&vp, v, O::identity(), O::op);
+    if vp.linear_wi() == 0 {
+      dst[vp.wg_id().x as usize] = total;
+    }
+  }
+}
+
+/// One workgroup per segment.
+struct SegmentedReduceKernel<T, O> {
+  src: *const [T],
+  offsets: *const [u32],
+  dst: *mut [T],
+  stage: Stage,
+  _o: PhantomData<O>,
+}
+impl<T, O> Kernel for SegmentedReduceKernel<T, O>
+  where T: CrossLane,
+        O: Monoid<T>,
+{
+  type Grid = Grid;
+  const WORKGROUP: Dim1D<RangeTo<u16>> = WORKGROUP;
+
+  type Queue = DeviceMultiQueue;
+  fn queue(&self) -> &DeviceMultiQueue { &self.stage.queue }
+
+  fn kernel(&self, vp: KVectorParams<Self>) {
+    let (src, offsets, dst) = unsafe { (&*self.src, &*self.offsets, &mut *self.dst) };
+    let segment = vp.wg_id().x as usize;
+    let (start, end) = (offsets[segment] as usize, offsets[segment + 1] as usize);
+
+    let mut acc = O::identity();
+    let mut i = start + vp.linear_wi() as usize;
+    while i < end {
+      acc = O::op(acc, src[i]);
+      i += WG as usize;
+    }
+
+    let total = collective::reduce(&vp, acc, O::identity(), O::op);
+    if vp.linear_wi() == 0 {
+      dst[segment] = total;
+    }
+  }
+}
+
+/// A single workgroup exclusive scan of the tile totals, in place.
+struct ScanPartialsKernel<T, O> {
+  partials: *mut [T],
+  stage: Stage,
+  _o: PhantomData<O>,
+}
+impl<T, O> Kernel for ScanPartialsKernel<T, O>
+  where T: CrossLane,
+        O: Monoid<T>,
+{
+  type Grid = Grid;
+  const WORKGROUP: Dim1D<RangeTo<u16>> = WORKGROUP;
+
+  type Queue = DeviceMultiQueue;
+  fn queue(&self) -> &DeviceMultiQueue { &self.stage.queue }
+
+  fn kernel(&self, vp: KVectorParams<Self>) {
+    let partials = unsafe { &mut *self.partials };
+    let mut carry = O::identity();
+    let mut base = 0;
+    while base < partials.len() {
+      let i = base + vp.linear_wi() as usize;
+      let v = if i < partials.len() { partials[i] } else { O::identity() };
+      let prefix = collective::exclusive_scan(&vp, v, O::identity(), O::op);
+      let total = collective::reduce(&vp, v, O::identity(), O::op);
+      if i < partials.len() {
+        partials[i] = O::op(carry, prefix);
+      }
+      carry = O::op(carry, total);
+      base += WG as usize;
+    }
+  }
+}
+
+/// Scan each tile, starting from that tile's scanned partial.
+struct ScanTilesKernel<T, O> {
+  src: *const [T],
+  dst: *mut [T],
+  partials: *const [T],
+  inclusive: bool,
+  stage: Stage,
+  _o: PhantomData<O>,
+}
+impl<T, O> Kernel for ScanTilesKernel<T, O>
+  where T: CrossLane,
+        O: Monoid<T>,
+{
+  type Grid = Grid;
+  const WORKGROUP: Dim1D<RangeTo<u16>> = WORKGROUP;
+
+  type Queue = DeviceMultiQueue;
+  fn queue(&self) -> &DeviceMultiQueue { &self.stage.queue }
+
+  fn kernel(&self, vp: KVectorParams<Self>) {
+    let (src, dst, partials) = unsafe { (&*self.src, &mut *self.dst, &*self.partials) };
+    let i = vp.gl_id() as usize;
+    let v = if i < src.len() { src[i] } else { O::identity() };
+    let s = if self.inclusive {
+      collective::inclusive_scan(&vp, v, O::identity(), O::op)
+    } else {
+      collective::exclusive_scan(&vp, v, O::identity(), O::op)
+    };
+    if i < dst.len() {
+      dst[i] = O::op(partials[vp.wg_id().x as usize], s);
+    }
+  }
+}
+
+/// Count each digit in each tile. `counts` is digit major, so that its exclusive scan gives
+/// the output offset of every (digit, tile) pair.
+struct RadixCountKernel<K> {
+  keys: *const [K],
+  counts: *mut [u32],
+  shift: u32,
+  stage: Stage,
+}
+impl<K> Kernel for RadixCountKernel<K>
+  where K: RadixKey,
+{
+  type Grid = Grid;
+  const WORKGROUP: Dim1D<RangeTo<u16>> = WORKGROUP;
+
+  type Queue = DeviceMultiQueue;
+  fn queue(&self) -> &DeviceMultiQueue { &self.stage.queue }
+
+  fn kernel(&self, vp: KVectorParams<Self>) {
+    let (keys, counts) = unsafe { (&*self.keys, &mut *self.counts) };
+    let tiles = counts.len() / DIGITS;
+    let tile = vp.wg_id().x as usize;
+    let i = vp.gl_id() as usize;
+    let d = if i < keys.len() { Some(digit(keys[i], self.shift)) } else { None };
+    for bucket in 0..DIGITS {
+      let c = collective::reduce(&vp, (d == Some(bucket)) as u32, 0, Sum::op);
+      if vp.linear_wi() == 0 {
+        counts[bucket * tiles + tile] = c;
+      }
+    }
+  }
+}
+
+/// Stably move every key (and value) to its position for this digit.
+struct RadixScatterKernel<K, V> {
+  keys_in: *const [K],
+  keys_out: *mut [K],
+  values: Option<(*const [V], *mut [V])>,
+  offsets: *const [u32],
+  shift: u32,
+  stage: Stage,
+}
+impl<K, V> Kernel for RadixScatterKernel<K, V>
+  where K: RadixKey,
+        V: Copy,
+{
+  type Grid = Grid;
+  const WORKGROUP: Dim1D<RangeTo<u16>> = WORKGROUP;
+
+  type Queue = DeviceMultiQueue;
+  fn queue(&self) -> &DeviceMultiQueue { &self.stage.queue }
+
+  fn kernel(&self, vp: KVectorParams<Self>) {
+    let (keys_in, keys_out, offsets) = unsafe {
+      (&*self.keys_in, &mut *self.keys_out, &*self.offsets)
+    };
+    let tiles = offsets.len() / DIGITS;
+    let tile = vp.wg_id().x as usize;
+    let i = vp.gl_id() as usize;
+    let d = if i < keys_in.len() { Some(digit(keys_in[i], self.shift)) } else { None };
+    for bucket in 0..DIGITS {
+      let hit = d == Some(bucket);
+      let rank = collective::exclusive_scan(&vp, hit as u32, 0, Sum::op);
+      if hit {
+        let to = (offsets[bucket * tiles + tile] + rank) as usize;
+        keys_out[to] = keys_in[i];
+        if let Some((values_in, values_out)) = self.values {
+          unsafe {
+            (&mut *values_out)[to] = (&*values_in)[i];
+          }
+        }
+      }
+    }
+  }
+}
+
+struct FillKernel<T> {
+  dst: *mut [T],
+  value: T,
+  stage: Stage,
+}
+impl<T> Kernel for FillKernel<T>
+  where T: Copy,
+{
+  type Grid = Grid;
+  const WORKGROUP: Dim1D<RangeTo<u16>> = WORKGROUP;
+
+  type Queue = DeviceMultiQueue;
+  fn queue(&self) -> &DeviceMultiQueue { &self.stage.queue }
+
+  fn kernel(&self, vp: KVectorParams<Self>) {
+    let dst = unsafe { &mut *self.dst };
+    let i = vp.gl_id() as usize;
+    if i < dst.len() {
+      dst[i] = self.value;
+    }
+  }
+}
+
+struct HistogramKernel<T> {
+  samples: *const [T],
+  bins: *const [AtomicU32],
+  lower: T,
+  upper: T,
+  stage: Stage,
+}
+impl<T> Kernel for HistogramKernel<T>
+  where T: AsPrimitive<f64>,
+{
+  type Grid = Grid;
+  const WORKGROUP: Dim1D<RangeTo<u16>> = WORKGROUP;
+
+  type Queue = DeviceMultiQueue;
+  fn queue(&self) -> &DeviceMultiQueue { &self.stage.queue }
+
+  fn kernel(&self, vp: KVectorParams<Self>) {
+    let (samples, bins) = unsafe { (&*self.samples, &*self.bins) };
+    let i = vp.gl_id() as usize;
+    if i >= samples.len() { return; }
+
+    if let Some(bin) = even_bin(samples[i], self.lower, self.upper, bins.len()) {
+      bins[bin].fetch_add(1, Ordering::Relaxed);
+    }
+  }
+}
+
+struct SelectFlagsKernel<T, P> {
+  src: *const [T],
+  flags: *mut [u32],
+  stage: Stage,
+  _p: PhantomData<P>,
+}
+impl<T, P> Kernel for SelectFlagsKernel<T, P>
+  where P: Predicate<T>,
+{
+  type Grid = Grid;
+  const WORKGROUP: Dim1D<RangeTo<u16>> = WORKGROUP;
+
+  type Queue = DeviceMultiQueue;
+  fn queue(&self) -> &DeviceMultiQueue { &self.stage.queue }
+
+  fn kernel(&self, vp: KVectorParams<Self>) {
+    let (src, flags) = unsafe { (&*self.src, &mut *self.flags) };
+    let i = vp.gl_id() as usize;
+    if i < src.len() {
+      flags[i] = P::test(&src[i]) as u32;
+    }
+  }
+}
+
+struct SelectScatterKernel<T> {
+  src: *const [T],
+  flags: *const [u32],
+  positions: *const [u32],
+  dst: *mut [T],
+  num_selected: *mut [u32],
+  stage: Stage,
+}
+impl<T> Kernel for SelectScatterKernel<T>
+  where T: Copy,
+{
+  type Grid = Grid;
+  const WORKGROUP: Dim1D<RangeTo<u16>> = WORKGROUP;
+
+  type Queue = DeviceMultiQueue;
+  fn queue(&self) -> &DeviceMultiQueue { &self.stage.queue }
+
+  fn kernel(&self, vp: KVectorParams<Self>) {
+    let (src, flags, positions, dst, num_selected) = unsafe {
+      (&*self.src, &*self.flags, &*self.positions, &mut *self.dst, &mut *self.num_selected)
+    };
+    let i = vp.gl_id() as usize;
+    if src.len() == 0 {
+      if i == 0 { num_selected[0] = 0; }
+      return;
+    }
+    if i >= src.len() { return; }
+
+    if flags[i] != 0 {
+      dst[positions[i] as usize] = src[i];
+    }
+    if i == src.len() - 1 {
+      num_selected[0] = positions[i] + flags[i];
+    }
+  }
+}
+
+stage_args!(TileReduceKernel<T, O>, SegmentedReduceKernel<T, O>, ScanPartialsKernel<T, O>,
+            ScanTilesKernel<T, O>, RadixCountKernel<K>, RadixScatterKernel<K, V>,
+            FillKernel<T>, HistogramKernel<T>, SelectFlagsKernel<T, P>,
+            SelectScatterKernel<T>, );
+
+/// The in flight stages of a primitive. Dropping this waits for every stage to complete.
+#[must_use]
+pub struct Pending<'a> {
+  /// Declared first so these are dropped, and waited on, before the scratch buffers are freed.
+  stages: Vec<Box<dyn Any>>,
+  scratch: Vec<Box<dyn Any>>,
+  last: Option<Arc<GlobalSignal>>,
+  _borrows: PhantomData<&'a mut ()>,
+}
+impl<'a> Pending<'a> {
+  fn new(dep: Option<Arc<GlobalSignal>>) -> Self {
+    Pending {
+      stages: Vec::new(),
+      scratch: Vec::new(),
+      last: dep,
+      _borrows: PhantomData,
+    }
+  }
+
+  /// The signal of the final stage. If nothing needed to be launched, this is the dep passed
+  /// in, if any.
+  pub fn signal(&self) -> Option<&Arc<GlobalSignal>> {
+    self.last.as_ref()
+  }
+
+  pub fn wait(self) -> Result<(), Value> {
+    if let Some(ref last) = self.last {
+      last.wait_for_zero(false)?;
+    }
+    Ok(())
+  }
+}
+
+fn check_len(buffer: &'static str, expected: usize, actual: usize) -> Result<(), Error> {
+  if expected != actual {
+    Err(Error::PrimitiveBufferLength { buffer, expected, actual, })
+  } else {
+    Ok(())
+  }
+}
+
+/// Launches the primitives' kernels onto a single queue.
+pub struct Primitives {
+  device: Arc<HsaAmdGpuAccel>,
+  queue: Arc<DeviceMultiQueue>,
+  args_pool: Arc<ArgsPool>,
+}
+impl Primitives {
+  pub fn new(device: &Arc<HsaAmdGpuAccel>) -> Result<Self, Error> {
+    let queue = device.create_multi_queue(None)?;
+    // a ring, so args are freed as each stage completes; `Primitives` is long lived.
+    let args_pool = ArgsPool::new_ring(device, ARGS_POOL_SIZE)?;
+    Ok(Self::with_queue(device, Arc::new(queue), Arc::new(args_pool)))
+  }
+  pub fn with_queue(device: &Arc<HsaAmdGpuAccel>,
+                    queue: Arc<DeviceMultiQueue>,
+                    args_pool: Arc<ArgsPool>)
+    -> Self
+  {
+    Primitives {
+      device: device.clone(),
+      queue,
+      args_pool,
+    }
+  }
+
+  pub fn device(&self) -> &Arc<HsaAmdGpuAccel> { &self.device }
+  pub fn queue(&self) -> &Arc<DeviceMultiQueue> { &self.queue }
+
+  fn launch<K, F>(&self, p: &mut Pending, tiles: usize, f: F) -> Result<(), Error>
+    where K: Kernel<Grid = Grid, Queue = DeviceMultiQueue, CompletionSignal = Arc<GlobalSignal>>,
+          K: 'static,
+          F: FnOnce(Stage) -> K,
+  {
+    let x = tiles.checked_mul(WG as usize)
+      .and_then(|x| u32::try_from(x).ok() )
+      .ok_or(Error::Overflow)?;
+    let grid = Dim1D { x: ..x, };
+
+    let completion = Arc::new(GlobalSignal::new(1)?);
+    let args = f(Stage {
+      queue: self.queue.clone(),
+      dep: p.last.take(),
+      completion: completion.clone(),
+    });
+
+    let mut invoc = K::module(&self.device)
+      .into_invoc(self.args_pool.clone());
+    let c = unsafe { invoc.unchecked_call_async(&grid, args)? };
+    p.stages.push(Box::new(c));
+    p.last = Some(completion);
+    Ok(())
+  }
+  /// Allocate an uninitialized device local buffer which lives as long as `p`.
+  fn scratch<T>(&self, p: &mut Pending, len: usize) -> Result<*mut [T], Error>
+    where T: 'static,
+  {
+    let b = unsafe { self.device.alloc_device_local_slice::<T>(len)? };
+    let ptr = b.as_ptr();
+    p.scratch.push(Box::new(b));
+    Ok(ptr)
+  }
+
+  fn scan_into<T, O>(&self, p: &mut Pending, src: *const [T], dst: *mut [T], inclusive: bool)
+    -> Result<(), Error>
+    where T: CrossLane + 'static,
+          O: Monoid<T>,
+  {
+    let tiles = tiles(unsafe { (&*src).len() });
+    let partials = self.scratch::<T>(p, tiles)?;
+    self.launch(p, tiles, |stage| TileReduceKernel::<T, O> {
+      src,
+      dst: partials,
+      stage,
+      _o: PhantomData,
+    })?;
+    self.launch(p, 1, |stage| ScanPartialsKernel::<T, O> {
+      partials,
+      stage,
+      _o: PhantomData,
+    })?;
+    self.launch(p, tiles, |stage| ScanTilesKernel::<T, O> {
+      src,
+      dst,
+      partials,
+      inclusive,
+      stage,
+      _o: PhantomData,
+    })
+  }
+
+  /// Reduce `src` into `dst[0]`.
+  ///
+  /// # Safety
+  ///
+  /// The returned `Pending` must not be leaked: the kernels could otherwise outlive the borrows
+  /// of the buffers. The buffers must be accessible by this device. The same applies to every
+  /// other primitive.
+  ///
+  /// Buffers of the wrong length are reported with `Error::PrimitiveBufferLength`, here and
+  /// in every other primitive.
+  pub unsafe fn reduce<'a, T, O>(&self, src: &'a RawPoolBox<[T]>, dst: &'a mut RawPoolBox<[T]>,
+                                 dep: Option<Arc<GlobalSignal>>)
+    -> Result<Pending<'a>, Error>
+    where T: CrossLane + 'static,
+          O: Monoid<T>,
+  {
+    check_len("dst", 1, dst.len())?;
+
+    let mut p = Pending::new(dep);
+    let mut src: *const [T] = src.as_ptr();
+    loop {
+      let tiles = tiles((&*src).len());
+      let dst = if tiles == 1 {
+        dst.as_ptr()
+      } else {
+        self.scratch::<T>(&mut p, tiles)?
+      };
+      self.launch(&mut p, tiles, |stage| TileReduceKernel::<T, O> {
+        src,
+        dst,
+        stage,
+        _o: PhantomData,
+      })?;
+      if tiles == 1 { break; }
+      src = dst;
+    }
+    Ok(p)
+  }
+  /// Reduce each segment `offsets[i]..offsets[i + 1]` of `src` into `dst[i]`. Empty segments
+  /// produce `O::identity()`.
+  ///
+  /// # Safety
+  ///
+  /// See `reduce`. Additionally, the offsets must be non-decreasing and in bounds of `src`;
+  /// they are not checked on the device.
+  pub unsafe fn segmented_reduce<'a, T, O>(&self, src: &'a RawPoolBox<[T]>,
+                                           offsets: &'a RawPoolBox<[u32]>,
+                                           dst: &'a mut RawPoolBox<[T]>,
+                                           dep: Option<Arc<GlobalSignal>>)
+    -> Result<Pending<'a>, Error>
+    where T: CrossLane + 'static,
+          O: Monoid<T>,
+  {
+    check_len("offsets", dst.len() + 1, offsets.len())?;
+
+    let mut p = Pending::new(dep);
+    if dst.len() == 0 { return Ok(p); }
+
+    let (src, offsets, dst) = (src.as_ptr(), offsets.as_ptr(), dst.as_ptr());
+    self.launch(&mut p, (&*dst).len(), |stage| SegmentedReduceKernel::<T, O> {
+      src,
+      offsets,
+      dst,
+      stage,
+      _o: PhantomData,
+    })?;
+    Ok(p)
+  }
+  /// `dst[i] = src[0] op .. op src[i]`. `src` and `dst` may be the same buffer.
+  ///
+  /// # Safety
+  ///
+  /// See `reduce`.
+  pub unsafe fn inclusive_scan<'a, T, O>(&self, src: &'a RawPoolBox<[T]>,
+                                         dst: &'a mut RawPoolBox<[T]>,
+                                         dep: Option<Arc<GlobalSignal>>)
+    -> Result<Pending<'a>, Error>
+    where T: CrossLane + 'static,
+          O: Monoid<T>,
+  {
+    check_len("dst", src.len(), dst.len())?;
+
+    let mut p = Pending::new(dep);
+    self.scan_into::<T, O>(&mut p, src.as_ptr(), dst.as_ptr(), true)?;
+    Ok(p)
+  }
+  /// `dst[i] = identity op src[0] op .. op src[i - 1]`.
+  ///
+  /// # Safety
+  ///
+  /// See `reduce`.
+  pub unsafe fn exclusive_scan<'a, T, O>(&self, src: &'a RawPoolBox<[T]>,
+                                         dst: &'a mut RawPoolBox<[T]>,
+                                         dep: Option<Arc<GlobalSignal>>)
+    -> Result<Pending<'a>, Error>
+    where T: CrossLane + 'static,
+          O: Monoid<T>,
+  {
+    check_len("dst", src.len(), dst.len())?;
+
+    let mut p = Pending::new(dep);
+    self.scan_into::<T, O>(&mut p, src.as_ptr(), dst.as_ptr(), false)?;
+    Ok(p)
+  }
+
+  unsafe fn radix_sort<'a, K, V>(&self, keys: *mut [K], values: Option<*mut [V]>,
+                                 dep: Option<Arc<GlobalSignal>>)
+    -> Result<Pending<'a>, Error>
+    where K: RadixKey,
+          V: Copy + 'static,
+  {
+    let mut p = Pending::new(dep);
+    let len = (&*keys).len();
+    if len < 2 { return Ok(p); }
+
+    let tiles = tiles(len);
+    let counts = self.scratch::<u32>(&mut p, DIGITS * tiles)?;
+    let offsets = self.scratch::<u32>(&mut p, DIGITS * tiles)?;
+    let mut keys = (keys, self.scratch::<K>(&mut p, len)?);
+    let mut values = match values {
+      Some(values) => Some((values, self.scratch::<V>(&mut p, len)?)),
+      None => None,
+    };
+
+    // `BITS` is a multiple of eight, so there are an even number of passes and the result
+    // ends up back in the caller's buffers.
+    for pass in 0..K::BITS / RADIX_BITS {
+      let shift = pass * RADIX_BITS;
+      self.launch(&mut p, tiles, |stage| RadixCountKernel::<K> {
+        keys: keys.0,
+        counts,
+        shift,
+        stage,
+      })?;
+      self.scan_into::<u32, Sum>(&mut p, counts, offsets, false)?;
+      self.launch(&mut p, tiles, |stage| RadixScatterKernel::<K, V> {
+        keys_in: keys.0,
+        keys_out: keys.1,
+        values: values.map(|(i, o)| (i as *const [V], o) ),
+        offsets,
+        shift,
+        stage,
+      })?;
+
+      keys = (keys.1, keys.0);
+      values = values.map(|(i, o)| (o, i) );
+    }
+
+    Ok(p)
+  }
+  /// Stably sort `keys` in place by `RadixKey::radix_bits`.
+  ///
+  /// # Safety
+  ///
+  /// See `reduce`.
+  pub unsafe fn radix_sort_keys<'a, K>(&self, keys: &'a mut RawPoolBox<[K]>,
+                                       dep: Option<Arc<GlobalSignal>>)
+    -> Result<Pending<'a>, Error>
+    where K: RadixKey,
+  {
+    self.radix_sort::<K, ()>(keys.as_ptr(), None, dep)
+  }
+  /// Stably sort `keys` in place, moving `values` along with them.
+  ///
+  /// # Safety
+  ///
+  /// See `reduce`.
+  pub unsafe fn radix_sort_pairs<'a, K, V>(&self, keys: &'a mut RawPoolBox<[K]>,
+                                           values: &'a mut RawPoolBox<[V]>,
+                                           dep: Option<Arc<GlobalSignal>>)
+    -> Result<Pending<'a>, Error>
+    where K: RadixKey,
+          V: Copy + 'static,
+  {
+    check_len("values", keys.len(), values.len())?;
+    self.radix_sort::<K, V>(keys.as_ptr(), Some(values.as_ptr()), dep)
+  }
+  /// Count the samples falling into each of `bins.len()` equal width bins spanning
+  /// `lower..upper`; see `even_bin`. `bins` is overwritten.
+  ///
+  /// # Safety
+  ///
+  /// See `reduce`.
+  pub unsafe fn histogram_even<'a, T>(&self, samples: &'a RawPoolBox<[T]>, lower: T, upper: T,
+                                      bins: &'a mut RawPoolBox<[u32]>,
+                                      dep: Option<Arc<GlobalSignal>>)
+    -> Result<Pending<'a>, Error>
+    where T: AsPrimitive<f64>,
+  {
+    let mut p = Pending::new(dep);
+    if bins.len() == 0 { return Ok(p); }
+
+    let (samples, bins) = (samples.as_ptr(), bins.as_ptr());
+    self.launch(&mut p, tiles((&*bins).len()), |stage| FillKernel {
+      dst: bins,
+      value: 0u32,
+      stage,
+    })?;
+    self.launch(&mut p, tiles((&*samples).len()), |stage| HistogramKernel {
+      samples,
+      bins: bins as *const [AtomicU32],
+      lower,
+      upper,
+      stage,
+    })?;
+    Ok(p)
+  }
+  /// Copy the elements of `src` which pass `P` to the start of `dst`, in order, and write how
+  /// many there were to `num_selected[0]`.
+  ///
+  /// # Safety
+  ///
+  /// See `reduce`.
+  pub unsafe fn select_if<'a, T, P>(&self, src: &'a RawPoolBox<[T]>,
+                                    dst: &'a mut RawPoolBox<[T]>,
+                                    num_selected: &'a mut RawPoolBox<[u32]>,
+                                    dep: Option<Arc<GlobalSignal>>)
+    -> Result<Pending<'a>, Error>
+    where T: Copy + 'static,
+          P: Predicate<T>,
+  {
+    if dst.len() < src.len() {
+      return Err(Error::PrimitiveBufferLength {
+        buffer: "dst",
+        expected: src.len(),
+        actual: dst.len(),
+      });
+    }
+    check_len("num_selected", 1, num_selected.len())?;
+
+    let mut p = Pending::new(dep);
+    let len = src.len();
+    let (src, dst, num_selected) = (src.as_ptr(), dst.as_ptr(), num_selected.as_ptr());
+    let flags = self.scratch::<u32>(&mut p, max(len, 1))?;
+    let positions = self.scratch::<u32>(&mut p, max(len, 1))?;
+    let (flags, positions) = (&mut (&mut *flags)[..len] as *mut [u32],
+                              &mut (&mut *positions)[..len] as *mut [u32]);
+    self.launch(&mut p, tiles(len), |stage| SelectFlagsKernel::<T, P> {
+      src,
+      flags,
+      stage,
+      _p: PhantomData,
+    })?;
+    self.scan_into::<u32, Sum>(&mut p, flags, positions, false)?;
+    self.launch(&mut p, tiles(len), |stage| SelectScatterKernel {
+      src,
+      flags,
+      positions,
+      dst,
+      num_selected,
+      stage,
+    })?;
+    Ok(p)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::utils::test::*;
+
+  const LENS: &[usize] = &[1, 255, 256, 257, 1000, 70000];
+
+  fn upload<T>(dev: &HsaAmdGpuAccel, data: &[T]) -> RawPoolBox<[T]>
+    where T: Copy,
+  {
+    unsafe {
+      let mut b = RawPoolBox::new_uninit_slice(dev.host_pool().clone(), data.len())
+        .unwrap();
+      b.as_pool_ptr().grant_agent_access(dev.agent()).unwrap();
+      b.as_mut().copy_from_slice(data);
+      b
+    }
+  }
+  fn prims() -> Primitives {
+    let dev = device();
+    Primitives::with_queue(&dev, Arc::new(dev.create_multi_queue(None).unwrap()), args_pool())
+  }
+  fn data(len: usize) -> Vec<i32> {
+    (0..len as i32).map(|i| (i * 7919) % 1013 - 500 ).collect()
+  }
+
+  #[test]
+  fn reduce_and_scan() {
+    let prims = prims();
+    let dev = prims.device().clone();
+    for &len in LENS {
+      let values = data(len);
+      let src = upload(&dev, &values);
+      let mut sum = upload(&dev, &[0]);
+      let mut inc = upload(&dev, &values);
+      let mut exc = upload(&dev, &values);
+      unsafe {
+        let p = prims.reduce::<i32, Sum>(&src, &mut sum, None).unwrap();
+        let p = prims.inclusive_scan::<i32, Sum>(&src, &mut inc, p.signal().cloned())
+          .unwrap();
+        prims.exclusive_scan::<i32, Sum>(&src, &mut exc, p.signal().cloned())
+          .unwrap()
+          .wait()
+          .unwrap();
+        drop(p);
+
+        assert_eq!(sum.as_ref()[0], host::reduce::<i32, Sum>(&values), "len = {}", len);
+        let mut expected = vec![0; len];
+        host::inclusive_scan::<i32, Sum>(&values, &mut expected);
+        assert_eq!(inc.as_ref(), &expected[..], "len = {}", len);
+        host::exclusive_scan::<i32, Sum>(&values, &mut expected);
+        assert_eq!(exc.as_ref(), &expected[..], "len = {}", len);
+      }
+    }
+  }
+  #[test]
+  fn args_pool_reuse() {
+    // well past what the args pool could hold if args were never freed.
+    let prims = Primitives::new(&device()).unwrap();
+    let dev = prims.device().clone();
+    let src = upload(&dev, &[1i32, 2, 3]);
+    let mut dst = upload(&dev, &[0]);
+    for i in 0..ARGS_POOL_SIZE / 64 {
+      unsafe {
+        prims.reduce::<i32, Sum>(&src, &mut dst, None)
+          .unwrap()
+          .wait()
+          .unwrap();
+        assert_eq!(dst.as_ref()[0], 6, "iteration {}", i);
+      }
+    }
+  }
+  #[test]
+  fn buffer_lengths() {
+    struct Odd;
+    impl Predicate<i32> for Odd {
+      fn test(v: &i32) -> bool { *v % 2 != 0 }
+    }
+
+    let prims = prims();
+    let dev = prims.device().clone();
+    let src = upload(&dev, &[1i32, 2, 3]);
+    let mut dst = upload(&dev, &[0, 0]);
+    unsafe {
+      match prims.reduce::<i32, Sum>(&src, &mut dst, None) {
+        Err(Error::PrimitiveBufferLength { buffer: "dst", expected: 1, actual: 2, }) => { },
+        r => panic!("unexpected: {:?}", r.map(|_| () )),
+      }
+      match prims.inclusive_scan::<i32, Sum>(&src, &mut dst, None) {
+        Err(Error::PrimitiveBufferLength { buffer: "dst", expected: 3, actual: 2, }) => { },
+        r => panic!("unexpected: {:?}", r.map(|_| () )),
+      }
+      let mut num_selected = upload(&dev, &[0]);
+      match prims.select_if::<i32, Odd>(&src, &mut dst, &mut num_selected, None) {
+        Err(Error::PrimitiveBufferLength { buffer: "dst", expected: 3, actual: 2, }) => { },
+        r => panic!("unexpected: {:?}", r.map(|_| () )),
+      }
+    }
+  }
+  #[test]
+  fn segmented_reduce() {
+    let prims = prims();
+    let dev = prims.device().clone();
+    let values = data(5000);
+    let offsets = [0u32, 0, 1, 300, 300, 4000, 5000];
+    let src = upload(&dev, &values);
+    let offsets_dev = upload(&dev, &offsets);
+    let mut dst = upload(&dev, &[0; 6]);
+    unsafe {
+      prims.segmented_reduce::<i32, Max>(&src, &offsets_dev, &mut dst, None)
+        .unwrap()
+        .wait()
+        .unwrap();
+
+      let mut expected = [0; 6];
+      host::segmented_reduce::<i32, Max>(&values, &offsets, &mut expected);
+      assert_eq!(dst.as_ref(), &expected[..]);
+    }
+  }
+  #[test]
+  fn radix_sort() {
+    let prims = prims();
+    let dev = prims.device().clone();
+    for &len in LENS {
+      let keys: Vec<f32> = data(len).into_iter().map(|v| v as f32 / 3.0 ).collect();
+      let values: Vec<u32> = (0..len as u32).collect();
+      let mut keys_dev = upload(&dev, &keys);
+      let mut values_dev = upload(&dev, &values);
+      unsafe {
+        prims.radix_sort_pairs(&mut keys_dev, &mut values_dev, None)
+          .unwrap()
+          .wait()
+          .unwrap();
+
+        let (mut keys, mut values) = (keys, values);
+        host::radix_sort_pairs(&mut keys, &mut values);
+        assert_eq!(keys_dev.as_ref(), &keys[..], "len = {}", len);
+        assert_eq!(values_dev.as_ref(), &values[..], "len = {}", len);
+      }
+    }
+  }
+  #[test]
+  fn histogram_and_select() {
+    struct Even;
+    impl Predicate<i32> for Even {
+      fn test(v: &i32) -> bool { *v % 2 == 0 }
+    }
+
+    let prims = prims();
+    let dev = prims.device().clone();
+    for &len in LENS {
+      let values = data(len);
+      let src = upload(&dev, &values);
+      let mut bins = upload(&dev, &[u32::max_value(); 7]);
+      let mut dst = upload(&dev, &vec![0; len]);
+      let mut num_selected = upload(&dev, &[u32::max_value()]);
+      unsafe {
+        let p = prims.histogram_even(&src, -300, 300, &mut bins, None).unwrap();
+        prims.select_if::<i32, Even>(&src, &mut dst, &mut num_selected, p.signal().cloned())
+          .unwrap()
+          .wait()
+          .unwrap();
+        drop(p);
+
+        let mut expected_bins = [0; 7];
+        host::histogram_even(&values, -300, 300, &mut expected_bins);
+        assert_eq!(bins.as_ref(), &expected_bins[..], "len = {}", len);
+
+        let mut expected = vec![0; len];
+        let n = host::select_if::<i32, Even>(&values, &mut expected);
+        assert_eq!(num_selected.as_ref()[0] as usize, n, "len = {}", len);
+        assert_eq!(&dst.as_ref()[..n], &expected[..n], "len = {}", len);
+      }
+    }
+  }
+}
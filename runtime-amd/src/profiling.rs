@@ -0,0 +1,153 @@
+//! Dispatch and async copy timing.
+//!
+//! Call `HsaAmdGpuAccel::enable_profiling` *before* creating any queues; only queues
+//! created afterwards will timestamp their dispatches. Then use
+//! `InvocCompletion::timing` or `H2DMemoryTransfer::timing` after the work completes.
+//! If `TraceRecorder::global()` is recording, completed dispatches are recorded
+//! automatically when their `InvocCompletion` is dropped.
+
+use std::borrow::Cow;
+use std::time::{Duration, Instant, };
+
+use hsa_rt::ApiContext;
+use hsa_rt::ext::profiling::ProfilingTime;
+
+pub use grt_core::trace::{TraceRecorder, TraceSpan, };
+
+use crate::HsaError;
+
+/// GPU start and end times of a dispatch or copy, in host time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct DispatchTiming {
+  pub start: Instant,
+  pub end: Instant,
+}
+impl DispatchTiming {
+  pub fn duration(&self) -> Duration {
+    self.end.saturating_duration_since(self.start)
+  }
+
+  pub fn to_span<N, T>(&self, name: N, category: &'static str, track: T) -> TraceSpan
+    where N: Into<Cow<'static, str>>,
+          T: Into<Cow<'static, str>>,
+  {
+    TraceSpan {
+      name: name.into(),
+      category,
+      track: track.into(),
+      start: self.start,
+      end: self.end,
+      args: vec![],
+    }
+  }
+  /// Record this in the global trace, if it is recording.
+  pub fn record<N, T>(&self, name: N, category: &'static str, track: T)
+    where N: Into<Cow<'static, str>>,
+          T: Into<Cow<'static, str>>,
+  {
+    TraceRecorder::global().record_with(|| self.to_span(name, category, track) );
+  }
+}
+
+/// Maps HSA system timestamps to host `Instant`s, using a single reference point
+/// sampled from both clocks.
+/// XXX the clocks will drift apart over very long runs; recalibrate?
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ClockConverter {
+  ticks: u64,
+  frequency: u64,
+  instant: Instant,
+}
+impl ClockConverter {
+  /// `ticks` and `instant` should have been sampled at the same moment.
+  pub fn new(ticks: u64, frequency: u64, instant: Instant) -> Self {
+    assert_ne!(frequency, 0);
+    ClockConverter {
+      ticks,
+      frequency,
+      instant,
+    }
+  }
+  pub fn calibrate(ctx: &ApiContext) -> Result<Self, HsaError> {
+    let frequency = ctx.timestamp_frequency()?;
+    let before = Instant::now();
+    let ticks = ctx.timestamp()?;
+    let after = Instant::now();
+    // assume the timestamp was read halfway in between:
+    let instant = before + (after - before) / 2;
+    Ok(Self::new(ticks, frequency, instant))
+  }
+
+  pub fn frequency(&self) -> u64 { self.frequency }
+
+  pub fn ticks_to_duration(&self, ticks: u64) -> Duration {
+    let secs = ticks / self.frequency;
+    let rem = ticks % self.frequency;
+    let nanos = (rem as u128 * 1_000_000_000) / self.frequency as u128;
+    Duration::new(secs, nanos as u32)
+  }
+  pub fn to_instant(&self, ticks: u64) -> Instant {
+    if ticks >= self.ticks {
+      self.instant + self.ticks_to_duration(ticks - self.ticks)
+    } else {
+      let d = self.ticks_to_duration(self.ticks - ticks);
+      self.instant.checked_sub(d)
+        .unwrap_or(self.instant)
+    }
+  }
+  pub fn convert(&self, time: ProfilingTime) -> DispatchTiming {
+    DispatchTiming {
+      start: self.to_instant(time.start),
+      end: self.to_instant(time.end),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn ticks_to_duration() {
+    let c = ClockConverter::new(0, 1_000_000_000, Instant::now());
+    assert_eq!(c.ticks_to_duration(1_500_000_000), Duration::from_millis(1500));
+
+    let c = ClockConverter::new(0, 3, Instant::now());
+    assert_eq!(c.ticks_to_duration(4), Duration::new(1, 333_333_333));
+
+    // no overflow for huge tick counts:
+    let c = ClockConverter::new(0, 100_000_000, Instant::now());
+    assert_eq!(c.ticks_to_duration(u64::max_value()),
+               Duration::new(u64::max_value() / 100_000_000, 95_516_150));
+  }
+
+  #[test]
+  fn convert() {
+    let now = Instant::now() + Duration::from_secs(10);
+    let c = ClockConverter::new(1_000_000, 1_000_000, now);
+
+    let t = c.convert(ProfilingTime {
+      start: 1_500_000,
+      end: 2_000_000,
+    });
+    assert_eq!(t.start, now + Duration::from_millis(500));
+    assert_eq!(t.end, now + Duration::from_secs(1));
+    assert_eq!(t.duration(), Duration::from_millis(500));
+
+    // before the reference point:
+    assert_eq!(c.to_instant(250_000), now - Duration::from_millis(750));
+  }
+
+  #[test]
+  fn span() {
+    let now = Instant::now();
+    let t = DispatchTiming {
+      start: now,
+      end: now + Duration::from_micros(3),
+    };
+    let s = t.to_span("k", "dispatch", "gpu");
+    assert_eq!(s.start, t.start);
+    assert_eq!(s.end, t.end);
+    assert_eq!(s.track, "gpu");
+  }
+}
@@ -0,0 +1,289 @@
+//! A pool of device queues, so that kernel args don't each need to create (or
+//! manually share) their own queue.
+//!
+//! Queues are created lazily, up to `QueuePoolConfig::max_queues`. Every queue in a
+//! pool has the same priority and compute unit mask, so partitioning a GPU between,
+//! eg, latency sensitive and batch work is done by creating two pools with disjoint
+//! `CuMask`s (see `CuMask::split`).
+
+use std::cell::RefCell;
+use std::ops::Range;
+use std::sync::{Arc, Weak, };
+use std::sync::atomic::{AtomicUsize, Ordering, };
+
+use parking_lot::RwLock;
+
+use serde::{Deserialize, Serialize, };
+
+pub use hsa_rt::ext::queue::QueuePriority;
+
+use crate::{HsaAmdGpuAccel, Error, };
+use crate::module::DeviceMultiQueue;
+
+/// A queue from a `QueuePool`.
+pub type PooledQueue = Arc<DeviceMultiQueue>;
+
+/// A set of compute units. CU `i` is bit `i % 32` of word `i / 32`, which is the
+/// layout `hsa_amd_queue_cu_set_mask` expects.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct CuMask {
+  cu_count: u32,
+  words: Vec<u32>,
+}
+impl CuMask {
+  fn words_for(cu_count: u32) -> usize {
+    ((cu_count + 31) / 32) as usize
+  }
+
+  /// No compute units set.
+  pub fn none(cu_count: u32) -> Self {
+    CuMask {
+      cu_count,
+      words: vec![0; Self::words_for(cu_count)],
+    }
+  }
+  pub fn all(cu_count: u32) -> Self {
+    Self::from_range(cu_count, 0..cu_count)
+  }
+  /// Panics if `range` extends past `cu_count`.
+  pub fn from_range(cu_count: u32, range: Range<u32>) -> Self {
+    assert!(range.end <= cu_count, "CU range {:?} out of bounds ({} CUs)",
+            range, cu_count);
+    let mut mask = Self::none(cu_count);
+    for cu in range {
+      mask.set(cu);
+    }
+    mask
+  }
+  /// Split `cu_count` compute units into `parts` contiguous masks of (nearly) equal
+  /// size. Leftover CUs go to the first masks.
+  pub fn split(cu_count: u32, parts: u32) -> Vec<Self> {
+    assert_ne!(parts, 0);
+    assert!(parts <= cu_count, "can't split {} CUs into {} parts", cu_count, parts);
+    let base = cu_count / parts;
+    let extra = cu_count % parts;
+    let mut start = 0;
+    (0..parts)
+      .map(|i| {
+        let len = base + if i < extra { 1 } else { 0 };
+        let mask = Self::from_range(cu_count, start..start + len);
+        start += len;
+        mask
+      })
+      .collect()
+  }
+
+  pub fn cu_count(&self) -> u32 { self.cu_count }
+
+  pub fn set(&mut self, cu: u32) {
+    assert!(cu < self.cu_count);
+    self.words[(cu / 32) as usize] |= 1 << (cu % 32);
+  }
+  pub fn unset(&mut self, cu: u32) {
+    assert!(cu < self.cu_count);
+    self.words[(cu / 32) as usize] &= !(1 << (cu % 32));
+  }
+  pub fn get(&self, cu: u32) -> bool {
+    cu < self.cu_count && self.words[(cu / 32) as usize] & (1 << (cu % 32)) != 0
+  }
+  /// The number of compute units set.
+  pub fn len(&self) -> u32 {
+    self.words.iter().map(|w| w.count_ones() ).sum()
+  }
+  pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+  pub fn as_words(&self) -> &[u32] { &self.words }
+
+  /// Is every CU set in this mask less than `cu_count`?
+  pub fn fits(&self, cu_count: u32) -> bool {
+    (cu_count..self.cu_count).all(|cu| !self.get(cu) )
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum QueueAssignment {
+  /// Each thread is assigned a queue the first time it asks, and keeps getting
+  /// that queue. Threads share queues only once there are more than `max_queues`.
+  PerThread,
+  /// Every request gets the next queue in turn.
+  RoundRobin,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct QueuePoolConfig {
+  pub max_queues: usize,
+  /// Minimum queue size, in packets. `None` uses the same default as
+  /// `HsaAmdGpuAccel::create_multi_queue`.
+  pub min_size: Option<u32>,
+  /// Private and group segment sizes, in bytes. Both must be set to have any
+  /// effect.
+  pub private_size: Option<u32>,
+  pub group_size: Option<u32>,
+  pub priority: QueuePriority,
+  /// `None` lets dispatches use every compute unit.
+  pub cu_mask: Option<CuMask>,
+  pub assignment: QueueAssignment,
+}
+impl Default for QueuePoolConfig {
+  fn default() -> Self {
+    QueuePoolConfig {
+      max_queues: 4,
+      min_size: None,
+      private_size: None,
+      group_size: None,
+      priority: QueuePriority::Normal,
+      cu_mask: None,
+      assignment: QueueAssignment::PerThread,
+    }
+  }
+}
+
+thread_local! {
+  /// The queue index this thread was assigned by each live `PerThread` pool. Freed when
+  /// the thread exits; entries for dropped pools are removed on the next lookup.
+  static THREAD_QUEUES: RefCell<Vec<(Weak<()>, usize)>> = RefCell::new(Vec::new());
+}
+
+pub struct QueuePool {
+  device: Arc<HsaAmdGpuAccel>,
+  config: QueuePoolConfig,
+  queues: RwLock<Vec<PooledQueue>>,
+  next: AtomicUsize,
+  /// Identifies this pool in `THREAD_QUEUES`.
+  token: Arc<()>,
+}
+impl QueuePool {
+  pub fn new(device: &Arc<HsaAmdGpuAccel>, config: QueuePoolConfig)
+    -> Result<Self, Error>
+  {
+    if config.max_queues == 0 {
+      return Err(Error::InvalidQueuePoolConfig("`max_queues` must be non-zero"));
+    }
+    if let Some(ref mask) = config.cu_mask {
+      if mask.is_empty() { return Err(Error::EmptyCuMask); }
+      let cu_count = device.agent().compute_unit_count()?;
+      if !mask.fits(cu_count) {
+        return Err(Error::CuMaskOutOfRange { cu_count, });
+      }
+    }
+
+    Ok(QueuePool {
+      device: device.clone(),
+      config,
+      queues: RwLock::new(Vec::new()),
+      next: AtomicUsize::new(0),
+      token: Arc::new(()),
+    })
+  }
+
+  pub fn device(&self) -> &Arc<HsaAmdGpuAccel> { &self.device }
+  pub fn config(&self) -> &QueuePoolConfig { &self.config }
+  /// The number of queues created so far.
+  pub fn len(&self) -> usize { self.queues.read().len() }
+
+  /// Get a queue, according to `QueuePoolConfig::assignment`.
+  pub fn get(&self) -> Result<PooledQueue, Error> {
+    let idx = match self.config.assignment {
+      QueueAssignment::RoundRobin => self.next_idx(),
+      QueueAssignment::PerThread => self.thread_idx(),
+    };
+    self.queue(idx)
+  }
+
+  fn next_idx(&self) -> usize {
+    self.next.fetch_add(1, Ordering::Relaxed) % self.config.max_queues
+  }
+  fn thread_idx(&self) -> usize {
+    THREAD_QUEUES.with(|slots| {
+      let mut slots = slots.borrow_mut();
+      slots.retain(|&(ref pool, _)| pool.strong_count() != 0 );
+      let token = Arc::downgrade(&self.token);
+      if let Some(&(_, idx)) = slots.iter().find(|&&(ref pool, _)| pool.ptr_eq(&token) ) {
+        return idx;
+      }
+      let idx = self.next_idx();
+      slots.push((token, idx));
+      idx
+    })
+  }
+
+  fn queue(&self, idx: usize) -> Result<PooledQueue, Error> {
+    if let Some(q) = self.queues.read().get(idx) {
+      return Ok(q.clone());
+    }
+
+    let mut queues = self.queues.write();
+    while queues.len() <= idx {
+      let q = self.create_queue()?;
+      queues.push(Arc::new(q));
+    }
+    Ok(queues[idx].clone())
+  }
+
+  fn create_queue(&self) -> Result<DeviceMultiQueue, Error> {
+    let c = &self.config;
+    let q = match (c.private_size, c.group_size) {
+      (Some(private), Some(group)) => {
+        self.device.create_multi_queue2(c.min_size, private, group)?
+      },
+      _ => self.device.create_multi_queue(c.min_size)?,
+    };
+    if c.priority != QueuePriority::Normal {
+      q.set_priority(c.priority)?;
+    }
+    if let Some(ref mask) = c.cu_mask {
+      q.set_cu_mask(mask.as_words())?;
+    }
+    Ok(q)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn cu_mask() {
+    let mut m = CuMask::none(40);
+    assert_eq!(m.as_words(), &[0, 0]);
+    assert!(m.is_empty());
+    m.set(0);
+    m.set(33);
+    assert_eq!(m.as_words(), &[1, 2]);
+    assert!(m.get(33));
+    assert!(!m.get(32));
+    assert!(!m.get(100));
+    m.unset(0);
+    assert_eq!(m.len(), 1);
+
+    assert_eq!(CuMask::all(40).as_words(), &[!0, 0xff]);
+    assert_eq!(CuMask::all(32).as_words(), &[!0]);
+    assert_eq!(CuMask::from_range(64, 30..34).as_words(), &[0xc000_0000, 0b11]);
+
+    assert!(CuMask::all(40).fits(40));
+    assert!(CuMask::from_range(64, 0..40).fits(40));
+    assert!(!CuMask::all(64).fits(40));
+  }
+
+  #[test]
+  fn split() {
+    let parts = CuMask::split(10, 3);
+    assert_eq!(parts.len(), 3);
+    assert_eq!(parts[0].as_words(), &[0b0000_0000_1111]);
+    assert_eq!(parts[1].as_words(), &[0b0000_0111_0000]);
+    assert_eq!(parts[2].as_words(), &[0b0011_1000_0000]);
+
+    // disjoint and covering:
+    let parts = CuMask::split(60, 7);
+    let mut all = CuMask::none(60);
+    for p in parts.iter() {
+      for cu in 0..60 {
+        if p.get(cu) {
+          assert!(!all.get(cu));
+          all.set(cu);
+        }
+      }
+    }
+    assert_eq!(all, CuMask::all(60));
+  }
+}
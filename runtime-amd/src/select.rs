@@ -0,0 +1,79 @@
+//! Selecting HSA GPUs with `grt_core::select`, as backend `hsa`.
+//!
+//! Devices are indexed like `HsaAmdGpuAccel::nth_device`, and have these properties:
+//! `arch` (eg `gfx90a`), `vendor` and `compute_units`.
+//!
+//! The enumerator is registered when this crate is loaded, and again when the first
+//! `HsaAmdGpuAccel` is created.
+
+use std::sync::Once;
+
+use grt_core::register_enumerator_on_load;
+use grt_core::select::{DeviceEnumerator, DeviceInfo, register_enumerator, };
+
+use super::*;
+
+register_enumerator_on_load!(HsaDeviceEnumerator);
+
+pub struct HsaDeviceEnumerator;
+impl HsaDeviceEnumerator {
+  /// Register with `grt_core::select`, for `DeviceSelector::new` and
+  /// `DeviceSelector::from_env`. This only needs to be called if our
+  /// `register_enumerator_on_load!` was dropped by the linker and no device has been
+  /// created yet.
+  pub fn register() {
+    register_enumerator(Arc::new(HsaDeviceEnumerator));
+  }
+  pub(crate) fn register_once() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(Self::register);
+  }
+
+  fn kernel_agents() -> Result<Vec<Agent>, Error> {
+    let hsa_context = ApiContext::try_upref()?;
+    Ok(hsa_context.agents()?
+      .into_iter()
+      .filter(|agent| agent.feature().ok() == Some(Feature::Kernel) )
+      .collect())
+  }
+
+  fn describe(info: &mut DeviceInfo, agent: &Agent) -> Result<(), Error> {
+    info.set("arch", agent.name()?);
+    info.set("vendor", agent.vendor_name()?);
+    info.set("compute_units", agent.compute_unit_count()?.to_string());
+    Ok(())
+  }
+}
+impl DeviceEnumerator for HsaDeviceEnumerator {
+  fn backend(&self) -> &str { "hsa" }
+
+  fn devices(&self) -> Result<Vec<DeviceInfo>, Box<dyn StdError + Send + Sync + 'static>> {
+    let mut out = vec![];
+    for agent in Self::kernel_agents()?.iter() {
+      let mut info = DeviceInfo::new(self.backend(), out.len());
+      Self::describe(&mut info, agent)?;
+      out.push(info);
+    }
+    Ok(out)
+  }
+  fn open(&self, ctx: &Context, index: usize)
+    -> Result<Arc<dyn Accelerator>, Box<dyn StdError + Send + Sync + 'static>>
+  {
+    let dev = HsaAmdGpuAccel::nth_device(ctx, index)?;
+    Ok(dev)
+  }
+
+  fn index_of(&self, accel: &dyn Accelerator) -> Option<usize> {
+    let accel = <HsaAmdGpuAccel as Accelerator>::downcast_ref(accel)?;
+    Self::kernel_agents().ok()?
+      .iter()
+      .position(|agent| agent == accel.agent() )
+  }
+  fn properties(&self, accel: &dyn Accelerator, info: &mut DeviceInfo) {
+    if let Some(accel) = <HsaAmdGpuAccel as Accelerator>::downcast_ref(accel) {
+      if let Err(err) = Self::describe(info, accel.agent()) {
+        warn!("failed to describe {:?}: {}", accel.id(), err);
+      }
+    }
+  }
+}
@@ -22,8 +22,10 @@ use crate::{Error, HsaAmdGpuAccel};
 use crate::module::{CallError, DeviceMultiQueue, DeviceSingleQueue, Completion, };
 use crate::signal::{SignalHandle, DeviceConsumable, DeviceSignal, GlobalSignal, HostConsumable,
                     SignalFactory, GlobalSignalRef, DeviceSignalRef};
+use crate::signal::pool::PooledSignal;
 use crate::boxed::{RawPoolBox, LocallyAccessiblePoolBox, };
 use crate::alloc::{LapBox, LapVec};
+use crate::lds::LdsDyn;
 
 /// This is unsafe because you must ensure the proper dep signals are registered!
 /// You should probably just use the `GeobacterDeps` derive macro to implement this.
@@ -109,6 +111,13 @@ pub unsafe trait Deps {
   {
     self.barrier(dev, queue)
   }
+  /// Like `global_barrier`, but the completion signal comes from `dev`'s signal pool.
+  fn pooled_barrier<Q>(&self, dev: &Arc<HsaAmdGpuAccel>, queue: &Q)
+    -> Result<PooledSignal<GlobalSignal>, Error>
+    where Q: RingQueue,
+  {
+    self.barrier(dev, queue)
+  }
 
   fn iter_deps<'a>(&'a self, f: &mut dyn FnMut(&'a dyn DeviceConsumable) -> Result<(), CallError>)
     -> Result<(), CallError>;
@@ -351,6 +360,13 @@ unsafe impl<T> Deps for PhantomData<T>
     Ok(())
   }
 }
+unsafe impl<T> Deps for LdsDyn<T> {
+  fn iter_deps<'a>(&'a self, _: &mut dyn FnMut(&'a dyn DeviceConsumable) -> Result<(), CallError>)
+    -> Result<(), CallError>
+  {
+    Ok(())
+  }
+}
 unsafe impl Deps for PhantomPinned {
   fn iter_deps<'a>(&'a self, _: &mut dyn FnMut(&'a dyn DeviceConsumable) -> Result<(), CallError>)
     -> Result<(), CallError>
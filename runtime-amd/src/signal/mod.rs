@@ -24,6 +24,7 @@ pub use hsa_rt::signal::{Value, SignalLoad, SignalStore, SignalSilentStore, Sign
 pub mod completion;
 pub mod deps;
 pub mod gpu;
+pub mod pool;
 
 pub trait SignalHandle {
   fn signal_ref(&self) -> SignalRef;
@@ -0,0 +1,355 @@
+//! Recycle signals instead of calling `hsa_signal_create`/`hsa_signal_destroy` for
+//! every dispatch or transfer.
+//!
+//! Every `HsaAmdGpuAccel` has a `SignalPool`. Use `PooledSignal<GlobalSignal>` or
+//! `PooledSignal<DeviceSignal>` anywhere a `SignalFactory` is accepted (kernel
+//! completion signals, `H2DMemcpyGroup::memcopy2`, `Deps::barrier`, etc); the signal
+//! is returned to the pool when the `PooledSignal` is dropped, ie usually when the
+//! `InvocCompletion` or `H2DMemoryTransfer` holding it is dropped.
+//!
+//! Host interrupt capable (`GlobalSignal`) and device only (`DeviceSignal`) signals
+//! are kept separately, so one is never handed out as the other.
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use serde::{Deserialize, Serialize, };
+
+use hsa_rt::error::Error as HsaError;
+use hsa_rt::signal::{Signal, SignalRef, SignalLoad, SignalSilentStore, Value, };
+
+use grt_core::{Accelerator, AcceleratorId, };
+
+use crate::HsaAmdGpuAccel;
+use crate::module::{Deps, CallError, };
+
+use super::{SignalHandle, DeviceConsumable, HostConsumable, DeviceSignal, GlobalSignal,
+            SignalFactory, reset_impl, };
+
+/// The default max number of cached signals, per kind.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct SignalPoolStats {
+  /// Signals handed out from the cache.
+  pub hits: u64,
+  /// Signals which had to be created.
+  pub misses: u64,
+  /// Signals returned to the cache.
+  pub recycled: u64,
+  /// Signals destroyed on return, either because the cache was full or because the
+  /// signal was still non-zero.
+  pub discarded: u64,
+  /// Signals currently cached.
+  pub cached: usize,
+}
+
+/// A bounded free list. Split out so the bookkeeping can be tested without HSA.
+#[derive(Debug)]
+pub(crate) struct FreeList<T> {
+  items: Vec<T>,
+  capacity: usize,
+  stats: SignalPoolStats,
+}
+impl<T> FreeList<T> {
+  pub(crate) fn new(capacity: usize) -> Self {
+    FreeList {
+      items: Vec::new(),
+      capacity,
+      stats: SignalPoolStats::default(),
+    }
+  }
+
+  pub(crate) fn take(&mut self) -> Option<T> {
+    let v = self.items.pop();
+    if v.is_some() {
+      self.stats.hits += 1;
+    } else {
+      self.stats.misses += 1;
+    }
+    v
+  }
+  /// Returns `v` if there is no room for it.
+  pub(crate) fn give(&mut self, v: T) -> Option<T> {
+    if self.items.len() >= self.capacity {
+      self.stats.discarded += 1;
+      return Some(v);
+    }
+    self.stats.recycled += 1;
+    self.items.push(v);
+    None
+  }
+  pub(crate) fn discarded(&mut self) {
+    self.stats.discarded += 1;
+  }
+
+  pub(crate) fn stats(&self) -> SignalPoolStats {
+    SignalPoolStats {
+      cached: self.items.len(),
+      .. self.stats
+    }
+  }
+  pub(crate) fn capacity(&self) -> usize { self.capacity }
+  /// Returns the items which no longer fit.
+  pub(crate) fn set_capacity(&mut self, capacity: usize) -> Vec<T> {
+    self.capacity = capacity;
+    if self.items.len() > capacity {
+      self.items.split_off(capacity)
+    } else {
+      Vec::new()
+    }
+  }
+  pub(crate) fn clear(&mut self) -> Vec<T> {
+    std::mem::replace(&mut self.items, Vec::new())
+  }
+}
+
+pub struct SignalPool {
+  global: Mutex<FreeList<Signal>>,
+  device: Mutex<FreeList<Signal>>,
+}
+impl SignalPool {
+  pub(crate) fn new() -> Self {
+    SignalPool {
+      global: Mutex::new(FreeList::new(DEFAULT_CAPACITY)),
+      device: Mutex::new(FreeList::new(DEFAULT_CAPACITY)),
+    }
+  }
+
+  fn list(&self, kind: SignalKind) -> &Mutex<FreeList<Signal>> {
+    match kind {
+      SignalKind::Global => &self.global,
+      SignalKind::Device => &self.device,
+    }
+  }
+
+  pub fn global_stats(&self) -> SignalPoolStats { self.global.lock().stats() }
+  pub fn device_stats(&self) -> SignalPoolStats { self.device.lock().stats() }
+
+  /// Set the max number of cached signals of each kind. Excess signals are destroyed.
+  pub fn set_capacity(&self, capacity: usize) {
+    let global = self.global.lock().set_capacity(capacity);
+    let device = self.device.lock().set_capacity(capacity);
+    drop((global, device));
+  }
+  pub fn capacity(&self) -> usize { self.global.lock().capacity() }
+
+  /// Destroy every cached signal.
+  pub fn trim(&self) {
+    let global = self.global.lock().clear();
+    let device = self.device.lock().clear();
+    drop((global, device));
+  }
+
+  fn take(&self, kind: SignalKind) -> Option<Signal> {
+    self.list(kind).lock().take()
+  }
+  fn give(&self, kind: SignalKind, signal: Signal) {
+    let excess = self.list(kind).lock().give(signal);
+    // destroy outside the lock:
+    drop(excess);
+  }
+  fn discarded(&self, kind: SignalKind) {
+    self.list(kind).lock().discarded();
+  }
+}
+impl fmt::Debug for SignalPool {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("SignalPool")
+      .field("global", &self.global_stats())
+      .field("device", &self.device_stats())
+      .finish()
+  }
+}
+
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SignalKind {
+  Global,
+  Device,
+}
+
+/// Signal types which can be stored in a `SignalPool`.
+/// You probably shouldn't implement this yourself.
+pub unsafe trait PoolableSignal: SignalHandle + Sized {
+  #[doc(hidden)]
+  const KIND: SignalKind;
+  #[doc(hidden)]
+  fn create(device: &Arc<HsaAmdGpuAccel>, initial: Value) -> Result<Self, HsaError>;
+  #[doc(hidden)]
+  fn from_signal(device: AcceleratorId, signal: Signal) -> Self;
+  #[doc(hidden)]
+  fn into_signal(self) -> Signal;
+}
+unsafe impl PoolableSignal for GlobalSignal {
+  const KIND: SignalKind = SignalKind::Global;
+  fn create(_: &Arc<HsaAmdGpuAccel>, initial: Value) -> Result<Self, HsaError> {
+    GlobalSignal::new(initial)
+  }
+  fn from_signal(_: AcceleratorId, signal: Signal) -> Self { GlobalSignal(signal) }
+  fn into_signal(self) -> Signal { self.0 }
+}
+unsafe impl PoolableSignal for DeviceSignal {
+  const KIND: SignalKind = SignalKind::Device;
+  fn create(device: &Arc<HsaAmdGpuAccel>, initial: Value) -> Result<Self, HsaError> {
+    device.new_device_signal(initial)
+  }
+  fn from_signal(device: AcceleratorId, signal: Signal) -> Self { DeviceSignal(signal, device) }
+  fn into_signal(self) -> Signal { self.0 }
+}
+
+/// A signal which is returned to its device's `SignalPool` when dropped.
+///
+/// The signal is only recycled if its value is zero when dropped, ie whatever was
+/// using it has completed. Otherwise it's destroyed, same as an unpooled signal.
+pub struct PooledSignal<S>
+  where S: PoolableSignal,
+{
+  device: Arc<HsaAmdGpuAccel>,
+  signal: Option<S>,
+}
+impl<S> PooledSignal<S>
+  where S: PoolableSignal,
+{
+  pub fn new(device: &Arc<HsaAmdGpuAccel>, initial: Value) -> Result<Self, HsaError> {
+    let signal = match device.signal_pool().take(S::KIND) {
+      Some(signal) => {
+        signal.as_ref().silent_store_relaxed(initial);
+        S::from_signal(device.id(), signal)
+      },
+      None => S::create(device, initial)?,
+    };
+    Ok(PooledSignal {
+      device: device.clone(),
+      signal: Some(signal),
+    })
+  }
+
+  #[inline(always)]
+  pub fn get(&self) -> &S {
+    self.signal.as_ref().unwrap()
+  }
+  pub fn device(&self) -> &Arc<HsaAmdGpuAccel> { &self.device }
+
+  /// Take the signal out of the pool for good.
+  pub fn into_inner(mut self) -> S {
+    self.signal.take().unwrap()
+  }
+}
+impl<S> Deref for PooledSignal<S>
+  where S: PoolableSignal,
+{
+  type Target = S;
+  #[inline(always)]
+  fn deref(&self) -> &S { self.get() }
+}
+impl<S> fmt::Debug for PooledSignal<S>
+  where S: PoolableSignal + fmt::Debug,
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_tuple("PooledSignal")
+      .field(&self.signal)
+      .finish()
+  }
+}
+impl<S> Drop for PooledSignal<S>
+  where S: PoolableSignal,
+{
+  fn drop(&mut self) {
+    let signal = match self.signal.take() {
+      Some(signal) => signal,
+      None => return,
+    };
+    let pool = self.device.signal_pool();
+    if signal.signal_ref().load_scacquire() != 0 {
+      // Still pending, or it errored. Don't hand it out again.
+      pool.discarded(S::KIND);
+      return;
+    }
+    pool.give(S::KIND, signal.into_signal());
+  }
+}
+impl<S> SignalHandle for PooledSignal<S>
+  where S: PoolableSignal,
+{
+  #[inline(always)]
+  fn signal_ref(&self) -> SignalRef { self.get().signal_ref() }
+  fn as_host_consumable(&self) -> Option<&dyn HostConsumable> {
+    self.get().as_host_consumable()
+  }
+}
+impl<S> DeviceConsumable for PooledSignal<S>
+  where S: PoolableSignal + DeviceConsumable,
+{
+  fn usable_on_device(&self, id: AcceleratorId) -> bool {
+    self.get().usable_on_device(id)
+  }
+}
+impl<S> HostConsumable for PooledSignal<S>
+  where S: PoolableSignal + HostConsumable,
+{ }
+impl<S> SignalFactory for PooledSignal<S>
+  where S: PoolableSignal,
+{
+  fn new(device: &Arc<HsaAmdGpuAccel>, initial: Value) -> Result<Self, HsaError> {
+    PooledSignal::new(device, initial)
+  }
+
+  #[inline(always)]
+  fn reset(&mut self, device: &Arc<HsaAmdGpuAccel>, initial: Value)
+    -> Result<(), HsaError>
+  {
+    reset_impl(self, device, initial)
+  }
+}
+unsafe impl<S> Deps for PooledSignal<S>
+  where S: PoolableSignal + Deps,
+{
+  fn iter_deps<'a>(&'a self, f: &mut dyn FnMut(&'a dyn DeviceConsumable) -> Result<(), CallError>)
+    -> Result<(), CallError>
+  {
+    self.get().iter_deps(f)
+  }
+}
+
+pub type PooledGlobalSignal = PooledSignal<GlobalSignal>;
+pub type PooledDeviceSignal = PooledSignal<DeviceSignal>;
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn free_list() {
+    let mut l = FreeList::new(2);
+    assert_eq!(l.take(), None);
+    assert_eq!(l.give(1), None);
+    assert_eq!(l.give(2), None);
+    assert_eq!(l.give(3), Some(3));
+    assert_eq!(l.take(), Some(2));
+    l.discarded();
+
+    let stats = l.stats();
+    assert_eq!(stats.hits, 1);
+    assert_eq!(stats.misses, 1);
+    assert_eq!(stats.recycled, 2);
+    assert_eq!(stats.discarded, 2);
+    assert_eq!(stats.cached, 1);
+  }
+
+  #[test]
+  fn free_list_capacity() {
+    let mut l = FreeList::new(4);
+    for i in 0..4 {
+      assert_eq!(l.give(i), None);
+    }
+    assert_eq!(l.set_capacity(1), vec![1, 2, 3]);
+    assert_eq!(l.stats().cached, 1);
+    assert_eq!(l.give(4), Some(4));
+    assert_eq!(l.clear(), vec![0]);
+    assert_eq!(l.stats().cached, 0);
+  }
+}
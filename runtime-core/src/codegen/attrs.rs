@@ -5,7 +5,7 @@ use std::str::FromStr;
 use num_traits::cast::{cast, NumCast};
 
 use rustc_ast::attr::mk_attr_outer;
-use rustc_ast::ast::{self, NestedMetaItem, MetaItem, MetaItemKind};
+use rustc_ast::ast::{self, LitKind, NestedMetaItem, MetaItem, MetaItemKind};
 use rustc_span::{Span, sym, Symbol};
 use rustc_middle::ty::TyCtxt;
 use rustc_hir::def_id::DefId;
@@ -29,6 +29,60 @@ pub trait ConditionItem: Debug + PartialEq<Self> + Sized {
 
     None
   }
+
+  /// Is this condition (from a `geobacter_attr`) met by the device, as described by
+  /// its root conditions? By default, this looks for an equal root condition;
+  /// override for conditions like minimums.
+  fn satisfied_by(&self, root_conditions: &[Self]) -> bool {
+    root_conditions.iter().any(|root_cond| root_cond == self )
+  }
+}
+
+/// Every condition key used by any platform. A platform should parse keys which it
+/// doesn't support, but which are listed here, into a condition which is never
+/// satisfied instead of erroring, so that a crate can have conditions for more than
+/// one platform.
+pub const KNOWN_CONDITION_KEYS: &[&str] = &[
+  "platform",
+  // AMDGPU:
+  "gfx", "feature", "wavefront_size", "min_lds_size",
+  // SPIR-V:
+  "capability", "extension",
+];
+pub fn is_known_condition_key(item: &MetaItem) -> bool {
+  KNOWN_CONDITION_KEYS.iter()
+    .any(|&key| item.has_name(Symbol::intern(key)) )
+}
+
+/// The string value of `key = "value"`, or an error.
+pub fn value_str(tcx: TyCtxt<'_>, item: &MetaItem) -> Option<Symbol> {
+  let v = item.value_str();
+  if v.is_none() {
+    let msg = format!("expected a string value for `{}`", item.name_or_empty());
+    tcx.sess.span_err(item.span, &msg);
+  }
+  v
+}
+/// The integer value of `key = 64` or `key = "64"`, or an error.
+pub fn value_u32(tcx: TyCtxt<'_>, item: &MetaItem) -> Option<u32> {
+  match item.name_value_literal().map(|lit| &lit.kind ) {
+    Some(&LitKind::Int(v, _)) => u32_from(tcx, item.span, v),
+    Some(&LitKind::Str(v, _)) => {
+      match v.as_str().parse::<u32>() {
+        Ok(v) => Some(v),
+        Err(_) => {
+          let msg = format!("expected an integer value for `{}`", item.name_or_empty());
+          tcx.sess.span_err(item.span, &msg);
+          None
+        },
+      }
+    },
+    _ => {
+      let msg = format!("expected an integer value for `{}`", item.name_or_empty());
+      tcx.sess.span_err(item.span, &msg);
+      None
+    },
+  }
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
@@ -232,7 +286,7 @@ pub fn geobacter_cfg_attrs<'tcx, T>(tcx: TyCtxt<'tcx>,
         // TODO eval these conditions in place instead of building this tree.
         let expr = ConditionalExpr::parse_from_attrs(tcx, cond);
         if let Some(expr) = expr {
-          if expr.eval(&|cond| root_conditions.iter().any(|root_cond| root_cond == cond )) {
+          if expr.eval(&|cond| cond.satisfied_by(root_conditions) ) {
             let sp = list[1].span();
 
             let attr = match list[1] {
@@ -248,6 +248,12 @@ pub trait PlatformCodegen: Sized + Clone + Debug + Send + Sync + 'static {
                                   _opts: &mut rustc_session::config::Options)
   { }
 
+  /// Override, or add to, the functions which will be replaced by stubs.
+  /// Called once per codegen, before any MIR is requested.
+  fn modify_stubber(&self, _target_desc: &Arc<AcceleratorTargetDesc>,
+                    _stubber: &mut stubbing::Stubber)
+  { }
+
   /// Add intrinsics which don't depend on the kernel.
   fn insert_intrinsics<F>(&self,
                           target_desc: &Arc<AcceleratorTargetDesc>,
@@ -131,6 +131,13 @@ impl Stubber {
 }
 
 impl Stubber {
+  /// Replace the function at the absolute path `path` with `stub`. These take
+  /// priority over the builtin stubs, so platforms can use this to provide a real
+  /// implementation for something we'd otherwise abort on (eg `__rust_alloc`).
+  pub fn add_stub(&mut self, path: impl Into<String>, stub: KernelInstanceRef<'static>) {
+    self.stubs.insert(path.into(), stub);
+  }
+
   /// We need to force a few functions to be used. DO NOT CALL.
   #[doc(hidden)]
   pub fn force_mir<T>()
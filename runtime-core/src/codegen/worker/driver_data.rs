@@ -41,7 +41,8 @@ pub struct DriverData<'tcx, P>
   /// Needs to be initialized after the TyCtxt is created.
   root_conditions: RwLock<Vec<P::Condition>>,
 
-  /// TODO: allow platform customization.
+  /// Initialized with the builtin stubs, plus whatever the platform adds in
+  /// `PlatformCodegen::modify_stubber`.
   pub(super) stubber: crate::codegen::stubbing::Stubber,
 
   /// maps `LOCAL_CRATE` (ie generated MIR wrappers) to their type.
@@ -74,6 +75,9 @@ impl<'tcx, P> DriverData<'tcx, P>
                     platform: &'tcx P)
     -> Self
   {
+    let mut stubber = crate::codegen::stubbing::Stubber::default();
+    platform.modify_stubber(target_desc, &mut stubber);
+
     DriverData {
       platform,
       context,
@@ -86,8 +90,7 @@ impl<'tcx, P> DriverData<'tcx, P>
       roots: RwLock::new(vec![]),
       root_conditions: RwLock::new(vec![]),
 
-      // TODO allow the platform to customize
-      stubber: Default::default(),
+      stubber,
 
       type_of: RwLock::new(Default::default()),
       intrinsics,
@@ -16,7 +16,7 @@ use std::io::{self, };
 use std::mem::{self, drop, };
 use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError, };
 use std::sync::{Arc, Weak, Once, };
-use std::time::Duration;
+use std::time::{Duration, Instant, };
 
 use rustc_ast::ast;
 use rustc_middle;
@@ -46,6 +46,7 @@ use parking_lot::RwLock;
 use tempfile::{Builder as TDBuilder, };
 
 use crate::{AcceleratorTargetDesc, context::Context, };
+use crate::trace::{TraceRecorder, TraceSpan, };
 use crate::utils::{HashMap, StableHash, };
 
 use self::error::IntoErrorWithKernelInstance;
@@ -239,6 +240,7 @@ impl<P> WorkerTranslatorData<P>
       };
 
       match internal_msg {
+        InternalMessage::Timeout if self.context.is_shut_down() => { return; },
         InternalMessage::Timeout => { },
         InternalMessage::AddAccel(accel) => {
           self.accels.write().push(accel);
@@ -335,6 +337,11 @@ impl<P> WorkerTranslatorData<P>
     -> Result<Arc<PCodegenResults<P>>, error::PError<P>>
   {
     use std::collections::hash_map::Entry;
+
+    // keeps `Context::shutdown` from finishing until we're done.
+    let _active = self.context.begin_codegen()
+      .ok_or(error::Error::ContextDead)?;
+
     loop {
       loop {
         let cache = self.cache.read();
@@ -371,12 +378,27 @@ impl<P> WorkerTranslatorData<P>
       }
     }
 
+    let start = Instant::now();
     let result = self.initialize_sess(|sess, cstore, | {
       self.codegen_kernel_inner(desc.clone(),
                                 sess,
                                 cstore)
           .map(Arc::new)
     });
+    TraceRecorder::global().record_with(|| {
+      let mut args = vec![("hash", format!("0x{:x}", desc.instance.stable_hash()))];
+      if result.is_err() {
+        args.push(("status", "error".into()));
+      }
+      TraceSpan {
+        name: format!("{:?}", desc.instance).into(),
+        category: "codegen",
+        track: format!("codegen {}", self.target_desc.target.llvm_target).into(),
+        start,
+        end: Instant::now(),
+        args,
+      }
+    });
 
     let mut cache = self.cache.write();
     match result {
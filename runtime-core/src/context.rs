@@ -4,10 +4,13 @@ use std::collections::hash_map::{Entry, };
 use std::error::Error;
 use std::fmt::Debug;
 use std::intrinsics::likely;
-use std::sync::{Arc, Weak, atomic::AtomicUsize, atomic::Ordering, };
+use std::mem;
+use std::path::Path;
+use std::ptr;
+use std::sync::{Arc, Weak, atomic::AtomicBool, atomic::AtomicUsize, atomic::Ordering, };
 
 use indexvec::{Idx, IndexVec};
-use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard, MappedRwLockReadGuard,
+use parking_lot::{Condvar, Mutex, RwLock, RwLockUpgradableReadGuard, MappedRwLockReadGuard,
                   RwLockReadGuard, RwLockWriteGuard, };
 
 use rustc_span::SessionGlobals;
@@ -15,7 +18,8 @@ use rustc_data_structures::rayon::ThreadPoolBuilder;
 
 use crate::{Accelerator, AcceleratorId, AcceleratorTargetDesc, Device};
 use crate::codegen::{PlatformCodegen, CodegenDriver, PKernelDesc};
-use crate::metadata::{context_metadata, LoadedCrateMetadata, };
+use crate::metadata::{context_metadata, insert_metadata, mapped_sources, merge_metadata,
+                      CrateSource, LoadedCrateMetadata, Metadata, MetadataLoadingError, };
 use crate::utils::{HashMap, };
 
 pub use rustc_session::config::OutputType;
@@ -54,15 +58,32 @@ impl AsyncCodegenMetadataLoader {
       r.as_ref().unwrap()
     }));
   }
+  /// Run `f` with exclusive access to the loaded metadata, loading it first if needed.
+  /// Codegen sessions started afterwards will see any changes.
+  fn update<F, R>(&self, f: F) -> Result<R, Box<dyn Error + Send + Sync + 'static>>
+    where F: FnOnce(&mut LoadedCrateMetadata) -> Result<R, MetadataLoadingError>,
+  {
+    let mut w = self.0.write();
+    if w.is_none() {
+      *w = Some(context_metadata()?);
+    }
+    Ok(f(w.as_mut().unwrap())?)
+  }
 }
 
-/// This structure should be used like you'd use a singleton.
 struct ContextData {
-  #[allow(dead_code)]
   session_globals: Arc<SessionGlobals>,
   metadata: AsyncCodegenMetadataLoader,
 
-  next_accel_id: AtomicUsize,
+  /// Set by `Context::shutdown`. Guarded by `codegen`, so no codegen starts after we've
+  /// waited for the in-flight ones.
+  shut_down: AtomicBool,
+  /// The number of in-flight codegen sessions.
+  codegen: Mutex<usize>,
+  codegen_idle: Condvar,
+
+  /// Every function slot we've stored module data in, so shutdown can remove it.
+  modules: Mutex<Vec<&'static ModuleSlots>>,
 
   m: RwLock<ContextDataMut>,
 }
@@ -73,74 +94,100 @@ struct ContextDataMut {
   translators: Translators,
 }
 
+/// A set of accelerators, their codegen drivers and the module data of every kernel loaded
+/// onto them. Contexts are independent: each can be shut down without affecting the others.
+/// The only state shared between them is the rustc symbol interner and the codegen thread
+/// pool.
 #[derive(Clone)]
 pub struct Context(Arc<ContextData>);
 
 unsafe impl Send for Context { }
 unsafe impl Sync for Context { }
 
-static mut CTX: Option<WeakContext> = None;
+static CTX: RwLock<Option<WeakContext>> = parking_lot::const_rwlock(None);
+/// Every context created, so we can find the context which owns an accelerator.
+static CONTEXTS: Mutex<Vec<WeakContext>> = parking_lot::const_mutex(Vec::new());
+/// Accelerator ids are unique in the process, not just in their context, so that ids from
+/// different contexts can't be confused.
+static NEXT_ACCEL_ID: AtomicUsize = AtomicUsize::new(0);
+
+struct ProcessGlobals(Arc<SessionGlobals>);
+unsafe impl Send for ProcessGlobals { }
+
+/// Initialize the state shared by every context. The rayon pool can only be built once.
+fn process_session_globals() -> Result<Arc<SessionGlobals>, Box<dyn Error>> {
+  use crate::rustc_span::edition::Edition;
+
+  static GLOBALS: Mutex<Option<ProcessGlobals>> = parking_lot::const_mutex(None);
+
+  let mut globals = GLOBALS.lock();
+  if let Some(ref globals) = *globals {
+    return Ok(globals.0.clone());
+  }
+
+  crate::utils::env::initialize();
+
+  crate::rustc_driver::init_rustc_env_logger();
+
+  let session_globals = rustc_span::SessionGlobals::new(Edition::Edition2018);
+  let session_globals = Arc::new(session_globals);
+  let pool_globals = session_globals.clone();
+
+  ThreadPoolBuilder::new()
+    // give us a huge stack (for codegen's use):
+    .stack_size(32 * 1024 * 1024)
+    .thread_name(|id| format!("grt-core-worker-{}", id) )
+    .deadlock_handler(|| unsafe { crate::rustc_middle::ty::query::handle_deadlock() })
+    .spawn_handler(move |tb| {
+      let mut b = std::thread::Builder::new();
+      if let Some(name) = tb.name() {
+        b = b.name(name.to_owned());
+      }
+      if let Some(stack_size) = tb.stack_size() {
+        b = b.stack_size(stack_size);
+      }
+      let pool_globals = pool_globals.clone();
+      b.spawn(move || {
+        rustc_span::SESSION_GLOBALS.set(&*pool_globals, move || {
+          tb.run()
+        });
+      })?;
+
+      Ok(())
+    })
+    .build_global()?;
+
+  *globals = Some(ProcessGlobals(session_globals.clone()));
+  Ok(session_globals)
+}
 
 impl Context {
+  /// The context returned by `Context::new`, if it is still alive and hasn't been shut down.
   pub fn global() -> Option<Context> {
-    // CTX can only be written during context initialization, which will run an acquire fence.
-    // Otherwise this races, but that's NBD because this will just return None in that case
-    unsafe {
-      if let Some(ctx) = CTX.clone() {
-        if let Some(ctx) = ctx.upgrade() {
-          return Some(ctx);
-        }
-      }
-    }
-    None
+    CTX.read().as_ref()?.upgrade()
   }
 
+  /// Returns the global context, creating it if needed.
   pub fn new() -> Result<Context, Box<dyn Error>> {
-    use crate::rustc_span::edition::Edition;
-
     if let Some(ctx) = Self::global() {
       return Ok(ctx);
     }
 
-    lazy_static::lazy_static! {
-      static ref INIT: Mutex<()> = parking_lot::const_mutex(());
-    }
-    let _ = INIT.lock(); // ensure only one of us is created
-    if let Some(ctx) = Self::global() {
+    // ensure only one of us is created
+    let mut global = CTX.write();
+    if let Some(ctx) = global.as_ref().and_then(|ctx| ctx.upgrade() ) {
       return Ok(ctx);
     }
 
-    crate::utils::env::initialize();
-
-    crate::rustc_driver::init_rustc_env_logger();
-
-    let session_globals = rustc_span::SessionGlobals::new(Edition::Edition2018);
-    let session_globals = Arc::new(session_globals);
-    let pool_globals = session_globals.clone();
+    let context = Self::new_isolated()?;
+    *global = Some(context.downgrade_ref());
 
-    ThreadPoolBuilder::new()
-      // give us a huge stack (for codegen's use):
-      .stack_size(32 * 1024 * 1024)
-      .thread_name(|id| format!("grt-core-worker-{}", id) )
-      .deadlock_handler(|| unsafe { crate::rustc_middle::ty::query::handle_deadlock() })
-      .spawn_handler(move |tb| {
-        let mut b = std::thread::Builder::new();
-        if let Some(name) = tb.name() {
-          b = b.name(name.to_owned());
-        }
-        if let Some(stack_size) = tb.stack_size() {
-          b = b.stack_size(stack_size);
-        }
-        let pool_globals = pool_globals.clone();
-        b.spawn(move || {
-          rustc_span::SESSION_GLOBALS.set(&*pool_globals, move || {
-            tb.run()
-          });
-        })?;
-
-        Ok(())
-      })
-      .build_global()?;
+    Ok(context)
+  }
+  /// Create a new context which is independent of the global context and any other context.
+  /// Accelerators, codegen drivers and kernel modules aren't shared with other contexts.
+  pub fn new_isolated() -> Result<Context, Box<dyn Error>> {
+    let session_globals = process_session_globals()?;
 
     let accelerators = IndexVec::new();
     let translators: Translators = Default::default();
@@ -153,26 +200,105 @@ impl Context {
       session_globals,
       metadata: AsyncCodegenMetadataLoader::default(),
 
-      next_accel_id: AtomicUsize::new(0),
+      shut_down: AtomicBool::new(false),
+      codegen: Mutex::new(0),
+      codegen_idle: Condvar::new(),
+
+      modules: Mutex::new(Vec::new()),
 
       m: RwLock::new(data),
     };
     let data = Arc::new(data);
     let context = Context(data);
 
-    // now install the global ref:
-    let weak = context.downgrade_ref();
-    unsafe {
-      CTX = Some(weak);
-    }
+    let mut all = CONTEXTS.lock();
+    all.retain(|ctx| ctx.0.strong_count() != 0 );
+    all.push(context.downgrade_ref());
 
     Ok(context)
   }
 
+  /// Find the live context which `id` was initialized in.
+  pub fn for_accel(id: AcceleratorId) -> Option<Context> {
+    let all = CONTEXTS.lock().clone();
+    all.into_iter()
+      .filter_map(|ctx| ctx.upgrade() )
+      .find(|ctx| ctx.get_accel_ref(id).is_some() )
+  }
+
+  pub fn is_shut_down(&self) -> bool {
+    self.0.shut_down.load(Ordering::Acquire)
+  }
+  /// Shut this context down: wait for in-flight codegen to finish, then drop this context's
+  /// accelerators, codegen drivers and the module data of every kernel loaded through it. An
+  /// accelerator's device resources are released as soon as the caller drops its own
+  /// references to it. Afterwards, no accelerators can be initialized in this context, and
+  /// codegen will fail. If this is the global context, `Context::new` will create a new one.
+  pub fn shutdown(&self) {
+    {
+      let mut active = self.0.codegen.lock();
+      self.0.shut_down.store(true, Ordering::Release);
+      while *active != 0 {
+        self.0.codegen_idle.wait(&mut active);
+      }
+    }
+
+    {
+      let mut global = CTX.write();
+      if global.as_ref().map(|ctx| ctx.is(self) ).unwrap_or(false) {
+        *global = None;
+      }
+    }
+
+    let modules = mem::replace(&mut *self.0.modules.lock(), Vec::new());
+    for slots in modules {
+      slots.0.write().retain(|data| !data.ctxt.is(self) );
+    }
+
+    // Accelerators hold a reference to us, so drop them outside of the lock.
+    let (accelerators, translators) = {
+      let mut w = self.0.m.write();
+      (mem::replace(&mut w.accelerators, IndexVec::new()),
+       mem::replace(&mut w.translators, Default::default()))
+    };
+    drop(translators);
+    drop(accelerators);
+  }
+
+  /// Returns `None` if this context has been shut down. Shutdown waits for the returned guard
+  /// to be dropped.
+  pub(crate) fn begin_codegen(&self) -> Option<CodegenGuard> {
+    let mut active = self.0.codegen.lock();
+    if self.is_shut_down() { return None; }
+    *active += 1;
+    Some(CodegenGuard(self))
+  }
+
   pub(crate) fn load_metadata(&self) -> LoadedMetadataResult {
     self.0.metadata.load()
   }
 
+  /// Load the crate metadata embedded in the object at `path`, eg a plugin loaded at runtime,
+  /// so that kernels defined in it can be compiled. The object must have been built by the
+  /// Geobacter toolchain. Returns false if its crate was already loaded.
+  pub fn register_metadata_source<P>(&self, path: P)
+    -> Result<bool, Box<dyn Error + Send + Sync + 'static>>
+    where P: AsRef<Path>,
+  {
+    let src = CrateSource::Mapped(path.as_ref().canonicalize()?);
+    // parse before taking the lock, so we don't block codegen.
+    let metadata = Metadata::new(src)?;
+    self.0.metadata.update(move |loaded| Ok(insert_metadata(loaded, metadata)) )
+  }
+  /// Load the metadata of every object mapped into this process since the metadata was
+  /// loaded, ie objects which have since been `dlopen`ed. Returns the number of objects added.
+  pub fn refresh_mapped_metadata(&self)
+    -> Result<usize, Box<dyn Error + Send + Sync + 'static>>
+  {
+    let sources = mapped_sources()?;
+    self.0.metadata.update(move |loaded| merge_metadata(loaded, sources) )
+  }
+
   #[doc(hidden)]
   #[inline(always)]
   pub fn with_rustc_span_globals<F, R>(&self, f: F) -> R
@@ -239,7 +365,7 @@ impl Context {
   }
 
   pub fn take_accel_id(&self) -> AcceleratorId {
-    let id = self.0.next_accel_id
+    let id = NEXT_ACCEL_ID
       .fetch_add(1, Ordering::AcqRel);
     if id > usize::max_value() / 2 {
       panic!("too many accelerators");
@@ -259,6 +385,9 @@ impl Context {
     let target_desc = accel.accel_target_desc().clone();
 
     let mut w = self.0.m.write();
+    if self.is_shut_down() {
+      return Err("context has been shut down".into());
+    }
     match w.translators.entry(target_desc) {
       Entry::Occupied(mut o) => {
         Arc::get_mut(accel).unwrap()
@@ -287,6 +416,18 @@ impl Context {
 }
 
 impl ContextDataMut { }
+
+pub(crate) struct CodegenGuard<'a>(&'a Context);
+impl<'a> Drop for CodegenGuard<'a> {
+  fn drop(&mut self) {
+    let mut active = (self.0).0.codegen.lock();
+    *active -= 1;
+    if *active == 0 {
+      (self.0).0.codegen_idle.notify_all();
+    }
+  }
+}
+
 impl Eq for Context { }
 impl PartialEq for Context {
   fn eq(&self, rhs: &Self) -> bool {
@@ -313,6 +454,9 @@ impl WeakContext {
     self.0.upgrade()
       .map(|v| Context(v) )
   }
+  fn is(&self, ctx: &Context) -> bool {
+    ptr::eq(self.0.as_ptr(), Arc::as_ptr(&ctx.0))
+  }
 }
 
 /// Platform and device specific module Stuff. Put your API handles
@@ -467,6 +611,11 @@ impl ModuleData {
     return Ok(module);
   }
 }
+/// The module data of a function, one entry per context it has been used in. These are
+/// never freed: there is one per kernel function, so they're leaked into the function's stash.
+#[derive(Default)]
+struct ModuleSlots(RwLock<Vec<Arc<ModuleData>>>);
+
 #[derive(Clone, Copy, Debug)]
 /// No PhantomData on this, this object doesn't own the arguments or return
 /// values of the function it represents.
@@ -474,39 +623,43 @@ impl ModuleData {
 pub struct ModuleContextData(&'static AtomicUsize);
 
 impl ModuleContextData {
-  pub fn upgrade(&self, context: &Context) -> Option<Arc<ModuleData>> {
+  fn slots(&self) -> Option<&'static ModuleSlots> {
     let ptr_usize = self.0.load(Ordering::Acquire);
     if ptr_usize == 0 { return None; }
-    let ptr = ptr_usize as *const ModuleData;
-
-    let arc = unsafe { Arc::from_raw(ptr) };
-    let arc_clone = arc.clone();
-    // don't downref the global arc:
-    Arc::into_raw(arc);
+    Some(unsafe { &*(ptr_usize as *const ModuleSlots) })
+  }
+  fn slots_or_init(&self) -> &'static ModuleSlots {
+    if let Some(slots) = self.slots() {
+      return slots;
+    }
 
-    let arc = arc_clone;
-    let expected_context = arc.ctxt.upgrade();
-    if expected_context.is_none() { return None; }
-    let expected_context = expected_context.unwrap();
-    assert!(expected_context == context,
-            "there are two context's live at the same time");
+    let slots = Box::into_raw(Box::new(ModuleSlots::default()));
+    // conservative orderings b/c this isn't the fast path.
+    let r = self.0
+      .compare_exchange(0, slots as usize,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst);
+    match r {
+      Ok(_) => unsafe { &*slots },
+      Err(actual) => {
+        // someone beat us.
+        unsafe { Box::from_raw(slots) };
+        unsafe { &*(actual as *const ModuleSlots) }
+      },
+    }
+  }
 
-    Some(arc)
+  pub fn upgrade(&self, context: &Context) -> Option<Arc<ModuleData>> {
+    let slots = self.slots()?.0.read();
+    slots.iter()
+      .find(|data| data.ctxt.is(context) )
+      .cloned()
   }
 
-  /// Drops all globally stored data. This doesn't clear any
-  /// data stored with any context.
+  /// Drops the module data of every context.
   pub fn drop(&self) {
-    let ptr_usize = self.0.load(Ordering::Acquire);
-    if ptr_usize == 0 { return; }
-
-    let owner = self.0
-      .compare_exchange(ptr_usize, 0,
-                        Ordering::SeqCst,
-                        Ordering::Relaxed);
-    if let Ok(_) = owner {
-      let ptr = ptr_usize as *const ModuleData;
-      unsafe { Arc::from_raw(ptr) };
+    if let Some(slots) = self.slots() {
+      slots.0.write().clear();
     }
   }
 
@@ -514,72 +667,38 @@ impl ModuleContextData {
     !self.is_some()
   }
   pub fn is_some(&self) -> bool {
-    self.0.load(Ordering::Acquire) != 0
+    self.slots()
+      .map(|slots| !slots.0.read().is_empty() )
+      .unwrap_or(false)
   }
 
   pub fn get_cache_data(&self, context: &Context)
     -> Arc<ModuleData>
   {
-    use std::intrinsics::unlikely;
-
-    let mut cached = self.upgrade(context);
-    if unlikely(cached.is_none()) {
-      let data = ModuleData::new(context);
-      let data = Arc::new(data);
-      let data_ptr = Arc::into_raw(data.clone());
-      let data_usize = data_ptr as usize;
-
-      // conservative orderings b/c this isn't the fast path.
-      let actual_data_usize = self.0
-        .compare_exchange(0, data_usize,
-                          Ordering::SeqCst,
-                          Ordering::SeqCst);
-      match actual_data_usize {
-        Ok(0) => {
-          cached = Some(data);
-        },
-        Ok(_) => unreachable!(),
-        Err(actual_data_usize) => {
-          // either someone beat us, or the data is from an old context.
-          let cached2 = self.upgrade(context);
-          if cached2.is_some() {
-            // someone beat us.
-            unsafe { Arc::from_raw(data_ptr) };
-            cached = cached2;
-          } else {
-            // the data is old. we need to clean up, while allowing for
-            // possible races in this process.
-
-            let r = self.0
-              .compare_exchange(actual_data_usize,
-                                data_usize,
-                                Ordering::SeqCst,
-                                Ordering::SeqCst);
-            match r {
-              Ok(actual_data_usize) => {
-                // do the cleanup:
-                let actual_data = actual_data_usize as *const ModuleData;
-                let _actual_data = unsafe { Arc::from_raw(actual_data) };
-                // let actual_data drop.
-
-                cached = Some(data);
-              },
-              Err(_) => {
-                // someone beat us.
-                unsafe { Arc::from_raw(data_ptr) };
-                let data = self.upgrade(context)
-                  .expect("someone beat us in setting context \
-                           module data, but didn't set it to \
-                           value data");
-                cached = Some(data);
-              },
-            }
-          }
-        },
-      }
+    if let Some(data) = self.upgrade(context) {
+      return data;
     }
 
-    cached.unwrap()
+    let slots = self.slots_or_init();
+    let data = {
+      let mut w = slots.0.write();
+      if let Some(data) = w.iter().find(|data| data.ctxt.is(context) ) {
+        // someone beat us.
+        return data.clone();
+      }
+
+      // drop the data of dead contexts while we're here.
+      w.retain(|data| data.ctxt.0.strong_count() != 0 );
+
+      let data = Arc::new(ModuleData::new(context));
+      w.push(data.clone());
+      data
+    };
+
+    // Don't hold the slot lock here; `Context::shutdown` takes these in the other order.
+    context.0.modules.lock().push(slots);
+
+    data
   }
 
   pub fn get<F, Args, Ret>(_: &F) -> Self
@@ -635,4 +754,33 @@ mod test {
     data.drop();
     assert!(data.is_none());
   }
+
+  #[test]
+  fn isolated_contexts() {
+    fn f() { }
+    let data = ModuleContextData::get(&f);
+    let a = Context::new_isolated().unwrap();
+    let b = Context::new_isolated().unwrap();
+    assert!(a != b && a != context());
+    assert_ne!(a.take_accel_id(), b.take_accel_id());
+
+    let a_data = data.get_cache_data(&a);
+    let b_data = data.get_cache_data(&b);
+    assert!(!Arc::ptr_eq(&a_data, &b_data));
+    assert!(Arc::ptr_eq(&a_data, &data.get_cache_data(&a)));
+
+    a.shutdown();
+    assert!(a.is_shut_down() && a.begin_codegen().is_none());
+    assert!(data.upgrade(&a).is_none());
+    assert!(Arc::ptr_eq(&b_data, &data.upgrade(&b).unwrap()));
+    assert!(b.begin_codegen().is_some());
+  }
+
+  #[test]
+  fn register_loaded_metadata_source() {
+    let exe = crate::platform::os::self_exe_path().unwrap();
+    // our own crate was loaded at startup.
+    assert!(!context().register_metadata_source(&exe).unwrap());
+    assert_eq!(context().refresh_mapped_metadata().unwrap(), 0);
+  }
 }
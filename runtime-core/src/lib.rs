@@ -64,11 +64,15 @@ use crate::context::{Context, PlatformModuleData, };
 use crate::rustc_target::spec::{abi::Abi, Target, TargetTriple, };
 use crate::serde::Serialize;
 
+pub use crate::metadata::CrateNameHash;
+
 pub mod context;
 pub mod codegen;
 mod metadata;
 mod platform;
+pub mod select;
 mod serde_utils;
+pub mod trace;
 mod utils;
 
 indexvec::newtype_index!(AcceleratorId);
@@ -0,0 +1,175 @@
+//! An on-disk store of inflated crate metadata, shared between processes.
+//!
+//! Blobs are stored once per crate, keyed by `CrateNameHash`, as `<name>-<hash>.rmeta`, and are
+//! memory mapped when loaded, so decoding only faults in the parts of a crate's metadata the
+//! collector actually reads. Each object we've loaded metadata from has an index under
+//! `objects/`, keyed by its path, size and modification time, which maps its metadata symbols
+//! to crates. That way a process which finds the index never has to inflate anything.
+//!
+//! Every file is written to a temporary and then renamed into place, so concurrent processes
+//! will never see a partial file. Any cache error just falls back to inflating the metadata.
+
+use std::fs::{self, File, };
+use std::io::{self, Write, };
+use std::time::UNIX_EPOCH;
+
+use memmap::MmapOptions;
+
+use tempfile::NamedTempFile;
+
+use super::*;
+
+pub struct MetadataCache {
+  dir: PathBuf,
+}
+
+impl MetadataCache {
+  /// The cache in `utils::env::metadata_cache_dir()`. `None` if it's disabled or can't be
+  /// created.
+  pub fn global() -> Option<&'static MetadataCache> {
+    lazy_static::lazy_static! {
+      static ref CACHE: Option<MetadataCache> = {
+        let dir = crate::utils::env::metadata_cache_dir()?;
+        MetadataCache::new(dir.clone())
+          .map_err(|err| {
+            warn!("failed to create metadata cache at {}: {}", dir.display(), err);
+          })
+          .ok()
+      };
+    }
+
+    CACHE.as_ref()
+  }
+  pub fn new(dir: PathBuf) -> io::Result<Self> {
+    fs::create_dir_all(dir.join("objects"))?;
+    Ok(MetadataCache {
+      dir,
+    })
+  }
+
+  pub fn dir(&self) -> &Path { &self.dir }
+
+  fn blob_path(&self, name: &CrateNameHash) -> PathBuf {
+    self.dir.join(format!("{}-{:016x}.rmeta", name.name, name.hash))
+  }
+  fn index_path(&self, object: &Path) -> io::Result<PathBuf> {
+    let meta = fs::metadata(object)?;
+    let mtime = meta.modified()?
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_nanos() )
+      .unwrap_or_default();
+    let key = seahash::hash(object.to_string_lossy().as_bytes());
+    let name = format!("{:016x}-{:x}-{:x}", key, meta.len(), mtime);
+    Ok(self.dir.join("objects").join(name))
+  }
+
+  /// Map the blob of every symbol in `symbols`, in order. Returns `None` if `object` hasn't
+  /// been indexed, or any blob is missing.
+  pub fn load_object<'a, I>(&self, object: &Path, symbols: I)
+    -> Option<Vec<(String, SharedMetadataBlob)>>
+    where I: Iterator<Item = &'a str>,
+  {
+    let index = self.index_path(object).ok()?;
+    let index = fs::read_to_string(index).ok()?;
+    let index: FxHashMap<&str, CrateNameHash> = index.lines()
+      .filter_map(parse_index_line)
+      .collect();
+
+    symbols
+      .map(|symbol| {
+        let name = index.get(symbol)?;
+        let blob = self.map_blob(name)?;
+        Some((symbol.to_string(), blob))
+      })
+      .collect()
+  }
+  /// Map the blob of crate `name`, checking it is actually that crate.
+  pub fn map_blob(&self, name: &CrateNameHash) -> Option<SharedMetadataBlob> {
+    let file = File::open(self.blob_path(name)).ok()?;
+    let map = unsafe { MmapOptions::new().map(&file).ok()? };
+    if !map.starts_with(METADATA_HEADER) {
+      warn!("corrupt cached metadata for {}-{:x}", name.name, name.hash);
+      return None;
+    }
+
+    let blob = SharedMetadataBlob::mapped(map);
+    if &blob.name() != name {
+      warn!("mismatched cached metadata for {}-{:x}", name.name, name.hash);
+      return None;
+    }
+    Some(blob)
+  }
+
+  /// Store every crate's blob which isn't already present, then `object`'s index.
+  pub fn store_object(&self, object: &Path, all: &[(String, SharedMetadataBlob)]) {
+    if let Err(err) = self.try_store_object(object, all) {
+      warn!("failed to cache metadata for {}: {}", object.display(), err);
+    }
+  }
+  fn try_store_object(&self, object: &Path, all: &[(String, SharedMetadataBlob)])
+    -> io::Result<()>
+  {
+    let mut index = String::new();
+    for &(ref symbol, ref blob) in all.iter() {
+      let name = blob.name();
+      let path = self.blob_path(&name);
+      if !path.exists() {
+        self.write(&path, blob.bytes())?;
+      }
+
+      index.push_str(&format!("{}\t{}\t{:016x}\n", symbol, name.name, name.hash));
+    }
+
+    self.write(&self.index_path(object)?, index.as_bytes())
+  }
+  fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut tmp = NamedTempFile::new_in(&self.dir)?;
+    tmp.write_all(bytes)?;
+    tmp.persist(path)
+      .map_err(|err| err.error )?;
+    Ok(())
+  }
+}
+
+fn parse_index_line(line: &str) -> Option<(&str, CrateNameHash)> {
+  let mut fields = line.split('\t');
+  let symbol = fields.next()?;
+  let name = fields.next()?;
+  let hash = u64::from_str_radix(fields.next()?, 16).ok()?;
+  Some((symbol, CrateNameHash {
+    name: Symbol::intern(name),
+    hash,
+  }))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::utils::test::*;
+
+  #[test]
+  fn store_and_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = MetadataCache::new(dir.path().into()).unwrap();
+    let exe = crate::platform::os::self_exe_path().unwrap();
+
+    context().with_rustc_span_globals(|| {
+      let src = CrateSource::Mapped(exe.clone());
+      let inflated = Metadata::new_with_cache(src.clone(), None).unwrap();
+      let symbols = || inflated.all.iter().map(|&(ref symbol, _)| &symbol[..] );
+      assert!(cache.load_object(&exe, symbols()).is_none());
+
+      cache.store_object(&exe, &inflated.all);
+      let mapped = cache.load_object(&exe, symbols())
+        .expect("cached object");
+      assert_eq!(mapped.len(), inflated.all.len());
+      for (&(ref ls, ref l), &(ref rs, ref r)) in inflated.all.iter().zip(mapped.iter()) {
+        assert_eq!(ls, rs);
+        assert_eq!(l.bytes(), r.bytes());
+      }
+
+      let cached = Metadata::new_with_cache(src, Some(&cache)).unwrap();
+      assert_eq!(cached.owner_name(), inflated.owner_name());
+    });
+  }
+}
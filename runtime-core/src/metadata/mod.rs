@@ -1,4 +1,5 @@
 
+use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::error::Error;
 use std::io;
@@ -18,6 +19,7 @@ use rustc_data_structures::sync::MetadataRef;
 use rustc_data_structures::owning_ref::{OwningRef, };
 use rustc_hir::def_id::{CrateNum, };
 use rustc_middle::middle::cstore::{MetadataLoader, CrateSource as RustcCrateSource, };
+use rustc_middle::ty::TyCtxt;
 use rustc_metadata::creader::CStore;
 use rustc_metadata::rmeta::{METADATA_HEADER, decoder,
                             decoder::MetadataBlob, CrateRoot,
@@ -29,6 +31,10 @@ use snap::read::FrameDecoder;
 
 use crate::utils::{new_hash_set, };
 
+pub use self::cache::MetadataCache;
+
+pub mod cache;
+
 #[derive(Debug)]
 pub enum MetadataLoadingError {
   Generic(Box<dyn Error + Send + Sync + 'static>),
@@ -38,6 +44,8 @@ pub enum MetadataLoadingError {
   Header,
   Deflate(PathBuf, String, io::Error),
   ObjectFormat(goblin::error::Error),
+  /// The object isn't an ELF or PE image.
+  UnsupportedObjectFormat(PathBuf),
 }
 impl Error for MetadataLoadingError { }
 impl fmt::Display for MetadataLoadingError {
@@ -52,6 +60,9 @@ impl fmt::Display for MetadataLoadingError {
       &MetadataLoadingError::Header => f.pad("corrupt/unsupported metadata header"),
       &MetadataLoadingError::Deflate(_, _, ref e) => fmt::Display::fmt(e, f),
       &MetadataLoadingError::ObjectFormat(ref e) => fmt::Display::fmt(e, f),
+      &MetadataLoadingError::UnsupportedObjectFormat(ref p) => {
+        write!(f, "can't load metadata from this object format: {}", p.display())
+      },
     }
   }
 }
@@ -80,7 +91,25 @@ pub struct CrateNameHash {
   pub name: Symbol,
   pub hash: u64,
 }
+impl CrateNameHash {
+  pub fn of(tcx: TyCtxt<'_>, cnum: CrateNum) -> Self {
+    CrateNameHash {
+      name: tcx.crate_name(cnum),
+      hash: tcx.crate_hash(cnum).as_u64(),
+    }
+  }
+}
 
+/// Builds the `CStore` crate data for a codegen session.
+///
+/// Every crate is still decoded up front, once per session: the `CStore` has to hold the
+/// data of every `CrateNum` before the `TyCtxt` is created, and rustc gives us no hook to
+/// add a crate once the collector first touches it. Decoding the roots is cheap when the
+/// blobs are mapped from the `MetadataCache`, since only the pages actually read get
+/// faulted in, but inflating them the first time isn't.
+/// Decoding each crate lazily, as the collector first touches its `CrateNum`, is out of
+/// scope until our rustc has a crate store which can be filled in after the `TyCtxt` is
+/// created.
 pub struct CrateMetadataLoader {
   name_to_blob: FxHashMap<CrateNameHash, (CrateSource, String, SharedMetadataBlob)>,
 
@@ -287,22 +316,53 @@ impl Default for CrateMetadataLoader {
 #[derive(Default)]
 pub struct CrateMetadata(pub Vec<decoder::CrateMetadata>);
 
-pub struct SharedMetadataBlob(Arc<Vec<u8>>, MetadataBlob);
+/// The bytes of a crate's metadata, either inflated into memory or mapped from the cache.
+pub enum MetadataBytes {
+  Inflated(Vec<u8>),
+  Mapped(Mmap),
+}
+impl Deref for MetadataBytes {
+  type Target = [u8];
+  fn deref(&self) -> &[u8] {
+    match self {
+      &MetadataBytes::Inflated(ref v) => &v[..],
+      &MetadataBytes::Mapped(ref m) => &m[..],
+    }
+  }
+}
+
+pub struct SharedMetadataBlob(Arc<MetadataBytes>, MetadataBlob);
 impl SharedMetadataBlob {
   pub fn new(data: Vec<u8>) -> SharedMetadataBlob {
-    let data = Arc::new(data);
+    SharedMetadataBlob::from_bytes(MetadataBytes::Inflated(data))
+  }
+  /// Decoding will only fault in the pages it actually reads.
+  pub fn mapped(map: Mmap) -> SharedMetadataBlob {
+    SharedMetadataBlob::from_bytes(MetadataBytes::Mapped(map))
+  }
+  fn from_bytes(bytes: MetadataBytes) -> SharedMetadataBlob {
+    let data = Arc::new(bytes);
     let inner = SharedMetadataBlob::create_metadata_blob(&data);
     SharedMetadataBlob(data, inner)
   }
 
-  fn create_metadata_blob(data: &Arc<Vec<u8>>) -> MetadataBlob {
+  fn create_metadata_blob(data: &Arc<MetadataBytes>) -> MetadataBlob {
     let inner = OwningRef::new(data.clone());
-    let inner = inner.map(|v| &v[..] )
+    let inner = inner.map(|v| &**v )
       .map_owner_box()
       .erase_send_sync_owner();
     MetadataBlob::new(inner)
   }
 
+  pub fn bytes(&self) -> &[u8] { &**self.0 }
+  pub fn name(&self) -> CrateNameHash {
+    let root = self.get_root();
+    CrateNameHash {
+      name: root.name(),
+      hash: root.hash().as_u64(),
+    }
+  }
+
   pub fn unwrap(self) -> MetadataBlob {
     let SharedMetadataBlob(_, inner) = self;
     inner
@@ -344,7 +404,13 @@ pub struct Metadata {
   pub all: Vec<(String, SharedMetadataBlob)>,
 }
 impl Metadata {
+  /// Uses the global `MetadataCache`, if enabled.
   pub fn new(src: CrateSource) -> Result<Metadata, MetadataLoadingError> {
+    Metadata::new_with_cache(src, MetadataCache::global())
+  }
+  pub fn new_with_cache(src: CrateSource, cache: Option<&MetadataCache>)
+    -> Result<Metadata, MetadataLoadingError>
+  {
     use std::fs::{File};
 
     use goblin::Object;
@@ -356,21 +422,16 @@ impl Metadata {
     };
 
     match Object::parse(&src_buffer)? {
-      Object::Elf(elf) => Metadata::new_elf(src, &src_buffer, elf),
-      Object::PE(pe) => Metadata::new_pe(src, &src_buffer, pe),
-
-      // TODO?
-      _ => panic!("TODO: can't load from this object format: {}", src.as_path().display()),
+      Object::Elf(elf) => Metadata::new_elf(src, &src_buffer, elf, cache),
+      Object::PE(pe) => Metadata::new_pe(src, &src_buffer, pe, cache),
+      _ => Err(MetadataLoadingError::UnsupportedObjectFormat(src.to_path_buf())),
     }
   }
 
-  fn new_elf(src: CrateSource, src_buffer: &Mmap, object: Elf)
+  fn new_elf(src: CrateSource, src_buffer: &Mmap, object: Elf,
+             cache: Option<&MetadataCache>)
     -> Result<Metadata, MetadataLoadingError>
   {
-    use std::io::{Read};
-
-    use crate::rustc_data_structures::rayon::prelude::*;
-
     let mut metadata_section = None;
     for section_header in object.section_headers.iter() {
       if section_header.sh_type == 0 { continue; }
@@ -411,7 +472,7 @@ impl Metadata {
       }
     }
 
-    let all: Vec<_> = syms.into_par_iter()
+    let compressed: Vec<_> = syms.into_iter()
       .map(|(sym, name)| {
         let start = sym.st_value as usize;
         let end = (sym.st_value + sym.st_size) as usize;
@@ -448,33 +509,17 @@ impl Metadata {
             .map_err(|_| MetadataLoadingError::Header)?
         };
         let comp_end = pos + comp_len;
-        let compressed_bytes = &region[pos..comp_end];
-
-        let mut inflated = Vec::new();
-        FrameDecoder::new(compressed_bytes)
-          .read_to_end(&mut inflated)
-          .map_err(|e| {
-            MetadataLoadingError::Deflate(src.as_path().into(), name.to_string(),
-                                          e)
-          })?;
-
-        Ok((name.to_string(), SharedMetadataBlob::new(inflated)))
+        Ok((name, &region[pos..comp_end]))
       })
       .collect::<Result<Vec<_>, MetadataLoadingError>>()?;
 
-    Ok(Metadata {
-      src,
-      owner_index: owner_index.unwrap(),
-      all,
-    })
+    let owner_index = owner_index.ok_or(MetadataLoadingError::Header)?;
+    Metadata::inflate(src, owner_index, compressed, cache)
   }
-  fn new_pe(src: CrateSource, src_buffer: &Mmap, object: PE)
+  fn new_pe(src: CrateSource, src_buffer: &Mmap, object: PE,
+            cache: Option<&MetadataCache>)
     -> Result<Metadata, MetadataLoadingError>
   {
-    use std::io::{Read};
-
-    use crate::rustc_data_structures::rayon::prelude::*;
-
     let metadata_section = object.sections.iter()
       .find(|section| {
         match section.name() {
@@ -543,19 +588,49 @@ impl Metadata {
       all_compressed.push((sym_name, compressed_bytes));
     }
 
-    let all = all_compressed.into_par_iter()
+    let compressed = all_compressed.into_iter()
+      .map(|(name, bytes)| (name.to_string(), bytes) )
+      .collect();
+    Metadata::inflate(src, owner_index, compressed, cache)
+  }
+  /// Inflate every crate's metadata, or, if this object has been loaded before, map it from
+  /// `cache` instead. Inflated metadata is written back to `cache`.
+  fn inflate(src: CrateSource, owner_index: usize, compressed: Vec<(String, &[u8])>,
+             cache: Option<&MetadataCache>)
+    -> Result<Metadata, MetadataLoadingError>
+  {
+    use std::io::{Read};
+
+    use crate::rustc_data_structures::rayon::prelude::*;
+
+    if let Some(cache) = cache {
+      let symbols = compressed.iter().map(|&(ref name, _)| &name[..] );
+      if let Some(all) = cache.load_object(src.as_path(), symbols) {
+        return Ok(Metadata {
+          src,
+          owner_index,
+          all,
+        });
+      }
+    }
+
+    let all = compressed.into_par_iter()
       .map(|(name, compressed_bytes)| {
         let mut inflated = Vec::new();
         FrameDecoder::new(compressed_bytes)
           .read_to_end(&mut inflated)
           .map_err(|e| {
-            MetadataLoadingError::Deflate(src.as_path().into(), name.into(), e)
+            MetadataLoadingError::Deflate(src.as_path().into(), name.clone(), e)
           })?;
 
-        Ok((name.to_string(), SharedMetadataBlob::new(inflated)))
+        Ok((name, SharedMetadataBlob::new(inflated)))
       })
       .collect::<Result<Vec<_>, MetadataLoadingError>>()?;
 
+    if let Some(cache) = cache {
+      cache.store_object(src.as_path(), &all);
+    }
+
     Ok(Metadata {
       src,
       owner_index,
@@ -566,6 +641,13 @@ impl Metadata {
   pub fn owner_blob(&self) -> &MetadataBlob {
     &self.all[self.owner_index].1
   }
+  pub fn owner_name(&self) -> CrateNameHash {
+    let root = self.owner_blob().get_root();
+    CrateNameHash {
+      name: root.name(),
+      hash: root.hash().as_u64(),
+    }
+  }
 }
 
 #[cfg(not(target_os = "macos"))]
@@ -588,27 +670,51 @@ impl MetadataLoader for DummyMetadataLoader {
     Err("this should never be called".into())
   }
 }
-pub(crate) type LoadedCrateMetadata = Box<[Metadata]>;
+pub(crate) type LoadedCrateMetadata = Vec<Metadata>;
 
-/// Loads the Rust metadata for all linked crates. This data isn't light;
-/// you'll probably want to store it somewhere and reuse it a lot.
+/// Loads the Rust metadata for all crates in the objects currently mapped into this process.
+/// This data isn't light; you'll probably want to store it somewhere and reuse it a lot.
 pub(crate) fn context_metadata()
   -> Result<LoadedCrateMetadata, Box<dyn Error + Send + Sync + 'static>>
 {
-  use crate::platform::os::{dylib_search_paths, self_exe_path};
+  let mut sources = mapped_sources()?;
+  if crate::utils::env::metadata_search_paths() {
+    sources.extend(search_path_sources());
+  }
+
+  let mut out = Vec::new();
+  merge_metadata(&mut out, sources)?;
+  Ok(out)
+}
+
+/// The executable and every shared object mapped into this process, including those
+/// `dlopen`ed after startup.
+pub(crate) fn mapped_sources() -> Result<BTreeSet<CrateSource>, io::Error> {
+  use crate::platform::os::{mapped_objects, self_exe_path};
+
+  let mut mapped = BTreeSet::new();
+  mapped.insert(CrateSource::Mapped(self_exe_path()?.canonicalize()?));
+  for path in mapped_objects()? {
+    // the object could have been unloaded since we read the maps.
+    if let Ok(path) = path.canonicalize() {
+      mapped.insert(CrateSource::Mapped(path));
+    }
+  }
+
+  Ok(mapped)
+}
+/// Every dylib in `LD_LIBRARY_PATH`. Slow on hosts with large library dirs, so only used when
+/// `GEOBACTER_METADATA_SEARCH_PATHS` is set, to find dependencies which aren't themselves
+/// linked (ie all of their used symbols were inlined into their dependents).
+fn search_path_sources() -> BTreeSet<CrateSource> {
+  use crate::platform::os::dylib_search_paths;
   use crate::rustc_data_structures::rayon::prelude::*;
 
-  use std::collections::BTreeSet;
   use std::env::consts::DLL_EXTENSION;
   use std::ffi::OsStr;
   use std::path::Component;
 
-  let this = CrateSource::Mapped(self_exe_path()?.canonicalize()?);
-  let mut mapped = BTreeSet::new();
-  mapped.insert(this);
-  let mut unique_metadata = new_hash_set();
-
-  let search_mapped = dylib_search_paths()
+  dylib_search_paths()
     .into_par_iter()
     .flat_map(|search_dir| {
       search_dir.read_dir()
@@ -637,34 +743,64 @@ pub(crate) fn context_metadata()
         })
         .unwrap_or_default()
     })
-    .map(CrateSource::SearchPaths);
-  mapped.par_extend(search_mapped);
+    .map(CrateSource::SearchPaths)
+    .collect()
+}
+
+/// Loads the metadata of every object in `sources` which isn't already in `into`. Objects
+/// without metadata, and objects owned by a crate we've already loaded, are skipped. Returns
+/// the number of objects added.
+pub(crate) fn merge_metadata<I>(into: &mut LoadedCrateMetadata, sources: I)
+  -> Result<usize, MetadataLoadingError>
+  where I: IntoIterator<Item = CrateSource>,
+{
+  let mut loaded = new_hash_set();
+  loaded.extend(into.iter().map(|md| md.src.to_path_buf() ));
 
-  let mapped = mapped.into_iter() // XXX using .into_par_iter() causes a deadlock...
-    .filter_map(|mapped| {
-      match Metadata::new(mapped) {
+  let mapped = sources.into_iter() // XXX using .into_par_iter() causes a deadlock...
+    .filter(|src| !loaded.contains(&**src) )
+    .filter_map(|src| {
+      match Metadata::new(src) {
         Err(MetadataLoadingError::SectionMissing) => { None },
         v => Some(v),
       }
     })
     .collect::<Vec<_>>();
 
-  let mut out_metadata = Vec::with_capacity(mapped.len());
+  let mut added = 0;
   for metadata in mapped.into_iter() {
-    let metadata = metadata?;
-
-    {
-      let owner = metadata.owner_blob();
-      let owner = owner.get_root();
-      let name = CrateNameHash {
-        name: owner.name(),
-        hash: owner.hash().as_u64(),
-      };
-      if !unique_metadata.insert(name) { continue; }
+    if insert_metadata(into, metadata?) {
+      added += 1;
     }
+  }
 
-    out_metadata.push(metadata);
+  Ok(added)
+}
+/// Returns false if `metadata`'s owning crate was already loaded.
+pub(crate) fn insert_metadata(into: &mut LoadedCrateMetadata, metadata: Metadata) -> bool {
+  let name = metadata.owner_name();
+  if into.iter().any(|md| md.owner_name() == name ) {
+    return false;
   }
 
-  Ok(out_metadata.into_boxed_slice())
+  into.push(metadata);
+  true
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn unsupported_object_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("not-an-object");
+    std::fs::write(&path, &[0u8; 64][..]).unwrap();
+
+    match Metadata::new_with_cache(CrateSource::Mapped(path.clone()), None) {
+      Err(MetadataLoadingError::UnsupportedObjectFormat(p)) => assert_eq!(p, path),
+      Err(err) => panic!("unexpected error: {}", err),
+      Ok(_) => panic!("loaded metadata from garbage"),
+    }
+  }
 }
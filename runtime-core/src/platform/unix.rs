@@ -16,3 +16,56 @@ pub fn dylib_search_paths() -> Vec<PathBuf> {
   let paths = var_os("LD_LIBRARY_PATH").unwrap_or("".into());
   split_paths(&paths).collect()
 }
+
+/// Every file mapped executable into this process, ie the executable and every shared object
+/// loaded by `ld.so` or `dlopen`ed since, as listed in `/proc/self/maps`.
+pub fn mapped_objects() -> Result<Vec<PathBuf>, IoError> {
+  use std::collections::BTreeSet;
+  use std::fs::read_to_string;
+
+  const P: &'static str = "/proc/self/maps";
+
+  let maps = read_to_string(P)?;
+  let objects: BTreeSet<_> = parse_maps(&maps)
+    .map(PathBuf::from)
+    .collect();
+  Ok(objects.into_iter().collect())
+}
+/// Yields the path of every executable file backed mapping. Objects which have been replaced
+/// on disk since they were mapped are skipped; their path no longer refers to what is mapped.
+fn parse_maps<'a>(maps: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+  maps.lines()
+    .filter_map(|line| {
+      // address perms offset dev inode pathname
+      let mut fields = line.splitn(6, ' ');
+      let perms = fields.nth(1)?;
+      if !perms.contains('x') { return None; }
+      let path = fields.nth(3)?.trim_start();
+      if !path.starts_with('/') || path.ends_with(" (deleted)") { return None; }
+      Some(path)
+    })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parse_proc_maps() {
+    let maps = "\
+55d0c0a00000-55d0c0a21000 r--p 00000000 fd:01 1234                       /usr/bin/app
+55d0c0a21000-55d0c0b00000 r-xp 00021000 fd:01 1234                       /usr/bin/app
+7f0000000000-7f0000100000 r-xp 00000000 fd:01 42                         /opt/lib/lib plugin.so
+7f0000200000-7f0000300000 r--p 00000000 fd:01 43                         /usr/lib/locale/locale-archive
+7f0000300000-7f0000400000 r-xp 00000000 fd:01 44                         /tmp/old.so (deleted)
+7f0000400000-7f0000401000 rw-p 00000000 00:00 0 
+7ffd00000000-7ffd00002000 r-xp 00000000 00:00 0                          [vdso]";
+    let paths: Vec<_> = parse_maps(maps).collect();
+    assert_eq!(paths, ["/usr/bin/app", "/opt/lib/lib plugin.so"]);
+  }
+  #[test]
+  fn mapped_objects_has_self() {
+    let exe = self_exe_path().unwrap();
+    assert!(mapped_objects().unwrap().contains(&exe));
+  }
+}
@@ -0,0 +1,575 @@
+//! Choosing accelerators by configuration, instead of in code.
+//!
+//! A spec is a comma separated list of entries, eg `GEOBACTER_DEVICES="hsa:gfx90a:*,vk:0"`.
+//! Each entry is a backend name, or `*` for every backend, followed by `:` separated filters:
+//!
+//! * `*` matches any device;
+//! * an integer `n` matches the `n`th device of the backend which matches the entry's other
+//!   filters;
+//! * `key=pattern` matches devices whose property `key` matches `pattern`;
+//! * any other `pattern` matches devices with any property matching `pattern`.
+//!
+//! Patterns are case insensitive, and `*` in a pattern matches any substring. Devices are
+//! ordered by the first entry which matches them, and then by their order in their backend.
+//! An empty spec selects every device.
+//!
+//! Backends describe their devices by registering a `DeviceEnumerator`, usually with
+//! `register_enumerator_on_load!`, so linking a backend is enough to select its devices.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::{Accelerator, };
+use crate::context::Context;
+use crate::utils::{HashMap, HashSet, new_hash_map, new_hash_set, };
+
+type BoxError = Box<dyn Error + Send + Sync + 'static>;
+
+/// Lists and creates the devices of a backend.
+pub trait DeviceEnumerator: Send + Sync + 'static {
+  /// The name of the backend in specs, eg `hsa`.
+  fn backend(&self) -> &str;
+
+  /// Describe every device of this backend, without creating them. A device's index must be
+  /// its position in the returned list.
+  fn devices(&self) -> Result<Vec<DeviceInfo>, BoxError>;
+  /// Create the device at `index` in `ctx`.
+  fn open(&self, ctx: &Context, index: usize) -> Result<Arc<dyn Accelerator>, BoxError>;
+
+  /// The index of `accel` in `devices()`, or `None` if it isn't one of this backend's
+  /// devices.
+  fn index_of(&self, accel: &dyn Accelerator) -> Option<usize>;
+  /// Add backend specific properties of `accel`, eg its name, to `info`.
+  fn properties(&self, _accel: &dyn Accelerator, _info: &mut DeviceInfo) { }
+}
+
+static ENUMERATORS: RwLock<Vec<Arc<dyn DeviceEnumerator>>> =
+  parking_lot::const_rwlock(Vec::new());
+
+/// Register `e` for use by `DeviceSelector::new`, replacing any enumerator previously
+/// registered for the same backend. Enumerators are kept sorted by backend name, so the
+/// order of devices doesn't depend on the order backends were registered in.
+pub fn register_enumerator(e: Arc<dyn DeviceEnumerator>) {
+  let mut all = ENUMERATORS.write();
+  all.retain(|prev| prev.backend() != e.backend() );
+  let pos = all.iter()
+    .position(|prev| prev.backend() > e.backend() )
+    .unwrap_or(all.len());
+  all.insert(pos, e);
+}
+
+/// Call `register_enumerator($e)` when the crate using this is loaded, before `main`.
+/// Backends should also register when they create a device, in case the linker dropped
+/// the static this expands to.
+#[macro_export]
+macro_rules! register_enumerator_on_load {
+  ($e:expr) => {
+    #[used]
+    #[cfg_attr(any(target_os = "linux", target_os = "android"),
+               link_section = ".init_array")]
+    #[cfg_attr(target_os = "macos", link_section = "__DATA,__mod_init_func")]
+    #[cfg_attr(windows, link_section = ".CRT$XCU")]
+    static GEOBACTER_REGISTER_ENUMERATOR: extern "C" fn() = {
+      extern "C" fn register() {
+        $crate::select::register_enumerator(::std::sync::Arc::new($e));
+      }
+      register
+    };
+  };
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceInfo {
+  pub backend: String,
+  pub index: usize,
+  /// Property names are lower case.
+  pub properties: Vec<(String, String)>,
+}
+impl DeviceInfo {
+  pub fn new(backend: &str, index: usize) -> Self {
+    DeviceInfo {
+      backend: backend.into(),
+      index,
+      properties: vec![],
+    }
+  }
+  /// Describe an existing accelerator, with the `platform`, `arch` and `triple` properties
+  /// taken from `Accelerator::platform()` and its `AcceleratorTargetDesc`.
+  pub fn from_accel(backend: &str, index: usize, accel: &dyn Accelerator) -> Self {
+    let desc = accel.accel_target_desc();
+    let mut info = DeviceInfo::new(backend, index);
+    if let Some(platform) = accel.platform() {
+      info.set("platform", format!("{:?}", platform));
+    }
+    info.set("arch", desc.target.options.cpu.clone());
+    info.set("triple", desc.target.llvm_target.clone());
+    info
+  }
+
+  pub fn with<V>(mut self, key: &str, value: V) -> Self
+    where V: Into<String>,
+  {
+    self.set(key, value);
+    self
+  }
+  pub fn set<V>(&mut self, key: &str, value: V)
+    where V: Into<String>,
+  {
+    let key = key.to_lowercase();
+    let value = value.into();
+    match self.properties.iter_mut().find(|&&mut (ref k, _)| k == &key ) {
+      Some(&mut (_, ref mut v)) => { *v = value; },
+      None => { self.properties.push((key, value)); },
+    }
+  }
+  pub fn get(&self, key: &str) -> Option<&str> {
+    let key = key.to_lowercase();
+    self.properties.iter()
+      .find(|&&(ref k, _)| k == &key )
+      .map(|&(_, ref v)| &v[..] )
+  }
+}
+
+#[derive(Debug)]
+pub enum DeviceSelectError {
+  Parse(String, &'static str),
+  /// No enumerator is registered for a backend named in the spec.
+  UnknownBackend(String),
+  Enumerate(String, BoxError),
+  Open(DeviceInfo, BoxError),
+}
+impl Error for DeviceSelectError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      &DeviceSelectError::Enumerate(_, ref e) |
+      &DeviceSelectError::Open(_, ref e) => Some(&**e),
+      _ => None,
+    }
+  }
+}
+impl fmt::Display for DeviceSelectError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &DeviceSelectError::Parse(ref entry, msg) => {
+        write!(f, "invalid device spec entry `{}`: {}", entry, msg)
+      },
+      &DeviceSelectError::UnknownBackend(ref backend) => {
+        write!(f, "no device enumerator for backend `{}`", backend)
+      },
+      &DeviceSelectError::Enumerate(ref backend, ref e) => {
+        write!(f, "failed to list {} devices: {}", backend, e)
+      },
+      &DeviceSelectError::Open(ref info, ref e) => {
+        write!(f, "failed to open {} device {}: {}", info.backend, info.index, e)
+      },
+    }
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Filter {
+  Any,
+  Index(usize),
+  Property(Option<String>, String),
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct SpecEntry {
+  backend: String,
+  filters: Vec<Filter>,
+}
+impl SpecEntry {
+  fn matches_backend(&self, backend: &str) -> bool {
+    glob_match(&self.backend, backend)
+  }
+  /// Ignores index filters.
+  fn matches_properties(&self, info: &DeviceInfo) -> bool {
+    self.filters.iter().all(|filter| {
+      match filter {
+        &Filter::Any | &Filter::Index(_) => true,
+        &Filter::Property(Some(ref key), ref pattern) => {
+          info.get(key)
+            .map(|v| glob_match(pattern, v) )
+            .unwrap_or(false)
+        },
+        &Filter::Property(None, ref pattern) => {
+          info.properties.iter()
+            .any(|&(_, ref v)| glob_match(pattern, v) )
+        },
+      }
+    })
+  }
+  fn matches_index(&self, nth: usize) -> bool {
+    self.filters.iter().all(|filter| {
+      match filter {
+        &Filter::Index(n) => n == nth,
+        _ => true,
+      }
+    })
+  }
+}
+
+/// A parsed device spec.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceSpec {
+  entries: Vec<SpecEntry>,
+}
+impl DeviceSpec {
+  /// The spec in `GEOBACTER_DEVICES`, or the empty spec if it isn't set.
+  pub fn from_env() -> Result<Self, DeviceSelectError> {
+    match crate::utils::env::devices_spec() {
+      Some(spec) => Self::parse(&spec),
+      None => Ok(Default::default()),
+    }
+  }
+  pub fn parse(spec: &str) -> Result<Self, DeviceSelectError> {
+    let mut entries = vec![];
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty() ) {
+      let err = |msg| DeviceSelectError::Parse(entry.into(), msg);
+
+      let mut fields = entry.split(':').map(str::trim);
+      let backend = fields.next().unwrap().to_lowercase();
+      if backend.is_empty() {
+        return Err(err("missing backend"));
+      }
+
+      let mut filters = vec![];
+      for field in fields {
+        let filter = if field.is_empty() {
+          return Err(err("empty filter"));
+        } else if field == "*" {
+          Filter::Any
+        } else if let Ok(n) = field.parse() {
+          Filter::Index(n)
+        } else if let Some(eq) = field.find('=') {
+          let key = field[..eq].trim();
+          if key.is_empty() {
+            return Err(err("empty property name"));
+          }
+          Filter::Property(Some(key.to_lowercase()), field[eq + 1..].trim().into())
+        } else {
+          Filter::Property(None, field.into())
+        };
+        filters.push(filter);
+      }
+
+      entries.push(SpecEntry {
+        backend,
+        filters,
+      });
+    }
+
+    Ok(DeviceSpec {
+      entries,
+    })
+  }
+
+  pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+  /// Is `backend` named by any entry?
+  pub fn uses_backend(&self, backend: &str) -> bool {
+    self.is_empty() || self.entries.iter().any(|e| e.matches_backend(backend) )
+  }
+  /// Is `backend` named explicitly, ie not only matched by a pattern?
+  fn names_backend(&self, backend: &str) -> bool {
+    self.entries.iter().any(|e| e.backend == backend )
+  }
+
+  /// Returns the positions in `devices` which this spec selects, in order. Device indices
+  /// are counted per backend, in the order they appear in `devices`.
+  pub fn select(&self, devices: &[DeviceInfo]) -> Vec<usize> {
+    if self.is_empty() {
+      return (0..devices.len()).collect();
+    }
+
+    let mut out = vec![];
+    let mut seen: HashSet<usize> = new_hash_set();
+    for entry in self.entries.iter() {
+      // the number of devices of each backend this entry matched so far:
+      let mut nth: HashMap<&str, usize> = new_hash_map();
+      for (pos, info) in devices.iter().enumerate() {
+        if !entry.matches_backend(&info.backend) || !entry.matches_properties(info) {
+          continue;
+        }
+
+        let n = nth.entry(&info.backend[..]).or_insert(0);
+        let matched = entry.matches_index(*n);
+        *n += 1;
+        if matched && seen.insert(pos) {
+          out.push(pos);
+        }
+      }
+    }
+
+    out
+  }
+  /// Like `select`, but only returns the devices which have been opened, ie which have
+  /// `Some` value.
+  fn select_opened<T>(&self, mut devices: Vec<(DeviceInfo, Option<T>)>) -> Vec<T> {
+    let infos: Vec<_> = devices.iter().map(|&(ref info, _)| info.clone() ).collect();
+    self.select(&infos)
+      .into_iter()
+      .filter_map(|pos| devices[pos].1.take() )
+      .collect()
+  }
+}
+
+/// Selects devices from the registered enumerators, according to a `DeviceSpec`.
+pub struct DeviceSelector {
+  spec: DeviceSpec,
+  enumerators: Vec<Arc<dyn DeviceEnumerator>>,
+}
+impl DeviceSelector {
+  /// Use the spec in `GEOBACTER_DEVICES`, and the registered enumerators.
+  pub fn from_env() -> Result<Self, DeviceSelectError> {
+    Ok(Self::new(DeviceSpec::from_env()?))
+  }
+  /// Use the registered enumerators.
+  pub fn new(spec: DeviceSpec) -> Self {
+    Self::with_enumerators(spec, ENUMERATORS.read().clone())
+  }
+  pub fn with_enumerators(spec: DeviceSpec, enumerators: Vec<Arc<dyn DeviceEnumerator>>)
+    -> Self
+  {
+    DeviceSelector {
+      spec,
+      enumerators,
+    }
+  }
+
+  pub fn spec(&self) -> &DeviceSpec { &self.spec }
+
+  /// Describe the selected devices, in order, without creating them. Each is returned with
+  /// the position of its enumerator.
+  pub fn plan(&self) -> Result<Vec<(usize, DeviceInfo)>, DeviceSelectError> {
+    for entry in self.spec.entries.iter() {
+      let known = self.enumerators.iter()
+        .any(|e| entry.matches_backend(e.backend()) );
+      if entry.backend != "*" && !known {
+        return Err(DeviceSelectError::UnknownBackend(entry.backend.clone()));
+      }
+    }
+
+    let mut all = vec![];
+    for (ei, e) in self.enumerators.iter().enumerate() {
+      if !self.spec.uses_backend(e.backend()) { continue; }
+
+      let devices = match e.devices() {
+        Ok(devices) => devices,
+        // Every linked backend is registered, so don't fail `*` because, eg, there's
+        // no CUDA driver on this machine.
+        Err(err) if !self.spec.names_backend(e.backend()) => {
+          warn!("skipping {} devices: {}", e.backend(), err);
+          continue;
+        },
+        Err(err) => {
+          return Err(DeviceSelectError::Enumerate(e.backend().into(), err));
+        },
+      };
+      all.extend(devices.into_iter().map(|info| (ei, info) ));
+    }
+
+    let infos: Vec<_> = all.iter().map(|&(_, ref info)| info.clone() ).collect();
+    let selected = self.spec.select(&infos);
+    Ok(selected.into_iter().map(|pos| all[pos].clone() ).collect())
+  }
+  /// Create the selected devices in `ctx`, in order.
+  pub fn select(&self, ctx: &Context)
+    -> Result<Vec<Arc<dyn Accelerator>>, DeviceSelectError>
+  {
+    self.plan()?
+      .into_iter()
+      .map(|(ei, info)| {
+        self.enumerators[ei].open(ctx, info.index)
+          .map_err(|err| DeviceSelectError::Open(info, err) )
+      })
+      .collect()
+  }
+  /// Order the accelerators already in `ctx` by the spec, dropping those it doesn't select.
+  /// Accelerators no enumerator owns are ignored. Indices count every device of a backend,
+  /// opened or not, so a spec picks the same devices as `select` does.
+  pub fn filter_accels(&self, ctx: &Context)
+    -> Result<Vec<Arc<dyn Accelerator>>, Box<dyn Error>>
+  {
+    let accels = ctx.filter_accels(|_| true )?;
+
+    let mut devices = vec![];
+    for e in self.enumerators.iter() {
+      let owned = accels.iter()
+        .filter_map(|accel| e.index_of(&**accel).map(|idx| (idx, accel) ) )
+        .collect::<Vec<_>>();
+      if owned.is_empty() { continue; }
+
+      let infos = e.devices()
+        .map_err(|err| DeviceSelectError::Enumerate(e.backend().into(), err) )?;
+      for mut info in infos.into_iter() {
+        let accel = owned.iter()
+          .find(|&&(idx, _)| idx == info.index )
+          .map(|&(_, accel)| accel.clone() );
+        if let Some(ref accel) = accel {
+          let desc = DeviceInfo::from_accel(e.backend(), info.index, &**accel);
+          for (key, value) in desc.properties.into_iter() {
+            info.set(&key, value);
+          }
+          e.properties(&**accel, &mut info);
+        }
+        devices.push((info, accel));
+      }
+    }
+
+    Ok(self.spec.select_opened(devices))
+  }
+}
+
+/// Case insensitive; `*` matches any substring.
+fn glob_match(pattern: &str, s: &str) -> bool {
+  let pattern = pattern.to_lowercase();
+  let s = s.to_lowercase();
+
+  let mut parts = pattern.split('*');
+  let first = parts.next().unwrap();
+  if !s.starts_with(first) { return false; }
+  let mut rest = &s[first.len()..];
+
+  let parts: Vec<_> = parts.collect();
+  let last = match parts.split_last() {
+    // no wildcards.
+    None => { return rest.is_empty(); },
+    Some((last, middle)) => {
+      for part in middle {
+        match rest.find(part) {
+          Some(i) => { rest = &rest[i + part.len()..]; },
+          None => { return false; },
+        }
+      }
+      last
+    },
+  };
+  rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn gpu(backend: &str, index: usize, arch: &str) -> DeviceInfo {
+    DeviceInfo::new(backend, index)
+      .with("arch", arch)
+      .with("Name", format!("{} {}", backend, arch))
+  }
+  fn devices() -> Vec<DeviceInfo> {
+    vec![
+      gpu("hsa", 0, "gfx906"),
+      gpu("hsa", 1, "gfx90a"),
+      gpu("hsa", 2, "gfx90a"),
+      gpu("vk", 0, "radv"),
+      gpu("vk", 1, "llvmpipe"),
+    ]
+  }
+  fn select(spec: &str) -> Vec<usize> {
+    DeviceSpec::parse(spec).unwrap().select(&devices())
+  }
+
+  #[test]
+  fn glob() {
+    assert!(glob_match("gfx90a", "GFX90A"));
+    assert!(!glob_match("gfx90", "gfx90a"));
+    assert!(glob_match("gfx9*", "gfx90a"));
+    assert!(glob_match("*90*", "gfx90a"));
+    assert!(glob_match("g*x*a", "gfx90a"));
+    assert!(!glob_match("g*x*c", "gfx90a"));
+    assert!(glob_match("*", ""));
+  }
+
+  #[test]
+  fn parse() {
+    assert!(DeviceSpec::parse("").unwrap().is_empty());
+    assert!(DeviceSpec::parse(" , ").unwrap().is_empty());
+    assert!(DeviceSpec::parse(":0").is_err());
+    assert!(DeviceSpec::parse("hsa::0").is_err());
+    assert!(DeviceSpec::parse("hsa:=gfx90a").is_err());
+
+    let spec = DeviceSpec::parse("HSA:Arch=gfx9*:1, vk").unwrap();
+    assert_eq!(spec.entries, vec![
+      SpecEntry {
+        backend: "hsa".into(),
+        filters: vec![
+          Filter::Property(Some("arch".into()), "gfx9*".into()),
+          Filter::Index(1),
+        ],
+      },
+      SpecEntry {
+        backend: "vk".into(),
+        filters: vec![],
+      },
+    ]);
+    assert!(spec.uses_backend("vk") && !spec.uses_backend("cuda"));
+  }
+
+  #[test]
+  fn selection_order() {
+    assert_eq!(select(""), vec![0, 1, 2, 3, 4]);
+    assert_eq!(select("hsa:gfx90a:*,vk:0"), vec![1, 2, 3]);
+    assert_eq!(select("vk:1,hsa"), vec![4, 0, 1, 2]);
+    // indices count matching devices:
+    assert_eq!(select("hsa:gfx90a:1"), vec![2]);
+    assert_eq!(select("hsa:arch=gfx9*:0"), vec![0]);
+    assert_eq!(select("*:0"), vec![0, 3]);
+    // duplicates are dropped:
+    assert_eq!(select("hsa:2,hsa"), vec![2, 0, 1]);
+    assert_eq!(select("hsa:name=*llvmpipe"), Vec::<usize>::new());
+    assert_eq!(select("*:*llvmpipe"), vec![4]);
+    assert_eq!(select("cuda"), Vec::<usize>::new());
+  }
+
+  struct FakeEnumerator(&'static str, Vec<&'static str>);
+  impl DeviceEnumerator for FakeEnumerator {
+    fn backend(&self) -> &str { self.0 }
+    fn devices(&self) -> Result<Vec<DeviceInfo>, BoxError> {
+      Ok(self.1.iter().enumerate().map(|(i, &arch)| gpu(self.0, i, arch) ).collect())
+    }
+    fn open(&self, _: &Context, _: usize) -> Result<Arc<dyn Accelerator>, BoxError> {
+      Err("fake device".into())
+    }
+    fn index_of(&self, _: &dyn Accelerator) -> Option<usize> { None }
+  }
+
+  #[test]
+  fn plan() {
+    let enumerators: Vec<Arc<dyn DeviceEnumerator>> = vec![
+      Arc::new(FakeEnumerator("hsa", vec!["gfx906", "gfx90a"])),
+      Arc::new(FakeEnumerator("vk", vec!["radv"])),
+    ];
+    let selector = |spec| {
+      let spec = DeviceSpec::parse(spec).unwrap();
+      DeviceSelector::with_enumerators(spec, enumerators.clone())
+    };
+
+    let plan = selector("vk,hsa:gfx90a").plan().unwrap();
+    assert_eq!(plan, vec![(1, gpu("vk", 0, "radv")), (0, gpu("hsa", 1, "gfx90a"))]);
+
+    match selector("hsa,cuda:0").plan() {
+      Err(DeviceSelectError::UnknownBackend(b)) => assert_eq!(b, "cuda"),
+      r => panic!("unexpected result: {:?}", r),
+    }
+  }
+
+  /// Indices count unopened devices too.
+  #[test]
+  fn select_opened() {
+    let opened = |spec| {
+      let devices = devices()
+        .into_iter()
+        .enumerate()
+        .map(|(pos, info)| (info, if pos == 2 || pos == 4 { Some(pos) } else { None }) )
+        .collect();
+      DeviceSpec::parse(spec).unwrap().select_opened(devices)
+    };
+    assert_eq!(opened("hsa:0"), Vec::<usize>::new());
+    assert_eq!(opened("hsa:2"), vec![2]);
+    assert_eq!(opened("hsa:gfx90a:1,vk:1"), vec![2, 4]);
+    assert_eq!(opened(""), vec![2, 4]);
+  }
+}
@@ -0,0 +1,270 @@
+//! A process wide recorder of timed spans (codegen, kernel dispatches, copies, etc),
+//! which can be exported as Chrome trace event JSON. Load the output in
+//! `chrome://tracing` or https://ui.perfetto.dev.
+//!
+//! Nothing is recorded until `TraceRecorder::start` is called.
+
+use std::borrow::Cow;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufWriter, Write, };
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering, };
+use std::time::Instant;
+
+use parking_lot::Mutex;
+
+use crate::utils::{HashMap, new_hash_map, };
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceSpan {
+  pub name: Cow<'static, str>,
+  /// Eg "codegen" or "dispatch".
+  pub category: &'static str,
+  /// Spans with the same track are drawn in the same row.
+  pub track: Cow<'static, str>,
+  pub start: Instant,
+  pub end: Instant,
+  /// Extra key/value info shown when the span is selected.
+  pub args: Vec<(&'static str, String)>,
+}
+
+pub struct TraceRecorder {
+  /// Timestamps in the output are relative to this.
+  epoch: Instant,
+  recording: AtomicBool,
+  spans: Mutex<Vec<TraceSpan>>,
+}
+impl TraceRecorder {
+  pub fn new() -> Self {
+    TraceRecorder {
+      epoch: Instant::now(),
+      recording: AtomicBool::new(false),
+      spans: Mutex::new(Vec::new()),
+    }
+  }
+
+  /// The process wide recorder. Geobacter records into this one.
+  pub fn global() -> &'static TraceRecorder {
+    lazy_static::lazy_static! {
+      static ref GLOBAL: TraceRecorder = TraceRecorder::new();
+    }
+    &*GLOBAL
+  }
+
+  pub fn epoch(&self) -> Instant { self.epoch }
+
+  pub fn start(&self) {
+    self.recording.store(true, Ordering::Release);
+  }
+  pub fn stop(&self) {
+    self.recording.store(false, Ordering::Release);
+  }
+  #[inline(always)]
+  pub fn is_recording(&self) -> bool {
+    self.recording.load(Ordering::Acquire)
+  }
+
+  /// Does nothing if we're not recording.
+  pub fn record(&self, span: TraceSpan) {
+    if !self.is_recording() { return; }
+    self.spans.lock().push(span);
+  }
+  /// Like `record`, but `f` is only called if we're recording. Use this if building
+  /// the span isn't free.
+  pub fn record_with<F>(&self, f: F)
+    where F: FnOnce() -> TraceSpan,
+  {
+    if !self.is_recording() { return; }
+    self.record(f());
+  }
+
+  /// Remove and return every span recorded so far.
+  pub fn take(&self) -> Vec<TraceSpan> {
+    std::mem::replace(&mut *self.spans.lock(), Vec::new())
+  }
+  pub fn clear(&self) {
+    self.spans.lock().clear();
+  }
+
+  /// Write every span recorded so far. The spans are not removed.
+  pub fn write_chrome_trace<W>(&self, out: W) -> io::Result<()>
+    where W: Write,
+  {
+    let spans = self.spans.lock();
+    write_chrome_trace(self.epoch, &spans, std::process::id(), out)
+  }
+  pub fn save_chrome_trace<P>(&self, path: P) -> io::Result<()>
+    where P: AsRef<Path>,
+  {
+    let out = BufWriter::new(File::create(path)?);
+    self.write_chrome_trace(out)
+  }
+}
+impl Default for TraceRecorder {
+  fn default() -> Self { Self::new() }
+}
+
+fn escape_json(s: &str, out: &mut String) {
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if (c as u32) < 0x20 => {
+        write!(out, "\\u{:04x}", c as u32).unwrap();
+      },
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+}
+/// Microseconds, which is what the format expects.
+fn micros_since(epoch: Instant, t: Instant) -> f64 {
+  let d = t.checked_duration_since(epoch)
+    .unwrap_or_default();
+  d.as_nanos() as f64 / 1000.0
+}
+
+/// Write `spans` as a Chrome trace event JSON object. Every track is given its own
+/// thread id (named after the track) in process `pid`.
+pub fn write_chrome_trace<W>(epoch: Instant, spans: &[TraceSpan], pid: u32, mut out: W)
+  -> io::Result<()>
+  where W: Write,
+{
+  let mut tracks: HashMap<&str, usize> = new_hash_map();
+  let mut track_names: Vec<&str> = Vec::new();
+  for span in spans.iter() {
+    let next = tracks.len();
+    tracks.entry(&*span.track)
+      .or_insert_with(|| {
+        track_names.push(&span.track);
+        next
+      });
+  }
+
+  let mut events: Vec<String> = Vec::with_capacity(spans.len() + track_names.len() + 1);
+
+  let mut e = String::new();
+  write!(e, r#"{{"name":"process_name","ph":"M","pid":{},"args":{{"name":"geobacter"}}}}"#,
+         pid).unwrap();
+  events.push(e);
+  for (tid, name) in track_names.iter().enumerate() {
+    let mut e = String::new();
+    write!(e, r#"{{"name":"thread_name","ph":"M","pid":{},"tid":{},"args":{{"name":"#,
+           pid, tid).unwrap();
+    escape_json(name, &mut e);
+    e.push_str("}}");
+    events.push(e);
+  }
+
+  for span in spans.iter() {
+    let ts = micros_since(epoch, span.start);
+    let dur = (micros_since(epoch, span.end) - ts).max(0.0);
+
+    let mut e = String::new();
+    e.push_str(r#"{"name":"#);
+    escape_json(&span.name, &mut e);
+    e.push_str(r#","cat":"#);
+    escape_json(span.category, &mut e);
+    write!(e, r#","ph":"X","ts":{:.3},"dur":{:.3},"pid":{},"tid":{}"#,
+           ts, dur, pid, tracks[&*span.track]).unwrap();
+    if !span.args.is_empty() {
+      e.push_str(r#","args":{"#);
+      for (i, (k, v)) in span.args.iter().enumerate() {
+        if i != 0 { e.push(','); }
+        escape_json(k, &mut e);
+        e.push(':');
+        escape_json(v, &mut e);
+      }
+      e.push('}');
+    }
+    e.push('}');
+    events.push(e);
+  }
+
+  out.write_all(br#"{"displayTimeUnit":"ns","traceEvents":["#)?;
+  for (i, e) in events.iter().enumerate() {
+    if i != 0 { out.write_all(b",\n")?; }
+    out.write_all(e.as_bytes())?;
+  }
+  out.write_all(b"]}\n")?;
+  out.flush()
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use super::*;
+
+  fn span(name: &'static str, track: &'static str, start: Instant, us: u64) -> TraceSpan {
+    TraceSpan {
+      name: name.into(),
+      category: "test",
+      track: track.into(),
+      start,
+      end: start + Duration::from_micros(us),
+      args: vec![],
+    }
+  }
+
+  #[test]
+  fn not_recording() {
+    let r = TraceRecorder::new();
+    r.record(span("a", "t", r.epoch(), 1));
+    r.record_with(|| unreachable!() );
+    assert!(r.take().is_empty());
+
+    r.start();
+    r.record(span("a", "t", r.epoch(), 1));
+    r.stop();
+    r.record(span("b", "t", r.epoch(), 1));
+    assert_eq!(r.take().len(), 1);
+    assert!(r.take().is_empty());
+  }
+
+  #[test]
+  fn chrome_trace() {
+    let epoch = Instant::now();
+    let mut s = span("kernel \"1\"", "gpu 0", epoch + Duration::from_micros(10), 5);
+    s.args.push(("grid", "64x1x1".into()));
+    let spans = vec![
+      span("codegen", "codegen", epoch, 2500),
+      s,
+      span("copy", "gpu 0", epoch + Duration::from_micros(20), 1),
+    ];
+
+    let mut out = Vec::new();
+    write_chrome_trace(epoch, &spans, 42, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    assert!(out.starts_with(r#"{"displayTimeUnit":"ns","traceEvents":["#));
+    assert!(out.ends_with("]}\n"));
+    assert!(out.contains(r#"{"name":"thread_name","ph":"M","pid":42,"tid":0,"args":{"name":"codegen"}}"#));
+    assert!(out.contains(r#"{"name":"thread_name","ph":"M","pid":42,"tid":1,"args":{"name":"gpu 0"}}"#));
+    assert!(out.contains(r#"{"name":"codegen","cat":"test","ph":"X","ts":0.000,"dur":2500.000,"pid":42,"tid":0}"#));
+    assert!(out.contains(r#"{"name":"kernel \"1\"","cat":"test","ph":"X","ts":10.000,"dur":5.000,"pid":42,"tid":1,"args":{"grid":"64x1x1"}}"#));
+    assert!(out.contains(r#""name":"copy","cat":"test","ph":"X","ts":20.000,"dur":1.000,"pid":42,"tid":1}"#));
+  }
+
+  #[test]
+  fn before_epoch() {
+    let start = Instant::now();
+    let epoch = start + Duration::from_micros(100);
+    let mut out = Vec::new();
+    write_chrome_trace(epoch, &[span("early", "t", start, 50)], 1, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains(r#""ts":0.000,"dur":0.000"#));
+  }
+
+  #[test]
+  fn escaping() {
+    let mut out = String::new();
+    escape_json("a\\b\n\u{1}", &mut out);
+    assert_eq!(out, r#""a\\b\n\u0001""#);
+  }
+}
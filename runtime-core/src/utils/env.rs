@@ -2,10 +2,12 @@
 //! Debugging environmental variables
 
 use std::env::var;
+use std::path::{Path, PathBuf, };
 use std::sync::atomic::*;
 
 static USE_LLC: AtomicBool = AtomicBool::new(false);
 static OPT_REMARKS: AtomicBool = AtomicBool::new(false);
+static METADATA_SEARCH_PATHS: AtomicBool = AtomicBool::new(false);
 
 fn key(key: &str) -> String {
   format!("GEOBACTER_{}", key)
@@ -23,6 +25,7 @@ fn b(k: &str) -> bool {
 pub(crate) fn initialize() {
   USE_LLC.store(b("USE_LLC"), Ordering::Relaxed);
   OPT_REMARKS.store(b("OPT_REMARKS"), Ordering::Relaxed);
+  METADATA_SEARCH_PATHS.store(b("METADATA_SEARCH_PATHS"), Ordering::Relaxed);
 
   fence(Ordering::Release);
 }
@@ -33,3 +36,29 @@ pub fn use_llc() -> bool {
 pub fn print_opt_remarks() -> bool {
   OPT_REMARKS.load(Ordering::Acquire)
 }
+/// Also scan every dylib in `LD_LIBRARY_PATH` for crate metadata, not just the mapped objects.
+pub fn metadata_search_paths() -> bool {
+  METADATA_SEARCH_PATHS.load(Ordering::Acquire)
+}
+/// Where inflated crate metadata is cached: `GEOBACTER_METADATA_CACHE_DIR`, or
+/// `geobacter/metadata` in the user's cache dir. `GEOBACTER_METADATA_CACHE=0` disables the
+/// cache.
+pub fn metadata_cache_dir() -> Option<PathBuf> {
+  use std::env::var_os;
+
+  if var(key("METADATA_CACHE")).map(|v| v == "0" ).unwrap_or(false) {
+    return None;
+  }
+  if let Some(dir) = var_os(key("METADATA_CACHE_DIR")) {
+    return Some(dir.into());
+  }
+
+  let cache = var_os("XDG_CACHE_HOME")
+    .map(PathBuf::from)
+    .or_else(|| var_os("HOME").map(|home| Path::new(&home).join(".cache") ))?;
+  Some(cache.join("geobacter").join("metadata"))
+}
+/// The device spec in `GEOBACTER_DEVICES`; see `crate::select`.
+pub fn devices_spec() -> Option<String> {
+  var(key("DEVICES")).ok()
+}
@@ -0,0 +1,197 @@
+//! Marshalling of kernel arguments for `cuLaunchKernel`, which wants an array of
+//! pointers, one to each parameter's value.
+
+use std::mem::size_of;
+use std::os::raw::c_void;
+use std::slice;
+
+use crate::error::Error;
+use crate::ptx::KernelParam;
+
+/// Types whose values can be copied byte for byte into a kernel's params, ie `Copy`
+/// types without padding, so no uninitialized bytes are read. Implement this for
+/// `repr(C)` structs whose fields are all `KernelArg` and which have no padding
+/// between or after them; for other types, write the bytes out yourself and use
+/// `LaunchArgs::push_bytes`.
+pub unsafe trait KernelArg: Copy { }
+macro_rules! kernel_arg {
+  ($($ty:ty,)*) => {$(
+    unsafe impl KernelArg for $ty { }
+  )*};
+}
+kernel_arg! {
+  bool, u8, i8, u16, i16, u32, i32, u64, i64, u128, i128, usize, isize, f32, f64,
+}
+unsafe impl<T> KernelArg for *const T { }
+unsafe impl<T> KernelArg for *mut T { }
+macro_rules! kernel_arg_array {
+  ($($len:expr,)*) => {$(
+    unsafe impl<T> KernelArg for [T; $len]
+      where T: KernelArg,
+    { }
+  )*};
+}
+kernel_arg_array! {
+  1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+  17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32,
+}
+
+/// Argument values for a kernel launch. Values are pushed in order, one per PTX
+/// param, and must be exactly the size of that param.
+#[derive(Clone, Debug)]
+pub struct LaunchArgs {
+  /// u64 for alignment; params are never aligned to more than 16 bytes, and
+  /// CUDA copies the values out anyway.
+  storage: Vec<u64>,
+  /// Byte offset into `storage` and size of each param.
+  layout: Vec<(usize, usize)>,
+  pushed: usize,
+}
+
+impl LaunchArgs {
+  pub fn new(params: &[KernelParam]) -> Self {
+    let mut offset = 0usize;
+    let layout = params.iter()
+      .map(|param| {
+        let align = param.align.max(1) as usize;
+        offset = (offset + align - 1) / align * align;
+        let v = (offset, param.size as usize);
+        offset += param.size as usize;
+        v
+      })
+      .collect();
+
+    LaunchArgs {
+      storage: vec![0u64; (offset + 7) / 8],
+      layout,
+      pushed: 0,
+    }
+  }
+
+  pub fn len(&self) -> usize { self.layout.len() }
+  pub fn pushed(&self) -> usize { self.pushed }
+  pub fn is_complete(&self) -> bool { self.pushed == self.layout.len() }
+  /// Forget all the pushed values, so these args can be reused.
+  pub fn clear(&mut self) {
+    self.pushed = 0;
+  }
+
+  pub fn push<T>(&mut self, v: &T) -> Result<&mut Self, Error>
+    where T: KernelArg,
+  {
+    let bytes = unsafe {
+      slice::from_raw_parts(v as *const T as *const u8, size_of::<T>())
+    };
+    self.push_bytes(bytes)
+  }
+  pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<&mut Self, Error> {
+    let index = self.pushed;
+    let &(offset, size) = self.layout.get(index)
+      .ok_or(Error::KernelArgCount {
+        expected: self.layout.len(),
+        actual: index + 1,
+      })?;
+    if bytes.len() != size {
+      return Err(Error::KernelArgSize {
+        index,
+        expected: size,
+        actual: bytes.len(),
+      });
+    }
+
+    self.storage_bytes_mut()[offset..offset + size].copy_from_slice(bytes);
+    self.pushed += 1;
+    Ok(self)
+  }
+
+  fn storage_bytes_mut(&mut self) -> &mut [u8] {
+    unsafe {
+      slice::from_raw_parts_mut(self.storage.as_mut_ptr() as *mut u8,
+                                self.storage.len() * size_of::<u64>())
+    }
+  }
+
+  /// The `kernelParams` array for `cuLaunchKernel`. Errors if not every param has
+  /// a value. The pointers are valid until `self` is next modified.
+  pub fn param_ptrs(&mut self) -> Result<Vec<*mut c_void>, Error> {
+    if !self.is_complete() {
+      return Err(Error::KernelArgCount {
+        expected: self.layout.len(),
+        actual: self.pushed,
+      });
+    }
+
+    let base = self.storage.as_mut_ptr() as *mut u8;
+    Ok(self.layout.iter()
+      .map(|&(offset, _)| unsafe { base.add(offset) as *mut c_void } )
+      .collect())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn param(ty: &str, size: u32, align: u32) -> KernelParam {
+    KernelParam {
+      name: String::new(),
+      ty: ty.into(),
+      size,
+      align,
+    }
+  }
+
+  #[test]
+  fn marshal() {
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    struct Agg {
+      a: [f32; 4],
+      b: u64,
+    }
+    unsafe impl KernelArg for Agg { }
+
+    let params = [param("u32", 4, 4), param("u64", 8, 8), param("b8", 24, 8)];
+    let mut args = LaunchArgs::new(&params);
+    assert_eq!(args.len(), 3);
+    args.push(&7u32).unwrap()
+      .push(&0xdead_beef_0000_0001u64).unwrap()
+      .push(&Agg { a: [1.0, 2.0, 3.0, 4.0], b: 9, }).unwrap();
+
+    let ptrs = args.param_ptrs().unwrap();
+    assert_eq!(ptrs.len(), 3);
+    unsafe {
+      assert_eq!(*(ptrs[0] as *const u32), 7);
+      assert_eq!(ptrs[1] as usize % 8, 0);
+      assert_eq!(*(ptrs[1] as *const u64), 0xdead_beef_0000_0001);
+      let agg = *(ptrs[2] as *const Agg);
+      assert_eq!(agg.a, [1.0, 2.0, 3.0, 4.0]);
+      assert_eq!(agg.b, 9);
+    }
+  }
+
+  #[test]
+  fn wrong_size() {
+    let mut args = LaunchArgs::new(&[param("u64", 8, 8)]);
+    match args.push(&1u32) {
+      Err(Error::KernelArgSize { index: 0, expected: 8, actual: 4, }) => { },
+      r => panic!("unexpected {:?}", r.map(|_| () )),
+    }
+    args.push(&1u64).unwrap();
+    match args.push(&1u64) {
+      Err(Error::KernelArgCount { expected: 1, actual: 2, }) => { },
+      r => panic!("unexpected {:?}", r.map(|_| () )),
+    }
+  }
+
+  #[test]
+  fn incomplete() {
+    let mut args = LaunchArgs::new(&[param("u32", 4, 4), param("u32", 4, 4)]);
+    args.push(&1u32).unwrap();
+    assert!(args.param_ptrs().is_err());
+    args.push(&2u32).unwrap();
+    assert!(args.param_ptrs().is_ok());
+    args.clear();
+    assert!(args.param_ptrs().is_err());
+  }
+}
@@ -0,0 +1,44 @@
+//! Conditions for `#[geobacter_attr(..)]`:
+//!
+//! * `platform = "cuda"`
+
+use grt_core::codegen::attrs::*;
+
+use rustc_ast::ast::MetaItem;
+use rustc_middle::ty::TyCtxt;
+use rustc_span::symbol::Symbol;
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Condition {
+  Platform,
+  /// A condition for another platform. Never satisfied.
+  Foreign,
+}
+impl ConditionItem for Condition {
+  fn parse_name_value(tcx: TyCtxt, item: &MetaItem) -> Option<Self> {
+    if item.has_name(Symbol::intern("platform")) {
+      let v = value_str(tcx, item)?;
+      return Some(if v.as_str() == "cuda" {
+        Condition::Platform
+      } else {
+        Condition::Foreign
+      });
+    }
+    if is_known_condition_key(item) {
+      return Some(Condition::Foreign);
+    }
+
+    let msg = format!("unknown attr key `{}`; expected `platform`",
+                      item.name_or_empty());
+    tcx.sess.span_err(item.span, &msg);
+
+    None
+  }
+
+  fn satisfied_by(&self, root_conditions: &[Self]) -> bool {
+    match self {
+      Condition::Foreign => false,
+      _ => root_conditions.iter().any(|root_cond| root_cond == self ),
+    }
+  }
+}
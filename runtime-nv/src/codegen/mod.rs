@@ -0,0 +1,159 @@
+use std::env::var_os;
+use std::fs::File;
+use std::geobacter::platform::Platform;
+use std::io::{Write, Read};
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+
+use tracing::*;
+
+use serde::{Deserialize, Serialize, };
+
+use grt_core::AcceleratorTargetDesc;
+use grt_core::codegen::*;
+use grt_core::codegen::help::LlvmBuildRoot;
+use grt_core::codegen::products::*;
+
+use rustc_data_structures::sync::Lrc;
+use rustc_geobacter::intrinsics::IntrinsicName;
+use rustc_geobacter::intrinsics::platform::PlatformIntrinsic;
+use rustc_middle::mir::CustomIntrinsicMirGen;
+use rustc_middle::ty::{Instance, TyCtxt, };
+
+use crate::error::Error;
+use crate::ptx::{self, KernelParam, };
+
+pub mod attrs;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct CudaKernelDesc;
+impl PlatformKernelDesc for CudaKernelDesc { }
+
+/// Filled in during `post_codegen`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct CudaCodegenDesc {
+  /// The name of the `.entry` in the PTX. This can differ from the symbol.
+  pub entry: String,
+  pub params: Vec<KernelParam>,
+}
+impl PlatformCodegenDesc for CudaCodegenDesc { }
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CudaPlatformCodegen;
+
+impl PlatformCodegen for CudaPlatformCodegen {
+  type Device = super::CudaAccel;
+  type KernelDesc = CudaKernelDesc;
+  type CodegenDesc = CudaCodegenDesc;
+  type Condition = attrs::Condition;
+
+  fn insert_intrinsics<F>(&self, _: &Arc<AcceleratorTargetDesc>,
+                          into: &mut F)
+    where F: for<'a> FnMut(&'a str, Lrc<dyn CustomIntrinsicMirGen>),
+  {
+    let platform = PlatformIntrinsic(Platform::Cuda);
+    into(PlatformIntrinsic::NAME, Lrc::new(platform));
+  }
+
+  fn root<'tcx>(&self, desc: PKernelDesc<Self>,
+                instance: Instance<'tcx>,
+                _tcx: TyCtxt<'tcx>,
+                _dd: &DriverData<'tcx, Self>)
+    -> Result<PCodegenDesc<'tcx, Self>, Error>
+  {
+    Ok(CodegenDesc {
+      instance,
+      kernel_instance: desc.instance.into(),
+      spec_params: desc.spec_params,
+      platform_desc: Default::default(),
+    })
+  }
+  fn root_conditions<'tcx>(&self, _root: &PCodegenDesc<Self>,
+                           _tcx: TyCtxt<'tcx>,
+                           _dd: &DriverData<'tcx, Self>)
+    -> Result<Vec<Self::Condition>, Error>
+  {
+    Ok(vec![attrs::Condition::Platform])
+  }
+  fn pre_codegen<'tcx>(&self, _tcx: TyCtxt<'tcx>,
+                       _dd: &DriverData<'tcx, Self>)
+    -> Result<(), Error>
+  {
+    Ok(())
+  }
+  fn post_codegen(&self,
+                  target_desc: &Arc<AcceleratorTargetDesc>,
+                  tdir: &Path,
+                  codegen: &mut PCodegenResults<Self>)
+    -> Result<(), Error>
+  {
+    // The "object" is just bitcode (`obj_is_bitcode`); PTX is the assembly output.
+    let _ = codegen.take_object();
+    let ptx = if let Some(ptx) = codegen.assembly_str_ref() {
+      ptx.to_owned()
+    } else {
+      // fallback to invoking llc manually:
+
+      let bc = codegen.take_bitcode()
+        .ok_or(Error::MissingBitcode)?;
+
+      let linked_bc = tdir.join("linked.bc");
+      {
+        let mut out = File::create(&linked_bc)?;
+        out.write_all(&bc)?;
+      }
+
+      // be helpful if this var isn't set:
+      if var_os("RUST_BUILD_ROOT").is_none() &&
+        var_os("LLVM_BUILD").is_none() {
+        info!("Set either LLVM_BUILD or RUST_BUILD_ROOT \
+               (RUST_BUILD_ROOT takes priority)");
+      }
+
+      let llvm = LlvmBuildRoot::default();
+      let out = tdir.join("codegen.ptx");
+      let mut llc = Command::new(llvm.llc());
+      llc.current_dir(tdir)
+        .arg(&linked_bc)
+        .arg(format!("-mcpu={}", target_desc.target.options.cpu))
+        .arg(format!("-mattr={}", target_desc.target.options.features))
+        .arg("-O3")
+        .arg("-filetype=asm")
+        .arg("-o").arg(&out);
+      run_cmd(llc)?;
+
+      info!("finished running llc");
+
+      let mut ptx = String::new();
+      File::open(&out)?.read_to_string(&mut ptx)?;
+      ptx
+    };
+
+    let entries = ptx::parse_entries(&ptx)?;
+    for root in codegen.entries.iter_mut() {
+      let name = ptx::ptx_name(&root.symbol);
+      let entry = entries.iter()
+        .find(|entry| entry.name == name )
+        .ok_or_else(|| Error::MissingPtxEntry(name.clone()) )?;
+      debug!("PTX entry `{}` params: {:?}", name, entry.params);
+
+      root.platform.params = entry.params.clone();
+      root.platform.entry = name;
+    }
+
+    codegen.put_exe(ptx.into_bytes());
+
+    Ok(())
+  }
+}
+
+pub fn run_cmd(mut cmd: Command) -> Result<(), Error> {
+  info!("running command {:?}", cmd);
+  let mut child = cmd.spawn()?;
+  if !child.wait()?.success() {
+    Err(Error::Cmd(format!("command failed: {:?}", cmd).into()))
+  } else {
+    Ok(())
+  }
+}
@@ -0,0 +1,222 @@
+//! A minimal binding to the CUDA driver API. `libcuda` is loaded at runtime so this
+//! crate builds, and can generate PTX, on machines without the driver installed.
+
+use std::ffi::{CStr, };
+use std::os::raw::{c_char, c_int, c_uint, c_void, };
+use std::ptr;
+use std::sync::Arc;
+
+use libloading::Library;
+
+use crate::error::Error;
+
+pub type CUresult = c_int;
+pub type CUdevice = c_int;
+pub type CUcontext = *mut c_void;
+pub type CUmodule = *mut c_void;
+pub type CUfunction = *mut c_void;
+pub type CUstream = *mut c_void;
+
+const CUDA_SUCCESS: CUresult = 0;
+const CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR: c_int = 75;
+const CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR: c_int = 76;
+
+#[cfg(windows)]
+const LIBRARY: &str = "nvcuda.dll";
+#[cfg(not(windows))]
+const LIBRARY: &str = "libcuda.so.1";
+
+macro_rules! driver_fns {
+  ($($field:ident: $sym:literal fn($($arg:ty),*),)*) => {
+    pub struct Driver {
+      $($field: unsafe extern "C" fn($($arg),*) -> CUresult,)*
+      _lib: Library,
+    }
+    impl Driver {
+      fn load_from(lib: Library) -> Result<Self, Error> {
+        Ok(Driver {
+          $($field: unsafe {
+            *lib.get(concat!($sym, "\0").as_bytes())
+              .map_err(Error::DriverLoad)?
+          },)*
+          _lib: lib,
+        })
+      }
+    }
+  };
+}
+
+driver_fns! {
+  init: "cuInit" fn(c_uint),
+  device_get_count: "cuDeviceGetCount" fn(*mut c_int),
+  device_get: "cuDeviceGet" fn(*mut CUdevice, c_int),
+  device_get_attribute: "cuDeviceGetAttribute" fn(*mut c_int, c_int, CUdevice),
+  device_get_name: "cuDeviceGetName" fn(*mut c_char, c_int, CUdevice),
+  primary_ctx_retain: "cuDevicePrimaryCtxRetain" fn(*mut CUcontext, CUdevice),
+  primary_ctx_release: "cuDevicePrimaryCtxRelease" fn(CUdevice),
+  ctx_push_current: "cuCtxPushCurrent_v2" fn(CUcontext),
+  ctx_pop_current: "cuCtxPopCurrent_v2" fn(*mut CUcontext),
+  ctx_synchronize: "cuCtxSynchronize" fn(),
+  module_load_data: "cuModuleLoadData" fn(*mut CUmodule, *const c_void),
+  module_unload: "cuModuleUnload" fn(CUmodule),
+  module_get_function: "cuModuleGetFunction" fn(*mut CUfunction, CUmodule, *const c_char),
+  launch_kernel: "cuLaunchKernel" fn(CUfunction, c_uint, c_uint, c_uint,
+                                     c_uint, c_uint, c_uint, c_uint, CUstream,
+                                     *mut *mut c_void, *mut *mut c_void),
+}
+
+fn check(call: &'static str, code: CUresult) -> Result<(), Error> {
+  if code == CUDA_SUCCESS {
+    Ok(())
+  } else {
+    Err(Error::Cuda { call, code, })
+  }
+}
+
+impl Driver {
+  /// Load and initialize the driver.
+  pub fn load() -> Result<Arc<Self>, Error> {
+    let lib = Library::new(LIBRARY)
+      .map_err(Error::DriverLoad)?;
+    let driver = Self::load_from(lib)?;
+    check("cuInit", unsafe { (driver.init)(0) })?;
+    Ok(Arc::new(driver))
+  }
+
+  pub fn device_count(&self) -> Result<u32, Error> {
+    let mut count = 0;
+    check("cuDeviceGetCount", unsafe { (self.device_get_count)(&mut count) })?;
+    Ok(count as _)
+  }
+}
+
+/// A device and its retained primary context.
+pub struct CudaDevice {
+  driver: Arc<Driver>,
+  dev: CUdevice,
+  ctx: CUcontext,
+  pub ordinal: u32,
+  pub name: String,
+  pub compute_capability: (u32, u32),
+}
+// The primary context can be made current on any thread.
+unsafe impl Send for CudaDevice { }
+unsafe impl Sync for CudaDevice { }
+
+impl CudaDevice {
+  pub fn new(driver: &Arc<Driver>, ordinal: u32) -> Result<Self, Error> {
+    let mut dev = 0;
+    check("cuDeviceGet", unsafe { (driver.device_get)(&mut dev, ordinal as _) })?;
+
+    let attr = |attr| -> Result<u32, Error> {
+      let mut v = 0;
+      check("cuDeviceGetAttribute", unsafe {
+        (driver.device_get_attribute)(&mut v, attr, dev)
+      })?;
+      Ok(v as _)
+    };
+    let compute_capability = (attr(CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR)?,
+                              attr(CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR)?);
+
+    let mut name = [0 as c_char; 256];
+    check("cuDeviceGetName", unsafe {
+      (driver.device_get_name)(name.as_mut_ptr(), name.len() as _, dev)
+    })?;
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) }
+      .to_string_lossy()
+      .into_owned();
+
+    let mut ctx = ptr::null_mut();
+    check("cuDevicePrimaryCtxRetain", unsafe {
+      (driver.primary_ctx_retain)(&mut ctx, dev)
+    })?;
+
+    Ok(CudaDevice {
+      driver: driver.clone(),
+      dev,
+      ctx,
+      ordinal,
+      name,
+      compute_capability,
+    })
+  }
+
+  /// Run `f` with our context current on this thread.
+  fn with_ctx<F, R>(&self, f: F) -> Result<R, Error>
+    where F: FnOnce(&Driver) -> Result<R, Error>,
+  {
+    check("cuCtxPushCurrent", unsafe { (self.driver.ctx_push_current)(self.ctx) })?;
+    let r = f(&self.driver);
+    let mut popped = ptr::null_mut();
+    check("cuCtxPopCurrent", unsafe { (self.driver.ctx_pop_current)(&mut popped) })?;
+    r
+  }
+
+  /// `ptx` must be nul terminated.
+  pub fn load_module(self: &Arc<Self>, ptx: &CStr, entry: &CStr)
+    -> Result<LoadedModule, Error>
+  {
+    self.with_ctx(|driver| {
+      let mut module = ptr::null_mut();
+      check("cuModuleLoadData", unsafe {
+        (driver.module_load_data)(&mut module, ptx.as_ptr() as *const c_void)
+      })?;
+      let mut function = ptr::null_mut();
+      let r = check("cuModuleGetFunction", unsafe {
+        (driver.module_get_function)(&mut function, module, entry.as_ptr())
+      });
+      if let Err(err) = r {
+        unsafe { (driver.module_unload)(module); }
+        return Err(err);
+      }
+
+      Ok(LoadedModule {
+        dev: self.clone(),
+        module,
+        function,
+      })
+    })
+  }
+}
+impl Drop for CudaDevice {
+  fn drop(&mut self) {
+    unsafe { (self.driver.primary_ctx_release)(self.dev); }
+  }
+}
+
+pub struct LoadedModule {
+  dev: Arc<CudaDevice>,
+  module: CUmodule,
+  function: CUfunction,
+}
+unsafe impl Send for LoadedModule { }
+unsafe impl Sync for LoadedModule { }
+
+impl LoadedModule {
+  /// Launch on the default stream, then wait for the launch to finish.
+  pub unsafe fn launch_sync(&self, grid: [u32; 3], block: [u32; 3],
+                            shared_mem_bytes: u32,
+                            params: &mut [*mut c_void])
+    -> Result<(), Error>
+  {
+    self.dev.with_ctx(|driver| {
+      check("cuLaunchKernel", (driver.launch_kernel)(self.function,
+                                                     grid[0], grid[1], grid[2],
+                                                     block[0], block[1], block[2],
+                                                     shared_mem_bytes,
+                                                     ptr::null_mut(),
+                                                     params.as_mut_ptr(),
+                                                     ptr::null_mut()))?;
+      check("cuCtxSynchronize", (driver.ctx_synchronize)())
+    })
+  }
+}
+impl Drop for LoadedModule {
+  fn drop(&mut self) {
+    let module = self.module;
+    let _ = self.dev.with_ctx(|driver| {
+      unsafe { (driver.module_unload)(module); }
+      Ok(())
+    });
+  }
+}
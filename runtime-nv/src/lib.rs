@@ -1,11 +1,446 @@
+//! Crate for Nvidia/CUDA accelerators.
+//!
+//! Kernels are compiled to PTX with LLVM's NVPTX backend. The CUDA driver is loaded
+//! at runtime, if present; without it, `CudaAccel::offline` can still generate PTX
+//! for a given compute capability, eg in tests or to inspect codegen.
+//!
+//! ```ignore
+//! fn kernel(args: (*mut f32, u32)) { /* ... */ }
+//!
+//! let module = accel.compile_kernel(&kernel)?;
+//! let mut args = module.args();
+//! args.push(&dev_ptr)?.push(&len)?;
+//! unsafe { module.launch([len / 256, 1, 1], [256, 1, 1], 0, &mut args)?; }
+//! ```
+//!
+//! Kernel params are whatever LLVM lowered the kernel's arguments to, and are
+//! parsed from the PTX; see `CudaModule::params`.
 
-extern crate runtime_core as rt_core;
+#![feature(rustc_private)]
+#![feature(geobacter)]
 
-use std::os::raw::{c_int, };
+extern crate rustc_ast;
+extern crate rustc_data_structures;
+extern crate rustc_geobacter;
+extern crate rustc_middle;
+extern crate rustc_span;
+extern crate rustc_target;
 
-use rt_core::{Accelerator, AcceleratorId, };
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::ffi::CString;
+use std::fmt;
+use std::geobacter::kernel::OptionalKernelFn;
+use std::geobacter::platform::Platform;
+use std::sync::Arc;
+
+use grt_core::{AcceleratorId, Accelerator, AcceleratorTargetDesc, PlatformTargetDesc, Device};
+use grt_core::codegen::{CodegenDriver, KernelDesc};
+use grt_core::codegen::products::PCodegenResults;
+use grt_core::context::*;
+
+use rustc_target::spec::{*, abi::Abi};
+
+use serde::*;
+
+use crate::codegen::*;
+use crate::driver::{CudaDevice, Driver, };
+use crate::module::CudaModule;
+
+pub use crate::args::{KernelArg, LaunchArgs, };
+pub use crate::error::Error;
+
+pub mod args;
+pub mod codegen;
+pub mod driver;
+pub mod error;
+pub mod module;
+pub mod ptx;
+pub mod select;
+
+/// Eg `7.5` for `sm_75`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+pub struct ComputeCapability {
+  pub major: u32,
+  pub minor: u32,
+}
+impl ComputeCapability {
+  /// The newest SM our LLVM knows about. PTX is JIT compiled by the driver, so newer
+  /// devices can still run code generated for this.
+  pub const MAX_TARGET: ComputeCapability = ComputeCapability::new(8, 0);
+
+  pub const fn new(major: u32, minor: u32) -> Self {
+    ComputeCapability { major, minor, }
+  }
+
+  /// The SM we'll generate code for.
+  pub fn target(self) -> Self {
+    if self > Self::MAX_TARGET {
+      Self::MAX_TARGET
+    } else {
+      self
+    }
+  }
+  /// The `-mcpu` for LLVM, eg `sm_75`.
+  pub fn target_cpu(self) -> String {
+    let t = self.target();
+    format!("sm_{}{}", t.major, t.minor)
+  }
+  /// The lowest PTX ISA version which supports our target SM, as an LLVM feature.
+  pub fn ptx_feature(self) -> &'static str {
+    match self.target() {
+      ComputeCapability { major: 8, .. } => "+ptx70",
+      ComputeCapability { major: 7, minor: 5, } => "+ptx63",
+      ComputeCapability { major: 7, minor: 2, } => "+ptx61",
+      ComputeCapability { major: 7, .. } => "+ptx60",
+      _ => "+ptx50",
+    }
+  }
+}
+impl fmt::Display for ComputeCapability {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}.{}", self.major, self.minor)
+  }
+}
 
 pub struct CudaAccel {
+  id: AcceleratorId,
+  ctx: Context,
+  /// `None` for offline accelerators.
+  dev: Option<Arc<CudaDevice>>,
+  compute_capability: ComputeCapability,
+
+  target_desc: Arc<AcceleratorTargetDesc>,
+
+  self_codegen: Option<Arc<CodegenDriver<CudaPlatformCodegen>>>,
+}
+
+impl CudaAccel {
+  fn new_raw(id: AcceleratorId, ctx: &Context, dev: Option<Arc<CudaDevice>>,
+             compute_capability: ComputeCapability)
+    -> Result<Arc<Self>, Error>
+  {
+    let target_desc = AcceleratorTargetDesc::new(CudaTargetDesc {
+      sm: compute_capability.target(),
+    });
+    let mut out = CudaAccel {
+      id,
+      ctx: ctx.clone(),
+      dev,
+      compute_capability,
+      target_desc: Arc::new(target_desc),
+      self_codegen: None,
+    };
+    out.init_target_desc()?;
+
+    let mut out = Arc::new(out);
+
+    ctx.initialize_accel(&mut out)?;
+    select::CudaDeviceEnumerator::register_once();
+
+    Ok(out)
+  }
+  fn from_device(ctx: &Context, dev: CudaDevice) -> Result<Arc<Self>, Error> {
+    let (major, minor) = dev.compute_capability;
+    let cc = ComputeCapability::new(major, minor);
+    Self::new_raw(ctx.take_accel_id(), ctx, Some(Arc::new(dev)), cc)
+  }
+
+  /// Every CUDA device. Errors if the driver isn't installed.
+  pub fn all_devices(ctx: &Context) -> Result<Vec<Arc<Self>>, Error> {
+    let driver = Driver::load()?;
+    let mut out = vec![];
+    for ordinal in 0..driver.device_count()? {
+      out.push(Self::from_device(ctx, CudaDevice::new(&driver, ordinal)?)?);
+    }
+    Ok(out)
+  }
+  /// The CUDA device with ordinal `n`. Errors if the driver isn't installed.
+  pub fn nth_device(ctx: &Context, n: u32) -> Result<Arc<Self>, Error> {
+    let driver = Driver::load()?;
+    Self::from_device(ctx, CudaDevice::new(&driver, n)?)
+  }
+  /// An accelerator which can only generate PTX for `compute_capability`; no
+  /// driver or hardware is needed.
+  pub fn offline(ctx: &Context, compute_capability: ComputeCapability)
+    -> Result<Arc<Self>, Error>
+  {
+    Self::new_raw(ctx.take_accel_id(), ctx, None, compute_capability)
+  }
+
+  pub fn ctx(&self) -> &Context { &self.ctx }
+  pub fn compute_capability(&self) -> ComputeCapability { self.compute_capability }
+  pub fn is_offline(&self) -> bool { self.dev.is_none() }
+  pub fn name(&self) -> Option<&str> {
+    self.dev.as_ref().map(|dev| &dev.name[..] )
+  }
+  /// The device's ordinal, or `None` if we're offline.
+  pub fn ordinal(&self) -> Option<u32> {
+    self.dev.as_ref().map(|dev| dev.ordinal )
+  }
+
+  /// Compile `f` to PTX, and, unless we're offline, load it.
+  pub fn compile_kernel<F, A>(self: &Arc<Self>, f: &F) -> Result<Arc<CudaModule>, Error>
+    where F: Fn(A) + Sized,
+  {
+    let instance = f.kernel_instance();
+    let context_data = ModuleContextData::get(f)
+      .get_cache_data(&self.ctx);
+    let desc = KernelDesc {
+      instance,
+      spec_params: Default::default(),
+      platform_desc: CudaKernelDesc,
+    };
+
+    Ok(context_data.compile(self, desc, self.codegen(), cfg!(test))?)
+  }
+}
+
+impl Accelerator for CudaAccel {
+  #[inline(always)]
+  fn id(&self) -> AcceleratorId { self.id.clone() }
+
+  #[inline(always)]
+  fn platform(&self) -> Option<Platform> { Some(Platform::Cuda) }
+
+  #[inline(always)]
+  fn accel_target_desc(&self) -> &Arc<AcceleratorTargetDesc> {
+    &self.target_desc
+  }
+
+  fn set_accel_target_desc(&mut self, desc: Arc<AcceleratorTargetDesc>) {
+    self.target_desc = desc;
+  }
+
+  fn create_target_codegen(self: &mut Arc<Self>, ctxt: &Context)
+    -> Result<Arc<dyn Any + Send + Sync + 'static>, Box<dyn StdError + Send + Sync + 'static>>
+    where Self: Sized,
+  {
+    let cg = CodegenDriver::new(ctxt,
+                                self.accel_target_desc().clone(),
+                                Default::default())?;
+    let cg_sync = Arc::new(cg);
+    Arc::get_mut(self)
+      .expect("there should only be a single ref at this point")
+      .self_codegen = Some(cg_sync.clone());
+
+    cg_sync.add_accel(self);
+
+    Ok(cg_sync)
+  }
+
+  fn set_target_codegen(self: &mut Arc<Self>,
+                        codegen_comms: Arc<dyn Any + Send + Sync + 'static>)
+    where Self: Sized,
+  {
+    let cg = codegen_comms
+      .downcast()
+      .expect("unexpected codegen type?");
+
+    Arc::get_mut(self)
+      .expect("there should only be a single ref at this point")
+      .self_codegen = Some(cg);
+
+    self.codegen().add_accel(self);
+  }
+}
+
+impl Device for CudaAccel {
+  type Error = Error;
+  type Codegen = CudaPlatformCodegen;
+  type TargetDesc = CudaTargetDesc;
+  type ModuleData = CudaModule;
+
+  fn codegen(&self) -> &Arc<CodegenDriver<Self::Codegen>> {
+    self.self_codegen
+      .as_ref()
+      .expect("we are uninitialized?")
+  }
+
+  fn load_kernel(self: &Arc<Self>, codegen: &PCodegenResults<Self::Codegen>)
+    -> Result<Arc<Self::ModuleData>, Error>
+  {
+    let ptx = codegen.exe_ref().ok_or(Error::MissingPtx)?;
+    let ptx = CString::new(ptx)?;
+
+    if codegen.entries.len() != 1 {
+      return Err(Error::EntryCount(codegen.entries.len()));
+    }
+    let root = &codegen.root().platform;
+
+    let loaded = match self.dev {
+      Some(ref dev) => {
+        let entry = CString::new(root.entry.clone())?;
+        Some(dev.load_module(&ptx, &entry)?)
+      },
+      None => None,
+    };
+
+    Ok(Arc::new(CudaModule {
+      ptx,
+      entry: root.entry.clone(),
+      params: root.params.clone(),
+      loaded,
+    }))
+  }
+}
+impl fmt::Debug for CudaAccel {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("CudaAccel")
+      .field("id", &self.id)
+      .field("name", &self.name())
+      .field("compute_capability", &self.compute_capability)
+      .field("target_desc", &self.target_desc)
+      .finish()
+  }
+}
+
+// private methods
+impl CudaAccel {
+  fn init_target_desc(&mut self) -> Result<(), Error> {
+    use std::str::FromStr;
+
+    let cc = self.compute_capability;
+    let desc = Arc::get_mut(&mut self.target_desc).unwrap();
+
+    desc.allow_indirect_function_calls = false;
+
+    desc.kernel_abi = Abi::PtxKernel;
+    desc.target.target_endian = desc.host_target
+      .target_endian
+      .clone();
+
+    let target = &mut desc.target;
+    target.llvm_target = "nvptx64-nvidia-cuda".into();
+    target.target_pointer_width = "64".into();
+    target.arch = "nvptx64".into();
+    target.options.cpu = cc.target_cpu();
+    target.options.features = cc.ptx_feature().into();
+    target.data_layout = "e-i64:64-i128:128-v16:16-v32:32-n16:32:64".into();
+
+    target.options.panic_strategy = PanicStrategy::Abort;
+    target.options.trap_unreachable = true;
+    target.options.position_independent_executables = false;
+    target.options.dynamic_linking = false;
+    target.options.executables = true;
+    target.options.requires_lto = false;
+    target.options.atomic_cas = true;
+    target.options.max_atomic_width = Some(64);
+    target.options.default_codegen_units = Some(1);
+    // LLVM can't emit NVPTX objects; PTX comes from the assembly output.
+    target.options.obj_is_bitcode = true;
+    target.options.simd_types_indirect = false;
+    target.options.is_builtin = false;
+
+    {
+      let addr_spaces = &mut target.options.addr_spaces;
+      addr_spaces.clear();
+
+      let flat = AddrSpaceKind::Flat;
+      let flat_idx = AddrSpaceIdx(0);
+
+      let global = AddrSpaceKind::ReadWrite;
+      let global_idx = AddrSpaceIdx(1);
+
+      let local = AddrSpaceKind::from_str("local").unwrap();
+      let local_idx = AddrSpaceIdx(3);
+
+      // Not the `.const` space (4): it's limited to 64KiB.
+      let constant = AddrSpaceKind::ReadOnly;
+      let constant_idx = AddrSpaceIdx(1);
+
+      // NVPTX moves allocas into the `.local` space itself.
+      let private = AddrSpaceKind::Alloca;
+      let private_idx = AddrSpaceIdx(0);
+
+      let props = AddrSpaceProps {
+        index: flat_idx,
+        shared_with: vec![private.clone(),
+                          local.clone(),
+                          constant.clone(),
+                          global.clone(), ]
+          .into_iter()
+          .collect(),
+      };
+      addr_spaces.insert(flat.clone(), props);
+
+      let insert_as = |addr_spaces: &mut BTreeMap<_, _>, kind,
+                       idx| {
+        let props = AddrSpaceProps {
+          index: idx,
+          shared_with: vec![flat.clone()]
+            .into_iter()
+            .collect(),
+        };
+        addr_spaces.insert(kind, props);
+      };
+      insert_as(addr_spaces, global, global_idx);
+      insert_as(addr_spaces, local, local_idx);
+      insert_as(addr_spaces, constant, constant_idx);
+      insert_as(addr_spaces, private, private_idx);
+    }
+
+    Ok(())
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Hash)]
+pub struct CudaTargetDesc {
+  /// The SM we generate code for, which may be older than the device's.
+  pub sm: ComputeCapability,
+}
+
+impl PlatformTargetDesc for CudaTargetDesc {
+  fn as_any_hash(&self) -> &dyn any_key::AnyHash {
+    self
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn compute_capability() {
+    let cc = ComputeCapability::new(7, 5);
+    assert_eq!(cc.target_cpu(), "sm_75");
+    assert_eq!(cc.ptx_feature(), "+ptx63");
+    assert_eq!(cc.to_string(), "7.5");
+
+    let cc = ComputeCapability::new(6, 1);
+    assert_eq!(cc.target_cpu(), "sm_61");
+    assert_eq!(cc.ptx_feature(), "+ptx50");
+
+    // newer than we know about:
+    let cc = ComputeCapability::new(8, 6);
+    assert_eq!(cc.target_cpu(), "sm_80");
+    assert_eq!(cc.ptx_feature(), "+ptx70");
+  }
+
+  /// Doesn't need a driver or hardware.
+  #[test]
+  fn offline_ptx() {
+    fn kernel(args: (*mut u32, u32)) {
+      let (ptr, v) = args;
+      unsafe { *ptr = v; }
+    }
+
+    let ctx = Context::new().unwrap();
+    let accel = CudaAccel::offline(&ctx, ComputeCapability::new(7, 0)).unwrap();
+    let module = accel.compile_kernel(&kernel).unwrap();
+
+    assert!(!module.is_loaded());
+    assert!(module.ptx().contains(".target sm_70"), "{}", module.ptx());
+    assert!(module.ptx().contains(&format!(".entry {}(", module.entry())));
+
+    let size: u32 = module.params().iter().map(|p| p.size ).sum();
+    assert!(size >= 12, "{:?}", module.params());
 
-  device_id: c_int,
-}
\ No newline at end of file
+    let mut args = module.args();
+    match unsafe { module.launch([1, 1, 1], [1, 1, 1], 0, &mut args) } {
+      Err(Error::Offline) => { },
+      r => panic!("unexpected {:?}", r),
+    }
+  }
+}
@@ -0,0 +1,78 @@
+
+use std::ffi::CString;
+use std::fmt;
+
+use grt_core::context::PlatformModuleData;
+
+use crate::args::LaunchArgs;
+use crate::driver::LoadedModule;
+use crate::error::Error;
+use crate::ptx::KernelParam;
+
+/// A compiled kernel. Offline accelerators only have the PTX.
+pub struct CudaModule {
+  pub(crate) ptx: CString,
+  pub(crate) entry: String,
+  pub(crate) params: Vec<KernelParam>,
+  pub(crate) loaded: Option<LoadedModule>,
+}
+
+impl CudaModule {
+  pub fn ptx(&self) -> &str {
+    self.ptx.to_str().expect("non-utf8 PTX")
+  }
+  /// The name of the kernel's `.entry`.
+  pub fn entry(&self) -> &str { &self.entry }
+  /// The kernel's params, as lowered by LLVM. `LaunchArgs` values are pushed in
+  /// this order.
+  pub fn params(&self) -> &[KernelParam] { &self.params }
+  pub fn is_loaded(&self) -> bool { self.loaded.is_some() }
+
+  pub fn args(&self) -> LaunchArgs {
+    LaunchArgs::new(&self.params)
+  }
+
+  /// Launch `grid` blocks of `block` threads each, and wait for the kernel to finish.
+  ///
+  /// # Safety
+  ///
+  /// `args` are passed as is; any pointers in them must be valid device (or
+  /// mapped host) pointers for however the kernel uses them.
+  pub unsafe fn launch(&self, grid: [u32; 3], block: [u32; 3],
+                       shared_mem_bytes: u32, args: &mut LaunchArgs)
+    -> Result<(), Error>
+  {
+    let loaded = self.loaded.as_ref().ok_or(Error::Offline)?;
+    if grid.iter().chain(block.iter()).any(|&v| v == 0 ) {
+      return Err(Error::ZeroGridLaunchAxis);
+    }
+    let mut params = args.param_ptrs()?;
+    loaded.launch_sync(grid, block, shared_mem_bytes, &mut params)
+  }
+}
+impl Eq for CudaModule { }
+impl PartialEq for CudaModule {
+  fn eq(&self, rhs: &Self) -> bool {
+    self.ptx == rhs.ptx && self.entry == rhs.entry
+  }
+}
+impl fmt::Debug for CudaModule {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("CudaModule")
+      .field("entry", &self.entry)
+      .field("params", &self.params)
+      .field("loaded", &self.loaded.is_some())
+      .finish()
+  }
+}
+impl PlatformModuleData for CudaModule {
+  fn eq(&self, rhs: &dyn PlatformModuleData) -> bool {
+    let rhs: Option<&Self> = Self::downcast_ref(rhs);
+    if let Some(rhs) = rhs {
+      self == rhs
+    } else {
+      false
+    }
+  }
+}
+
@@ -0,0 +1,209 @@
+//! Just enough of a PTX parser to find kernel entry points and their parameters.
+//!
+//! LLVM lowers kernel arguments however the `ptx-kernel` ABI says to (eg scalar pairs
+//! can be split, and aggregates passed as `.b8` arrays), so the PTX is the only
+//! authoritative source for what `cuLaunchKernel` expects.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize, };
+
+/// A `.param` of a kernel entry point. Sizes are in bytes.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct KernelParam {
+  pub name: String,
+  /// The PTX type, without the leading `.`, eg `u64` or `b8`.
+  pub ty: String,
+  pub size: u32,
+  pub align: u32,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct PtxEntry {
+  pub name: String,
+  pub params: Vec<KernelParam>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PtxError {
+  pub entry: String,
+  pub msg: String,
+}
+impl fmt::Display for PtxError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "in PTX entry `{}`: {}", self.entry, self.msg)
+  }
+}
+impl std::error::Error for PtxError { }
+
+/// The name LLVM will give `symbol` in PTX. `.` isn't allowed in PTX identifiers,
+/// so the NVPTX backend replaces it with `_$_`.
+pub fn ptx_name(symbol: &str) -> String {
+  symbol.replace('.', "_$_")
+}
+
+/// The size of a fundamental PTX type, in bytes.
+fn type_size(ty: &str) -> Option<u32> {
+  Some(match ty {
+    "b8" | "u8" | "s8" | "pred" => 1,
+    "b16" | "u16" | "s16" | "f16" => 2,
+    "b32" | "u32" | "s32" | "f32" | "f16x2" => 4,
+    "b64" | "u64" | "s64" | "f64" => 8,
+    _ => return None,
+  })
+}
+
+/// Parse a single param declaration, eg `.param .align 8 .b8 foo_param_0[24]`.
+fn parse_param(entry: &str, decl: &str) -> Result<KernelParam, PtxError> {
+  let err = |msg: String| PtxError {
+    entry: entry.into(),
+    msg,
+  };
+
+  let mut tokens = decl.split_whitespace();
+  if tokens.next() != Some(".param") {
+    return Err(err(format!("expected `.param`, found `{}`", decl)));
+  }
+
+  let mut align = None;
+  let mut ty = None;
+  let mut name = None;
+  while let Some(token) = tokens.next() {
+    if token == ".align" {
+      let v = tokens.next()
+        .and_then(|v| v.parse::<u32>().ok() )
+        .ok_or_else(|| err(format!("bad `.align` in `{}`", decl)) )?;
+      // `.align`s after the type are for the pointee of `.ptr` params.
+      if ty.is_none() {
+        align = Some(v);
+      }
+    } else if let Some(t) = token.strip_prefix('.') {
+      if ty.is_none() && type_size(t).is_some() {
+        ty = Some(t);
+      }
+      // ignore `.ptr` and its state space
+    } else {
+      name = Some(token);
+    }
+  }
+
+  let ty = ty.ok_or_else(|| err(format!("no type in `{}`", decl)) )?;
+  let name = name.ok_or_else(|| err(format!("no name in `{}`", decl)) )?;
+  let (name, len) = match name.find('[') {
+    Some(idx) => {
+      let len = name[idx + 1..].strip_suffix(']')
+        .and_then(|len| len.parse::<u32>().ok() )
+        .ok_or_else(|| err(format!("bad array length in `{}`", decl)) )?;
+      (&name[..idx], len)
+    },
+    None => (name, 1),
+  };
+  let elem_size = type_size(ty).unwrap();
+
+  Ok(KernelParam {
+    name: name.into(),
+    ty: ty.into(),
+    size: elem_size * len,
+    align: align.unwrap_or(elem_size),
+  })
+}
+
+/// Find every `.entry` in `ptx`.
+pub fn parse_entries(ptx: &str) -> Result<Vec<PtxEntry>, PtxError> {
+  let mut out = vec![];
+
+  let mut rest = ptx;
+  while let Some(idx) = rest.find(".entry") {
+    rest = &rest[idx + ".entry".len()..];
+    // `.entry` must be followed by whitespace; ignore eg `.entry_foo` in comments.
+    if !rest.starts_with(char::is_whitespace) { continue; }
+
+    let open = rest.find('(')
+      .ok_or_else(|| PtxError {
+        entry: rest.split_whitespace().next().unwrap_or("").into(),
+        msg: "expected `(`".into(),
+      })?;
+    let name = rest[..open].trim().to_owned();
+    rest = &rest[open + 1..];
+    let close = rest.find(')')
+      .ok_or_else(|| PtxError {
+        entry: name.clone(),
+        msg: "expected `)`".into(),
+      })?;
+    let params = rest[..close]
+      .split(',')
+      .map(str::trim)
+      .filter(|decl| !decl.is_empty() )
+      .map(|decl| parse_param(&name, decl) )
+      .collect::<Result<Vec<_>, _>>()?;
+    rest = &rest[close + 1..];
+
+    out.push(PtxEntry {
+      name,
+      params,
+    });
+  }
+
+  Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const PTX: &str = r#"
+//
+// Generated by LLVM NVPTX Back-End
+//
+
+.version 6.3
+.target sm_70
+.address_size 64
+
+	// .globl	_ZN6kernel17h0123456789abcdefE
+.visible .entry _ZN6kernel17h0123456789abcdefE(
+	.param .u64 _ZN6kernel17h0123456789abcdefE_param_0,
+	.param .u32 _ZN6kernel17h0123456789abcdefE_param_1,
+	.param .align 8 .b8 _ZN6kernel17h0123456789abcdefE_param_2[24],
+	.param .u64 .ptr .global .align 4 _ZN6kernel17h0123456789abcdefE_param_3
+)
+{
+	ret;
+}
+
+	// .globl	empty
+.entry empty()
+{
+	ret;
+}
+"#;
+
+  #[test]
+  fn entries() {
+    let entries = parse_entries(PTX).unwrap();
+    assert_eq!(entries.len(), 2);
+
+    let k = &entries[0];
+    assert_eq!(k.name, "_ZN6kernel17h0123456789abcdefE");
+    let params: Vec<_> = k.params.iter()
+      .map(|p| (&p.ty[..], p.size, p.align) )
+      .collect();
+    assert_eq!(params, vec![("u64", 8, 8), ("u32", 4, 4), ("b8", 24, 8), ("u64", 8, 8)]);
+    assert_eq!(k.params[2].name, "_ZN6kernel17h0123456789abcdefE_param_2");
+
+    assert_eq!(entries[1].name, "empty");
+    assert_eq!(entries[1].params, vec![]);
+  }
+
+  #[test]
+  fn bad_param() {
+    let err = parse_entries(".entry k(.param .u32 x[4)").unwrap_err();
+    assert_eq!(err.entry, "k");
+  }
+
+  #[test]
+  fn names() {
+    assert_eq!(ptx_name("_ZN4core3ptr13drop_in_place17h12E.llvm.123"),
+               "_ZN4core3ptr13drop_in_place17h12E_$_llvm_$_123");
+  }
+}
@@ -0,0 +1,68 @@
+//! Selecting CUDA devices with `grt_core::select`, as backend `nv`.
+//!
+//! Devices are indexed by their CUDA ordinal, and have these properties: `name`,
+//! `compute_capability` (eg `7.5`) and `arch` (the SM we generate code for, eg
+//! `sm_75`). Offline accelerators aren't devices.
+//!
+//! The enumerator is registered when this crate is loaded, and again when the first
+//! `CudaAccel` is created.
+
+use std::error::Error as StdError;
+use std::sync::{Arc, Once, };
+
+use grt_core::Accelerator;
+use grt_core::context::Context;
+use grt_core::register_enumerator_on_load;
+use grt_core::select::{DeviceEnumerator, DeviceInfo, register_enumerator, };
+
+use crate::{CudaAccel, ComputeCapability, };
+use crate::driver::{CudaDevice, Driver, };
+
+type BoxError = Box<dyn StdError + Send + Sync + 'static>;
+
+register_enumerator_on_load!(CudaDeviceEnumerator);
+
+pub struct CudaDeviceEnumerator;
+impl CudaDeviceEnumerator {
+  /// Register with `grt_core::select`, for `DeviceSelector::new` and
+  /// `DeviceSelector::from_env`. This only needs to be called if our
+  /// `register_enumerator_on_load!` was dropped by the linker and no device has been
+  /// created yet.
+  pub fn register() {
+    register_enumerator(Arc::new(CudaDeviceEnumerator));
+  }
+  pub(crate) fn register_once() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(Self::register);
+  }
+}
+impl DeviceEnumerator for CudaDeviceEnumerator {
+  fn backend(&self) -> &str { "nv" }
+
+  fn devices(&self) -> Result<Vec<DeviceInfo>, BoxError> {
+    let driver = Driver::load()?;
+    let mut out = vec![];
+    for ordinal in 0..driver.device_count()? {
+      let dev = CudaDevice::new(&driver, ordinal)?;
+      let (major, minor) = dev.compute_capability;
+      let cc = ComputeCapability::new(major, minor);
+      let info = DeviceInfo::new(self.backend(), ordinal as usize)
+        .with("name", dev.name.clone())
+        .with("compute_capability", cc.to_string())
+        .with("arch", cc.target_cpu());
+      out.push(info);
+    }
+    Ok(out)
+  }
+  fn open(&self, ctx: &Context, index: usize)
+    -> Result<Arc<dyn Accelerator>, BoxError>
+  {
+    let dev = CudaAccel::nth_device(ctx, index as u32)?;
+    Ok(dev)
+  }
+
+  fn index_of(&self, accel: &dyn Accelerator) -> Option<usize> {
+    let accel = <CudaAccel as Accelerator>::downcast_ref(accel)?;
+    accel.ordinal().map(|ordinal| ordinal as usize )
+  }
+}
@@ -1,3 +1,11 @@
+//! Conditions for `#[geobacter_attr(..)]`:
+//!
+//! * `platform = "spirv"`
+//! * `capability = "Int64"`: a SPIR-V capability the kernel may declare.
+//! * `extension = "SPV_KHR_variable_pointers"`: a SPIR-V extension the kernel may use.
+//!
+//! Capabilities and extensions are derived from the target's features, which are set
+//! from the device's features.
 
 use grt_core::codegen::attrs::*;
 
@@ -7,20 +15,127 @@ use rustc_span::symbol::Symbol;
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub enum Condition {
-  /// XXX make more specific
   Platform,
+  Capability(String),
+  Extension(String),
+  /// A condition for another platform. Never satisfied.
+  Foreign,
+}
+impl Condition {
+  /// The capability and extension conditions implied by `features`, eg
+  /// `"+shader,+i64"`.
+  pub fn from_features(features: &str) -> Vec<Self> {
+    let mut out = vec![];
+    let mut add = |caps: &[&str], ext: Option<&str>| {
+      for &cap in caps.iter() {
+        let cap = Condition::Capability(cap.into());
+        if !out.contains(&cap) {
+          out.push(cap);
+        }
+      }
+      if let Some(ext) = ext {
+        let ext = Condition::Extension(ext.into());
+        if !out.contains(&ext) {
+          out.push(ext);
+        }
+      }
+    };
+
+    for feature in features.split(',').filter_map(|f| f.strip_prefix('+') ) {
+      match feature {
+        "shader" => add(&["Shader"], None),
+        "variable-pointers" => {
+          add(&["VariablePointers", "VariablePointersStorageBuffer"],
+              Some("SPV_KHR_variable_pointers"));
+        },
+        "variable-pointers-storage-buffer" => {
+          add(&["VariablePointersStorageBuffer"], Some("SPV_KHR_variable_pointers"));
+        },
+        "i8" => add(&["Int8"], None),
+        "i16" => add(&["Int16"], None),
+        "i64" => add(&["Int64"], None),
+        "f16" => add(&["Float16"], None),
+        "f64" => add(&["Float64"], None),
+        "i64-atomics" => add(&["Int64Atomics"], None),
+        "storage-uniform-i8-access" => {
+          add(&["UniformAndStorageBuffer8BitAccess", "StorageBuffer8BitAccess"],
+              Some("SPV_KHR_8bit_storage"));
+        },
+        "storage-buffer-i8-access" => {
+          add(&["StorageBuffer8BitAccess"], Some("SPV_KHR_8bit_storage"));
+        },
+        "storage-uniform-i16-access" => {
+          add(&["StorageUniform16", "StorageBuffer16BitAccess"],
+              Some("SPV_KHR_16bit_storage"));
+        },
+        "storage-buffer-i16-access" => {
+          add(&["StorageBuffer16BitAccess"], Some("SPV_KHR_16bit_storage"));
+        },
+        "storage-input-output-i16-access" => {
+          add(&["StorageInputOutput16"], Some("SPV_KHR_16bit_storage"));
+        },
+        "physical-storage-buffer-addresses" => {
+          add(&["PhysicalStorageBufferAddresses"],
+              Some("SPV_KHR_physical_storage_buffer"));
+        },
+        _ => { },
+      }
+    }
+
+    out
+  }
 }
 impl ConditionItem for Condition {
   fn parse_name_value(tcx: TyCtxt, item: &MetaItem) -> Option<Self> {
-    if item.has_name(Symbol::intern("platform")) &&
-      item.value_str().unwrap().as_str() == "spirv" {
-      return Some(Condition::Platform);
+    if item.has_name(Symbol::intern("platform")) {
+      let v = value_str(tcx, item)?;
+      return Some(if v.as_str() == "spirv" {
+        Condition::Platform
+      } else {
+        Condition::Foreign
+      });
     }
-    let msg = format!("unknown attr key `{}`; (no keys currently)",
+    if item.has_name(Symbol::intern("capability")) {
+      return Some(Condition::Capability(value_str(tcx, item)?.to_string()));
+    }
+    if item.has_name(Symbol::intern("extension")) {
+      return Some(Condition::Extension(value_str(tcx, item)?.to_string()));
+    }
+    if is_known_condition_key(item) {
+      return Some(Condition::Foreign);
+    }
+
+    let msg = format!("unknown attr key `{}`; expected one of `platform`, \
+                       `capability`, or `extension`",
                       item.name_or_empty());
     tcx.sess.span_err(item.span, &msg);
 
-
     None
   }
+
+  fn satisfied_by(&self, root_conditions: &[Self]) -> bool {
+    match self {
+      Condition::Foreign => false,
+      _ => root_conditions.iter().any(|root_cond| root_cond == self ),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn features() {
+    let conds = Condition::from_features("+shader,+i64,+variable-pointers,\
+                                          +variable-pointers-storage-buffer,-f64");
+    assert_eq!(conds, vec![
+      Condition::Capability("Shader".into()),
+      Condition::Capability("Int64".into()),
+      Condition::Capability("VariablePointers".into()),
+      Condition::Capability("VariablePointersStorageBuffer".into()),
+      Condition::Extension("SPV_KHR_variable_pointers".into()),
+    ]);
+    assert!(!Condition::Foreign.satisfied_by(&conds));
+  }
 }
@@ -0,0 +1,363 @@
+//! Checks that the Rust layout of types used in `Uniform`, `Buffer`, and
+//! `PushConstant` globals follows Vulkan's std140/std430 rules.
+//!
+//! We don't get to choose the layout: the host writes these types with Rust's
+//! layout, so the `Offset`/`ArrayStride` decorations we emit are Rust's. This just
+//! makes sure those decorations are legal for the block's layout rules (see
+//! "Offset and Stride Assignment" in the Vulkan spec), and explains what to change
+//! if they aren't. This works on `LayoutNode`s only, so it can be tested without a
+//! `TyCtxt`.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum LayoutRules {
+  /// `Uniform` blocks.
+  Std140,
+  /// `Buffer` blocks and push constants.
+  Std430,
+}
+impl fmt::Display for LayoutRules {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LayoutRules::Std140 => f.write_str("std140"),
+      LayoutRules::Std430 => f.write_str("std430"),
+    }
+  }
+}
+
+/// A type, as laid out by Rust. Sizes, offsets, and strides are in bytes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LayoutNode {
+  pub size: u64,
+  pub kind: LayoutKind,
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LayoutKind {
+  /// Integers and floats. Bools aren't allowed in blocks.
+  Scalar,
+  /// `repr(simd)` types.
+  Vector {
+    elem_size: u64,
+    len: u64,
+  },
+  Array {
+    elem: Box<LayoutNode>,
+    stride: u64,
+    /// `None` for runtime sized arrays.
+    len: Option<u64>,
+  },
+  Struct(Vec<LayoutField>),
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LayoutField {
+  pub name: String,
+  pub offset: u64,
+  pub node: LayoutNode,
+}
+
+impl LayoutNode {
+  pub fn scalar(size: u64) -> Self {
+    LayoutNode {
+      size,
+      kind: LayoutKind::Scalar,
+    }
+  }
+  pub fn vector(elem_size: u64, len: u64, size: u64) -> Self {
+    LayoutNode {
+      size,
+      kind: LayoutKind::Vector { elem_size, len, },
+    }
+  }
+  pub fn array(elem: LayoutNode, stride: u64, len: Option<u64>) -> Self {
+    LayoutNode {
+      size: stride * len.unwrap_or(0),
+      kind: LayoutKind::Array {
+        elem: Box::new(elem),
+        stride,
+        len,
+      },
+    }
+  }
+  pub fn structure(size: u64, fields: Vec<LayoutField>) -> Self {
+    LayoutNode {
+      size,
+      kind: LayoutKind::Struct(fields),
+    }
+  }
+
+  fn is_aggregate(&self) -> bool {
+    match self.kind {
+      LayoutKind::Array { .. } | LayoutKind::Struct(_) => true,
+      _ => false,
+    }
+  }
+
+  /// The base alignment (or extended alignment, for std140) required of this type.
+  pub fn alignment(&self, rules: LayoutRules) -> u64 {
+    let align = match self.kind {
+      LayoutKind::Scalar => return self.size.max(1),
+      LayoutKind::Vector { elem_size, len, } => {
+        return if len == 2 { elem_size * 2 } else { elem_size * 4 };
+      },
+      LayoutKind::Array { ref elem, .. } => elem.alignment(rules),
+      LayoutKind::Struct(ref fields) => {
+        fields.iter()
+          .map(|f| f.node.alignment(rules) )
+          .max()
+          .unwrap_or(1)
+      },
+    };
+    match rules {
+      LayoutRules::Std140 => round_up(align, 16),
+      LayoutRules::Std430 => align,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LayoutError {
+  /// Eg `PARAMS.lights[].color`.
+  pub path: String,
+  pub rules: LayoutRules,
+  pub msg: String,
+}
+impl fmt::Display for LayoutError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "`{}` violates {} layout rules: {}", self.path, self.rules, self.msg)
+  }
+}
+
+fn round_up(v: u64, align: u64) -> u64 {
+  (v + align - 1) / align * align
+}
+
+/// Check `root` against `rules`, returning every violation. `name` is used as the
+/// root of the error paths.
+pub fn validate(rules: LayoutRules, name: &str, root: &LayoutNode) -> Vec<LayoutError> {
+  let mut v = Validator {
+    rules,
+    errors: vec![],
+  };
+  v.node(name, root);
+  v.errors
+}
+
+struct Validator {
+  rules: LayoutRules,
+  errors: Vec<LayoutError>,
+}
+impl Validator {
+  fn err(&mut self, path: &str, msg: String) {
+    self.errors.push(LayoutError {
+      path: path.into(),
+      rules: self.rules,
+      msg,
+    });
+  }
+
+  fn node(&mut self, path: &str, node: &LayoutNode) {
+    match node.kind {
+      LayoutKind::Scalar | LayoutKind::Vector { .. } => { },
+      LayoutKind::Array { ref elem, stride, len, } => {
+        let align = node.alignment(self.rules);
+        if stride % align != 0 {
+          let msg = format!("array stride is {}, but must be a multiple of {}; \
+                             pad the element type to a multiple of {} bytes",
+                            stride, align, align);
+          self.err(path, msg);
+        } else if stride < elem.size {
+          let msg = format!("array stride {} is smaller than its element ({} bytes)",
+                            stride, elem.size);
+          self.err(path, msg);
+        }
+        if len == Some(0) {
+          self.err(path, "zero length arrays aren't allowed".into());
+        }
+        self.node(&format!("{}[]", path), elem);
+      },
+      LayoutKind::Struct(ref fields) => self.fields(path, fields),
+    }
+  }
+
+  fn fields(&mut self, path: &str, fields: &[LayoutField]) {
+    let mut sorted: Vec<_> = fields.iter().collect();
+    sorted.sort_by_key(|f| f.offset );
+
+    // the first offset the next member can use
+    let mut next = 0u64;
+    let mut prev: Option<&LayoutField> = None;
+    for (idx, field) in sorted.iter().enumerate() {
+      let fpath = format!("{}.{}", path, field.name);
+      let align = field.node.alignment(self.rules);
+
+      if let LayoutKind::Array { len: None, .. } = field.node.kind {
+        if idx + 1 != sorted.len() {
+          self.err(&fpath, "runtime arrays must be the last member".into());
+        }
+      }
+
+      if field.offset % align != 0 {
+        let msg = format!("offset is {}, but must be a multiple of {}",
+                          field.offset, align);
+        self.err(&fpath, msg);
+      } else if field.offset < next {
+        let prev = prev.unwrap();
+        let msg = format!("offset is {}, but must be at least {} because `{}` is a \
+                           {} and its end must be padded to {} bytes",
+                          field.offset, next, prev.name,
+                          if let LayoutKind::Array { .. } = prev.node.kind {
+                            "array"
+                          } else {
+                            "struct"
+                          },
+                          prev.node.alignment(self.rules));
+        self.err(&fpath, msg);
+      }
+
+      let end = field.offset + field.node.size;
+      next = if field.node.is_aggregate() {
+        round_up(end, align)
+      } else {
+        end
+      };
+      prev = Some(field);
+
+      self.node(&fpath, &field.node);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use LayoutRules::*;
+
+  fn field(name: &str, offset: u64, node: LayoutNode) -> LayoutField {
+    LayoutField {
+      name: name.into(),
+      offset,
+      node,
+    }
+  }
+  fn f32() -> LayoutNode { LayoutNode::scalar(4) }
+  fn vec3() -> LayoutNode { LayoutNode::vector(4, 3, 16) }
+  fn vec2() -> LayoutNode { LayoutNode::vector(4, 2, 8) }
+
+  #[test]
+  fn alignment() {
+    assert_eq!(f32().alignment(Std430), 4);
+    assert_eq!(f32().alignment(Std140), 4);
+    assert_eq!(vec2().alignment(Std430), 8);
+    assert_eq!(vec3().alignment(Std430), 16);
+    assert_eq!(LayoutNode::scalar(8).alignment(Std430), 8);
+
+    let arr = LayoutNode::array(f32(), 4, Some(4));
+    assert_eq!(arr.alignment(Std430), 4);
+    assert_eq!(arr.alignment(Std140), 16);
+
+    let s = LayoutNode::structure(8, vec![
+      field("a", 0, f32()),
+      field("b", 4, f32()),
+    ]);
+    assert_eq!(s.alignment(Std430), 4);
+    assert_eq!(s.alignment(Std140), 16);
+  }
+
+  #[test]
+  fn valid_struct() {
+    // struct { a: f32, b: vec2, c: vec3, d: [vec3; 2], }
+    let s = LayoutNode::structure(64, vec![
+      field("a", 0, f32()),
+      field("b", 8, vec2()),
+      field("c", 16, vec3()),
+      field("d", 32, LayoutNode::array(vec3(), 16, Some(2))),
+    ]);
+    assert_eq!(validate(Std430, "S", &s), vec![]);
+    assert_eq!(validate(Std140, "S", &s), vec![]);
+  }
+
+  #[test]
+  fn misaligned_member() {
+    // repr(C, packed) struct { a: f32, b: vec2 }
+    let s = LayoutNode::structure(12, vec![
+      field("a", 0, f32()),
+      field("b", 4, vec2()),
+    ]);
+    let errors = validate(Std430, "S", &s);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, "S.b");
+    assert!(errors[0].msg.contains("multiple of 8"), "{}", errors[0]);
+  }
+
+  #[test]
+  fn std140_array_stride() {
+    // [f32; 4]: fine in a buffer, not in a uniform.
+    let s = LayoutNode::structure(16, vec![
+      field("xs", 0, LayoutNode::array(f32(), 4, Some(4))),
+    ]);
+    assert_eq!(validate(Std430, "U", &s), vec![]);
+    let errors = validate(Std140, "U", &s);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, "U.xs");
+    assert!(errors[0].msg.contains("stride is 4"), "{}", errors[0]);
+  }
+
+  #[test]
+  fn padding_after_aggregate() {
+    let inner = LayoutNode::structure(4, vec![field("x", 0, f32())]);
+    // Rust puts `b` right after `a`; std140 wants it at 16.
+    let s = LayoutNode::structure(8, vec![
+      field("a", 0, inner.clone()),
+      field("b", 4, f32()),
+    ]);
+    assert_eq!(validate(Std430, "U", &s), vec![]);
+    let errors = validate(Std140, "U", &s);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, "U.b");
+    assert!(errors[0].msg.contains("at least 16"), "{}", errors[0]);
+  }
+
+  #[test]
+  fn reordered_fields() {
+    // Rust reordered these; that's fine as long as each offset is legal.
+    let s = LayoutNode::structure(16, vec![
+      field("small", 8, f32()),
+      field("big", 0, LayoutNode::scalar(8)),
+    ]);
+    assert_eq!(validate(Std430, "S", &s), vec![]);
+  }
+
+  #[test]
+  fn runtime_array() {
+    let rt = LayoutNode::array(f32(), 4, None);
+    let ok = LayoutNode::structure(4, vec![
+      field("len", 0, f32()),
+      field("data", 4, rt.clone()),
+    ]);
+    assert_eq!(validate(Std430, "B", &ok), vec![]);
+
+    let bad = LayoutNode::structure(4, vec![
+      field("data", 0, rt),
+      field("len", 0, f32()),
+    ]);
+    let errors = validate(Std430, "B", &bad);
+    assert!(errors.iter().any(|e| e.path == "B.data" ), "{:?}", errors);
+  }
+
+  #[test]
+  fn nested_paths() {
+    let light = LayoutNode::structure(12, vec![
+      field("pos", 0, vec2()),
+      field("power", 8, f32()),
+    ]);
+    let s = LayoutNode::structure(24, vec![
+      field("lights", 0, LayoutNode::array(light, 12, Some(2))),
+    ]);
+    let errors = validate(Std430, "L", &s);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, "L.lights");
+    assert!(errors[0].to_string().starts_with("`L.lights` violates std430"));
+  }
+}
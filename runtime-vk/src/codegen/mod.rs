@@ -24,13 +24,21 @@ use rustc_middle::middle::codegen_fn_attrs::*;
 use rustc_middle::mir::*;
 use rustc_middle::ty::*;
 use rustc_middle::ty::layout::{LayoutCx, TyAndLayout};
+use rustc_span::Span;
+use rustc_span::symbol::Symbol;
 use rustc_target::abi::{FieldsShape, Size};
 use rustc_target::spec::*;
 
 use crate::error::Error;
 use crate::module::*;
 
+use self::layout::{LayoutField, LayoutNode, LayoutRules, };
+
 pub mod attrs;
+pub mod layout;
+pub mod push_constant;
+pub mod spec_constant;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct CodegenShaderInterface {
   pub(crate) input: StaticShaderInterfaceDef,
@@ -89,10 +97,12 @@ impl PlatformCodegen for VkPlatformCodegen {
   }
   fn root_conditions<'tcx>(&self, _root: &PCodegenDesc<Self>,
                            _tcx: TyCtxt<'tcx>,
-                           _dd: &DriverData<'tcx, Self>)
+                           dd: &DriverData<'tcx, Self>)
     -> Result<Vec<Self::Condition>, Error>
   {
-    Ok(vec![attrs::Condition::Platform])
+    let mut out = vec![attrs::Condition::Platform];
+    out.extend(attrs::Condition::from_features(&dd.target_desc.target.options.features));
+    Ok(out)
   }
   fn pre_codegen<'tcx>(&self, _tcx: TyCtxt<'tcx>,
                        _dd: &DriverData<'tcx, Self>)
@@ -167,6 +177,9 @@ impl PlatformCodegen for VkPlatformCodegen {
       b
     };
 
+    let exe = lower_spec_constants(&exe)?;
+    check_push_constants(&exe, &codegen.entries)?;
+
     codegen.put_exe(exe);
 
     Ok(())
@@ -240,6 +253,83 @@ fn default_ty_spec() -> SpirVTypeSpec {
   SpirVTypeSpec::Struct(vec![])
 }
 
+/// The field a `repr(transparent)` type wraps, ie the one which isn't a ZST.
+fn transparent_field<'tcx>(lcx: &LayoutCx<'tcx, TyCtxt<'tcx>>, layout: TyAndLayout<'tcx>)
+  -> TyAndLayout<'tcx>
+{
+  (0..layout.fields.count())
+    .map(|idx| layout.field(lcx, idx).unwrap() )
+    .find(|field| !field.is_zst() )
+    .unwrap_or_else(|| layout.field(lcx, 0).unwrap() )
+}
+
+/// Convert Rust's layout of a block's type for `layout::validate`. Returns `None` if
+/// the type can't be used in a block; the error will have already been reported,
+/// either here or by `build_spirv_ty_metadata`.
+fn layout_node<'tcx>(tcx: TyCtxt<'tcx>, span: Span, layout: TyAndLayout<'tcx>)
+  -> Option<LayoutNode>
+{
+  let lcx = LayoutCx { tcx, param_env: ParamEnv::reveal_all(), };
+
+  let node = match *layout.ty.kind() {
+    Bool => {
+      tcx.sess.span_err(span, "`bool` can't be used in a `Uniform`, `Buffer`, \
+                               or `PushConstant`; use `u32` instead");
+      return None;
+    }
+    Char | Int(_) | Uint(_) | Float(_) => {
+      LayoutNode::scalar(layout.size.bytes())
+    }
+    Adt(adt_def, _) if adt_def.repr.simd() => {
+      let (elem_size, len) = match layout.fields {
+        FieldsShape::Array { stride, count, } => (stride.bytes(), count),
+        _ => unreachable!("{:#?}", layout),
+      };
+      LayoutNode::vector(elem_size, len, layout.size.bytes())
+    }
+    Adt(adt_def, _) if adt_def.repr.transparent() => {
+      return layout_node(tcx, span, transparent_field(&lcx, layout));
+    }
+    Adt(adt_def, _) if !adt_def.is_struct() => return None,
+    Tuple(_) | Adt(..) => {
+      let offsets = match layout.fields {
+        FieldsShape::Arbitrary { ref offsets, .. } => offsets,
+        _ => unreachable!("{:#?}", layout),
+      };
+      let mut fields = Vec::with_capacity(offsets.len());
+      for (idx, &offset) in offsets.iter().enumerate() {
+        let field = layout.field(&lcx, idx).unwrap();
+        if field.is_zst() { continue; }
+
+        let name = match *layout.ty.kind() {
+          Adt(adt_def, _) => adt_def.non_enum_variant().fields[idx].ident.to_string(),
+          _ => idx.to_string(),
+        };
+        fields.push(LayoutField {
+          name,
+          offset: offset.bytes(),
+          node: layout_node(tcx, span, field)?,
+        });
+      }
+      LayoutNode::structure(layout.size.bytes(), fields)
+    }
+    Array(..) | Slice(_) => {
+      let (stride, count) = match layout.fields {
+        FieldsShape::Array { stride, count, } => (stride.bytes(), count),
+        _ => unreachable!("{:#?}", layout),
+      };
+      let len = match *layout.ty.kind() {
+        Array(..) => Some(count),
+        _ => None,
+      };
+      let elem = layout_node(tcx, span, layout.field(&lcx, 0).unwrap())?;
+      LayoutNode::array(elem, stride, len)
+    }
+    _ => return None,
+  };
+  Some(node)
+}
+
 enum SpirvLangItemStorageClass {
   BuiltinInput,
   BuiltinOutput,
@@ -247,6 +337,8 @@ enum SpirvLangItemStorageClass {
   ShaderOutput,
   Buffer,
   Uniform,
+  PushConstant,
+  SpecConstant,
 }
 
 impl SpirvLangItemStorageClass {
@@ -267,6 +359,13 @@ impl SpirvLangItemStorageClass {
       SpirvLangItemStorageClass::Buffer => {
         AddrSpaceIdx(8)
       }
+      SpirvLangItemStorageClass::PushConstant => {
+        AddrSpaceIdx(10)
+      }
+      SpirvLangItemStorageClass::SpecConstant => {
+        // constant
+        AddrSpaceIdx(4)
+      }
     }
   }
 }
@@ -284,6 +383,11 @@ struct LangItems {
   shader_output: DefId,
   buffer: DefId,
   uniform: DefId,
+  /// Not a real lang item; `crate::push_constant::PushConstant` is a diagnostic
+  /// item. `None` if the crate being compiled doesn't depend on us.
+  push_constant: Option<DefId>,
+  /// Ditto, `crate::spec_constant::SpecConstant`.
+  spec_constant: Option<DefId>,
 }
 
 impl LangItems {
@@ -299,6 +403,10 @@ impl LangItems {
       shader_output: tcx.require_lang_item(LangItem::SpirvShaderOutput, None),
       buffer: tcx.require_lang_item(LangItem::SpirvBufferObject, None),
       uniform: tcx.require_lang_item(LangItem::SpirvUniformObject, None),
+      push_constant: tcx
+        .get_diagnostic_item(Symbol::intern("geobacter_spirv_push_constant")),
+      spec_constant: tcx
+        .get_diagnostic_item(Symbol::intern("geobacter_spirv_spec_constant")),
     }
   }
   fn get_item(&self, did: DefId) -> Option<SpirvLangItemStorageClass> {
@@ -320,6 +428,12 @@ impl LangItems {
     if self.uniform == did {
       return Some(SpirvLangItemStorageClass::Uniform);
     }
+    if self.push_constant == Some(did) {
+      return Some(SpirvLangItemStorageClass::PushConstant);
+    }
+    if self.spec_constant == Some(did) {
+      return Some(SpirvLangItemStorageClass::SpecConstant);
+    }
 
     None
   }
@@ -349,71 +463,72 @@ impl VkPlatformCodegen {
         // extract the inner type which this type wraps. This can be important
         // for wrappers which wrap SIMD types, for example SPIRV Vector and Matrix
         // types.
-        let field_ty = layout.field(&lcx, 0).unwrap();
+        let field_ty = transparent_field(&lcx, layout);
 
         warn!("repr(transparent): extracted {:#?}", field_ty);
         Self::build_spirv_ty_metadata(tcx, lang_items,
                                       inst, field_ty)
       }
+      Adt(adt_def, _) if !adt_def.is_struct() => {
+        let msg = format!("enums and unions can't be used in a SPIR-V interface: `{}`",
+                          layout.ty);
+        tcx.sess.span_err(tcx.def_span(inst.def_id()), &msg);
+        default_node()
+      }
       Tuple(_) | Adt(..) => {
-        // TODO: this is not complete, or even correct in all cases. Need to fix upstream things
-        //  first.
-        let with_padded_indices = move |count: usize,
-                                        iter: &mut dyn Iterator<Item = (Size, u32)>| {
-          let mut nodes: Vec<SpirVStructMember> = vec![];
-          nodes.reserve(count);
-          for (field_idx, (offset, padded_index)) in iter.enumerate() {
-            for _ in field_idx..(padded_index as usize) {
-              // TODO: offsets here
-              nodes.push(SpirVStructMember {
-                node: default_node(),
-                decorations: vec![],
-              });
-            }
-
-            let field = layout.field(&lcx, field_idx).unwrap();
-            let mut node = SpirVStructMember {
-              node: Self::build_spirv_ty_metadata(tcx, lang_items,
-                                                  inst, field),
-              decorations: vec![],
-            };
-            /*let offset_decor = || {
-              let mut field = field;
-              loop {
-                match field.ty {
-                  Adt(def, ..) if def.repr.transparent() => {
-                    field = layout.field(&lcx, 0).unwrap();
-                    continue;
-                  },
-                  Adt(def, ..) if def.repr.simd() => return true,
-                  // nested ADTs will already offset
-                  Tuple(_) | Adt(..) | Array(..) => return false,
-                  _ => return true,
-                }
-              }
-            };*/
-            node.decorations.push(("Offset".into(), vec![offset.bytes() as _]));
-            nodes.push(node);
-          }
-          // XXX trailing padding
-          nodes
+        let offsets = match layout.fields {
+          FieldsShape::Arbitrary { ref offsets, .. } => offsets,
+          _ => unreachable!("{:#?}", layout),
         };
-        let nodes = match layout.fields {
+        // The LLVM struct member for each field, and the number of LLVM members.
+        // These can differ from the field order: Rust can reorder fields, and
+        // padding is inserted as extra members.
+        let (count, indices) = match layout.fields {
           FieldsShape::Arbitrary {
-            padded_indices: Some((_count, ref indices)),
-            ref offsets, ..
+            padded_indices: Some((count, ref indices)), ..
           } => {
-            let _iter = offsets.iter().cloned().zip(indices.iter().cloned());
-            unimplemented!();
-            //with_padded_indices(count as usize, &mut iter)
+            (count as usize, &indices[..])
           }
-          FieldsShape::Arbitrary { padded_indices: None, ref offsets, .. } => {
-            let count = offsets.len();
-            with_padded_indices(count, &mut offsets.iter().cloned().zip(0..count as u32))
+          FieldsShape::Arbitrary { padded_indices: None, ref memory_index, .. } => {
+            (memory_index.len(), &memory_index[..])
           }
           _ => unreachable!("{:#?}", layout),
         };
 
+        let mut members: Vec<Option<(SpirVStructMember, Size)>> = (0..count)
+          .map(|_| None )
+          .collect();
+        for (field_idx, &offset) in offsets.iter().enumerate() {
+          let field = layout.field(&lcx, field_idx).unwrap();
+          let end = offset + field.size;
+          let mut node = SpirVStructMember {
+            node: Self::build_spirv_ty_metadata(tcx, lang_items,
+                                                inst, field),
+            decorations: vec![],
+          };
+          node.decorations.push(("Offset".into(), vec![offset.bytes() as _]));
+          members[indices[field_idx] as usize] = Some((node, end));
+        }
+
+        // Padding members start where the previous member ended. Trailing padding
+        // doesn't need a member (but gets one if LLVM has one): the `ArrayStride` of
+        // any array of this struct already accounts for it.
+        let mut end = Size::ZERO;
+        let nodes = members.into_iter()
+          .map(|member| {
+            match member {
+              Some((node, field_end)) => {
+                end = field_end;
+                node
+              },
+              None => SpirVStructMember {
+                node: default_node(),
+                decorations: vec![("Offset".into(), vec![end.bytes() as _])],
+              },
+            }
+          })
+          .collect();
+
         SpirVAttrNode {
           type_spec: SpirVTypeSpec::Struct(nodes),
           decorations: vec![],
@@ -435,12 +550,33 @@ impl VkPlatformCodegen {
         }
       }
 
-      _ => panic!("shouldn't be allowed or unimplemented TODO: {:?}", layout.ty),
+      _ => {
+        let msg = format!("unsupported type in a SPIR-V interface: `{}`", layout.ty);
+        tcx.sess.span_err(tcx.def_span(inst.def_id()), &msg);
+        default_node()
+      }
     };
 
     node
   }
 
+  /// Report every std140/std430 violation in the layout of the block `inst`.
+  fn check_block_layout<'tcx>(tcx: TyCtxt<'tcx>,
+                              inst: Instance<'tcx>,
+                              layout: TyAndLayout<'tcx>,
+                              rules: LayoutRules)
+  {
+    let span = tcx.def_span(inst.def_id());
+    let node = match layout_node(tcx, span, layout) {
+      Some(node) => node,
+      None => return,
+    };
+    let name = tcx.item_name(inst.def_id()).to_string();
+    for err in layout::validate(rules, &name, &node) {
+      tcx.sess.span_err(span, &err.to_string());
+    }
+  }
+
   fn build_spirv_metadata<'tcx>(tcx: TyCtxt<'tcx>,
                                 lang_items: &LangItems,
                                 inst: Instance<'tcx>)
@@ -483,12 +619,56 @@ impl VkPlatformCodegen {
         let set = consts.next().expect("expected constant param; got none");
         let binding = consts.next().expect("expected constant param; got none");
 
+        let rules = if adt_def.did == lang_items.uniform {
+          LayoutRules::Std140
+        } else {
+          LayoutRules::Std430
+        };
+        Self::check_block_layout(tcx, inst, layout, rules);
+
         attrs.pipeline_binding = Some(binding);
         attrs.pipeline_descriptor_set = Some(set);
 
         // These also need the block decoration:
         node.decorations.push(("Block".into(), vec![]));
       }
+      Adt(adt_def, _) if Some(adt_def.did) == lang_items.push_constant => {
+        // `PushConstant` is `repr(transparent)`, so `node` is already the inner type.
+        // No set/binding; the only offset is the one for the whole pipeline, which
+        // is always zero (see `StaticPipelineLayoutDesc::push_constants_range`).
+        attrs.storage_class = Some("PushConstant".into());
+        node.decorations.push(("Block".into(), vec![]));
+
+        Self::check_block_layout(tcx, inst, layout, LayoutRules::Std430);
+      }
+      Adt(adt_def, substs) if Some(adt_def.did) == lang_items.spec_constant => {
+        // Not a variable at all: this is emitted as an initialized `UniformConstant`
+        // variable (see `SpirvLangItemStorageClass::addr_space`), which
+        // `spec_constant::lower` then turns into an `OpSpecConstant`, using the
+        // static's initializer as the default.
+        let lcx = LayoutCx { tcx, param_env: reveal_all, };
+        let inner = layout.field(&lcx, 0).unwrap();
+        match inner.ty.kind() {
+          Bool | Int(_) | Uint(_) | Float(_) => { },
+          _ => {
+            let msg = format!("`SpecConstant` must be a scalar, not `{}`", inner.ty);
+            tcx.sess.span_err(tcx.def_span(inst.def_id()), &msg);
+          },
+        }
+
+        let substs = tcx
+          .subst_and_normalize_erasing_regions(inst.substs, reveal_all, &substs);
+        match substs.consts().next() {
+          Some(id) => {
+            let id = id.eval_bits(tcx, reveal_all, tcx.types.u32) as u32;
+            node.decorations.push(("SpecId".into(), vec![id as _]));
+          },
+          None => {
+            let msg = format!("`{}` is missing the `SpecConstant` ID", layout.ty);
+            tcx.sess.span_err(tcx.def_span(inst.def_id()), &msg);
+          },
+        }
+      }
       _ => {}
     }
 
@@ -498,6 +678,49 @@ impl VkPlatformCodegen {
   }
 }
 
+fn spirv_words(exe: &[u8]) -> Result<Vec<u32>, Error> {
+  if exe.len() % 4 != 0 {
+    return Err(spec_constant::SpecConstantError::Malformed.into());
+  }
+  Ok(exe.chunks_exact(4)
+    .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]) )
+    .collect())
+}
+
+/// Run `spec_constant::lower` on the SPIR-V module `exe`.
+fn lower_spec_constants(exe: &[u8]) -> Result<Vec<u8>, Error> {
+  let words = spirv_words(exe)?;
+  let mut out = Vec::with_capacity(exe.len());
+  for word in spec_constant::lower(&words)? {
+    out.extend_from_slice(&word.to_le_bytes());
+  }
+  Ok(out)
+}
+
+/// Check the push constant range of each entry's pipeline layout covers the
+/// `PushConstant` block in `exe`. Otherwise pipeline creation would fail (or worse,
+/// the driver reads past the pushed bytes).
+fn check_push_constants(exe: &[u8], entries: &[EntryDesc<VkEntryDesc>])
+  -> Result<(), Error>
+{
+  let kernel = push_constant::block_size(&spirv_words(exe)?)?;
+  for entry in entries.iter() {
+    let declared = entry.platform.pipeline.push_constants_size();
+    let ok = match kernel {
+      // `declared` is the size of the Rust type, so it includes any trailing padding.
+      Some(kernel) => declared >= kernel,
+      None => declared == 0,
+    };
+    if !ok {
+      return Err(Error::PushConstantsLayout {
+        declared,
+        kernel: kernel.unwrap_or_default(),
+      });
+    }
+  }
+  Ok(())
+}
+
 pub fn run_cmd(mut cmd: Command) -> Result<(), Error> {
   info!("running command {:?}", cmd);
   let mut child = cmd.spawn()?;
@@ -0,0 +1,196 @@
+//! Finds the size of the push constant block in a SPIR-V module, so it can be checked
+//! against the range in the pipeline layout. The layout intrinsic doesn't know about
+//! `crate::push_constant::PushConstant`, so the size in the layout is whatever the
+//! user passed to `StaticPipelineLayoutDesc::with_push_constants`.
+
+use std::collections::BTreeMap;
+
+use super::spec_constant::{Inst, SpecConstantError, parse, };
+
+const OP_TYPE_BOOL: u16 = 20;
+const OP_TYPE_INT: u16 = 21;
+const OP_TYPE_FLOAT: u16 = 22;
+const OP_TYPE_VECTOR: u16 = 23;
+const OP_TYPE_MATRIX: u16 = 24;
+const OP_TYPE_ARRAY: u16 = 28;
+const OP_TYPE_STRUCT: u16 = 30;
+const OP_TYPE_POINTER: u16 = 32;
+const OP_CONSTANT: u16 = 43;
+const OP_VARIABLE: u16 = 59;
+const OP_DECORATE: u16 = 71;
+const OP_MEMBER_DECORATE: u16 = 72;
+
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+
+struct Types<'a> {
+  defs: BTreeMap<u32, &'a Inst>,
+  array_strides: BTreeMap<u32, u32>,
+  /// `(struct, member) -> (offset, matrix stride)`
+  members: BTreeMap<(u32, u32), (Option<u32>, Option<u32>)>,
+}
+impl<'a> Types<'a> {
+  fn new(insts: &'a [Inst]) -> Result<Self, SpecConstantError> {
+    let mut types = Types {
+      defs: BTreeMap::new(),
+      array_strides: BTreeMap::new(),
+      members: BTreeMap::new(),
+    };
+    for inst in insts.iter() {
+      match inst.opcode {
+        OP_TYPE_BOOL..=OP_TYPE_MATRIX | OP_TYPE_ARRAY | OP_TYPE_STRUCT |
+        OP_TYPE_POINTER => {
+          types.defs.insert(inst.operand(0)?, inst);
+        },
+        OP_CONSTANT | OP_VARIABLE => {
+          types.defs.insert(inst.operand(1)?, inst);
+        },
+        OP_DECORATE if inst.operand(1)? == DECORATION_ARRAY_STRIDE => {
+          types.array_strides.insert(inst.operand(0)?, inst.operand(2)?);
+        },
+        OP_MEMBER_DECORATE => {
+          let key = (inst.operand(0)?, inst.operand(1)?);
+          match inst.operand(2)? {
+            DECORATION_OFFSET => {
+              types.members.entry(key).or_default().0 = Some(inst.operand(3)?);
+            },
+            DECORATION_MATRIX_STRIDE => {
+              types.members.entry(key).or_default().1 = Some(inst.operand(3)?);
+            },
+            _ => { },
+          }
+        },
+        _ => { },
+      }
+    }
+    Ok(types)
+  }
+
+  fn def(&self, id: u32) -> Result<&'a Inst, SpecConstantError> {
+    self.defs.get(&id)
+      .copied()
+      .ok_or(SpecConstantError::Malformed)
+  }
+
+  /// The size of the type `id`, in bytes, when laid out in a block.
+  fn size(&self, id: u32) -> Result<u32, SpecConstantError> {
+    let ty = self.def(id)?;
+    let size = match ty.opcode {
+      // not allowed in blocks, but they're a word anyway.
+      OP_TYPE_BOOL => 4,
+      OP_TYPE_INT | OP_TYPE_FLOAT => ty.operand(1)? / 8,
+      OP_TYPE_VECTOR | OP_TYPE_MATRIX => {
+        self.size(ty.operand(1)?)? * ty.operand(2)?
+      },
+      OP_TYPE_ARRAY => {
+        let len = self.def(ty.operand(2)?)?;
+        if len.opcode != OP_CONSTANT {
+          return Err(SpecConstantError::Malformed);
+        }
+        let stride = match self.array_strides.get(&id) {
+          Some(&stride) => stride,
+          None => self.size(ty.operand(1)?)?,
+        };
+        stride * len.operand(2)?
+      },
+      OP_TYPE_STRUCT => {
+        let mut end = 0;
+        for (idx, &member) in ty.operands[1..].iter().enumerate() {
+          let (offset, matrix_stride) = self.members
+            .get(&(id, idx as u32))
+            .copied()
+            .unwrap_or_default();
+          let member_ty = self.def(member)?;
+          let size = match matrix_stride {
+            Some(stride) if member_ty.opcode == OP_TYPE_MATRIX => {
+              stride * member_ty.operand(2)?
+            },
+            _ => self.size(member)?,
+          };
+          end = offset.unwrap_or(end) + size;
+        }
+        end
+      },
+      // runtime arrays, pointers, etc
+      _ => return Err(SpecConstantError::Malformed),
+    };
+    Ok(size)
+  }
+}
+
+/// The size, in bytes, of the `PushConstant` storage class variable in the SPIR-V
+/// module `words`, ie the end of its last member. `None` if there isn't one.
+pub fn block_size(words: &[u32]) -> Result<Option<u32>, SpecConstantError> {
+  let insts = parse(words)?;
+  let types = Types::new(&insts)?;
+
+  let var = insts.iter()
+    .filter(|inst| inst.opcode == OP_VARIABLE )
+    .find(|inst| inst.operands.get(2) == Some(&STORAGE_CLASS_PUSH_CONSTANT) );
+  let var = match var {
+    Some(var) => var,
+    None => return Ok(None),
+  };
+  let ptr = types.def(var.operand(0)?)?;
+  if ptr.opcode != OP_TYPE_POINTER {
+    return Err(SpecConstantError::Malformed);
+  }
+  types.size(ptr.operand(2)?).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const MAGIC: u32 = 0x0723_0203;
+
+  fn module(insts: &[(u16, &[u32])]) -> Vec<u32> {
+    let mut out = vec![MAGIC, 0x0001_0000, 0, 100, 0];
+    for &(opcode, operands) in insts.iter() {
+      out.push(((operands.len() as u32 + 1) << 16) | opcode as u32);
+      out.extend_from_slice(operands);
+    }
+    out
+  }
+
+  #[test]
+  fn none() {
+    let m = module(&[
+      (OP_TYPE_INT, &[1, 32, 0]),
+    ]);
+    assert_eq!(block_size(&m), Ok(None));
+  }
+
+  /// `struct { scale: f32, offset: [u32; 3] @ 16, dir: vec3 @ 32 }`
+  #[test]
+  fn structure() {
+    let m = module(&[
+      (OP_MEMBER_DECORATE, &[6, 0, DECORATION_OFFSET, 0]),
+      (OP_MEMBER_DECORATE, &[6, 1, DECORATION_OFFSET, 16]),
+      (OP_MEMBER_DECORATE, &[6, 2, DECORATION_OFFSET, 32]),
+      (OP_DECORATE, &[4, DECORATION_ARRAY_STRIDE, 4]),
+      (OP_TYPE_INT, &[1, 32, 0]),
+      (OP_TYPE_FLOAT, &[2, 32]),
+      (OP_CONSTANT, &[1, 3, 3]),
+      (OP_TYPE_ARRAY, &[4, 1, 3]),
+      (OP_TYPE_VECTOR, &[5, 2, 3]),
+      (OP_TYPE_STRUCT, &[6, 2, 4, 5]),
+      (OP_TYPE_POINTER, &[7, STORAGE_CLASS_PUSH_CONSTANT, 6]),
+      (OP_VARIABLE, &[7, 8, STORAGE_CLASS_PUSH_CONSTANT]),
+    ]);
+    assert_eq!(block_size(&m), Ok(Some(44)));
+  }
+
+  #[test]
+  fn malformed() {
+    // the pointee isn't defined:
+    let m = module(&[
+      (OP_TYPE_POINTER, &[7, STORAGE_CLASS_PUSH_CONSTANT, 6]),
+      (OP_VARIABLE, &[7, 8, STORAGE_CLASS_PUSH_CONSTANT]),
+    ]);
+    assert_eq!(block_size(&m), Err(SpecConstantError::Malformed));
+  }
+}
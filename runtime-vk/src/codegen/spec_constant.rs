@@ -0,0 +1,386 @@
+//! Lowers `crate::spec_constant::SpecConstant` globals to real specialization constants.
+//!
+//! The backend has no notion of spec constants, so a `SpecConstant` static is emitted as
+//! an ordinary `UniformConstant` variable, initialized with its default value and
+//! decorated with `SpecId`. This rewrites the SPIR-V module so each such variable becomes
+//! an `OpSpecConstant` (or `OpSpecConstantTrue`/`OpSpecConstantFalse`) with the same
+//! result ID, and each load from it becomes an `OpCopyObject` of the constant. Reusing
+//! the variable's ID means the `SpecId` decoration stays put and the ID bound doesn't
+//! change. The only thing a kernel can do with a `SpecConstant` is read it, so any
+//! other use of the variable is an error.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+const MAGIC: u32 = 0x0723_0203;
+const HEADER_LEN: usize = 5;
+
+const OP_NAME: u16 = 5;
+const OP_MEMBER_NAME: u16 = 6;
+const OP_ENTRY_POINT: u16 = 15;
+const OP_TYPE_BOOL: u16 = 20;
+const OP_TYPE_INT: u16 = 21;
+const OP_TYPE_FLOAT: u16 = 22;
+const OP_TYPE_POINTER: u16 = 32;
+const OP_CONSTANT_TRUE: u16 = 41;
+const OP_CONSTANT_FALSE: u16 = 42;
+const OP_CONSTANT: u16 = 43;
+const OP_CONSTANT_NULL: u16 = 46;
+const OP_SPEC_CONSTANT_TRUE: u16 = 48;
+const OP_SPEC_CONSTANT_FALSE: u16 = 49;
+const OP_SPEC_CONSTANT: u16 = 50;
+const OP_FUNCTION_CALL: u16 = 57;
+const OP_VARIABLE: u16 = 59;
+const OP_LOAD: u16 = 61;
+const OP_STORE: u16 = 62;
+const OP_COPY_MEMORY: u16 = 63;
+const OP_COPY_MEMORY_SIZED: u16 = 64;
+const OP_ACCESS_CHAIN: u16 = 65;
+const OP_IN_BOUNDS_ACCESS_CHAIN: u16 = 66;
+const OP_PTR_ACCESS_CHAIN: u16 = 67;
+const OP_IN_BOUNDS_PTR_ACCESS_CHAIN: u16 = 70;
+const OP_DECORATE: u16 = 71;
+const OP_COMPOSITE_CONSTRUCT: u16 = 80;
+const OP_COPY_OBJECT: u16 = 83;
+const OP_CONVERT_PTR_TO_U: u16 = 117;
+const OP_PTR_CAST_TO_GENERIC: u16 = 121;
+const OP_BITCAST: u16 = 124;
+const OP_SELECT: u16 = 169;
+const OP_ATOMIC_LOAD: u16 = 227;
+const OP_ATOMIC_STORE: u16 = 228;
+const OP_ATOMIC_XOR: u16 = 242;
+const OP_PHI: u16 = 245;
+const OP_RETURN_VALUE: u16 = 254;
+const OP_PTR_EQUAL: u16 = 401;
+const OP_PTR_DIFF: u16 = 403;
+
+const DECORATION_SPEC_ID: u32 = 1;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SpecConstantError {
+  /// Not a SPIR-V module, or a truncated one.
+  Malformed,
+  /// The variable decorated with `SpecId` has no initializer, or its type isn't a
+  /// `bool`, integer, or float.
+  NotAScalar { id: u32, },
+  /// The variable is used by something other than a load, eg its address is taken.
+  NotALoad { id: u32, opcode: u16, },
+}
+impl fmt::Display for SpecConstantError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SpecConstantError::Malformed => f.write_str("malformed SPIR-V module"),
+      SpecConstantError::NotAScalar { id, } => {
+        write!(f, "`SpecConstant` %{} isn't an initialized scalar", id)
+      },
+      SpecConstantError::NotALoad { id, opcode, } => {
+        write!(f, "`SpecConstant` %{} can only be read, but is used by opcode {}",
+               id, opcode)
+      },
+    }
+  }
+}
+impl std::error::Error for SpecConstantError { }
+
+pub(super) struct Inst {
+  pub(super) opcode: u16,
+  pub(super) operands: Vec<u32>,
+}
+impl Inst {
+  /// Operand `idx`, or `Malformed` if the instruction is too short to have it.
+  pub(super) fn operand(&self, idx: usize) -> Result<u32, SpecConstantError> {
+    self.operands.get(idx)
+      .copied()
+      .ok_or(SpecConstantError::Malformed)
+  }
+  /// The result ID of the instructions `lower` needs to look up.
+  fn result(&self) -> Option<u32> {
+    match self.opcode {
+      OP_TYPE_BOOL | OP_TYPE_INT | OP_TYPE_FLOAT | OP_TYPE_POINTER => {
+        self.operands.first().copied()
+      },
+      OP_CONSTANT_TRUE | OP_CONSTANT_FALSE | OP_CONSTANT | OP_CONSTANT_NULL |
+      OP_VARIABLE => self.operands.get(1).copied(),
+      _ => None,
+    }
+  }
+  /// The operands of instructions which can take a pointer to a variable, other than
+  /// `OpLoad`. Other operands can be literals, so checking every operand for the
+  /// variable's ID would raise spurious errors, eg for `Aligned 4` memory operands.
+  fn pointer_operands(&self) -> &[u32] {
+    let range = match self.opcode {
+      OP_STORE | OP_COPY_MEMORY | OP_COPY_MEMORY_SIZED => 0..2,
+      OP_RETURN_VALUE | OP_ATOMIC_STORE => 0..1,
+      OP_ACCESS_CHAIN | OP_IN_BOUNDS_ACCESS_CHAIN | OP_PTR_ACCESS_CHAIN |
+      OP_IN_BOUNDS_PTR_ACCESS_CHAIN | OP_COPY_OBJECT | OP_CONVERT_PTR_TO_U |
+      OP_PTR_CAST_TO_GENERIC | OP_BITCAST => 2..3,
+      OP_ATOMIC_LOAD..=OP_ATOMIC_XOR => 2..3,
+      OP_SELECT => 3..5,
+      OP_PTR_EQUAL..=OP_PTR_DIFF => 2..4,
+      OP_FUNCTION_CALL => 3..self.operands.len(),
+      OP_COMPOSITE_CONSTRUCT | OP_PHI => 2..self.operands.len(),
+      _ => 0..0,
+    };
+    self.operands.get(range).unwrap_or(&[])
+  }
+}
+
+pub(super) fn parse(words: &[u32]) -> Result<Vec<Inst>, SpecConstantError> {
+  if words.len() < HEADER_LEN || words[0] != MAGIC {
+    return Err(SpecConstantError::Malformed);
+  }
+  let mut out = vec![];
+  let mut rest = &words[HEADER_LEN..];
+  while let Some(&first) = rest.first() {
+    let len = (first >> 16) as usize;
+    if len == 0 || len > rest.len() {
+      return Err(SpecConstantError::Malformed);
+    }
+    out.push(Inst {
+      opcode: first as u16,
+      operands: rest[1..len].to_vec(),
+    });
+    rest = &rest[len..];
+  }
+  Ok(out)
+}
+
+/// The number of words taken by the literal string at the start of `words`.
+fn string_words(words: &[u32]) -> usize {
+  words.iter()
+    .position(|w| w.to_le_bytes().contains(&0) )
+    .map(|idx| idx + 1)
+    .unwrap_or(words.len())
+}
+
+/// Lower every variable decorated with `SpecId` in the SPIR-V module `words`. Modules
+/// without any are returned unchanged.
+pub fn lower(words: &[u32]) -> Result<Vec<u32>, SpecConstantError> {
+  let mut insts = parse(words)?;
+
+  let spec_vars = insts.iter()
+    .filter(|inst| inst.opcode == OP_DECORATE )
+    .filter(|inst| inst.operands.get(1) == Some(&DECORATION_SPEC_ID) )
+    .map(|inst| inst.operands[0] )
+    .collect::<Vec<_>>();
+  if spec_vars.is_empty() {
+    return Ok(words.to_vec());
+  }
+
+  let defs = insts.iter()
+    .enumerate()
+    .filter_map(|(idx, inst)| inst.result().map(|id| (id, idx)) )
+    .collect::<BTreeMap<_, _>>();
+  let def = |id: u32| defs.get(&id).map(|&idx| &insts[idx] );
+
+  let mut replacements = BTreeMap::new();
+  for &id in spec_vars.iter() {
+    let not_a_scalar = SpecConstantError::NotAScalar { id, };
+    let var = def(id)
+      .filter(|var| var.opcode == OP_VARIABLE )
+      .ok_or_else(|| not_a_scalar.clone() )?;
+    let init = var.operands.get(3)
+      .and_then(|&init| def(init) )
+      .ok_or_else(|| not_a_scalar.clone() )?;
+    let ty = def(var.operand(0)?)
+      .filter(|ptr| ptr.opcode == OP_TYPE_POINTER )
+      .and_then(|ptr| ptr.operands.get(2) )
+      .ok_or_else(|| not_a_scalar.clone() )?;
+    let ty_inst = def(*ty).ok_or_else(|| not_a_scalar.clone() )?;
+
+    let (opcode, value) = match (ty_inst.opcode, init.opcode) {
+      (OP_TYPE_BOOL, OP_CONSTANT_TRUE) => (OP_SPEC_CONSTANT_TRUE, vec![]),
+      (OP_TYPE_BOOL, OP_CONSTANT_FALSE) |
+      (OP_TYPE_BOOL, OP_CONSTANT_NULL) => (OP_SPEC_CONSTANT_FALSE, vec![]),
+      (OP_TYPE_INT, OP_CONSTANT) |
+      (OP_TYPE_FLOAT, OP_CONSTANT) => {
+        let value = init.operands.get(2..)
+          .filter(|value| !value.is_empty() )
+          .ok_or(SpecConstantError::Malformed)?;
+        (OP_SPEC_CONSTANT, value.to_vec())
+      },
+      (OP_TYPE_INT, OP_CONSTANT_NULL) |
+      (OP_TYPE_FLOAT, OP_CONSTANT_NULL) => {
+        // literals narrower than 32 bits still take a whole word.
+        let width = ty_inst.operands.get(1).copied().unwrap_or(32);
+        (OP_SPEC_CONSTANT, vec![0; if width > 32 { 2 } else { 1 }])
+      },
+      _ => return Err(not_a_scalar),
+    };
+    let mut operands = vec![*ty, id];
+    operands.extend(value);
+    replacements.insert(id, Inst { opcode, operands, });
+  }
+
+  let mut out = Vec::with_capacity(insts.len());
+  for mut inst in insts.drain(..) {
+    match inst.opcode {
+      OP_VARIABLE => {
+        if let Some(replacement) = replacements.remove(&inst.operand(1)?) {
+          inst = replacement;
+        }
+      },
+      OP_LOAD => {
+        if spec_vars.contains(&inst.operand(2)?) {
+          // drop any memory operands; they only apply to loads.
+          inst.opcode = OP_COPY_OBJECT;
+          inst.operands.truncate(3);
+        }
+      },
+      OP_DECORATE => {
+        // eg `Alignment`, which doesn't apply to constants.
+        if spec_vars.contains(&inst.operand(0)?) &&
+          inst.operand(1)? != DECORATION_SPEC_ID { continue; }
+      },
+      OP_NAME | OP_MEMBER_NAME => { },
+      OP_ENTRY_POINT => {
+        // the interface starts after the execution model, entry point, and name.
+        let name = inst.operands.get(2..).ok_or(SpecConstantError::Malformed)?;
+        let start = 2 + string_words(name);
+        let interface = inst.operands.split_off(start.min(inst.operands.len()));
+        inst.operands.extend(interface.into_iter().filter(|id| !spec_vars.contains(id) ));
+      },
+      opcode => {
+        if let Some(&id) = inst.pointer_operands().iter().find(|id| spec_vars.contains(id) ) {
+          return Err(SpecConstantError::NotALoad { id, opcode, });
+        }
+      },
+    }
+    out.push(inst);
+  }
+
+  let mut words = words[..HEADER_LEN].to_vec();
+  for inst in out.into_iter() {
+    words.push(((inst.operands.len() as u32 + 1) << 16) | inst.opcode as u32);
+    words.extend(inst.operands);
+  }
+  Ok(words)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const OP_FUNCTION: u16 = 54;
+  const UNIFORM_CONSTANT: u32 = 0;
+  const ALIGNMENT: u32 = 44;
+
+  fn module(insts: &[(u16, &[u32])]) -> Vec<u32> {
+    let mut out = vec![MAGIC, 0x0001_0000, 0, 100, 0];
+    for &(opcode, operands) in insts.iter() {
+      out.push(((operands.len() as u32 + 1) << 16) | opcode as u32);
+      out.extend_from_slice(operands);
+    }
+    out
+  }
+
+  /// `%1 = u32`, `%2 = *UniformConstant u32`, `%3 = 16u32`, `%4` is the spec constant
+  /// variable, and `%5`/`%6` are in the kernel.
+  fn kernel(uses: &[(u16, &[u32])]) -> Vec<u32> {
+    let mut insts: Vec<(u16, &[u32])> = vec![
+      // GLCompute %5 "main" %4
+      (OP_ENTRY_POINT, &[5, 5, 0x6e69_616d, 0, 4]),
+      (OP_NAME, &[4, 0x454c_4954, 0]),
+      (OP_DECORATE, &[4, DECORATION_SPEC_ID, 7]),
+      (OP_DECORATE, &[4, ALIGNMENT, 4]),
+      (OP_TYPE_INT, &[1, 32, 0]),
+      (OP_TYPE_POINTER, &[2, UNIFORM_CONSTANT, 1]),
+      (OP_CONSTANT, &[1, 3, 16]),
+      (OP_VARIABLE, &[2, 4, UNIFORM_CONSTANT, 3]),
+      (OP_FUNCTION, &[1, 5, 0, 9]),
+    ];
+    insts.extend_from_slice(uses);
+    module(&insts)
+  }
+
+  #[test]
+  fn unchanged() {
+    let m = module(&[
+      (OP_TYPE_INT, &[1, 32, 0]),
+      (OP_CONSTANT, &[1, 3, 16]),
+    ]);
+    assert_eq!(lower(&m).unwrap(), m);
+  }
+
+  #[test]
+  fn lowered() {
+    let m = kernel(&[(OP_LOAD, &[1, 6, 4, 2])]);
+    let expected = module(&[
+      (OP_ENTRY_POINT, &[5, 5, 0x6e69_616d, 0]),
+      (OP_NAME, &[4, 0x454c_4954, 0]),
+      (OP_DECORATE, &[4, DECORATION_SPEC_ID, 7]),
+      (OP_TYPE_INT, &[1, 32, 0]),
+      (OP_TYPE_POINTER, &[2, UNIFORM_CONSTANT, 1]),
+      (OP_CONSTANT, &[1, 3, 16]),
+      (OP_SPEC_CONSTANT, &[1, 4, 16]),
+      (OP_FUNCTION, &[1, 5, 0, 9]),
+      (OP_COPY_OBJECT, &[1, 6, 4]),
+    ]);
+    assert_eq!(lower(&m).unwrap(), expected);
+  }
+
+  #[test]
+  fn literal_operands() {
+    // `OpStore %6 %3 Aligned 4`: the alignment isn't a use of `%4`.
+    let m = kernel(&[(OP_LOAD, &[1, 6, 4]), (OP_STORE, &[6, 3, 2, 4])]);
+    assert!(lower(&m).is_ok());
+  }
+
+  #[test]
+  fn bool_and_null() {
+    let m = module(&[
+      (OP_DECORATE, &[4, DECORATION_SPEC_ID, 0]),
+      (OP_DECORATE, &[8, DECORATION_SPEC_ID, 1]),
+      (OP_TYPE_BOOL, &[1]),
+      (OP_TYPE_POINTER, &[2, UNIFORM_CONSTANT, 1]),
+      (OP_CONSTANT_TRUE, &[1, 3]),
+      (OP_VARIABLE, &[2, 4, UNIFORM_CONSTANT, 3]),
+      (OP_TYPE_FLOAT, &[5, 64]),
+      (OP_TYPE_POINTER, &[6, UNIFORM_CONSTANT, 5]),
+      (OP_CONSTANT_NULL, &[5, 7]),
+      (OP_VARIABLE, &[6, 8, UNIFORM_CONSTANT, 7]),
+    ]);
+    let expected = module(&[
+      (OP_DECORATE, &[4, DECORATION_SPEC_ID, 0]),
+      (OP_DECORATE, &[8, DECORATION_SPEC_ID, 1]),
+      (OP_TYPE_BOOL, &[1]),
+      (OP_TYPE_POINTER, &[2, UNIFORM_CONSTANT, 1]),
+      (OP_CONSTANT_TRUE, &[1, 3]),
+      (OP_SPEC_CONSTANT_TRUE, &[1, 4]),
+      (OP_TYPE_FLOAT, &[5, 64]),
+      (OP_TYPE_POINTER, &[6, UNIFORM_CONSTANT, 5]),
+      (OP_CONSTANT_NULL, &[5, 7]),
+      (OP_SPEC_CONSTANT, &[5, 8, 0, 0]),
+    ]);
+    assert_eq!(lower(&m).unwrap(), expected);
+  }
+
+  #[test]
+  fn errors() {
+    let m = kernel(&[(OP_STORE, &[4, 6])]);
+    assert_eq!(lower(&m), Err(SpecConstantError::NotALoad { id: 4, opcode: OP_STORE, }));
+    let m = kernel(&[(OP_ACCESS_CHAIN, &[2, 6, 4, 3])]);
+    assert_eq!(lower(&m), Err(SpecConstantError::NotALoad {
+      id: 4,
+      opcode: OP_ACCESS_CHAIN,
+    }));
+
+    let m = module(&[
+      (OP_DECORATE, &[4, DECORATION_SPEC_ID, 0]),
+      (OP_TYPE_INT, &[1, 32, 0]),
+      (OP_TYPE_POINTER, &[2, UNIFORM_CONSTANT, 1]),
+      (OP_VARIABLE, &[2, 4, UNIFORM_CONSTANT]),
+    ]);
+    assert_eq!(lower(&m), Err(SpecConstantError::NotAScalar { id: 4, }));
+
+    assert_eq!(lower(&[0; 5]), Err(SpecConstantError::Malformed));
+    // each instruction is intact, but too short for its opcode:
+    for &truncated in [(OP_LOAD, &[1, 6][..]), (OP_VARIABLE, &[2]), (OP_DECORATE, &[])].iter() {
+      let m = kernel(&[truncated]);
+      assert_eq!(lower(&m), Err(SpecConstantError::Malformed));
+    }
+    let mut m = kernel(&[]);
+    m.pop();
+    assert_eq!(lower(&m), Err(SpecConstantError::Malformed));
+  }
+}
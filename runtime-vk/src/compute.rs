@@ -0,0 +1,578 @@
+//! Typed compute dispatch, the Vulkan equivalent of `runtime-amd`'s
+//! `FuncModule`/`Invoc`.
+//!
+//! ```ignore
+//! let module = dev.compute_module(kernel, wg_size);
+//! let bindings = Bindings::new()
+//!   .buffer(0, 0, data.clone());
+//! module.dispatch(&queue, [4096, 1, 1], &bindings)?
+//!   .wait(None)?;
+//! ```
+//!
+//! Nothing here is specific to hardware ICDs, so this works with lavapipe or
+//! SwiftShader too (set `VK_ICD_FILENAMES`), which is handy for CI.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex, };
+use std::time::Duration;
+
+use vk::buffer::BufferAccess;
+use vk::command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder, CommandBuffer,
+                         CommandBufferExecFuture, };
+use vk::descriptor::descriptor::{DescriptorDesc, DescriptorDescTy, };
+use vk::descriptor::descriptor_set::{DescriptorSet, DescriptorSetDesc, DescriptorWrite,
+                                     DescriptorPool, DescriptorPoolAlloc,
+                                     StdDescriptorPoolAlloc, UnsafeDescriptorSet,
+                                     UnsafeDescriptorSetLayout, };
+use vk::descriptor::pipeline_layout::{PipelineLayout, PipelineLayoutAbstract,
+                                      PipelineLayoutDesc, };
+use vk::device::{Device, DeviceOwned, Queue, };
+use vk::image::ImageViewAccess;
+use vk::pipeline::ComputePipeline;
+use vk::pipeline::shader::SpecializationConstants;
+use vk::sync::{FenceSignalFuture, GpuFuture, NowFuture, };
+
+use grt_core::codegen::SpecParamsDesc;
+
+use crate::{VkAccel, Error, };
+use crate::module::{SpirvModule, StaticPipelineLayoutDesc, };
+
+pub type VkComputePipeline = ComputePipeline<PipelineLayout<StaticPipelineLayoutDesc>>;
+pub type ComputeFuture = FenceSignalFuture<CommandBufferExecFuture<NowFuture, AutoCommandBuffer>>;
+
+pub type WorkgroupSize = (std::num::NonZeroU32, std::num::NonZeroU32, std::num::NonZeroU32);
+
+/// Round `grid` (in invocations) up to a whole number of workgroups, and return the
+/// number of workgroups along each axis.
+pub fn group_counts(grid: [u32; 3], wg: WorkgroupSize) -> Result<[u32; 3], Error> {
+  let wg = [wg.0.get(), wg.1.get(), wg.2.get()];
+  let mut out = [0u32; 3];
+  for i in 0..3 {
+    if grid[i] == 0 {
+      return Err(Error::ZeroGridLaunchAxis);
+    }
+    // can't overflow: grid[i] >= 1 and wg[i] >= 1
+    out[i] = (grid[i] - 1) / wg[i] + 1;
+  }
+  Ok(out)
+}
+
+/// A resource bound to a descriptor set binding.
+#[derive(Clone)]
+pub enum Binding {
+  /// Uniform or storage buffers.
+  Buffer(Arc<dyn BufferAccess + Send + Sync>),
+  /// Sampled or storage images.
+  Image(Arc<dyn ImageViewAccess + Send + Sync>),
+}
+impl fmt::Debug for Binding {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Binding::Buffer(_) => f.write_str("Binding::Buffer(..)"),
+      Binding::Image(_) => f.write_str("Binding::Image(..)"),
+    }
+  }
+}
+
+/// The resources for a dispatch, keyed by `(set, binding)`. These must match the
+/// `Buffer`/`Uniform`/etc globals used by the kernel.
+#[derive(Clone, Debug, Default)]
+pub struct Bindings {
+  entries: BTreeMap<(u32, u32), Binding>,
+}
+impl Bindings {
+  pub fn new() -> Self { Default::default() }
+
+  pub fn buffer<B>(mut self, set: u32, binding: u32, buffer: Arc<B>) -> Self
+    where B: BufferAccess + Send + Sync + 'static,
+  {
+    self.entries.insert((set, binding), Binding::Buffer(buffer));
+    self
+  }
+  pub fn image<I>(mut self, set: u32, binding: u32, image: Arc<I>) -> Self
+    where I: ImageViewAccess + Send + Sync + 'static,
+  {
+    self.entries.insert((set, binding), Binding::Image(image));
+    self
+  }
+  pub fn set(&mut self, set: u32, binding: u32, v: Binding) {
+    self.entries.insert((set, binding), v);
+  }
+  pub fn get(&self, set: u32, binding: u32) -> Option<&Binding> {
+    self.entries.get(&(set, binding))
+  }
+}
+
+/// A descriptor set built at dispatch time from a `Bindings`. Keeps the bound
+/// resources alive for as long as the command buffer is.
+pub struct ComputeDescriptorSet {
+  inner: StdDescriptorPoolAlloc,
+  layout: Arc<UnsafeDescriptorSetLayout>,
+  buffers: Vec<(Arc<dyn BufferAccess + Send + Sync>, u32)>,
+  images: Vec<(Arc<dyn ImageViewAccess + Send + Sync>, u32)>,
+}
+impl ComputeDescriptorSet {
+  fn new(device: &Arc<Device>, layout: Arc<UnsafeDescriptorSetLayout>,
+         desc: &StaticPipelineLayoutDesc, set: u32, bindings: &Bindings)
+    -> Result<Self, Error>
+  {
+    let mut pool = Device::standard_descriptor_pool(device);
+    let mut out = ComputeDescriptorSet {
+      inner: pool.alloc(&layout)?,
+      layout,
+      buffers: vec![],
+      images: vec![],
+    };
+
+    let count = desc.num_bindings_in_set(set as usize).unwrap_or_default();
+    let mut writes = Vec::with_capacity(count);
+    for binding in 0..count as u32 {
+      let d = match desc.descriptor(set as usize, binding as usize) {
+        Some(d) => d,
+        None => continue,
+      };
+      let v = bindings.get(set, binding)
+        .ok_or(Error::MissingBinding { set, binding, })?;
+      writes.push(out.write(set, binding, &d, v)?);
+    }
+
+    unsafe {
+      out.inner.inner_mut().write(device, writes.into_iter());
+    }
+    Ok(out)
+  }
+
+  fn write(&mut self, set: u32, binding: u32, desc: &DescriptorDesc, v: &Binding)
+    -> Result<DescriptorWrite, Error>
+  {
+    let mismatch = || Error::BindingTypeMismatch { set, binding, };
+    let write = match (&desc.ty, v) {
+      (&DescriptorDescTy::Buffer(ref b), &Binding::Buffer(ref buf)) => {
+        if b.dynamic.unwrap_or(false) {
+          return Err(Error::UnsupportedBinding { set, binding, });
+        }
+        self.buffers.push((buf.clone(), binding));
+        if b.storage.unwrap_or(true) {
+          DescriptorWrite::storage_buffer(binding, 0, buf)
+        } else {
+          DescriptorWrite::uniform_buffer(binding, 0, buf)
+        }
+      },
+      (&DescriptorDescTy::Image(ref i), &Binding::Image(ref img)) => {
+        self.images.push((img.clone(), binding));
+        if i.sampled {
+          DescriptorWrite::sampled_image(binding, 0, img)
+        } else {
+          DescriptorWrite::storage_image(binding, 0, img)
+        }
+      },
+      (&DescriptorDescTy::Buffer(_), _) |
+      (&DescriptorDescTy::Image(_), _) => {
+        return Err(mismatch());
+      },
+      _ => {
+        return Err(Error::UnsupportedBinding { set, binding, });
+      },
+    };
+    Ok(write)
+  }
+}
+unsafe impl DescriptorSet for ComputeDescriptorSet {
+  fn inner(&self) -> &UnsafeDescriptorSet { self.inner.inner() }
+  fn num_buffers(&self) -> usize { self.buffers.len() }
+  fn buffer(&self, index: usize) -> Option<(&dyn BufferAccess, u32)> {
+    self.buffers.get(index)
+      .map(|&(ref b, binding)| (&**b as &dyn BufferAccess, binding) )
+  }
+  fn num_images(&self) -> usize { self.images.len() }
+  fn image(&self, index: usize) -> Option<(&dyn ImageViewAccess, u32)> {
+    self.images.get(index)
+      .map(|&(ref i, binding)| (&**i as &dyn ImageViewAccess, binding) )
+  }
+}
+unsafe impl DescriptorSetDesc for ComputeDescriptorSet {
+  fn num_bindings(&self) -> usize { self.layout.num_bindings() }
+  fn descriptor(&self, binding: usize) -> Option<DescriptorDesc> {
+    self.layout.descriptor(binding)
+  }
+}
+unsafe impl DeviceOwned for ComputeDescriptorSet {
+  fn device(&self) -> &Arc<Device> { self.layout.device() }
+}
+
+/// A compute kernel compiled for a specific device and workgroup size. The module
+/// and pipeline are built on first use and cached.
+///
+/// `S` holds the values for the kernel's `SpecConstant`s (see `crate::spec_constant`).
+pub struct ComputeModule<F, S = ()>
+  where F: Fn() + Sized,
+        S: SpecializationConstants,
+{
+  dev: Arc<VkAccel>,
+  f: F,
+  workgroup_size: WorkgroupSize,
+  /// The size of the kernel's push constant block; zero if none.
+  push_constants: u32,
+  spec_constants: S,
+  spec_params: SpecParamsDesc,
+  module: Mutex<Option<Arc<SpirvModule>>>,
+  pipeline: Mutex<Option<Arc<VkComputePipeline>>>,
+}
+impl<F> ComputeModule<F, ()>
+  where F: Fn() + Sized,
+{
+  pub fn new(dev: &Arc<VkAccel>, f: F, workgroup_size: WorkgroupSize) -> Self {
+    ComputeModule {
+      dev: dev.clone(),
+      f,
+      workgroup_size,
+      push_constants: 0,
+      spec_constants: (),
+      spec_params: Default::default(),
+      module: Mutex::new(None),
+      pipeline: Mutex::new(None),
+    }
+  }
+}
+impl<F, S> ComputeModule<F, S>
+  where F: Fn() + Sized,
+        S: SpecializationConstants,
+{
+  /// Specialize the pipeline with `values`. Doesn't recompile the kernel.
+  pub fn with_spec_constants<S2>(self, values: S2) -> ComputeModule<F, S2>
+    where S2: SpecializationConstants,
+  {
+    ComputeModule {
+      dev: self.dev,
+      f: self.f,
+      workgroup_size: self.workgroup_size,
+      push_constants: self.push_constants,
+      spec_constants: values,
+      spec_params: self.spec_params,
+      module: self.module,
+      pipeline: Mutex::new(None),
+    }
+  }
+  /// Change the specialization constant values. The pipeline will be recreated on
+  /// next use, but the kernel isn't recompiled.
+  pub fn set_spec_constants(&mut self, values: S) {
+    self.spec_constants = values;
+    *self.pipeline.get_mut().unwrap() = None;
+  }
+  pub fn spec_constants(&self) -> &S { &self.spec_constants }
+
+  /// Undefine all params. If this kernel was already compiled, it will be compiled
+  /// again.
+  pub fn clear_params(&mut self) {
+    self.invalidate();
+    self.spec_params.clear();
+  }
+  /// Undefine a specialization entry. If the key (`f`) has no entry, this does nothing.
+  ///
+  /// If this kernel was already compiled, it will be compiled again.
+  pub fn undefine_param<P, R>(&mut self, f: P)
+    where P: Fn() -> R,
+  {
+    self.invalidate();
+    self.spec_params.undefine(f)
+  }
+  /// (Re-)Define a specialization param, keyed by `f`, exactly as on `runtime-amd`'s
+  /// `FuncModule`. The kernel reads the value with `std::geobacter::spec_param::get`.
+  ///
+  /// Params are baked into the SPIR-V, so unlike `set_spec_constants` this
+  /// recompiles the kernel.
+  pub fn define_param<P, R>(&mut self, f: P, value: &R)
+    where P: Fn() -> R,
+          R: Copy + Unpin + 'static,
+  {
+    self.invalidate();
+    self.spec_params.define(f, value)
+  }
+  fn invalidate(&mut self) {
+    *self.module.get_mut().unwrap() = None;
+    *self.pipeline.get_mut().unwrap() = None;
+  }
+
+  /// The kernel reads a `PushConstant<P>`. Use `dispatch_with_constants` to provide
+  /// the value. `compile` returns `Error::PushConstantsAlignment` if `P`'s size isn't a
+  /// multiple of four, and `Error::PushConstantsLayout` if `P` is smaller than the
+  /// kernel's `PushConstant<T>`.
+  pub fn with_push_constants<P>(mut self) -> Self
+    where P: Copy,
+  {
+    self.push_constants = size_of::<P>() as u32;
+    self.invalidate();
+    self
+  }
+
+  pub fn device(&self) -> &Arc<VkAccel> { &self.dev }
+  pub fn workgroup_size(&self) -> WorkgroupSize { self.workgroup_size }
+  pub fn push_constants_size(&self) -> u32 { self.push_constants }
+
+  /// Compile the kernel, if it hasn't been already.
+  pub fn compile(&self) -> Result<Arc<SpirvModule>, Error> {
+    let mut module = self.module.lock().unwrap();
+    if let Some(ref module) = *module {
+      return Ok(module.clone());
+    }
+    let pipeline = StaticPipelineLayoutDesc::layout_for::<F>()
+      .with_push_constants_size(self.push_constants)?;
+    let m = self.dev
+      .compile_compute_with_params(&self.f, Some(self.workgroup_size), pipeline,
+                                   self.spec_params.clone())?;
+    *module = Some(m.clone());
+    Ok(m)
+  }
+  /// Build the compute pipeline, if it hasn't been already.
+  pub fn pipeline(&self) -> Result<Arc<VkComputePipeline>, Error> {
+    let mut pipeline = self.pipeline.lock().unwrap();
+    if let Some(ref pipeline) = *pipeline {
+      return Ok(pipeline.clone());
+    }
+
+    let entry = self.compile()?
+      .compute_entry()
+      .ok_or(Error::NotAComputeKernel)?
+      .specialize::<S>();
+    let p = ComputePipeline::new(self.dev.device().clone(), &entry,
+                                 &self.spec_constants)?;
+    let p = Arc::new(p);
+    *pipeline = Some(p.clone());
+    Ok(p)
+  }
+
+  /// Build the descriptor sets for every set in the kernel's pipeline layout.
+  pub fn descriptor_sets(&self, bindings: &Bindings)
+    -> Result<Vec<Arc<ComputeDescriptorSet>>, Error>
+  {
+    let pipeline = self.pipeline()?;
+    let desc = self.compile()?.pipeline_layout();
+    let device = self.dev.device();
+
+    (0..desc.num_sets())
+      .map(|set| {
+        let layout = pipeline.layout()
+          .descriptor_set_layout(set)
+          .expect("pipeline layout doesn't match its desc?")
+          .clone();
+        ComputeDescriptorSet::new(device, layout, &desc, set as u32, bindings)
+          .map(Arc::new)
+      })
+      .collect()
+  }
+
+  /// Dispatch at least `grid` invocations (rounded up to a multiple of the workgroup
+  /// size) on `queue`. `queue` must belong to this module's device and support
+  /// compute.
+  pub fn dispatch(&self, queue: &Arc<Queue>, grid: [u32; 3], bindings: &Bindings)
+    -> Result<ComputeCompletion, Error>
+  {
+    self.dispatch_with_constants(queue, grid, bindings, ())
+  }
+  /// Like `dispatch`, but also push `constants`, which must be the same type given
+  /// to `with_push_constants`.
+  pub fn dispatch_with_constants<P>(&self, queue: &Arc<Queue>, grid: [u32; 3],
+                                    bindings: &Bindings, constants: P)
+    -> Result<ComputeCompletion, Error>
+    where P: Copy + Send + Sync + 'static,
+  {
+    let groups = group_counts(grid, self.workgroup_size)?;
+    self.dispatch_groups(queue, groups, bindings, constants)
+  }
+  /// Like `dispatch_with_constants`, but `groups` is the number of workgroups along
+  /// each axis. Use `()` if the kernel has no push constants.
+  pub fn dispatch_groups<P>(&self, queue: &Arc<Queue>, groups: [u32; 3],
+                            bindings: &Bindings, constants: P)
+    -> Result<ComputeCompletion, Error>
+    where P: Copy + Send + Sync + 'static,
+  {
+    if !queue.family().supports_compute() {
+      return Err(Error::MissingComputeQueue);
+    }
+    // vulkano copies `size` bytes from `constants`, so this must be exact:
+    if size_of::<P>() as u32 != self.push_constants {
+      return Err(Error::PushConstantsSize {
+        expected: self.push_constants,
+        actual: size_of::<P>() as u32,
+      });
+    }
+
+    let pipeline = self.pipeline()?;
+    let sets = self.descriptor_sets(bindings)?;
+
+    let mut cmd_buf =
+      AutoCommandBufferBuilder::primary_one_time_submit(self.dev.device().clone(),
+                                                        queue.family())?;
+    cmd_buf.dispatch(groups, pipeline, sets, constants)?;
+    let cmd_buf = cmd_buf.build()?;
+
+    let future = cmd_buf.execute(queue.clone())?
+      .then_signal_fence_and_flush()?;
+    Ok(ComputeCompletion {
+      future,
+    })
+  }
+}
+impl<F, S> fmt::Debug for ComputeModule<F, S>
+  where F: Fn() + Sized,
+        S: SpecializationConstants,
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("ComputeModule")
+      .field("dev", &self.dev.id)
+      .field("workgroup_size", &self.workgroup_size)
+      .field("push_constants", &self.push_constants)
+      .finish()
+  }
+}
+
+/// Signaled when a dispatch has finished. Dropping this waits for the dispatch.
+#[must_use]
+pub struct ComputeCompletion {
+  future: ComputeFuture,
+}
+impl ComputeCompletion {
+  /// Wait for the dispatch to complete. Returns `Error::Flush(FlushError::Timeout)`
+  /// if `timeout` was reached first.
+  pub fn wait(&self, timeout: Option<Duration>) -> Result<(), Error> {
+    self.future.wait(timeout)?;
+    Ok(())
+  }
+  /// Use this to chain more work after the dispatch.
+  pub fn into_future(self) -> ComputeFuture { self.future }
+}
+impl fmt::Debug for ComputeCompletion {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("ComputeCompletion { .. }")
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::geobacter::spirv::{*, workitem::*, };
+  use std::iter;
+  use std::num::NonZeroU32;
+
+  use grt_core::context::Context;
+
+  use vk::buffer::BufferUsage;
+  use vk::buffer::cpu_access::CpuAccessibleBuffer;
+  use vk::device::{DeviceExtensions, Features, };
+  use vk::instance::{Instance, InstanceExtensions, PhysicalDevice, PhysicalDeviceType, };
+
+  const ELEMENTS: usize = 4096;
+
+  static mut DATA: Buffer<RuntimeArray32<u32>, 0, 0> = Buffer::new(RuntimeArray32::new());
+
+  fn scale() {
+    let id = global_invocation_id()[0] as usize;
+    unsafe {
+      if let Some(data) = DATA.get_mut(id) {
+        *data *= 12;
+      }
+    }
+  }
+
+  /// A CPU (ie lavapipe or SwiftShader) device, or `None` if no such ICD is installed.
+  fn software_device() -> Option<(Arc<VkAccel>, Arc<Queue>)> {
+    let instance = Instance::new(None, &InstanceExtensions::none(), None)
+      .map_err(|err| eprintln!("skipping: no Vulkan instance: {}", err) )
+      .ok()?;
+    let phy = PhysicalDevice::enumerate(&instance)
+      .find(|phy| phy.ty() == PhysicalDeviceType::Cpu );
+    let phy = match phy {
+      Some(phy) => phy,
+      None => {
+        eprintln!("skipping: no software Vulkan ICD (set VK_ICD_FILENAMES to lavapipe \
+                   or SwiftShader)");
+        return None;
+      },
+    };
+    let q_fam = phy.queue_families()
+      .find(|fam| fam.supports_compute() )?;
+
+    let mut features = Features::none();
+    features.robust_buffer_access = true;
+    let (device, mut queues) = Device::new(phy, &features, &DeviceExtensions::none(),
+                                           Some((q_fam, 1.0)))
+      .expect("create vk device");
+    let queue = queues.next()
+      .expect("no compute queue?");
+
+    let ctx = Context::new()
+      .expect("create context");
+    let dev = VkAccel::new(&ctx, device)
+      .expect("create accelerator");
+    Some((dev, queue))
+  }
+
+  fn wg(x: u32, y: u32, z: u32) -> WorkgroupSize {
+    (NonZeroU32::new(x).unwrap(), NonZeroU32::new(y).unwrap(),
+     NonZeroU32::new(z).unwrap())
+  }
+
+  #[test]
+  fn groups() {
+    assert_eq!(group_counts([4096, 1, 1], wg(256, 1, 1)).unwrap(), [16, 1, 1]);
+    assert_eq!(group_counts([4097, 3, 1], wg(256, 2, 1)).unwrap(), [17, 2, 1]);
+    assert_eq!(group_counts([1, 1, 1], wg(64, 64, 64)).unwrap(), [1, 1, 1]);
+    assert_eq!(group_counts([!0, 1, 1], wg(1, 1, 1)).unwrap(), [!0, 1, 1]);
+    match group_counts([0, 1, 1], wg(1, 1, 1)) {
+      Err(Error::ZeroGridLaunchAxis) => { },
+      r => panic!("unexpected: {:?}", r),
+    }
+  }
+
+  #[test]
+  fn push_constants_size() {
+    let layout = StaticPipelineLayoutDesc::default();
+    assert_eq!(layout.with_push_constants_size(16).unwrap().push_constants_size(), 16);
+    assert_eq!(layout.with_push_constants::<[u32; 3]>().unwrap().push_constants_size(), 12);
+    match layout.with_push_constants::<[u16; 3]>() {
+      Err(Error::PushConstantsAlignment { size: 6, }) => { },
+      r => panic!("unexpected: {:?}", r),
+    }
+  }
+
+  #[test]
+  fn software_dispatch() {
+    let (dev, queue) = match software_device() {
+      Some(v) => v,
+      None => return,
+    };
+
+    let module = dev.compute_module(scale, wg(256, 1, 1));
+
+    let data = (0..ELEMENTS as u32).collect::<Vec<_>>();
+    let buffer = unsafe {
+      let usage = BufferUsage {
+        storage_buffer: true,
+        .. BufferUsage::none()
+      };
+      let size = <RuntimeArray32<u32>>::layout(ELEMENTS as _)
+        .unwrap()
+        .size();
+      let buf = <CpuAccessibleBuffer<RuntimeArray32<u32>>>::raw(dev.device().clone(),
+                                                                 size, usage, true,
+                                                                 iter::empty())
+        .expect("create buffer");
+      buf.write().unwrap().initialize_copy_from_slice(&data);
+      buf
+    };
+
+    let bindings = Bindings::new()
+      .buffer(0, 0, buffer.clone());
+    module.dispatch(&queue, [ELEMENTS as _, 1, 1], &bindings)
+      .expect("dispatch")
+      .wait(None)
+      .expect("wait");
+
+    let content = buffer.read().unwrap();
+    for (idx, &value) in content.iter().enumerate() {
+      assert_eq!(value, (idx * 12) as u32, "element {}", idx);
+    }
+  }
+}
@@ -23,12 +23,59 @@ pub enum Error {
   OutOfHostMemory,
   OutOfDeviceMemory,
   MissingRequiredFeature,
+  /// The module has no `GLCompute` entry point.
+  NotAComputeKernel,
+  /// The queue's family doesn't support compute.
+  MissingComputeQueue,
+  ZeroGridLaunchAxis,
+  MissingBinding {
+    set: u32,
+    binding: u32,
+  },
+  /// Eg a buffer was provided for an image binding.
+  BindingTypeMismatch {
+    set: u32,
+    binding: u32,
+  },
+  /// Dynamic buffers, samplers, input attachments, etc aren't supported (yet).
+  UnsupportedBinding {
+    set: u32,
+    binding: u32,
+  },
+  /// Vulkan requires the push constant range size be a multiple of four.
+  PushConstantsAlignment {
+    size: u32,
+  },
+  /// The push constants passed to a dispatch don't match the kernel's.
+  PushConstantsSize {
+    expected: u32,
+    actual: u32,
+  },
+  /// The push constant range in the pipeline layout doesn't match the kernel's
+  /// `PushConstant<T>` block. `kernel` is zero if the kernel has no push constants.
+  PushConstantsLayout {
+    declared: u32,
+    kernel: u32,
+  },
+  /// A `SpecConstant` couldn't be lowered to an `OpSpecConstant`.
+  SpecConstant(crate::codegen::spec_constant::SpecConstantError),
+  ComputePipeline(vk::pipeline::ComputePipelineCreationError),
+  Dispatch(vk::command_buffer::DispatchError),
+  CommandBufferBuild(vk::command_buffer::BuildError),
+  CommandBufferExec(vk::command_buffer::CommandBufferExecError),
+  Flush(vk::sync::FlushError),
 }
 impl StdError for Error {
   fn source(&self) -> Option<&(dyn StdError + 'static)> {
     match self {
       Error::Generic(ref inner) => Some(&**inner),
       Error::Io(inner) => Some(inner),
+      Error::SpecConstant(inner) => Some(inner),
+      Error::ComputePipeline(inner) => Some(inner),
+      Error::Dispatch(inner) => Some(inner),
+      Error::CommandBufferBuild(inner) => Some(inner),
+      Error::CommandBufferExec(inner) => Some(inner),
+      Error::Flush(inner) => Some(inner),
       Error::CodegenInitConditions(inner) |
       Error::CodegenInitRoot(inner) |
       Error::CodegenPostCodegen(inner) |
@@ -52,6 +99,42 @@ impl From<vk::OomError> for Error {
     }
   }
 }
+impl From<crate::codegen::spec_constant::SpecConstantError> for Error {
+  #[inline(always)]
+  fn from(v: crate::codegen::spec_constant::SpecConstantError) -> Self {
+    Error::SpecConstant(v)
+  }
+}
+impl From<vk::pipeline::ComputePipelineCreationError> for Error {
+  #[inline(always)]
+  fn from(v: vk::pipeline::ComputePipelineCreationError) -> Self {
+    Error::ComputePipeline(v)
+  }
+}
+impl From<vk::command_buffer::DispatchError> for Error {
+  #[inline(always)]
+  fn from(v: vk::command_buffer::DispatchError) -> Self {
+    Error::Dispatch(v)
+  }
+}
+impl From<vk::command_buffer::BuildError> for Error {
+  #[inline(always)]
+  fn from(v: vk::command_buffer::BuildError) -> Self {
+    Error::CommandBufferBuild(v)
+  }
+}
+impl From<vk::command_buffer::CommandBufferExecError> for Error {
+  #[inline(always)]
+  fn from(v: vk::command_buffer::CommandBufferExecError) -> Self {
+    Error::CommandBufferExec(v)
+  }
+}
+impl From<vk::sync::FlushError> for Error {
+  #[inline(always)]
+  fn from(v: vk::sync::FlushError) -> Self {
+    Error::Flush(v)
+  }
+}
 impl From<grt_core::codegen::error::Error<Error>> for Error {
   #[inline(always)]
   fn from(v: grt_core::codegen::error::Error<Error>) -> Self {
@@ -66,6 +149,8 @@ impl From<grt_core::codegen::error::Error<Error>> for Error {
       InitRoot(inner) => Error::CodegenInitRoot(Box::new(inner)),
       InitConditions(inner) => Error::CodegenInitConditions(Box::new(inner)),
       PreCodegen(inner) => Error::CodegenPreCodegen(Box::new(inner)),
+      // this is a mistake in the caller's layout, not a codegen failure:
+      PostCodegen(inner @ Error::PushConstantsLayout { .. }) => inner,
       PostCodegen(inner) => Error::CodegenPostCodegen(Box::new(inner)),
       ContextDead => Error::ContextDead,
     }
@@ -1,8 +1,10 @@
 //! Crate for Vulkan accelerators.
 
 #![feature(rustc_private)]
+#![feature(const_generics)]
 #![feature(intrinsics)]
 #![feature(geobacter)]
+#![feature(rustc_attrs)]
 
 extern crate rustc_ast;
 extern crate rustc_data_structures;
@@ -24,7 +26,7 @@ use std::num::NonZeroU32;
 use std::sync::Arc;
 
 use grt_core::{AcceleratorId, Accelerator, AcceleratorTargetDesc, PlatformTargetDesc, Device};
-use grt_core::codegen::{CodegenDriver, KernelDesc};
+use grt_core::codegen::{CodegenDriver, KernelDesc, SpecParamsDesc, };
 use grt_core::codegen::products::PCodegenResults;
 use grt_core::context::*;
 
@@ -38,8 +40,12 @@ use crate::module::*;
 pub use crate::error::Error;
 
 pub mod codegen;
+pub mod compute;
 pub mod error;
 pub mod module;
+pub mod push_constant;
+pub mod select;
+pub mod spec_constant;
 
 mod serde_utils;
 
@@ -47,6 +53,8 @@ pub struct VkAccel {
   id: AcceleratorId,
   ctx: Context,
   dev: Arc<vk::device::Device>,
+  /// The queues created with `dev`, if we created it; see `select::VkDeviceEnumerator`.
+  queues: Vec<Arc<vk::device::Queue>>,
 
   target_desc: Arc<AcceleratorTargetDesc>,
 
@@ -54,7 +62,8 @@ pub struct VkAccel {
 }
 
 impl VkAccel {
-  fn new_raw(id: AcceleratorId, ctx: &Context, dev: Arc<vk::device::Device>)
+  fn new_raw(id: AcceleratorId, ctx: &Context, dev: Arc<vk::device::Device>,
+             queues: Vec<Arc<vk::device::Queue>>)
     -> Result<Arc<Self>, Error>
   {
     let features = dev.enabled_features();
@@ -67,6 +76,7 @@ impl VkAccel {
       id,
       ctx: ctx.clone(),
       dev,
+      queues,
       target_desc: Arc::new(target_desc),
       self_codegen: None,
     };
@@ -75,13 +85,23 @@ impl VkAccel {
     let mut out = Arc::new(out);
 
     ctx.initialize_accel(&mut out)?;
+    select::VkDeviceEnumerator::register_once();
 
     Ok(out)
   }
   #[inline(always)]
   pub fn new(ctx: &Context, dev: Arc<vk::device::Device>) -> Result<Arc<Self>, Error> {
+    Self::with_queues(ctx, dev, vec![])
+  }
+  /// Like `new`, but also keep the queues created with `dev`, so they can be retrieved
+  /// with `queues`.
+  #[inline(always)]
+  pub fn with_queues(ctx: &Context, dev: Arc<vk::device::Device>,
+                     queues: Vec<Arc<vk::device::Queue>>)
+    -> Result<Arc<Self>, Error>
+  {
     let id = ctx.take_accel_id();
-    Self::new_raw(id, ctx, dev)
+    Self::new_raw(id, ctx, dev, queues)
   }
   #[inline(always)]
   pub fn instance(&self) -> &Arc<vk::instance::Instance> {
@@ -91,6 +111,68 @@ impl VkAccel {
   pub fn device(&self) -> &Arc<vk::device::Device> {
     &self.dev
   }
+  /// Empty unless this was created with `with_queues`.
+  #[inline(always)]
+  pub fn queues(&self) -> &[Arc<vk::device::Queue>] {
+    &self.queues
+  }
+
+  /// Create a compute module for `f`. Compilation and pipeline creation are deferred
+  /// until first use; call `ComputeModule::pipeline` to do them eagerly.
+  pub fn compute_module<F>(self: &Arc<Self>, f: F, workgroup_size: compute::WorkgroupSize)
+    -> compute::ComputeModule<F, ()>
+    where F: Fn() + Sized,
+  {
+    compute::ComputeModule::new(self, f, workgroup_size)
+  }
+
+  pub fn compile_compute<F>(self: &Arc<Self>, k: F,
+                            workgroup_size: Option<compute::WorkgroupSize>)
+    -> Result<Arc<module::SpirvModule>, Error>
+    where F: Fn() + Sized,
+  {
+    let pipeline = StaticPipelineLayoutDesc::layout_for::<F>();
+    self.compile_compute_with_layout(&k, workgroup_size, pipeline)
+  }
+  /// Use this if `k` has push constants, ie `pipeline` was created with
+  /// `StaticPipelineLayoutDesc::with_push_constants`.
+  pub fn compile_compute_with_layout<F>(self: &Arc<Self>, k: &F,
+                                        workgroup_size: Option<compute::WorkgroupSize>,
+                                        pipeline: StaticPipelineLayoutDesc)
+    -> Result<Arc<module::SpirvModule>, Error>
+    where F: Fn() + Sized,
+  {
+    self.compile_compute_with_params(k, workgroup_size, pipeline, Default::default())
+  }
+  /// Like `compile_compute_with_layout`, but bake `spec_params` into the kernel. The
+  /// kernel reads them with `std::geobacter::spec_param::get`.
+  pub fn compile_compute_with_params<F>(self: &Arc<Self>, k: &F,
+                                        workgroup_size: Option<compute::WorkgroupSize>,
+                                        pipeline: StaticPipelineLayoutDesc,
+                                        spec_params: SpecParamsDesc)
+    -> Result<Arc<module::SpirvModule>, Error>
+    where F: Fn() + Sized,
+  {
+    use std::geobacter::platform::*;
+
+    let instance = k.kernel_instance();
+    let context_data = ModuleContextData::get(k)
+      .get_cache_data(&self.ctx);
+    let desc = VkEntryDesc {
+      exe_model: spirv::ExeModel::GLCompute,
+      workgroup_size,
+      pipeline,
+      interface: Default::default(),
+    };
+
+    let desc = KernelDesc {
+      instance,
+      spec_params,
+      platform_desc: desc,
+    };
+
+    Ok(context_data.compile(self, desc, self.codegen(), cfg!(test))?)
+  }
 }
 
 impl Accelerator for VkAccel {
@@ -206,26 +288,7 @@ impl VkAccel {
     -> Result<Arc<module::SpirvModule>, Error>
     where F: Fn() + Sized,
   {
-    use std::geobacter::platform::*;
-
-    let pipeline = StaticPipelineLayoutDesc::layout_for::<F>();
-    let instance = k.kernel_instance();
-    let context_data = ModuleContextData::get(&k)
-      .get_cache_data(&self.ctx);
-    let desc = VkEntryDesc {
-      exe_model: spirv::ExeModel::GLCompute,
-      workgroup_size,
-      pipeline,
-      interface: Default::default(),
-    };
-
-    let desc = KernelDesc {
-      instance,
-      spec_params: Default::default(),
-      platform_desc: desc,
-    };
-
-    Ok(context_data.compile(self, desc, self.codegen(), cfg!(test))?)
+    self.compile_compute(k, workgroup_size)
   }
 
   /// XXX neither `vert` or `frag` can be used as a compute entry point, but
@@ -404,6 +467,9 @@ impl VkAccel {
       let phys_buffer = AddrSpaceKind::from_str("phys-buffer").unwrap();
       let phys_buffer_idx = AddrSpaceIdx(9);
 
+      let push_constant = AddrSpaceKind::from_str("push-constant").unwrap();
+      let push_constant_idx = AddrSpaceIdx(10);
+
       let props = AddrSpaceProps {
         index: flat_idx,
         shared_with: vec![private.clone(),
@@ -416,6 +482,7 @@ impl VkAccel {
                           uniform.clone(),
                           buffer.clone(),
                           phys_buffer.clone(),
+                          push_constant.clone(),
         ]
           .into_iter()
           .collect(),
@@ -442,6 +509,7 @@ impl VkAccel {
       insert_as(addr_spaces, uniform, uniform_idx);
       insert_as(addr_spaces, buffer, buffer_idx);
       insert_as(addr_spaces, phys_buffer, phys_buffer_idx);
+      insert_as(addr_spaces, push_constant, push_constant_idx);
     }
 
     Ok(())
@@ -6,6 +6,8 @@ use std::geobacter::platform::spirv::ExeModel;
 use std::geobacter::spirv::pipeline_layout::*;
 use std::geobacter::spirv::shader_interface::*;
 use std::iter::Iterator;
+use std::marker::PhantomData;
+use std::mem::size_of;
 use std::ops::Range;
 use std::slice;
 use std::sync::Arc;
@@ -18,6 +20,7 @@ use vk::descriptor::pipeline_layout::*;
 
 use grt_core::context::PlatformModuleData;
 
+use crate::Error;
 use crate::codegen::CodegenShaderInterface;
 
 #[derive(Debug)]
@@ -179,7 +182,7 @@ impl Eq for SpirvModule { }
 impl PartialEq for SpirvModule {
   fn eq(&self, rhs: &Self) -> bool {
     self.entries == rhs.entries &&
-      self.pipeline_desc.0.as_ptr() == rhs.pipeline_desc.0.as_ptr()
+      self.pipeline_desc == rhs.pipeline_desc
   }
 }
 impl PlatformModuleData for SpirvModule {
@@ -308,6 +311,51 @@ unsafe impl vk::pipeline::shader::EntryPointAbstract for SpirvComputeKernel {
   }
   fn layout(&self) -> &Self::PipelineLayout { &self.module.pipeline_desc }
 }
+impl SpirvComputeKernel {
+  /// Use `S` as this kernel's specialization constants when creating a pipeline.
+  #[inline(always)]
+  pub fn specialize<S>(self) -> SpecializedComputeKernel<S>
+    where S: vk::pipeline::shader::SpecializationConstants,
+  {
+    SpecializedComputeKernel {
+      kernel: self,
+      _spec: PhantomData,
+    }
+  }
+}
+
+/// A `SpirvComputeKernel` with `S` for its specialization constants. See
+/// `crate::spec_constant`.
+#[derive(Debug)]
+pub struct SpecializedComputeKernel<S> {
+  kernel: SpirvComputeKernel,
+  _spec: PhantomData<fn() -> S>,
+}
+impl<S> SpecializedComputeKernel<S> {
+  pub fn into_inner(self) -> SpirvComputeKernel { self.kernel }
+}
+impl<S> Clone for SpecializedComputeKernel<S> {
+  fn clone(&self) -> Self {
+    SpecializedComputeKernel {
+      kernel: self.kernel.clone(),
+      _spec: PhantomData,
+    }
+  }
+}
+unsafe impl<S> vk::pipeline::shader::EntryPointAbstract for SpecializedComputeKernel<S>
+  where S: vk::pipeline::shader::SpecializationConstants,
+{
+  type PipelineLayout = StaticPipelineLayoutDesc;
+  type SpecializationConstants = S;
+
+  fn module(&self) -> &vk::pipeline::shader::ShaderModule {
+    &self.kernel.module.entries[self.kernel.entry as usize].spirv
+  }
+  fn name(&self) -> &CStr {
+    &self.kernel.module.entries[self.kernel.entry as usize].name
+  }
+  fn layout(&self) -> &Self::PipelineLayout { &self.kernel.module.pipeline_desc }
+}
 unsafe impl vk::pipeline::shader::EntryPointAbstract for SpirvGraphicsShader {
   type PipelineLayout = StaticPipelineLayoutDesc;
   type SpecializationConstants = ();
@@ -479,22 +527,46 @@ extern "rust-intrinsic" {
   fn geobacter_spirv_pipeline_layout_desc2<F1, F2>() -> CompilerDescriptorSetBindingsDesc;
 }
 
+/// The second field is the size, in bytes, of the push constant block (see
+/// `crate::push_constant::PushConstant`). Zero means the kernel doesn't use push
+/// constants.
 #[derive(Clone, Copy, Debug, Default)]
-pub struct StaticPipelineLayoutDesc(pub CompilerDescriptorSetBindingsDesc);
+pub struct StaticPipelineLayoutDesc(pub CompilerDescriptorSetBindingsDesc, pub u32);
 impl StaticPipelineLayoutDesc {
   #[inline(always)]
   pub fn layout_for<F>() -> Self {
     StaticPipelineLayoutDesc(unsafe {
       geobacter_spirv_pipeline_layout_desc1::<F>()
-    })
+    }, 0)
   }
   #[inline(always)]
   pub fn layout_for2<F1, F2>() -> Self {
     StaticPipelineLayoutDesc(unsafe {
       geobacter_spirv_pipeline_layout_desc2::<F1, F2>()
-    })
+    }, 0)
   }
 
+  /// Add a push constant range for `P`, which must be the `T` of the
+  /// `PushConstant<T>` used by the entry points. Compiling returns
+  /// `Error::PushConstantsLayout` if the range doesn't cover the kernel's block.
+  #[inline(always)]
+  pub fn with_push_constants<P>(self) -> Result<Self, Error>
+    where P: Copy,
+  {
+    self.with_push_constants_size(size_of::<P>() as u32)
+  }
+  /// Vulkan requires `size` be a multiple of four; returns
+  /// `Error::PushConstantsAlignment` otherwise.
+  pub fn with_push_constants_size(mut self, size: u32) -> Result<Self, Error> {
+    if size % 4 != 0 {
+      return Err(Error::PushConstantsAlignment { size, });
+    }
+    self.1 = size;
+    Ok(self)
+  }
+  #[inline(always)]
+  pub fn push_constants_size(&self) -> u32 { self.1 }
+
   #[inline(always)]
   pub fn set_iter(&self) -> impl Iterator<Item = StaticDescSet> {
     self.0.iter().map(|&set| StaticDescSet(set) )
@@ -527,21 +599,30 @@ unsafe impl PipelineLayoutDesc for StaticPipelineLayoutDesc {
       .map(convert_descriptor_desc)
   }
 
-  // we currently don't allow any push constants
-  fn num_push_constants_ranges(&self) -> usize { 0 }
-  fn push_constants_range(&self, _num: usize) -> Option<PipelineLayoutDescPcRange> {
-    None
+  /// SPIR-V allows only one push constant block per entry point, so there is at
+  /// most one range, shared by every stage.
+  fn num_push_constants_ranges(&self) -> usize {
+    if self.1 == 0 { 0 } else { 1 }
+  }
+  fn push_constants_range(&self, num: usize) -> Option<PipelineLayoutDescPcRange> {
+    if num != 0 || self.1 == 0 { return None; }
+    Some(PipelineLayoutDescPcRange {
+      offset: 0,
+      size: self.1 as usize,
+      stages: ShaderStages::all(),
+    })
   }
 }
 impl Eq for StaticPipelineLayoutDesc { }
 impl PartialEq for StaticPipelineLayoutDesc {
   fn eq(&self, rhs: &Self) -> bool {
-    self.0.as_ptr() == rhs.0.as_ptr()
+    self.0.as_ptr() == rhs.0.as_ptr() && self.1 == rhs.1
   }
 }
 impl std::hash::Hash for StaticPipelineLayoutDesc {
   fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-    self.0.as_ptr().hash(state)
+    self.0.as_ptr().hash(state);
+    self.1.hash(state);
   }
 }
 #[derive(Clone, Copy, Debug, Default)]
@@ -0,0 +1,52 @@
+//! Push constants: small, read only kernel parameters which are recorded directly
+//! into the command buffer, instead of going through a uniform buffer.
+//!
+//! ```ignore
+//! #[derive(Clone, Copy)]
+//! #[repr(C)]
+//! struct Params {
+//!   scale: f32,
+//!   len: u32,
+//! }
+//! static PARAMS: PushConstant<Params> = PushConstant::new(Params { scale: 0.0, len: 0, });
+//!
+//! fn kernel() {
+//!   let params = *PARAMS;
+//!   // ...
+//! }
+//!
+//! let module = dev.compute_module(kernel, wg_size)
+//!   .with_push_constants::<Params>();
+//! module.dispatch_with_constants(&queue, grid, &bindings,
+//!                                Params { scale: 2.0, len: 4096, })?;
+//! ```
+//!
+//! SPIR-V only allows a single push constant block per entry point, so a kernel
+//! should use at most one `PushConstant` global. `T` should be a struct; it is
+//! decorated with `Block`, and its fields with their `Offset`s.
+
+use std::ops::Deref;
+
+/// The value of a `PushConstant` static is only used on the host; on the device,
+/// it's whatever was pushed for the dispatch.
+#[rustc_diagnostic_item = "geobacter_spirv_push_constant"]
+#[repr(transparent)]
+pub struct PushConstant<T>(T)
+  where T: Copy;
+impl<T> PushConstant<T>
+  where T: Copy,
+{
+  pub const fn new(init: T) -> Self {
+    PushConstant(init)
+  }
+
+  #[inline(always)]
+  pub fn get(&self) -> T { self.0 }
+}
+impl<T> Deref for PushConstant<T>
+  where T: Copy,
+{
+  type Target = T;
+  #[inline(always)]
+  fn deref(&self) -> &T { &self.0 }
+}
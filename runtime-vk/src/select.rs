@@ -0,0 +1,95 @@
+//! Selecting Vulkan devices with `grt_core::select`, as backend `vk`.
+//!
+//! Devices are indexed like `PhysicalDevice::enumerate`, and have these properties:
+//! `name`, `type` (eg `DiscreteGpu` or `Cpu`) and `vendor_id`. Opened devices have a
+//! queue in every queue family; get them with `VkAccel::queues`.
+//!
+//! The enumerator is registered when this crate is loaded, and again when the first
+//! `VkAccel` is created.
+
+use std::error::Error as StdError;
+use std::sync::{Arc, Mutex, Once, };
+
+use grt_core::Accelerator;
+use grt_core::context::Context;
+use grt_core::register_enumerator_on_load;
+use grt_core::select::{DeviceEnumerator, DeviceInfo, register_enumerator, };
+
+use vk::device::{Device, DeviceExtensions, Features, };
+use vk::instance::{Instance, InstanceExtensions, PhysicalDevice, };
+
+use crate::VkAccel;
+
+type BoxError = Box<dyn StdError + Send + Sync + 'static>;
+
+register_enumerator_on_load!(VkDeviceEnumerator::default());
+
+#[derive(Default)]
+pub struct VkDeviceEnumerator {
+  /// Created on first use, so loading this crate doesn't touch the Vulkan loader.
+  instance: Mutex<Option<Arc<Instance>>>,
+}
+impl VkDeviceEnumerator {
+  /// Register with `grt_core::select`, for `DeviceSelector::new` and
+  /// `DeviceSelector::from_env`. This only needs to be called if our
+  /// `register_enumerator_on_load!` was dropped by the linker and no device has been
+  /// created yet.
+  pub fn register() {
+    register_enumerator(Arc::new(VkDeviceEnumerator::default()));
+  }
+  pub(crate) fn register_once() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(Self::register);
+  }
+
+  fn instance(&self) -> Result<Arc<Instance>, BoxError> {
+    let mut instance = self.instance.lock().unwrap();
+    if let Some(ref instance) = *instance {
+      return Ok(instance.clone());
+    }
+    let i = Instance::new(None, &InstanceExtensions::none(), None)?;
+    *instance = Some(i.clone());
+    Ok(i)
+  }
+}
+impl DeviceEnumerator for VkDeviceEnumerator {
+  fn backend(&self) -> &str { "vk" }
+
+  fn devices(&self) -> Result<Vec<DeviceInfo>, BoxError> {
+    let instance = self.instance()?;
+    let out = PhysicalDevice::enumerate(&instance)
+      .map(|phy| {
+        DeviceInfo::new(self.backend(), phy.index())
+          .with("name", phy.name().to_string())
+          .with("type", format!("{:?}", phy.ty()))
+          .with("vendor_id", format!("{:#06x}", phy.pci_vendor_id()))
+      })
+      .collect();
+    Ok(out)
+  }
+  fn open(&self, ctx: &Context, index: usize)
+    -> Result<Arc<dyn Accelerator>, BoxError>
+  {
+    let instance = self.instance()?;
+    let phy = PhysicalDevice::from_index(&instance, index)
+      .ok_or_else(|| format!("no Vulkan device {}", index) )?;
+
+    // required by `VkAccel`:
+    let features = Features {
+      variable_pointers: true,
+      variable_pointers_storage_buffer: true,
+      .. Features::none()
+    };
+    let families = phy.queue_families()
+      .map(|fam| (fam, 0.5) );
+    let (dev, queues) = Device::new(phy, &features, &DeviceExtensions::none(),
+                                    families)?;
+    let dev = VkAccel::with_queues(ctx, dev, queues.collect())?;
+    Ok(dev)
+  }
+
+  fn index_of(&self, accel: &dyn Accelerator) -> Option<usize> {
+    let accel = <VkAccel as Accelerator>::downcast_ref(accel)?;
+    Some(accel.device().physical_device().index())
+  }
+}
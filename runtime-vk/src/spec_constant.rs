@@ -0,0 +1,74 @@
+//! SPIR-V specialization constants.
+//!
+//! Spec params (`SpecParamsDesc`, `std::geobacter::spec_param`) work here as on the
+//! other runtimes: set them with `ComputeModule::define_param` and they're baked into
+//! the kernel, so every new set of values is a new codegen run. A `SpecConstant` is
+//! instead lowered to an `OpSpecConstant` decorated with `SpecId = ID`, and its value
+//! is provided when the pipeline is created. One compiled module can then be
+//! specialized any number of times, eg for a parameter sweep.
+//!
+//! ```ignore
+//! static TILE: SpecConstant<u32, 0> = SpecConstant::new(16);
+//! static SCALE: SpecConstant<f32, 1> = SpecConstant::new(1.0);
+//!
+//! #[derive(Clone, Copy)]
+//! #[repr(C)]
+//! struct Spec {
+//!   tile: u32,
+//!   scale: f32,
+//! }
+//! unsafe impl SpecializationConstants for Spec {
+//!   fn descriptors() -> &'static [SpecializationMapEntry] {
+//!     static D: [SpecializationMapEntry; 2] = [
+//!       SpecializationMapEntry { constant_id: 0, offset: 0, size: 4, },
+//!       SpecializationMapEntry { constant_id: 1, offset: 4, size: 4, },
+//!     ];
+//!     &D
+//!   }
+//! }
+//!
+//! fn kernel() {
+//!   let tile = TILE.get();
+//!   // ...
+//! }
+//!
+//! let mut module = dev.compute_module(kernel, wg_size)
+//!   .with_spec_constants(Spec { tile: 16, scale: 1.0, });
+//! for &scale in scales.iter() {
+//!   // only recreates the pipeline; the kernel isn't recompiled:
+//!   module.set_spec_constants(Spec { tile: 16, scale, });
+//!   module.dispatch(&queue, grid, &bindings)?.wait(None)?;
+//! }
+//! ```
+//!
+//! `ID` is the `constant_id` used by `vk::pipeline::shader::SpecializationConstants`;
+//! IDs are chosen by the user so they don't change between builds. Constants not
+//! given a value at pipeline creation use the value passed to `SpecConstant::new`.
+//! `T` must be a `bool`, integer, or float. Read the value with `get`, and don't take
+//! the `SpecConstant`'s address, eg by passing it by reference to a function which
+//! isn't inlined; it's a constant on the device, not a variable.
+
+use std::ptr::read_volatile;
+
+pub use vk::pipeline::shader::{SpecializationConstants, SpecializationMapEntry, };
+
+#[rustc_diagnostic_item = "geobacter_spirv_spec_constant"]
+#[repr(transparent)]
+pub struct SpecConstant<T, const ID: u32>(T)
+  where T: Copy;
+impl<T, const ID: u32> SpecConstant<T, ID>
+  where T: Copy,
+{
+  pub const fn new(default: T) -> Self {
+    SpecConstant(default)
+  }
+
+  #[inline(always)]
+  pub const fn id(&self) -> u32 { ID }
+  /// On the host, this is always the default value. The load is volatile so LLVM
+  /// can't fold it into the default value before it's lowered to a spec constant.
+  #[inline(always)]
+  pub fn get(&self) -> T {
+    unsafe { read_volatile(&self.0) }
+  }
+}
@@ -224,3 +224,48 @@ pub fn checkout_repo_commit(task: &str, dest: &Path,
     .arg("--init");
   run_unlogged_cmd(task, cmd);
 }
+
+/// The environment variables which add `key = value` to the config of every git process,
+/// after the `count` entries already passed through the environment (`GIT_CONFIG_COUNT`).
+pub fn config_env(count: Option<&str>, key: &str, value: &str)
+  -> Result<Vec<(String, String)>, Box<dyn Error>>
+{
+  let n: usize = match count {
+    Some(count) => count.parse()
+      .map_err(|_| format!("invalid GIT_CONFIG_COUNT `{}`", count) )?,
+    None => 0,
+  };
+  Ok(vec![
+    (format!("GIT_CONFIG_KEY_{}", n), key.into()),
+    (format!("GIT_CONFIG_VALUE_{}", n), value.into()),
+    ("GIT_CONFIG_COUNT".into(), (n + 1).to_string()),
+  ])
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn config_env_appends() {
+    let env = |count| {
+      config_env(count, "url./srv/mirror/.insteadOf", "https://github.com/").unwrap()
+    };
+    let pairs = |pairs: &[(&str, &str)]| {
+      pairs.iter()
+        .map(|&(k, v)| (k.to_string(), v.to_string()) )
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(env(None), pairs(&[
+      ("GIT_CONFIG_KEY_0", "url./srv/mirror/.insteadOf"),
+      ("GIT_CONFIG_VALUE_0", "https://github.com/"),
+      ("GIT_CONFIG_COUNT", "1"),
+    ]));
+    assert_eq!(env(Some("2")), pairs(&[
+      ("GIT_CONFIG_KEY_2", "url./srv/mirror/.insteadOf"),
+      ("GIT_CONFIG_VALUE_2", "https://github.com/"),
+      ("GIT_CONFIG_COUNT", "3"),
+    ]));
+    assert!(config_env(Some("two"), "a.b", "c").is_err());
+  }
+}
@@ -6,10 +6,24 @@
 //! It is intended that development of the Rust Geobacter patches
 //! is done in a separate checkout, which is passed to us via `--repo-url`
 //!
+//! For builds without network access, `--source-dir` builds an existing
+//! checkout or extracted source tarball, `--mirror` fetches everything
+//! from a local mirror of GitHub instead, and `--stage0` bootstraps with a
+//! local toolchain instead of the one x.py would download. x.py also
+//! downloads the crates the build needs, unless they're vendored, which
+//! they are in source tarballs.
+//!
+//! Every build writes a lock manifest (`geobacter-toolchain.lock` in the
+//! target dir) recording the commits of the sources, the `config.toml` and
+//! the stage0 compiler used. `--from-manifest` rebuilds the toolchain it
+//! describes; add `--stage0` to do that without network access, with a local
+//! copy of the same stage0 toolchain.
+//!
 
-use std::env::current_dir;
+use std::env::{current_dir, set_var, var, };
+use std::env::consts::EXE_SUFFIX;
 use std::error::Error;
-use std::fs::{File, read_dir, };
+use std::fs::{self, File, read_dir, };
 use std::io::*;
 use std::path::{Path, PathBuf, };
 use std::process::Command;
@@ -19,7 +33,10 @@ use clap::*;
 
 use which::which;
 
+use crate::manifest::{Manifest, MANIFEST_NAME, Stage0, };
+
 mod git;
+mod manifest;
 
 const RUST_REPO_URL: &str = "https://github.com/geobacter-rs/rust.git";
 const RUST_REPO_BRANCH: &str = "merge-head";
@@ -37,6 +54,29 @@ pub fn main() -> Result<(), Box<dyn Error>> {
     .default_value(RUST_REPO_BRANCH)
     .requires("repo-url");
 
+  let source_dir = Arg::with_name("source-dir")
+    .long("source-dir")
+    .help("build the Rust sources in this dir, eg a local checkout or an extracted \
+           source tarball, instead of checking them out")
+    .takes_value(true);
+  let mirror = Arg::with_name("mirror")
+    .long("mirror")
+    .help("fetch GitHub repos, including submodules, from this mirror instead, eg \
+           `/srv/mirror` for `/srv/mirror/geobacter-rs/rust.git`. Needs git 2.31 or later")
+    .takes_value(true);
+  let stage0 = Arg::with_name("stage0")
+    .long("stage0")
+    .help("bootstrap with the `rustc` and `cargo` in this toolchain's `bin` dir, instead \
+           of downloading the toolchain in `src/stage0.txt`, which this must match. With \
+           `--from-manifest`, this must be the stage0 recorded in the manifest")
+    .takes_value(true)
+    .conflicts_with("build-config");
+  let from_manifest = Arg::with_name("from-manifest")
+    .long("from-manifest")
+    .help("rebuild the toolchain described by this lock manifest")
+    .takes_value(true)
+    .conflicts_with("build-config");
+
   // If "build-config" is not given, we manage our own.
   let config_path = Arg::with_name("build-config")
     .long("config")
@@ -91,6 +131,10 @@ pub fn main() -> Result<(), Box<dyn Error>> {
     .author("Richard Diamond <dick@vitalitystudios.com>")
     .arg(repo_url)
     .arg(repo_branch)
+    .arg(source_dir)
+    .arg(mirror)
+    .arg(stage0)
+    .arg(from_manifest)
     .arg(config_path)
     .arg(build_docs)
     .arg(target_dir)
@@ -109,6 +153,30 @@ const RUST_FLAGS: &str = "-Z always-encode-mir -Z always-emit-metadata";
 trait Builder {
   fn repo_url(&self) -> &str;
   fn repo_branch(&self) -> &str;
+  fn source_dir(&self) -> Option<&Path>;
+  fn mirror(&self) -> Option<&str>;
+  fn stage0(&self) -> Option<&Path>;
+  /// The `rustc` and `cargo` of the `--stage0` toolchain.
+  fn stage0_tools(&self) -> Option<(PathBuf, PathBuf)> {
+    let bin = self.stage0()?
+      .canonicalize()
+      .unwrap_or_else(|_| self.stage0().unwrap().into() )
+      .join("bin");
+    Some((bin.join(format!("rustc{}", EXE_SUFFIX)),
+          bin.join(format!("cargo{}", EXE_SUFFIX))))
+  }
+  /// The `rustc` which bootstrapped the toolchain: `--stage0`'s, or the one x.py
+  /// downloaded. `None` if x.py hasn't downloaded it yet.
+  fn stage0_rustc(&self) -> Option<PathBuf> {
+    if let Some((rustc, _)) = self.stage0_tools() {
+      return Some(rustc);
+    }
+    read_dir(self.build_dir()).ok()?
+      .filter_map(|dir| dir.ok() )
+      .map(|dir| dir.path().join("stage0/bin").join(format!("rustc{}", EXE_SUFFIX)) )
+      .find(|rustc| rustc.exists() )
+  }
+  fn manifest_path(&self) -> Option<&Path>;
   fn rustup_enabled(&self) -> bool;
   fn rustup_toolchain(&self) -> &str;
   fn rustup(&self) -> Option<&str> {
@@ -136,8 +204,17 @@ trait Builder {
     self.target_dir().join("build")
   }
   fn src_dir(&self) -> PathBuf {
+    if let Some(dir) = self.source_dir() {
+      // x.py is run from the target dir, so this must be absolute.
+      return dir.canonicalize()
+        .unwrap_or_else(|_| dir.into() );
+    }
+
     self.target_dir().join("src")
   }
+  fn lock_manifest_path(&self) -> PathBuf {
+    self.target_dir().join(MANIFEST_NAME)
+  }
   fn x_py(&self) -> PathBuf {
     self.src_dir().join("x.py")
   }
@@ -197,11 +274,52 @@ trait Builder {
     if self.rustup_enabled() && which("rustup").is_err() {
       return Err("I need `rustup` somewhere in PATH".into());
     }
+    if self.source_dir().is_some() && !self.x_py().exists() {
+      return Err(format!("{} doesn't exist; is `--source-dir` a Rust checkout?",
+                         self.x_py().display()).into());
+    }
+    if let Some((rustc, cargo)) = self.stage0_tools() {
+      for tool in [rustc, cargo].iter() {
+        if !tool.exists() {
+          return Err(format!("{} doesn't exist; is `--stage0` a Rust toolchain?",
+                             tool.display()).into());
+        }
+      }
+    }
+
+    Ok(())
+  }
 
+  /// Rewrite GitHub URLs to the mirror. This is passed to every git process through
+  /// the environment, so it applies to submodules and x.py's own fetches too. Config
+  /// already passed through the environment is kept.
+  fn setup_mirror(&self) -> Result<(), Box<dyn Error>> {
+    let mirror = match self.mirror() {
+      Some(mirror) => mirror,
+      None => { return Ok(()); },
+    };
+    let mut mirror = Path::new(mirror).canonicalize()
+      .map(|dir| dir.to_str().expect("non-utf8 in path").to_string() )
+      .unwrap_or_else(|_| mirror.to_string() );
+    if !mirror.ends_with('/') {
+      mirror.push('/');
+    }
+
+    let count = var("GIT_CONFIG_COUNT").ok();
+    let env = git::config_env(count.as_deref(), &format!("url.{}.insteadOf", mirror),
+                              "https://github.com/")?;
+    for (key, value) in env {
+      set_var(key, value);
+    }
     Ok(())
   }
 
   fn checkout_rust_sources(&self) {
+    if let Some(dir) = self.source_dir() {
+      println!("Using the Rust sources in {}", dir.display());
+      return;
+    }
+
     let url = self.repo_url();
     let branch = self.repo_branch();
     git::checkout_or_override("rust", &self.src_dir(),
@@ -209,6 +327,19 @@ trait Builder {
                               true)
       .unwrap();
   }
+  /// Check out the sources `manifest` was generated from, or if `--source-dir` was given,
+  /// check it has those sources.
+  fn checkout_manifest_sources(&self, manifest: &Manifest) -> Result<(), Box<dyn Error>> {
+    if self.source_dir().is_none() {
+      let url = manifest.rust.url.as_ref()
+        .ok_or("the manifest was generated from a `--source-dir`, so I need one too")?;
+      git::checkout_or_override_commit("rust", &self.src_dir(),
+                                       None, url, &manifest.rust.commit,
+                                       false)?;
+    }
+
+    manifest.check_sources(&self.src_dir())
+  }
   fn write_config_toml(&self) {
     if self.given_config_path().is_some() { return; }
 
@@ -240,7 +371,16 @@ compiler-docs = true
 submodules = true
 low-priority = true
 full-bootstrap = true
-
+"#).unwrap();
+    if self.src_dir().join("vendor").is_dir() {
+      // a source tarball, which includes every crate we need.
+      writeln!(config, "vendor = true").unwrap();
+    }
+    if let Some((rustc, cargo)) = self.stage0_tools() {
+      writeln!(config, "rustc = {:?}", rustc.to_str().expect("non-utf8 in path")).unwrap();
+      writeln!(config, "cargo = {:?}", cargo.to_str().expect("non-utf8 in path")).unwrap();
+    }
+    write!(config, r#"
 [rust]
 debuginfo-level = {}
 debuginfo-level-std = 2
@@ -296,13 +436,62 @@ ar = "ar"
     }
   }
 
+  /// Record the sources, config and stage0 we just built with.
+  fn write_lock_manifest(&self, from: Option<&Manifest>, stage0: Option<Stage0>)
+    -> Result<PathBuf, Box<dyn Error>>
+  {
+    let (url, branch) = match from {
+      Some(from) => (from.rust.url.as_deref(), from.rust.branch.as_deref()),
+      None if self.source_dir().is_some() => (None, None),
+      None => (Some(self.repo_url()), Some(self.repo_branch())),
+    };
+    let manifest = Manifest::collect(&self.src_dir(), url, branch,
+                                     &self.config_path(), stage0)?;
+
+    let path = self.lock_manifest_path();
+    manifest.write(&path)?;
+    Ok(path)
+  }
+
   fn run(&self) -> Result<(), Box<dyn Error>> {
     self.check_required_tools()?;
-    self.checkout_rust_sources();
-    self.write_config_toml();
+    self.setup_mirror()?;
+
+    let from = match self.manifest_path() {
+      Some(path) => Some(Manifest::read(path)?),
+      None => None,
+    };
+    if let Some(ref manifest) = from {
+      self.checkout_manifest_sources(manifest)?;
+      let config = match self.stage0_tools() {
+        Some((rustc, cargo)) => {
+          // fail before spending hours building with the wrong compiler:
+          manifest.check_stage0(&Stage0::of(&rustc)?)?;
+          manifest.config.with_stage0(&rustc, &cargo)
+        },
+        None => manifest.config.contents.clone(),
+      };
+      fs::write(self.config_path(), config)?;
+    } else {
+      self.checkout_rust_sources();
+      self.write_config_toml();
+    }
     self.build_toolchain();
     self.build_docs();
+
+    let stage0 = match self.stage0_rustc() {
+      Some(rustc) => Some(Stage0::of(&rustc)?),
+      None => {
+        println!("warning: couldn't find the stage0 rustc; not recording it");
+        None
+      },
+    };
+    if let (Some(manifest), Some(stage0)) = (from.as_ref(), stage0.as_ref()) {
+      manifest.check_stage0(stage0)?;
+    }
+
     self.install_toolchain_into_rustup();
+    let lock = self.write_lock_manifest(from.as_ref(), stage0)?;
 
     let driver = if !self.rustup_enabled() {
       Some(self.geobacter_driver())
@@ -324,6 +513,7 @@ ar = "ar"
     run_unlogged_cmd("verify-rustc", cmd);
 
     println!("Complete! :)");
+    println!("The sources and config used are recorded in {}", lock.display());
     if let Some(ref driver) = driver {
       println!("To use your new toolchain set \"RUSTC={}\"",
                driver.display());
@@ -344,6 +534,25 @@ impl<'a> Builder for ArgMatches<'a> {
     self.value_of("repo-branch").unwrap()
   }
 
+  fn source_dir(&self) -> Option<&Path> {
+    self.value_of("source-dir")
+      .map(Path::new)
+  }
+
+  fn mirror(&self) -> Option<&str> {
+    self.value_of("mirror")
+  }
+
+  fn stage0(&self) -> Option<&Path> {
+    self.value_of("stage0")
+      .map(Path::new)
+  }
+
+  fn manifest_path(&self) -> Option<&Path> {
+    self.value_of("from-manifest")
+      .map(Path::new)
+  }
+
   fn rustup_enabled(&self) -> bool {
     !self.is_present("no-rustup")
   }
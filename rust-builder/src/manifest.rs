@@ -0,0 +1,378 @@
+//! The lock manifest, which records exactly what a toolchain was built from: the commit of
+//! the Rust sources and of each of their submodules, the `config.toml`, and the stage0
+//! compiler which bootstrapped it. Passing it to `--from-manifest` rebuilds the same
+//! toolchain.
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use git2::{ObjectType, Oid, Repository, };
+
+use serde::{Serialize, Deserialize, };
+
+pub const MANIFEST_VERSION: u32 = 1;
+pub const MANIFEST_NAME: &str = "geobacter-toolchain.lock";
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+  pub version: u32,
+  pub rust: Source,
+  #[serde(default)]
+  pub submodules: Vec<Submodule>,
+  pub config: Config,
+  /// `None` in manifests written before this was recorded.
+  #[serde(default)]
+  pub stage0: Option<Stage0>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Source {
+  /// The upstream URL, even if the sources were fetched from a mirror. `None` if the
+  /// sources were given with `--source-dir`.
+  pub url: Option<String>,
+  pub branch: Option<String>,
+  pub commit: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Submodule {
+  pub path: String,
+  pub url: Option<String>,
+  pub commit: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+  /// The git blob ID of `contents`.
+  pub hash: String,
+  pub contents: String,
+}
+
+/// Identifies the compiler used to bootstrap the toolchain, from its `rustc -vV`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Stage0 {
+  pub release: String,
+  pub commit_hash: String,
+}
+impl Stage0 {
+  /// Run `rustc -vV`.
+  pub fn of(rustc: &Path) -> Result<Self, Box<dyn Error>> {
+    let out = Command::new(rustc)
+      .arg("-vV")
+      .output()
+      .map_err(|err| format!("failed to run {}: {}", rustc.display(), err) )?;
+    if !out.status.success() {
+      return Err(format!("`{} -vV` failed", rustc.display()).into());
+    }
+    Self::parse(&String::from_utf8_lossy(&out.stdout))
+  }
+  fn parse(version: &str) -> Result<Self, Box<dyn Error>> {
+    let field = |name: &str| {
+      version.lines()
+        .filter_map(|line| {
+          let mut kv = line.splitn(2, ':');
+          Some((kv.next()?.trim(), kv.next()?.trim()))
+        })
+        .find(|&(key, _)| key == name )
+        .map(|(_, value)| value.to_string() )
+        .ok_or_else(|| format!("no `{}` in `rustc -vV` output", name) )
+    };
+    Ok(Stage0 {
+      release: field("release")?,
+      commit_hash: field("commit-hash")?,
+    })
+  }
+}
+
+fn config_hash(contents: &str) -> Result<String, Box<dyn Error>> {
+  Ok(Oid::hash_object(ObjectType::Blob, contents.as_bytes())?.to_string())
+}
+
+impl Config {
+  pub fn new(contents: String) -> Result<Self, Box<dyn Error>> {
+    Ok(Config {
+      hash: config_hash(&contents)?,
+      contents,
+    })
+  }
+  /// Check `contents` hasn't been edited since the manifest was generated.
+  pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+    let hash = config_hash(&self.contents)?;
+    if hash != self.hash {
+      return Err(format!("manifest config hash is {}, but its contents hash to {}",
+                         self.hash, hash).into());
+    }
+    Ok(())
+  }
+  /// `contents`, but bootstrapping with `rustc` and `cargo` instead of whatever the
+  /// `[build]` table said.
+  pub fn with_stage0(&self, rustc: &Path, cargo: &Path) -> String {
+    let tools = format!("rustc = {:?}\ncargo = {:?}\n",
+                        rustc.to_str().expect("non-utf8 in path"),
+                        cargo.to_str().expect("non-utf8 in path"));
+    let mut out = String::with_capacity(self.contents.len() + tools.len());
+    let mut in_build = false;
+    let mut found_build = false;
+    for line in self.contents.lines() {
+      let trimmed = line.trim();
+      if trimmed.starts_with('[') {
+        in_build = trimmed == "[build]";
+      }
+      let key = trimmed.split('=').next().unwrap_or_default().trim();
+      if in_build && (key == "rustc" || key == "cargo") {
+        continue;
+      }
+      out.push_str(line);
+      out.push('\n');
+      if in_build && !found_build {
+        found_build = true;
+        out.push_str(&tools);
+      }
+    }
+    if !found_build {
+      out.push_str("\n[build]\n");
+      out.push_str(&tools);
+    }
+    out
+  }
+}
+
+/// The checked out commit of `src` and its submodules. Rust source tarballs aren't git
+/// checkouts, but have their commit in `git-commit-hash`.
+fn source_commits(src: &Path) -> Result<(String, Vec<Submodule>), Box<dyn Error>> {
+  let repo = match Repository::open(src) {
+    Ok(repo) => repo,
+    Err(_) => {
+      let commit = fs::read_to_string(src.join("git-commit-hash"))
+        .map_err(|_| {
+          format!("{} is not a git checkout and has no `git-commit-hash`", src.display())
+        })?;
+      return Ok((commit.trim().into(), vec![]));
+    },
+  };
+
+  let commit = repo.head()?
+    .peel_to_commit()?
+    .id()
+    .to_string();
+
+  let mut submodules = vec![];
+  for sm in repo.submodules()? {
+    // if the submodule isn't checked out yet, x.py will check out the commit in the index.
+    let id = sm.workdir_id()
+      .or_else(|| sm.index_id() )
+      .ok_or_else(|| format!("submodule {} has no commit", sm.path().display()) )?;
+    submodules.push(Submodule {
+      path: sm.path().to_string_lossy().into_owned(),
+      url: sm.url().map(String::from),
+      commit: id.to_string(),
+    });
+  }
+  submodules.sort_by(|l, r| l.path.cmp(&r.path) );
+
+  Ok((commit, submodules))
+}
+
+impl Manifest {
+  /// Describe the sources in `src`, the config at `config`, and the bootstrap compiler.
+  pub fn collect(src: &Path, url: Option<&str>, branch: Option<&str>, config: &Path,
+                 stage0: Option<Stage0>)
+    -> Result<Self, Box<dyn Error>>
+  {
+    let (commit, submodules) = source_commits(src)?;
+    Ok(Manifest {
+      version: MANIFEST_VERSION,
+      rust: Source {
+        url: url.map(String::from),
+        branch: branch.map(String::from),
+        commit,
+      },
+      submodules,
+      config: Config::new(fs::read_to_string(config)?)?,
+      stage0,
+    })
+  }
+
+  pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+    let manifest: Manifest = toml::from_str(&fs::read_to_string(path)?)
+      .map_err(|err| format!("failed to parse manifest {}: {}", path.display(), err) )?;
+    if manifest.version != MANIFEST_VERSION {
+      return Err(format!("manifest {} has version {}, expected {}", path.display(),
+                         manifest.version, MANIFEST_VERSION).into());
+    }
+    manifest.config.verify()?;
+    Ok(manifest)
+  }
+  pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut out = String::from("# Generated by rust-builder; rebuild this toolchain with \
+                                `rust-builder --from-manifest <this file>`.\n");
+    out.push_str(&toml::to_string_pretty(self)?);
+    fs::write(path, out)?;
+    Ok(())
+  }
+
+  /// Check the sources in `src` are the ones this manifest was generated from.
+  pub fn check_sources(&self, src: &Path) -> Result<(), Box<dyn Error>> {
+    let (commit, submodules) = source_commits(src)?;
+    let mismatches = self.mismatches(&commit, &submodules);
+    if mismatches.is_empty() {
+      Ok(())
+    } else {
+      Err(format!("sources in {} don't match the manifest:\n{}", src.display(),
+                  mismatches.join("\n")).into())
+    }
+  }
+  /// Check `found` is the compiler this manifest's toolchain was bootstrapped with, if
+  /// it recorded one.
+  pub fn check_stage0(&self, found: &Stage0) -> Result<(), Box<dyn Error>> {
+    match self.stage0 {
+      Some(ref expected) if expected != found => {
+        Err(format!("stage0 compiler doesn't match the manifest: expected {} ({}), \
+                     found {} ({})", expected.release, expected.commit_hash,
+                    found.release, found.commit_hash).into())
+      },
+      _ => Ok(()),
+    }
+  }
+  fn mismatches(&self, commit: &str, submodules: &[Submodule]) -> Vec<String> {
+    let mut out = vec![];
+    if commit != self.rust.commit {
+      out.push(format!("rust: expected {}, found {}", self.rust.commit, commit));
+    }
+    for expected in self.submodules.iter() {
+      match submodules.iter().find(|sm| sm.path == expected.path ) {
+        Some(sm) if sm.commit != expected.commit => {
+          out.push(format!("{}: expected {}, found {}", expected.path, expected.commit,
+                           sm.commit));
+        },
+        Some(_) => { },
+        None => out.push(format!("{}: missing", expected.path)),
+      }
+    }
+    for sm in submodules.iter() {
+      if !self.submodules.iter().any(|expected| expected.path == sm.path ) {
+        out.push(format!("{}: not in the manifest", sm.path));
+      }
+    }
+    out
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn manifest() -> Manifest {
+    Manifest {
+      version: MANIFEST_VERSION,
+      rust: Source {
+        url: Some("https://github.com/geobacter-rs/rust.git".into()),
+        branch: Some("merge-head".into()),
+        commit: "1111111111111111111111111111111111111111".into(),
+      },
+      submodules: vec![
+        Submodule {
+          path: "src/llvm-project".into(),
+          url: Some("https://github.com/geobacter-rs/llvm-project.git".into()),
+          commit: "2222222222222222222222222222222222222222".into(),
+        },
+      ],
+      config: Config::new("[llvm]\nassertions = false\n\n[rust]\nlld = false\n".into())
+        .unwrap(),
+      stage0: Some(Stage0 {
+        release: "1.47.0".into(),
+        commit_hash: "18bf6b4f01a6feaf7259ba7cdae58031af1b7b39".into(),
+      }),
+    }
+  }
+
+  #[test]
+  fn round_trip() {
+    let manifest = manifest();
+    let text = toml::to_string_pretty(&manifest).unwrap();
+    let parsed: Manifest = toml::from_str(&text).unwrap();
+    assert_eq!(parsed, manifest);
+    parsed.config.verify().unwrap();
+  }
+
+  #[test]
+  fn config_hash_is_git_blob_id() {
+    // `printf 'hello\n' | git hash-object --stdin`
+    assert_eq!(config_hash("hello\n").unwrap(), "ce013625030ba8dba906f756967f9e9ca394464a");
+
+    let mut config = manifest().config;
+    config.contents.push_str("incremental = true\n");
+    assert!(config.verify().is_err());
+  }
+
+  #[test]
+  fn mismatches() {
+    let manifest = manifest();
+    let rust = &manifest.rust.commit;
+    assert!(manifest.mismatches(rust, &manifest.submodules).is_empty());
+
+    let mut submodules = manifest.submodules.clone();
+    submodules[0].commit = "3333333333333333333333333333333333333333".into();
+    submodules.push(Submodule {
+      path: "src/tools/cargo".into(),
+      url: None,
+      commit: "4444444444444444444444444444444444444444".into(),
+    });
+    let mismatches = manifest.mismatches("5555555555555555555555555555555555555555",
+                                         &submodules);
+    assert_eq!(mismatches, [
+      "rust: expected 1111111111111111111111111111111111111111, \
+       found 5555555555555555555555555555555555555555",
+      "src/llvm-project: expected 2222222222222222222222222222222222222222, \
+       found 3333333333333333333333333333333333333333",
+      "src/tools/cargo: not in the manifest",
+    ]);
+    assert_eq!(manifest.mismatches(rust, &[]), ["src/llvm-project: missing"]);
+  }
+
+  #[test]
+  fn stage0() {
+    let version = "rustc 1.47.0 (18bf6b4f0 2020-10-07)\n\
+                   binary: rustc\n\
+                   commit-hash: 18bf6b4f01a6feaf7259ba7cdae58031af1b7b39\n\
+                   commit-date: 2020-10-07\n\
+                   host: x86_64-unknown-linux-gnu\n\
+                   release: 1.47.0\n\
+                   LLVM version: 11.0\n";
+    let manifest = manifest();
+    let stage0 = Stage0::parse(version).unwrap();
+    assert_eq!(Some(&stage0), manifest.stage0.as_ref());
+    manifest.check_stage0(&stage0).unwrap();
+
+    let other = Stage0 {
+      release: "1.48.0".into(),
+      commit_hash: "7eac88abb2e57e752f3302f02be5f3ce3d7adfb4".into(),
+    };
+    assert!(manifest.check_stage0(&other).is_err());
+    assert!(Stage0::parse("rustc 1.47.0\n").is_err());
+
+    // older manifests don't have it:
+    let text = toml::to_string_pretty(&Manifest { stage0: None, .. manifest }).unwrap();
+    let parsed: Manifest = toml::from_str(&text).unwrap();
+    assert_eq!(parsed.stage0, None);
+    parsed.check_stage0(&other).unwrap();
+  }
+
+  #[test]
+  fn with_stage0() {
+    let config = Config::new("[llvm]\nninja = true\n\n[build]\nrustc = \"/net/bin/rustc\"\n\
+                              full-bootstrap = true\n\n[rust]\nlld = false\n".into())
+      .unwrap();
+    let (rustc, cargo) = (Path::new("/s0/bin/rustc"), Path::new("/s0/bin/cargo"));
+    assert_eq!(config.with_stage0(rustc, cargo),
+               "[llvm]\nninja = true\n\n[build]\nrustc = \"/s0/bin/rustc\"\n\
+                cargo = \"/s0/bin/cargo\"\nfull-bootstrap = true\n\n[rust]\nlld = false\n");
+
+    let config = Config::new("[rust]\nlld = false\n".into()).unwrap();
+    assert_eq!(config.with_stage0(rustc, cargo),
+               "[rust]\nlld = false\n\n[build]\nrustc = \"/s0/bin/rustc\"\n\
+                cargo = \"/s0/bin/cargo\"\n");
+  }
+}
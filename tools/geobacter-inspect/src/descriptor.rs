@@ -0,0 +1,122 @@
+//! The 64 byte kernel descriptor (`<kernel>.kd`) of code object v3 and later.
+//!
+//! See https://llvm.org/docs/AMDGPUUsage.html#kernel-descriptor
+
+use serde::Serialize;
+
+pub const KERNEL_DESCRIPTOR_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct KernelDescriptor {
+  pub group_segment_fixed_size: u32,
+  pub private_segment_fixed_size: u32,
+  pub kernarg_size: u32,
+  /// Relative to the descriptor. Zero in relocatable objects, where it's filled in by a
+  /// relocation.
+  pub kernel_code_entry_byte_offset: i64,
+  pub compute_pgm_rsrc3: u32,
+  pub compute_pgm_rsrc1: u32,
+  pub compute_pgm_rsrc2: u32,
+  pub kernel_code_properties: u16,
+}
+
+/// Bits of `kernel_code_properties`, which say which SGPRs are initialized for the kernel.
+const CODE_PROPERTIES: &[(u16, &str)] = &[
+  (1 << 0, "private_segment_buffer"),
+  (1 << 1, "dispatch_ptr"),
+  (1 << 2, "queue_ptr"),
+  (1 << 3, "kernarg_segment_ptr"),
+  (1 << 4, "dispatch_id"),
+  (1 << 5, "flat_scratch_init"),
+  (1 << 6, "private_segment_size"),
+  (1 << 10, "wavefront_size32"),
+];
+/// Bits of `compute_pgm_rsrc2`.
+const RSRC2_FLAGS: &[(u32, &str)] = &[
+  (1 << 0, "private_segment_wavefront_offset"),
+  (1 << 7, "workgroup_id_x"),
+  (1 << 8, "workgroup_id_y"),
+  (1 << 9, "workgroup_id_z"),
+  (1 << 10, "workgroup_info"),
+];
+
+fn bits(v: u32, shift: u32, width: u32) -> u32 {
+  (v >> shift) & ((1 << width) - 1)
+}
+
+impl KernelDescriptor {
+  /// Parse a descriptor. Returns `None` if `bytes` is too short.
+  pub fn parse(bytes: &[u8]) -> Option<Self> {
+    let bytes = bytes.get(..KERNEL_DESCRIPTOR_SIZE)?;
+    let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+    let u32_at = |at: usize| {
+      u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    };
+    let mut i64_bytes = [0u8; 8];
+    i64_bytes.copy_from_slice(&bytes[16..24]);
+
+    Some(KernelDescriptor {
+      group_segment_fixed_size: u32_at(0),
+      private_segment_fixed_size: u32_at(4),
+      kernarg_size: u32_at(8),
+      kernel_code_entry_byte_offset: i64::from_le_bytes(i64_bytes),
+      compute_pgm_rsrc3: u32_at(44),
+      compute_pgm_rsrc1: u32_at(48),
+      compute_pgm_rsrc2: u32_at(52),
+      kernel_code_properties: u16_at(56),
+    })
+  }
+
+  /// The granulated VGPR count, ie `COMPUTE_PGM_RSRC1.GRANULATED_WORKITEM_VGPR_COUNT`. The
+  /// granule size depends on the target and wavefront size.
+  pub fn vgpr_blocks(&self) -> u32 { bits(self.compute_pgm_rsrc1, 0, 6) }
+  /// The granulated SGPR count, ie `COMPUTE_PGM_RSRC1.GRANULATED_WAVEFRONT_SGPR_COUNT`.
+  /// Always zero on GFX10 and later.
+  pub fn sgpr_blocks(&self) -> u32 { bits(self.compute_pgm_rsrc1, 6, 4) }
+  pub fn user_sgpr_count(&self) -> u32 { bits(self.compute_pgm_rsrc2, 1, 5) }
+  /// Which of the work-item ID VGPRs are initialized: 0 for just X, up to 2 for X, Y and Z.
+  pub fn workitem_id_vgprs(&self) -> u32 { bits(self.compute_pgm_rsrc2, 11, 2) }
+
+  /// The names of the SGPRs (and other inputs) which are enabled.
+  pub fn enabled_inputs(&self) -> Vec<&'static str> {
+    let props = CODE_PROPERTIES.iter()
+      .filter(|&&(bit, _)| self.kernel_code_properties & bit != 0 )
+      .map(|&(_, name)| name );
+    let rsrc2 = RSRC2_FLAGS.iter()
+      .filter(|&&(bit, _)| self.compute_pgm_rsrc2 & bit != 0 )
+      .map(|&(_, name)| name );
+    props.chain(rsrc2).collect()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parse() {
+    let mut kd = [0u8; KERNEL_DESCRIPTOR_SIZE];
+    kd[0..4].copy_from_slice(&1024u32.to_le_bytes());
+    kd[4..8].copy_from_slice(&260u32.to_le_bytes());
+    kd[8..12].copy_from_slice(&12u32.to_le_bytes());
+    kd[16..24].copy_from_slice(&(-0x100i64).to_le_bytes());
+    // 2 VGPR blocks, 1 SGPR block:
+    kd[48..52].copy_from_slice(&(2u32 | 1 << 6).to_le_bytes());
+    kd[52..56].copy_from_slice(&0x8cu32.to_le_bytes());
+    kd[56..58].copy_from_slice(&0x9u16.to_le_bytes());
+
+    let kd = KernelDescriptor::parse(&kd).unwrap();
+    assert_eq!(kd.group_segment_fixed_size, 1024);
+    assert_eq!(kd.private_segment_fixed_size, 260);
+    assert_eq!(kd.kernarg_size, 12);
+    assert_eq!(kd.kernel_code_entry_byte_offset, -0x100);
+    assert_eq!(kd.vgpr_blocks(), 2);
+    assert_eq!(kd.sgpr_blocks(), 1);
+    assert_eq!(kd.user_sgpr_count(), 6);
+    assert_eq!(kd.workitem_id_vgprs(), 0);
+    assert_eq!(kd.enabled_inputs(),
+               ["private_segment_buffer", "kernarg_segment_ptr", "workgroup_id_x"]);
+
+    assert!(KernelDescriptor::parse(&[0u8; 63]).is_none());
+  }
+}
@@ -0,0 +1,50 @@
+//! Differences between two code objects, or two directories of them.
+
+pub use geobacter_json_diff::Difference;
+
+use serde_json::Value;
+
+/// Every difference between the JSON forms of two inputs (see `Input::to_json`). Kernels
+/// and symbols are keyed by name, so they're matched up even if they move; other arrays,
+/// eg kernel arguments in the metadata, are compared by index.
+pub fn diff(old: &Value, new: &Value) -> Vec<Difference> {
+  geobacter_json_diff::diff(old, new)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use crate::object::Input;
+  use crate::test::fixture;
+
+  fn read(name: &str) -> Value {
+    Input::read(&fixture(name)).unwrap().to_json()
+  }
+
+  #[test]
+  fn identical() {
+    assert!(diff(&read("scale-fill.o"), &read("scale-fill.o")).is_empty());
+  }
+
+  #[test]
+  fn objects() {
+    let diffs = diff(&read("scale-fill.o"), &read("scale-fill-stack.o"))
+      .into_iter()
+      .map(|d| d.to_string() )
+      .collect::<Vec<_>>();
+
+    let expected = [
+      "~ kernels.scale.group_segment_size: 1024 -> 2048",
+      "~ kernels.scale.descriptor.group_segment_fixed_size: 1024 -> 2048",
+      "~ metadata.amdhsa.kernels[0].group_segment_fixed_size: 1024 -> 2048",
+    ];
+    for expected in expected.iter() {
+      assert!(diffs.iter().any(|d| d.starts_with(expected) ),
+              "missing {:?} in {:#?}", expected, diffs);
+    }
+    assert!(diffs.iter().any(|d| d.starts_with("+ kernels.stack: {") ), "{:#?}", diffs);
+    assert!(diffs.iter().any(|d| d.starts_with("+ symbols.stack.kd: {") ), "{:#?}", diffs);
+    assert!(diffs.iter().all(|d| !d.contains("kernels.fill") ), "{:#?}", diffs);
+  }
+}
@@ -0,0 +1,143 @@
+//! Inspect AMDGPU code objects: their kernels' descriptors and register, LDS and scratch
+//! usage, their symbols and the whole `NT_AMDGPU_METADATA` note. Inputs can be code object
+//! files or directories of them, eg the directory the runtime writes each kernel's code
+//! object to when `GEOBACTER_KERNEL_DUMP_DIR` is set.
+//!
+//! Everything but `--disassemble`, which uses comgr, just reads the ELF image, so no GPU
+//! is needed. `--diff OLD NEW` lists the differences between two code objects (or two
+//! directories, matching objects by file name), exiting with status 1 if there are any.
+
+extern crate rmp_serde as rmps;
+
+use std::path::Path;
+use std::process::exit;
+
+use clap::*;
+
+use crate::diff::diff;
+use crate::object::Input;
+use crate::text::write_object;
+
+mod descriptor;
+mod diff;
+mod object;
+mod text;
+
+pub fn main() {
+  let inputs = Arg::with_name("INPUT")
+    .help("code objects, or directories of them, to describe")
+    .multiple(true)
+    .required_unless("diff");
+  let kernel = Arg::with_name("kernel")
+    .long("kernel")
+    .help("only describe this kernel")
+    .takes_value(true);
+  let no_metadata = Arg::with_name("no-metadata")
+    .long("no-metadata")
+    .help("don't print the NT_AMDGPU_METADATA note");
+  let disassemble = Arg::with_name("disassemble")
+    .long("disassemble")
+    .help("disassemble each kernel; only executable code objects can be disassembled");
+  let json = Arg::with_name("json")
+    .long("json")
+    .help("print JSON instead of text")
+    .conflicts_with_all(&["disassemble", "diff"]);
+  let diff_arg = Arg::with_name("diff")
+    .long("diff")
+    .help("print the differences between two code objects or directories, exiting with 1 \
+           if there are any")
+    .takes_value(true)
+    .number_of_values(2)
+    .value_names(&["OLD", "NEW"])
+    .conflicts_with("INPUT");
+
+  let matches = App::new("geobacter-inspect")
+    .about("Describe AMDGPU code objects")
+    .arg(inputs)
+    .arg(kernel)
+    .arg(no_metadata)
+    .arg(disassemble)
+    .arg(json)
+    .arg(diff_arg)
+    .get_matches();
+
+  if let Some(mut paths) = matches.values_of("diff") {
+    let old = read_input(paths.next().unwrap());
+    let new = read_input(paths.next().unwrap());
+    match (&old, &new) {
+      (Input::Object(_), Input::Object(_)) | (Input::Dir(_), Input::Dir(_)) => { },
+      _ => {
+        eprintln!("can't diff a code object against a directory");
+        exit(2);
+      },
+    }
+
+    let differences = diff(&old.to_json(), &new.to_json());
+    for difference in differences.iter() {
+      println!("{}", difference);
+    }
+    exit(if differences.is_empty() { 0 } else { 1 });
+  }
+
+  let kernel = matches.value_of("kernel");
+  let metadata = !matches.is_present("no-metadata");
+  let inputs = matches.values_of("INPUT")
+    .unwrap()
+    .map(read_input)
+    .collect::<Vec<_>>();
+
+  if matches.is_present("json") {
+    let inputs = inputs.iter()
+      .map(Input::to_json)
+      .collect::<Vec<_>>();
+    println!("{}", serde_json::to_string_pretty(&inputs).unwrap());
+    return;
+  }
+
+  let mut failed = false;
+  for object in inputs.iter().flat_map(Input::objects) {
+    let mut out = String::new();
+    write_object(&mut out, object, kernel, metadata).unwrap();
+    print!("{}", out);
+
+    if !matches.is_present("disassemble") { continue; }
+    let kernels = object.kernels.keys()
+      .filter(|&name| kernel.map(|k| k == name ).unwrap_or(true) );
+    for name in kernels {
+      match object.disassemble(name) {
+        Ok(text) => print!("{}", text),
+        Err(err) => {
+          eprintln!("failed to disassemble `{}` in `{}`: {}", name,
+                    object.path.display(), err);
+          failed = true;
+        },
+      }
+    }
+  }
+
+  if failed {
+    exit(1);
+  }
+}
+
+fn read_input(path: &str) -> Input {
+  Input::read(Path::new(path))
+    .unwrap_or_else(|err| {
+      eprintln!("{}", err);
+      exit(2);
+    })
+}
+
+#[cfg(test)]
+mod test {
+  use std::path::PathBuf;
+
+  /// The fixtures are gfx900 relocatable objects, built from the `.ll` file of the same
+  /// name with `llc -mtriple=amdgcn-amd-amdhsa -mcpu=gfx900
+  /// --amdhsa-code-object-version=3 -filetype=obj`.
+  pub fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+      .join("testdata")
+      .join(name)
+  }
+}
@@ -0,0 +1,311 @@
+//! Reading code objects, either a single file or every code object in a directory, such as
+//! the one the runtime writes kernels to when `GEOBACTER_KERNEL_DUMP_DIR` is set.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf, };
+
+use amd_comgr::data::{Data, ExecutableData, };
+
+use goblin::elf::{Elf, header, sym, };
+use goblin::elf::section_header::SHT_NOBITS;
+use goblin::elf::sym::Sym;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use amdgpu_metadata::{HsaMetadataMap, metadata_note, };
+
+use crate::descriptor::{KernelDescriptor, KERNEL_DESCRIPTOR_SIZE, };
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const EM_AMDGPU: u16 = 224;
+const EF_AMDGPU_MACH: u32 = 0xff;
+
+/// `EF_AMDGPU_MACH_*`, for the processors LLVM knew about when this was written.
+const MACHS: &[(u32, &str)] = &[
+  (0x020, "gfx600"), (0x021, "gfx601"), (0x022, "gfx700"), (0x023, "gfx701"),
+  (0x024, "gfx702"), (0x025, "gfx703"), (0x026, "gfx704"), (0x028, "gfx801"),
+  (0x029, "gfx802"), (0x02a, "gfx803"), (0x02b, "gfx810"), (0x02c, "gfx900"),
+  (0x02d, "gfx902"), (0x02e, "gfx904"), (0x02f, "gfx906"), (0x030, "gfx908"),
+  (0x031, "gfx909"), (0x032, "gfx90c"), (0x033, "gfx1010"), (0x034, "gfx1011"),
+  (0x035, "gfx1012"), (0x036, "gfx1030"), (0x037, "gfx1031"), (0x038, "gfx1032"),
+  (0x039, "gfx1033"), (0x03a, "gfx602"), (0x03b, "gfx705"), (0x03c, "gfx805"),
+];
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CodeObject {
+  /// Not serialized, so diffs only show differences in the objects themselves.
+  #[serde(skip)]
+  pub path: PathBuf,
+  #[serde(skip)]
+  pub data: Vec<u8>,
+  /// `false` for relocatable objects, which haven't been linked yet.
+  pub executable: bool,
+  /// The processor from `e_flags`, eg `gfx900`.
+  pub target: String,
+  pub e_flags: u32,
+  /// Keyed by the kernel's name, which doesn't have the `.kd` suffix.
+  pub kernels: BTreeMap<String, Kernel>,
+  /// Keyed by name. If multiple local symbols share a name, only the last is kept.
+  pub symbols: BTreeMap<String, Symbol>,
+  /// The whole `NT_AMDGPU_METADATA` note.
+  pub metadata: Value,
+}
+
+/// A kernel's resource usage, from its metadata, and its descriptor.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Kernel {
+  pub descriptor_symbol: String,
+  pub kernarg_segment_size: u32,
+  pub kernarg_segment_align: u32,
+  /// Static LDS usage.
+  pub group_segment_size: u32,
+  /// Static scratch usage, per work-item.
+  pub private_segment_size: u32,
+  pub wavefront_size: u32,
+  pub max_flat_workgroup_size: u32,
+  pub sgpr_count: u32,
+  pub vgpr_count: u32,
+  pub sgpr_spill_count: Option<u32>,
+  pub vgpr_spill_count: Option<u32>,
+  /// `None` if the descriptor symbol isn't defined.
+  pub descriptor: Option<KernelDescriptor>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Symbol {
+  pub kind: &'static str,
+  pub binding: &'static str,
+  /// `None` for undefined and absolute symbols.
+  pub section: Option<String>,
+  /// For executables, this is the symbol's virtual address.
+  pub value: u64,
+  pub size: u64,
+}
+
+fn symbol_name<'a>(elf: &Elf<'a>, sym: &Sym) -> Option<&'a str> {
+  elf.strtab.get(sym.st_name)
+    .and_then(|name| name.ok() )
+    .filter(|name| !name.is_empty() )
+}
+/// The contents of a defined symbol.
+fn symbol_data<'a>(elf: &Elf, data: &'a [u8], sym: &Sym) -> Option<&'a [u8]> {
+  if sym.st_shndx == 0 { return None; }
+
+  let sh = elf.section_headers.get(sym.st_shndx)?;
+  if sh.sh_type == SHT_NOBITS { return None; }
+
+  let start = sh.sh_offset.checked_add(sym.st_value.checked_sub(sh.sh_addr)?)?;
+  let end = start.checked_add(sym.st_size)?;
+  data.get(start as usize..end as usize)
+}
+
+impl CodeObject {
+  pub fn read(path: &Path) -> Result<Self> {
+    let data = fs::read(path)
+      .map_err(|err| format!("failed to read `{}`: {}", path.display(), err) )?;
+    Self::parse(path.into(), data)
+      .map_err(|err| format!("failed to parse `{}`: {}", path.display(), err).into() )
+  }
+  pub fn parse(path: PathBuf, data: Vec<u8>) -> Result<Self> {
+    let elf = Elf::parse(&data)?;
+    if elf.header.e_machine != EM_AMDGPU {
+      return Err("not an AMDGPU code object".into());
+    }
+
+    let parsed = HsaMetadataMap::parse(&elf, &data)?;
+    let note = metadata_note(&elf, &data)?
+      .expect("metadata was parsed");
+    let metadata: Value = rmps::decode::from_slice(note)?;
+
+    let mut symbols = BTreeMap::new();
+    for sym in elf.syms.iter() {
+      let name = match symbol_name(&elf, &sym) {
+        Some(name) => name,
+        None => { continue; },
+      };
+      let section = elf.section_headers.get(sym.st_shndx)
+        .filter(|_| sym.st_shndx != 0 )
+        .and_then(|sh| elf.shdr_strtab.get(sh.sh_name) )
+        .and_then(|name| name.ok() )
+        .map(String::from);
+      symbols.insert(name.into(), Symbol {
+        kind: sym::type_to_str(sym.st_type()),
+        binding: sym::bind_to_str(sym.st_bind()),
+        section,
+        value: sym.st_value,
+        size: sym.st_size,
+      });
+    }
+
+    let kernels = parsed.kernels.iter()
+      .map(|md| {
+        let descriptor = elf.syms.iter()
+          .find(|sym| symbol_name(&elf, sym) == Some(md.kernel_desc_symbol) )
+          .and_then(|sym| symbol_data(&elf, &data, &sym) )
+          .filter(|kd| kd.len() == KERNEL_DESCRIPTOR_SIZE )
+          .and_then(KernelDescriptor::parse);
+        let kernel = Kernel {
+          descriptor_symbol: md.kernel_desc_symbol.into(),
+          kernarg_segment_size: md.kernarg_segment_size,
+          kernarg_segment_align: md.kernarg_segment_align,
+          group_segment_size: md.group_segment_size,
+          private_segment_size: md.private_segment_size,
+          wavefront_size: md.wavefront_size,
+          max_flat_workgroup_size: md.max_flat_workgroup_size,
+          sgpr_count: md.sgpr_count,
+          vgpr_count: md.vgpr_count,
+          sgpr_spill_count: md.sgpr_spill_count,
+          vgpr_spill_count: md.vgpr_spill_count,
+          descriptor,
+        };
+        (md.name.to_string(), kernel)
+      })
+      .collect();
+
+    let e_flags = elf.header.e_flags;
+    let mach = e_flags & EF_AMDGPU_MACH;
+    let target = MACHS.iter()
+      .find(|&&(m, _)| m == mach )
+      .map(|&(_, name)| name.to_string() )
+      .unwrap_or_else(|| format!("unknown (0x{:x})", mach) );
+    let executable = matches!(elf.header.e_type, header::ET_DYN | header::ET_EXEC);
+
+    Ok(CodeObject {
+      path,
+      executable,
+      target,
+      e_flags,
+      kernels,
+      symbols,
+      metadata,
+      data,
+    })
+  }
+
+  /// Disassemble `kernel` with comgr. Relocatable objects can't be disassembled.
+  pub fn disassemble(&self, kernel: &str) -> Result<String> {
+    if !self.executable {
+      return Err("only executable code objects can be disassembled".into());
+    }
+
+    let mut exe = ExecutableData::new()?;
+    exe.set_data(&self.data)?;
+    exe.disassemble(kernel)?
+      .ok_or_else(|| format!("no code for kernel `{}`", kernel).into() )
+  }
+}
+
+/// A code object, or a directory of them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+  Object(CodeObject),
+  /// Keyed by file name. Files which aren't ELF images are skipped.
+  Dir(BTreeMap<String, CodeObject>),
+}
+impl Input {
+  pub fn read(path: &Path) -> Result<Self> {
+    if !path.is_dir() {
+      return Ok(Input::Object(CodeObject::read(path)?));
+    }
+
+    let mut objects = BTreeMap::new();
+    for entry in fs::read_dir(path)? {
+      let path = entry?.path();
+      if !path.is_file() { continue; }
+
+      let data = fs::read(&path)?;
+      if !data.starts_with(ELF_MAGIC) { continue; }
+
+      let object = CodeObject::parse(path.clone(), data)
+        .map_err(|err| format!("failed to parse `{}`: {}", path.display(), err) )?;
+      let name = path.file_name()
+        .unwrap()
+        .to_string_lossy()
+        .into_owned();
+      objects.insert(name, object);
+    }
+
+    Ok(Input::Dir(objects))
+  }
+
+  pub fn objects(&self) -> Vec<&CodeObject> {
+    match self {
+      Input::Object(object) => vec![object],
+      Input::Dir(objects) => objects.values().collect(),
+    }
+  }
+
+  pub fn to_json(&self) -> Value {
+    let v = match self {
+      Input::Object(object) => serde_json::to_value(object),
+      Input::Dir(objects) => serde_json::to_value(objects),
+    };
+    v.expect("serialize code object")
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use crate::test::fixture;
+
+  #[test]
+  fn kernels() {
+    let object = CodeObject::read(&fixture("scale-fill-stack.o")).unwrap();
+    assert!(!object.executable);
+    assert_eq!(object.target, "gfx900");
+    assert_eq!(object.kernels.keys().collect::<Vec<_>>(), ["fill", "scale", "stack"]);
+
+    let scale = &object.kernels["scale"];
+    assert_eq!(scale.descriptor_symbol, "scale.kd");
+    assert_eq!(scale.group_segment_size, 2048);
+    assert_eq!(scale.private_segment_size, 0);
+    assert_eq!(scale.kernarg_segment_size, 12);
+    assert_eq!(scale.wavefront_size, 64);
+    assert_eq!(scale.vgpr_spill_count, Some(0));
+    let kd = scale.descriptor.unwrap();
+    assert_eq!(kd.group_segment_fixed_size, 2048);
+    assert_eq!(kd.kernarg_size, 12);
+    assert!(kd.enabled_inputs().contains(&"kernarg_segment_ptr"));
+
+    let stack = &object.kernels["stack"];
+    assert_eq!(stack.private_segment_size, 260);
+    assert_eq!(stack.descriptor.unwrap().private_segment_fixed_size, 260);
+
+    assert_eq!(object.metadata["amdhsa.version"], serde_json::json!([1, 0]));
+    assert_eq!(object.metadata["amdhsa.kernels"].as_array().unwrap().len(), 3);
+  }
+
+  #[test]
+  fn symbols() {
+    let object = CodeObject::read(&fixture("scale-fill.o")).unwrap();
+    let fill = &object.symbols["fill"];
+    assert_eq!(fill.kind, "FUNC");
+    assert_eq!(fill.binding, "GLOBAL");
+    assert_eq!(fill.section.as_deref(), Some(".text"));
+    let fill_kd = &object.symbols["fill.kd"];
+    assert_eq!(fill_kd.kind, "OBJECT");
+    assert_eq!(fill_kd.size, KERNEL_DESCRIPTOR_SIZE as u64);
+  }
+
+  #[test]
+  fn dir() {
+    let input = Input::read(&fixture("")).unwrap();
+    let names = match &input {
+      Input::Dir(objects) => objects.keys().cloned().collect::<Vec<_>>(),
+      _ => unreachable!(),
+    };
+    // the LLVM IR sources are skipped:
+    assert_eq!(names, ["scale-fill-stack.o", "scale-fill.o"]);
+    assert_eq!(input.objects().len(), 2);
+
+    let err = CodeObject::read(&fixture("scale-fill.ll")).unwrap_err();
+    assert!(err.to_string().contains("scale-fill.ll"), "{}", err);
+  }
+}
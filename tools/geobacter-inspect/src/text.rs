@@ -0,0 +1,114 @@
+//! The human readable output.
+
+use std::fmt::{self, Write, };
+
+use crate::object::*;
+
+fn or_na<T: fmt::Display>(v: Option<T>) -> String {
+  v.map(|v| v.to_string() )
+    .unwrap_or_else(|| "N/A".into() )
+}
+
+/// Describe `object`. Only kernels named `kernel` are included if it's `Some`, and the
+/// metadata note is only included if `metadata` is set.
+pub fn write_object<W: Write>(out: &mut W, object: &CodeObject, kernel: Option<&str>,
+                              metadata: bool)
+  -> fmt::Result
+{
+  writeln!(out, "{}:", object.path.display())?;
+  writeln!(out, "\tType = {}", if object.executable { "executable" } else { "relocatable" })?;
+  writeln!(out, "\tTarget = {}", object.target)?;
+  writeln!(out, "\tFlags = 0x{:x}", object.e_flags)?;
+
+  let kernels = object.kernels.iter()
+    .filter(|&(name, _)| kernel.map(|k| k == name ).unwrap_or(true) );
+  for (name, k) in kernels {
+    writeln!(out, "\tKernel {}:", name)?;
+    writeln!(out, "\t\tDescriptor Symbol: {}", k.descriptor_symbol)?;
+    writeln!(out, "\t\tKernarg Segment Size: {}", k.kernarg_segment_size)?;
+    writeln!(out, "\t\tKernarg Segment Align: {}", k.kernarg_segment_align)?;
+    writeln!(out, "\t\tLDS Size: {}", k.group_segment_size)?;
+    writeln!(out, "\t\tScratch Size: {}", k.private_segment_size)?;
+    writeln!(out, "\t\tWavefront Size: {}", k.wavefront_size)?;
+    writeln!(out, "\t\tMax Flat Workgroup Size: {}", k.max_flat_workgroup_size)?;
+    writeln!(out, "\t\tSGPRs: {}", k.sgpr_count)?;
+    writeln!(out, "\t\tVGPRs: {}", k.vgpr_count)?;
+    writeln!(out, "\t\tSGPR Spills: {}", or_na(k.sgpr_spill_count))?;
+    writeln!(out, "\t\tVGPR Spills: {}", or_na(k.vgpr_spill_count))?;
+
+    let kd = match k.descriptor {
+      Some(ref kd) => kd,
+      None => {
+        writeln!(out, "\t\tDescriptor: N/A")?;
+        continue;
+      },
+    };
+    writeln!(out, "\t\tDescriptor:")?;
+    writeln!(out, "\t\t\tGroup Segment Fixed Size: {}", kd.group_segment_fixed_size)?;
+    writeln!(out, "\t\t\tPrivate Segment Fixed Size: {}", kd.private_segment_fixed_size)?;
+    writeln!(out, "\t\t\tKernarg Size: {}", kd.kernarg_size)?;
+    writeln!(out, "\t\t\tKernel Code Entry Byte Offset: {}",
+             kd.kernel_code_entry_byte_offset)?;
+    writeln!(out, "\t\t\tCOMPUTE_PGM_RSRC1: 0x{:08x}", kd.compute_pgm_rsrc1)?;
+    writeln!(out, "\t\t\tCOMPUTE_PGM_RSRC2: 0x{:08x}", kd.compute_pgm_rsrc2)?;
+    writeln!(out, "\t\t\tCOMPUTE_PGM_RSRC3: 0x{:08x}", kd.compute_pgm_rsrc3)?;
+    writeln!(out, "\t\t\tKernel Code Properties: 0x{:04x}", kd.kernel_code_properties)?;
+    writeln!(out, "\t\t\tVGPR Blocks: {}", kd.vgpr_blocks())?;
+    writeln!(out, "\t\t\tSGPR Blocks: {}", kd.sgpr_blocks())?;
+    writeln!(out, "\t\t\tUser SGPRs: {}", kd.user_sgpr_count())?;
+    writeln!(out, "\t\t\tWork-item ID VGPRs: {}", kd.workitem_id_vgprs() + 1)?;
+    writeln!(out, "\t\t\tEnabled Inputs: {}", kd.enabled_inputs().join(", "))?;
+  }
+
+  writeln!(out, "\tSymbols:")?;
+  for (name, sym) in object.symbols.iter() {
+    writeln!(out, "\t\t{:016x} {:>6} {:<7} {:<6} {:<8} {}", sym.value, sym.size,
+             sym.kind, sym.binding, or_na(sym.section.as_ref()), name)?;
+  }
+
+  if metadata {
+    let md = serde_json::to_string_pretty(&object.metadata)
+      .expect("serialize metadata");
+    writeln!(out, "\tMetadata:")?;
+    for line in md.lines() {
+      writeln!(out, "\t\t{}", line)?;
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use crate::test::fixture;
+
+  #[test]
+  fn fixture_text() {
+    let object = CodeObject::read(&fixture("scale-fill.o")).unwrap();
+    let mut out = String::new();
+    write_object(&mut out, &object, Some("scale"), true).unwrap();
+
+    let expected = [
+      "\tType = relocatable\n\tTarget = gfx900\n",
+      "\tKernel scale:\n\t\tDescriptor Symbol: scale.kd\n",
+      "\t\tLDS Size: 1024\n",
+      "\t\tSGPR Spills: 0\n",
+      "\t\t\tGroup Segment Fixed Size: 1024\n",
+      "\t\t\tEnabled Inputs: private_segment_buffer, kernarg_segment_ptr, workgroup_id_x\n",
+      "\t\t0000000000000000     64 OBJECT  GLOBAL .rodata  scale.kd\n",
+      "\tMetadata:\n",
+      "\"amdhsa.kernels\"",
+    ];
+    for expected in expected.iter() {
+      assert!(out.contains(expected), "missing {:?} in:\n{}", expected, out);
+    }
+    assert!(!out.contains("Kernel fill"));
+
+    let mut out = String::new();
+    write_object(&mut out, &object, None, false).unwrap();
+    assert!(out.contains("\tKernel fill:\n"));
+    assert!(!out.contains("Metadata"));
+  }
+}
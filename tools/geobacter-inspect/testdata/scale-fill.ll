@@ -0,0 +1,28 @@
+target triple = "amdgcn-amd-amdhsa"
+
+@lds = internal addrspace(3) global [256 x float] undef, align 4
+
+define amdgpu_kernel void @scale(float addrspace(1)* %out, float %s) {
+  %id = call i32 @llvm.amdgcn.workitem.id.x()
+  %p = getelementptr float, float addrspace(1)* %out, i32 %id
+  %v = load float, float addrspace(1)* %p
+  %l = getelementptr [256 x float], [256 x float] addrspace(3)* @lds, i32 0, i32 %id
+  store float %v, float addrspace(3)* %l
+  call void @llvm.amdgcn.s.barrier()
+  %x = sub i32 255, %id
+  %l2 = getelementptr [256 x float], [256 x float] addrspace(3)* @lds, i32 0, i32 %x
+  %w = load float, float addrspace(3)* %l2
+  %r = fmul float %w, %s
+  store float %r, float addrspace(1)* %p
+  ret void
+}
+
+define amdgpu_kernel void @fill(i32 addrspace(1)* %out, i32 %n) {
+  %id = call i32 @llvm.amdgcn.workitem.id.x()
+  %p = getelementptr i32, i32 addrspace(1)* %out, i32 %id
+  store i32 %n, i32 addrspace(1)* %p
+  ret void
+}
+
+declare i32 @llvm.amdgcn.workitem.id.x()
+declare void @llvm.amdgcn.s.barrier()
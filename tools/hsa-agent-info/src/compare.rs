@@ -0,0 +1,45 @@
+//! Differences between two snapshots.
+
+pub use geobacter_json_diff::Difference;
+
+use crate::snapshot::Snapshot;
+
+/// Every difference between `old` and `new`, as paths into the JSON form of the
+/// snapshots. Elements of arrays are compared by index.
+pub fn compare(old: &Snapshot, new: &Snapshot) -> Vec<Difference> {
+  let old = serde_json::to_value(old).expect("serialize snapshot");
+  let new = serde_json::to_value(new).expect("serialize snapshot");
+  geobacter_json_diff::diff(&old, &new)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use crate::test::recorded;
+
+  #[test]
+  fn identical() {
+    assert!(compare(&recorded(), &recorded()).is_empty());
+  }
+
+  #[test]
+  fn differences() {
+    let old = recorded();
+    let mut new = recorded();
+    new.agents[1].memory_pools[0].size *= 2;
+    new.agents[1].caches.pop();
+    let mut gpu = new.agents[1].clone();
+    gpu.name = "gfx906".into();
+    new.agents.push(gpu);
+
+    let diffs = compare(&old, &new)
+      .into_iter()
+      .map(|d| d.to_string() )
+      .collect::<Vec<_>>();
+    assert_eq!(diffs.len(), 3, "{:#?}", diffs);
+    assert_eq!(diffs[0], "- agents[1].caches[1]: {\"level\":2,\"name\":\"L2\",\"size\":4096}");
+    assert_eq!(diffs[1], "~ agents[1].memory_pools[0].size: 8589934592 -> 17179869184");
+    assert!(diffs[2].starts_with("+ agents[2]: {"), "{}", diffs[2]);
+  }
+}
@@ -1,96 +1,102 @@
+//! Describe the HSA agents on this system: their ISAs, memory regions and AMD memory
+//! pools, and which agents can access each other's memory.
+//!
+//! `--json` prints the same information as a snapshot (see `snapshot::Snapshot`), and
+//! `--compare OLD NEW` lists the differences between two such snapshots, exiting with
+//! status 1 if there are any.
 
 extern crate hsa_rt as hsa;
 
-use hsa::agent::*;
+use std::fs::File;
+use std::io::BufReader;
+use std::process::exit;
+
+use clap::*;
+
+use crate::compare::compare;
+use crate::snapshot::{Snapshot, SCHEMA_VERSION, };
+use crate::text::write_snapshot;
+
+mod compare;
+mod snapshot;
+mod text;
 
 pub fn main() {
+  let json = Arg::with_name("json")
+    .long("json")
+    .help("print a JSON snapshot instead of text")
+    .conflicts_with("compare");
+  let compare_arg = Arg::with_name("compare")
+    .long("compare")
+    .help("print the differences between two JSON snapshots, exiting with 1 if there are any")
+    .takes_value(true)
+    .number_of_values(2)
+    .value_names(&["OLD", "NEW"]);
+
+  let matches = App::new("hsa-agent-info")
+    .about("Describe the HSA agents on this system")
+    .arg(json)
+    .arg(compare_arg)
+    .get_matches();
+
+  if let Some(mut paths) = matches.values_of("compare") {
+    let old = read_snapshot(paths.next().unwrap());
+    let new = read_snapshot(paths.next().unwrap());
+    let differences = compare(&old, &new);
+    for difference in differences.iter() {
+      println!("{}", difference);
+    }
+    exit(if differences.is_empty() { 0 } else { 1 });
+  }
+
   let ctxt = hsa::ApiContext::try_upref()
     .expect("error creating HSA context");
-  let agents = ctxt.agents()
-    .expect("find_agents");
+  let snapshot = Snapshot::collect(&ctxt)
+    .expect("failed to query HSA agents");
 
-  let cpu = agents.iter()
-    .find(|agent| Some(DeviceType::Cpu) == agent.device_type().ok() )
-    .map(Agent::clone)
-    .expect("no CPUs found??");
+  if matches.is_present("json") {
+    println!("{}", serde_json::to_string_pretty(&snapshot).unwrap());
+  } else {
+    let mut out = String::new();
+    write_snapshot(&mut out, &snapshot).unwrap();
+    print!("{}", out);
+  }
+}
 
-  for (num, agent) in agents.into_iter().enumerate() {
-    println!("Agent #{}:", num);
-    println!("\tName = {}", agent.name().expect("failed to get agent name"));
-    println!("\tVendor = {}", agent.vendor_name().unwrap());
-    println!("\tFeature = {}", if let Ok(f) = agent.feature() {
-      format!("{:?}", f)
-    } else {
-      "N/A".into()
+fn read_snapshot(path: &str) -> Snapshot {
+  let snapshot: Snapshot = File::open(path)
+    .map_err(|err| err.to_string() )
+    .and_then(|file| {
+      serde_json::from_reader(BufReader::new(file))
+        .map_err(|err| err.to_string() )
+    })
+    .unwrap_or_else(|err| {
+      eprintln!("failed to read snapshot `{}`: {}", path, err);
+      exit(2);
     });
-    println!("\tDevice type = {:?}", agent.device_type().unwrap());
-    println!("\tQueue size = {:?}", agent.queue_size().unwrap());
-    println!("\tQueue type = {:?}", agent.queue_type().unwrap());
-    println!("\tVersion = {:?}", agent.version().unwrap());
-
-    // not sure why, but this gives us a bunch of invalid, possibly corrupt, data.
-    /*let caches = agent.caches().unwrap();
-    for (num, cache) in caches.into_iter().enumerate() {
-      println!("\tCache #{}:", num);
-      println!("\t\tName: {}", cache.name().unwrap_or_else(|_| "<bad name!>".into() ));
-      println!("\t\tLevel: {}", cache.level().unwrap());
-      println!("\t\tSize: {}", cache.size().unwrap());
-    }*/
+  if snapshot.schema_version != SCHEMA_VERSION {
+    eprintln!("snapshot `{}` has schema version {}, expected {}",
+              path, snapshot.schema_version, SCHEMA_VERSION);
+    exit(2);
+  }
+  snapshot
+}
 
-    match agent.isas() {
-      Ok(isas) => {
-        for (num, isa) in isas.into_iter().enumerate() {
-          println!("\tISA #{}:", num);
-          let info = isa.info().expect("get all ISA info");
+#[cfg(test)]
+mod test {
+  use super::*;
 
-          println!("{:#?}", info);
-        }
-      },
-      Err(e) => {
-        println!("\tFailed to get available ISAs: `{:?}`", e);
-      },
-    }
+  /// A CPU and a gfx900, recorded with `--json`.
+  pub fn recorded() -> Snapshot {
+    serde_json::from_str(include_str!("../testdata/cpu-gfx900.json"))
+      .expect("parse recorded snapshot")
+  }
 
-    match agent.all_regions() {
-      Ok(regions) => {
-        for region in regions.into_iter() {
-          println!("\tRegion ID(0x{:x}):", region.id());
-          println!("\t\tSegment: {:?}", region.segment().expect("can't get region segment"));
-          println!("\t\tGlobal Flags: {:?}",
-                   region.global_flags().expect("can't get region global flags"));
-          println!("\t\tSize: {}", region.size().expect("can't get region size"));
-          println!("\t\tAlloc Max Size: {}", region.alloc_max_size().unwrap());
-          println!("\t\tAlloc Max Private Workgroup Size: {}",
-                   match region.alloc_max_private_workgroup_size() {
-                     Ok(size) => format!("{}", size),
-                     Err(_) => "N/A".to_string(),
-                   });
-          println!("\t\tRuntime Alloc Allowed: {}",
-                   region.runtime_alloc_allowed().unwrap());
-          println!("\t\tRuntime Alloc Granule: {}",
-                   region.runtime_alloc_granule().unwrap());
-          println!("\t\tRuntime Alloc Alignment: {}",
-                   region.runtime_alloc_alignment().unwrap());
-        }
-      },
-      Err(e) => {
-        println!("\tFailed to get available regions: `{:?}`", e);
-      },
-    }
-    if let Ok(pools) = agent.amd_memory_pools() {
-      for pool in pools.into_iter() {
-        println!("\tPool ID(0x{:x}):", pool.id());
-        println!("\t\tSegment: {:?}", pool.segment().expect("can't get region segment"));
-        println!("\t\tGlobal Flags: {:?}",
-                 pool.global_flags().expect("can't get region global flags"));
-        println!("\t\tSize: {}", pool.total_size().expect("can't get region size"));
-        println!("\t\tAlloc Allowed: {}", pool.alloc_allowed());
-        println!("\t\tAlloc Granule: {}",
-                 pool.alloc_granule().unwrap().unwrap_or(1));
-        println!("\t\tAlloc Alignment: {}",
-                 pool.alloc_alignment().unwrap().unwrap_or(1));
-        println!("\t\tCPU Access: {:?}", pool.agent_access(&cpu));
-      }
-    }
+  #[test]
+  fn json_round_trip() {
+    let snapshot = recorded();
+    let json = serde_json::to_string_pretty(&snapshot).unwrap();
+    let parsed: Snapshot = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed, snapshot);
   }
 }
@@ -0,0 +1,250 @@
+//! A serializable description of the system's HSA agents. This is what `--json` prints
+//! and `--compare` reads back, so changes to these types must bump `SCHEMA_VERSION`.
+//!
+//! Handles (region and pool IDs) differ between processes, so they aren't included.
+//! Agents are referred to by their index in `Snapshot::agents`, which is the order HSA
+//! enumerates them in.
+
+use std::ops::Range;
+
+use serde::{Serialize, Deserialize, };
+
+use hsa::ApiContext;
+use hsa::agent::*;
+use hsa::error::Error;
+use hsa::ext::amd::{AgentAccess, MemoryPool, };
+use hsa::mem::region::{GlobalFlags, Region, Segment, };
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+  pub schema_version: u32,
+  pub agents: Vec<AgentSnapshot>,
+  /// How each agent may access each other agent's global memory.
+  pub peer_access: Vec<PeerAccess>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AgentSnapshot {
+  pub name: String,
+  pub vendor: String,
+  pub device_type: DeviceType,
+  /// `None` if the agent doesn't report one.
+  pub feature: Option<Feature>,
+  pub version: (u16, u16),
+  pub queue_size: Range<u32>,
+  pub queue_type: QueueType,
+  pub caches: Vec<CacheInfo>,
+  pub isas: Vec<IsaInfo>,
+  pub regions: Vec<RegionSnapshot>,
+  pub memory_pools: Vec<PoolSnapshot>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RegionSnapshot {
+  pub segment: String,
+  pub global_flags: Vec<String>,
+  pub size: usize,
+  pub alloc_max_size: usize,
+  pub alloc_max_private_workgroup_size: Option<u32>,
+  pub runtime_alloc_allowed: bool,
+  pub runtime_alloc_granule: usize,
+  pub runtime_alloc_alignment: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PoolSnapshot {
+  pub segment: String,
+  pub global_flags: Vec<String>,
+  pub size: usize,
+  pub alloc_allowed: bool,
+  /// `None` if allocation isn't allowed.
+  pub alloc_granule: Option<usize>,
+  /// `None` if allocation isn't allowed.
+  pub alloc_alignment: Option<usize>,
+  /// The access each agent has to this pool, indexed like `Snapshot::agents`.
+  pub agent_access: Vec<Option<AgentAccess>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PeerAccess {
+  pub agent: usize,
+  pub owner: usize,
+  /// The most permissive access `agent` has to any of `owner`'s global memory pools.
+  pub access: AgentAccess,
+}
+
+impl Snapshot {
+  pub fn collect(ctxt: &ApiContext) -> Result<Self, Error> {
+    let agents = ctxt.agents()?;
+    let snapshots = agents.iter()
+      .map(|agent| AgentSnapshot::collect(agent, &agents) )
+      .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Snapshot {
+      schema_version: SCHEMA_VERSION,
+      peer_access: peer_access(&snapshots),
+      agents: snapshots,
+    })
+  }
+}
+
+impl AgentSnapshot {
+  fn collect(agent: &Agent, agents: &[Agent]) -> Result<Self, Error> {
+    // Caches and ISAs are optional; not every agent has them.
+    let caches = agent.caches()
+      .unwrap_or_default()
+      .iter()
+      .map(Cache::info)
+      .collect::<Result<_, _>>()?;
+    let isas = agent.isas()
+      .unwrap_or_default()
+      .iter()
+      .map(Isa::info)
+      .collect::<Result<_, _>>()?;
+    let regions = agent.all_regions()?
+      .iter()
+      .map(RegionSnapshot::collect)
+      .collect::<Result<_, _>>()?;
+    let memory_pools = agent.amd_memory_pools()?
+      .iter()
+      .map(|pool| PoolSnapshot::collect(pool, agents) )
+      .collect::<Result<_, _>>()?;
+
+    Ok(AgentSnapshot {
+      name: agent.name()?,
+      vendor: agent.vendor_name()?,
+      device_type: agent.device_type()?,
+      feature: agent.feature().ok(),
+      version: agent.version()?,
+      queue_size: agent.queue_size()?,
+      queue_type: agent.queue_type()?,
+      caches,
+      isas,
+      regions,
+      memory_pools,
+    })
+  }
+}
+
+impl RegionSnapshot {
+  fn collect(region: &Region) -> Result<Self, Error> {
+    Ok(RegionSnapshot {
+      segment: segment_name(region.segment()?).into(),
+      global_flags: global_flag_names(region.global_flags()?),
+      size: region.size()?,
+      alloc_max_size: region.alloc_max_size()?,
+      alloc_max_private_workgroup_size: region.alloc_max_private_workgroup_size().ok(),
+      runtime_alloc_allowed: region.runtime_alloc_allowed()?,
+      runtime_alloc_granule: region.runtime_alloc_granule()?,
+      runtime_alloc_alignment: region.runtime_alloc_alignment()?,
+    })
+  }
+}
+
+impl PoolSnapshot {
+  fn collect(pool: &MemoryPool, agents: &[Agent]) -> Result<Self, Error> {
+    Ok(PoolSnapshot {
+      segment: segment_name(pool.segment()?).into(),
+      global_flags: global_flag_names(pool.global_flags()?),
+      size: pool.total_size()?,
+      alloc_allowed: pool.alloc_allowed(),
+      alloc_granule: pool.alloc_granule()?,
+      alloc_alignment: pool.alloc_alignment()?,
+      agent_access: agents.iter()
+        .map(|agent| pool.agent_access(agent).ok() )
+        .collect(),
+    })
+  }
+}
+
+fn segment_name(segment: Segment) -> &'static str {
+  match segment {
+    Segment::Global => "global",
+    Segment::ReadOnly => "read_only",
+    Segment::Private => "private",
+    Segment::Group => "group",
+    Segment::KernelArg => "kernel_arg",
+  }
+}
+fn global_flag_names(flags: Option<GlobalFlags>) -> Vec<String> {
+  let flags = match flags {
+    Some(flags) => flags,
+    None => { return vec![]; },
+  };
+
+  let mut names = vec![];
+  if flags.kernel_arg() {
+    names.push("kernel_arg".into());
+  }
+  if flags.fine_grained() {
+    names.push("fine_grained".into());
+  }
+  if flags.coarse_grained() {
+    names.push("coarse_grained".into());
+  }
+  names
+}
+
+fn access_rank(access: AgentAccess) -> u8 {
+  match access {
+    AgentAccess::Never => 0,
+    AgentAccess::DefaultDisallowed => 1,
+    AgentAccess::DefaultAllowed => 2,
+  }
+}
+/// Summarize the pools' `agent_access` into agent to agent access. Agents with no
+/// global memory pools are never owners.
+pub fn peer_access(agents: &[AgentSnapshot]) -> Vec<PeerAccess> {
+  let mut out = vec![];
+  for (owner, owner_snapshot) in agents.iter().enumerate() {
+    for agent in 0..agents.len() {
+      if agent == owner { continue; }
+
+      let access = owner_snapshot.memory_pools.iter()
+        .filter(|pool| pool.segment == "global" )
+        .filter_map(|pool| pool.agent_access.get(agent).copied().flatten() )
+        .max_by_key(|&access| access_rank(access) );
+      if let Some(access) = access {
+        out.push(PeerAccess {
+          agent,
+          owner,
+          access,
+        });
+      }
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use crate::test::recorded;
+
+  #[test]
+  fn recorded_peer_access() {
+    let snapshot = recorded();
+    assert_eq!(snapshot.schema_version, SCHEMA_VERSION);
+    assert_eq!(peer_access(&snapshot.agents), snapshot.peer_access);
+  }
+
+  #[test]
+  fn peer_access_prefers_most_permissive() {
+    let mut snapshot = recorded();
+    // the GPU's fine grained pool; the CPU can't access its coarse grained pool by
+    // default.
+    let mut pool = snapshot.agents[1].memory_pools[0].clone();
+    pool.global_flags = vec!["fine_grained".into()];
+    pool.agent_access[0] = Some(AgentAccess::DefaultAllowed);
+    snapshot.agents[1].memory_pools.push(pool);
+
+    let access = peer_access(&snapshot.agents);
+    let cpu_to_gpu = access.iter()
+      .find(|a| a.agent == 0 && a.owner == 1 )
+      .unwrap();
+    assert_eq!(cpu_to_gpu.access, AgentAccess::DefaultAllowed);
+  }
+}
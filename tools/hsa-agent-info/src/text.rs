@@ -0,0 +1,119 @@
+//! The human readable output.
+
+use std::fmt::{self, Write, };
+
+use crate::snapshot::*;
+
+fn or_na<T: fmt::Display>(v: Option<T>) -> String {
+  v.map(|v| v.to_string() )
+    .unwrap_or_else(|| "N/A".into() )
+}
+fn flags(flags: &[String]) -> String {
+  if flags.is_empty() {
+    "none".into()
+  } else {
+    flags.join(", ")
+  }
+}
+
+pub fn write_snapshot<W: Write>(out: &mut W, snapshot: &Snapshot) -> fmt::Result {
+  for (num, agent) in snapshot.agents.iter().enumerate() {
+    writeln!(out, "Agent #{}:", num)?;
+    writeln!(out, "\tName = {}", agent.name)?;
+    writeln!(out, "\tVendor = {}", agent.vendor)?;
+    writeln!(out, "\tFeature = {}", or_na(agent.feature.map(|f| format!("{:?}", f) )))?;
+    writeln!(out, "\tDevice type = {:?}", agent.device_type)?;
+    writeln!(out, "\tQueue size = {:?}", agent.queue_size)?;
+    writeln!(out, "\tQueue type = {:?}", agent.queue_type)?;
+    writeln!(out, "\tVersion = {:?}", agent.version)?;
+
+    for (num, cache) in agent.caches.iter().enumerate() {
+      writeln!(out, "\tCache #{}:", num)?;
+      writeln!(out, "\t\tName: {}", cache.name)?;
+      writeln!(out, "\t\tLevel: {}", cache.level)?;
+      writeln!(out, "\t\tSize: {}", cache.size)?;
+    }
+
+    for (num, isa) in agent.isas.iter().enumerate() {
+      writeln!(out, "\tISA #{}:", num)?;
+      writeln!(out, "\t\tName: {}", isa.name)?;
+      writeln!(out, "\t\tFast f16: {}", isa.fast_f16)?;
+      writeln!(out, "\t\tWorkgroup Max Dim: {:?}", isa.workgroup_max_dim)?;
+      writeln!(out, "\t\tWorkgroup Max Size: {}", isa.workgroup_max_size)?;
+      writeln!(out, "\t\tGrid Max Dim: {:?}", isa.grid_max_dim)?;
+      writeln!(out, "\t\tGrid Max Size: {}", isa.grid_max_size)?;
+      writeln!(out, "\t\tFBarrier Max Size: {}", isa.fbarrier_max_size)?;
+      let wavefronts = isa.wavefronts.iter()
+        .map(|wf| wf.size.to_string() )
+        .collect::<Vec<_>>();
+      writeln!(out, "\t\tWavefront Sizes: {}", wavefronts.join(", "))?;
+    }
+
+    for (num, region) in agent.regions.iter().enumerate() {
+      writeln!(out, "\tRegion #{}:", num)?;
+      writeln!(out, "\t\tSegment: {}", region.segment)?;
+      writeln!(out, "\t\tGlobal Flags: {}", flags(&region.global_flags))?;
+      writeln!(out, "\t\tSize: {}", region.size)?;
+      writeln!(out, "\t\tAlloc Max Size: {}", region.alloc_max_size)?;
+      writeln!(out, "\t\tAlloc Max Private Workgroup Size: {}",
+               or_na(region.alloc_max_private_workgroup_size))?;
+      writeln!(out, "\t\tRuntime Alloc Allowed: {}", region.runtime_alloc_allowed)?;
+      writeln!(out, "\t\tRuntime Alloc Granule: {}", region.runtime_alloc_granule)?;
+      writeln!(out, "\t\tRuntime Alloc Alignment: {}", region.runtime_alloc_alignment)?;
+    }
+
+    for (num, pool) in agent.memory_pools.iter().enumerate() {
+      writeln!(out, "\tPool #{}:", num)?;
+      writeln!(out, "\t\tSegment: {}", pool.segment)?;
+      writeln!(out, "\t\tGlobal Flags: {}", flags(&pool.global_flags))?;
+      writeln!(out, "\t\tSize: {}", pool.size)?;
+      writeln!(out, "\t\tAlloc Allowed: {}", pool.alloc_allowed)?;
+      writeln!(out, "\t\tAlloc Granule: {}", or_na(pool.alloc_granule))?;
+      writeln!(out, "\t\tAlloc Alignment: {}", or_na(pool.alloc_alignment))?;
+      for (agent, access) in pool.agent_access.iter().enumerate() {
+        writeln!(out, "\t\tAgent #{} Access: {}", agent,
+                 or_na(access.map(|a| format!("{:?}", a) )))?;
+      }
+    }
+  }
+
+  if !snapshot.peer_access.is_empty() {
+    writeln!(out, "Peer Access:")?;
+    for peer in snapshot.peer_access.iter() {
+      writeln!(out, "\tAgent #{} -> Agent #{}: {:?}", peer.agent, peer.owner, peer.access)?;
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use crate::test::recorded;
+
+  #[test]
+  fn recorded_text() {
+    let mut out = String::new();
+    write_snapshot(&mut out, &recorded()).unwrap();
+
+    let expected = [
+      "Agent #1:",
+      "\tName = gfx900",
+      "\tFeature = Kernel",
+      "\tCache #0:\n\t\tName: L1\n\t\tLevel: 1\n\t\tSize: 16",
+      "\t\tName: amdgcn-amd-amdhsa--gfx900\n",
+      "\t\tWavefront Sizes: 64\n",
+      "\t\tGlobal Flags: coarse_grained\n",
+      "\t\tAlloc Max Private Workgroup Size: N/A\n",
+      "\t\tAgent #0 Access: DefaultDisallowed\n",
+      "Peer Access:\n\tAgent #1 -> Agent #0: DefaultAllowed\n",
+      "\tAgent #0 -> Agent #1: DefaultDisallowed\n",
+    ];
+    for expected in expected.iter() {
+      assert!(out.contains(expected), "missing {:?} in:\n{}", expected, out);
+    }
+    assert!(out.contains("\tFeature = N/A\n"));
+  }
+}
@@ -0,0 +1,136 @@
+//! Differences between two JSON values, as paths into them, eg
+//! `agents[1].caches[1]`. Used by the tools to compare their JSON output.
+
+use std::fmt;
+
+use serde_json::Value;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Difference {
+  Added {
+    path: String,
+    new: Value,
+  },
+  Removed {
+    path: String,
+    old: Value,
+  },
+  Changed {
+    path: String,
+    old: Value,
+    new: Value,
+  },
+}
+impl fmt::Display for Difference {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Difference::Added { path, new, } => write!(f, "+ {}: {}", path, new),
+      Difference::Removed { path, old, } => write!(f, "- {}: {}", path, old),
+      Difference::Changed { path, old, new, } => {
+        write!(f, "~ {}: {} -> {}", path, old, new)
+      },
+    }
+  }
+}
+
+/// Every difference between `old` and `new`. Object members are matched up by key;
+/// elements of arrays are compared by index.
+pub fn diff(old: &Value, new: &Value) -> Vec<Difference> {
+  let mut out = vec![];
+  diff_values(String::new(), old, new, &mut out);
+  out
+}
+
+fn diff_values(path: String, old: &Value, new: &Value, out: &mut Vec<Difference>) {
+  match (old, new) {
+    (Value::Object(old), Value::Object(new)) => {
+      for (key, old_value) in old.iter() {
+        let path = join(&path, key);
+        match new.get(key) {
+          Some(new_value) => diff_values(path, old_value, new_value, out),
+          None => out.push(Difference::Removed { path, old: old_value.clone(), }),
+        }
+      }
+      for (key, new_value) in new.iter() {
+        if !old.contains_key(key) {
+          out.push(Difference::Added {
+            path: join(&path, key),
+            new: new_value.clone(),
+          });
+        }
+      }
+    },
+    (Value::Array(old), Value::Array(new)) => {
+      for (idx, old_value) in old.iter().enumerate() {
+        let path = format!("{}[{}]", path, idx);
+        match new.get(idx) {
+          Some(new_value) => diff_values(path, old_value, new_value, out),
+          None => out.push(Difference::Removed { path, old: old_value.clone(), }),
+        }
+      }
+      for (idx, new_value) in new.iter().enumerate().skip(old.len()) {
+        out.push(Difference::Added {
+          path: format!("{}[{}]", path, idx),
+          new: new_value.clone(),
+        });
+      }
+    },
+    (old, new) if old != new => {
+      out.push(Difference::Changed {
+        path,
+        old: old.clone(),
+        new: new.clone(),
+      });
+    },
+    _ => { },
+  }
+}
+fn join(path: &str, key: &str) -> String {
+  if path.is_empty() {
+    key.into()
+  } else if key.starts_with('.') {
+    // keys which already start with a dot, eg the code object metadata's `.sgpr_count`.
+    format!("{}{}", path, key)
+  } else {
+    format!("{}.{}", path, key)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use serde_json::json;
+
+  use super::*;
+
+  fn diffs(old: Value, new: Value) -> Vec<String> {
+    diff(&old, &new)
+      .into_iter()
+      .map(|d| d.to_string() )
+      .collect()
+  }
+
+  #[test]
+  fn identical() {
+    let v = json!({ "a": [1, { "b": 2 }], "c": null });
+    assert!(diff(&v, &v).is_empty());
+  }
+
+  #[test]
+  fn paths() {
+    let old = json!({
+      "agents": [{ "caches": [1, 2], "name": "gfx900" }],
+      "meta": { ".sgpr_count": 8 },
+    });
+    let new = json!({
+      "agents": [{ "caches": [1], "name": "gfx906" }, {}],
+      "meta": { ".sgpr_count": 10, ".vgpr_count": 4 },
+    });
+    assert_eq!(diffs(old, new), [
+      "- agents[0].caches[1]: 2",
+      "~ agents[0].name: \"gfx900\" -> \"gfx906\"",
+      "+ agents[1]: {}",
+      "~ meta.sgpr_count: 8 -> 10",
+      "+ meta.vgpr_count: 4",
+    ]);
+  }
+}